@@ -2,20 +2,29 @@
  * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
  * All rights reserved.
  */
-use crate::{error::Error, Result, WebResult};
+use crate::{config::EnvReader, db::DB, error::Error, Result, WebResult};
 use chrono::prelude::*;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{
+    decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
 use lazy_static::lazy_static;
 use log;
+use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fmt;
 use warp::{
     filters::header::headers_cloned,
-    http::header::{HeaderMap, HeaderValue, AUTHORIZATION},
-    reject, Filter, Rejection,
+    http::header::{HeaderMap, HeaderValue, AUTHORIZATION, COOKIE, SET_COOKIE},
+    reject,
+    reply::Response,
+    Filter, Rejection, Reply,
 };
 
 const BEARER: &str = "Bearer ";
+const JWT_COOKIE_NAME: &str = "labyrinth_jwt";
+const JWT_LIFETIME_DAYS: i64 = 30;
+pub const JWT_SECRET_KEY_FILE: &str = "JWT_SECRET_KEY";
 
 pub struct JwtSecretKey {
     pub token: Vec<u8>,
@@ -52,12 +61,104 @@ impl fmt::Display for JwtSecretKey {
     }
 }
 
+/// JWT settings, tunable via `JWT_COOKIE`, `JWT_LEEWAY_SECS`,
+/// `JWT_ACCESS_TOKEN_MINUTES` and `JWT_REFRESH_TOKEN_MINUTES`.
+#[derive(Debug, Clone, Copy)]
+pub struct JwtParams {
+    /// If set, the JWT is additionally issued as a `Secure; HttpOnly`
+    /// cookie, and accepted from it if the request has no `Authorization`
+    /// header.
+    pub cookie: bool,
+    /// Seconds a JWT is still accepted after it has expired, to make up
+    /// for clock skew.
+    pub leeway_secs: u64,
+    /// If set, JWTs expire after this many minutes and clients have to
+    /// exchange a refresh token for a new one via `POST /user/refresh`.
+    pub access_token_minutes: Option<i64>,
+    /// Minutes of inactivity after which a refresh token expires.
+    pub refresh_token_minutes: i64,
+}
+
 lazy_static! {
-    static ref JWT_KEY: JwtSecretKey = JwtSecretKey::new_from_file("JWT_SECRET_KEY");
+    static ref JWT_KEY: JwtSecretKey = JwtSecretKey::new_from_file(JWT_SECRET_KEY_FILE);
+    pub static ref JWT_PARAMS: JwtParams = JwtParams::from_env().unwrap_or_default();
+}
+
+impl Default for JwtParams {
+    fn default() -> Self {
+        JwtParams {
+            cookie: false,
+            leeway_secs: 30,
+            access_token_minutes: None,
+            refresh_token_minutes: 24 * 60,
+        }
+    }
+}
+
+impl JwtParams {
+    /// Reads the parameters from the environment, returning a description
+    /// of every invalid one.
+    pub fn from_env() -> std::result::Result<JwtParams, Vec<String>> {
+        let defaults: JwtParams = JwtParams::default();
+        let mut reader: EnvReader = EnvReader::new();
+        let params = JwtParams {
+            cookie: reader.flag("JWT_COOKIE", defaults.cookie),
+            leeway_secs: reader.parsed("JWT_LEEWAY_SECS", defaults.leeway_secs),
+            access_token_minutes: reader.parsed_option("JWT_ACCESS_TOKEN_MINUTES"),
+            refresh_token_minutes: reader
+                .parsed("JWT_REFRESH_TOKEN_MINUTES", defaults.refresh_token_minutes),
+        };
+        if params
+            .access_token_minutes
+            .map_or(false, |minutes| minutes < 1)
+        {
+            reader.problem("JWT_ACCESS_TOKEN_MINUTES must be at least 1".to_string());
+        }
+        if params.refresh_token_minutes < 1 {
+            reader.problem("JWT_REFRESH_TOKEN_MINUTES must be at least 1".to_string());
+        }
+        reader.finish(params)
+    }
+}
+
+/// Tells if short-lived JWTs plus refresh tokens are issued instead of
+/// long-lived JWTs.
+pub fn refresh_tokens_enabled() -> bool {
+    JWT_PARAMS.access_token_minutes.is_some()
+}
+
+fn jwt_lifetime() -> chrono::Duration {
+    match JWT_PARAMS.access_token_minutes {
+        Some(minutes) => chrono::Duration::minutes(minutes),
+        None => chrono::Duration::days(JWT_LIFETIME_DAYS),
+    }
+}
+
+/// Creates a random refresh token and the digest under which it is
+/// stored. Tokens are random, so a fast digest is sufficient to keep
+/// leaked database contents from being usable as tokens.
+pub fn create_refresh_token() -> (String, String) {
+    let mut bytes: [u8; 32] = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token: String = base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD);
+    let digest: String = refresh_token_digest(&token);
+    (token, digest)
+}
+
+pub fn refresh_token_digest(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Creates a random password reset token and the digest under which it is
+/// stored, see `create_refresh_token()`.
+pub fn create_password_reset_token() -> (String, String) {
+    create_refresh_token()
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, PartialOrd)]
 pub enum Role {
+    /// may watch players, but not play
+    Spectator,
     User,
     Designer,
     Admin,
@@ -70,11 +171,13 @@ impl Default for Role {
 }
 
 impl Role {
-    const RANKING: &'static [&'static Role] = &[&Role::User, &Role::Designer, &Role::Admin];
+    const RANKING: &'static [&'static Role] =
+        &[&Role::Spectator, &Role::User, &Role::Designer, &Role::Admin];
     pub fn from_str(role: &str) -> Role {
         match role.to_ascii_lowercase().as_str() {
             "admin" => Role::Admin,
             "designer" => Role::Designer,
+            "spectator" => Role::Spectator,
             _ => Role::User,
         }
     }
@@ -103,6 +206,7 @@ impl Role {
 impl fmt::Display for Role {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Role::Spectator => write!(f, "Spectator"),
             Role::User => write!(f, "User"),
             Role::Admin => write!(f, "Admin"),
             Role::Designer => write!(f, "Designer"),
@@ -115,53 +219,119 @@ struct Claims {
     sub: String,
     role: String,
     exp: usize,
+    /// the user's `token_version` at the time of issue
+    #[serde(default)]
+    ver: u32,
 }
 
-pub fn with_auth(role: Role) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+/// Lets in requests with a valid JWT for at least `role`. Revoking JWTs on
+/// logout requires comparing their version with the user's, which costs a
+/// database lookup per request (or a cache hit, see `USER_CACHE`).
+pub fn with_auth(
+    role: Role,
+    db: DB,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
     headers_cloned()
-        .map(move |headers: HeaderMap<HeaderValue>| (role.clone(), headers))
+        .map(move |headers: HeaderMap<HeaderValue>| (role.clone(), headers, db.clone()))
         .and_then(authorize)
 }
 
-pub fn create_jwt(uid: &str, role: &Role) -> Result<String> {
-    let expiration: i64 = Utc::now()
-        .checked_add_signed(chrono::Duration::days(30))
+pub fn create_jwt(
+    uid: &str,
+    role: &Role,
+    token_version: u32,
+    now: DateTime<Utc>,
+) -> Result<String> {
+    let expiration: i64 = now
+        .checked_add_signed(jwt_lifetime())
         .expect("valid timestamp")
         .timestamp();
     let claims: Claims = Claims {
         sub: uid.to_owned(),
         role: role.to_string(),
         exp: expiration as usize,
+        ver: token_version,
     };
     let header: jsonwebtoken::Header = Header::new(Algorithm::HS512);
     encode(&header, &claims, &EncodingKey::from_secret(&JWT_KEY.token))
         .map_err(|_| Error::JWTTokenCreationError)
 }
 
-async fn authorize((role, headers): (Role, HeaderMap<HeaderValue>)) -> WebResult<String> {
+pub async fn authorize(
+    (role, headers, db): (Role, HeaderMap<HeaderValue>, DB),
+) -> WebResult<String> {
     match jwt_from_header(&headers) {
         Ok(jwt) => {
-            log::info!("JWT = {}", &jwt);
-            // TODO: check if token has expired
-            let decoded = decode::<Claims>(
-                &jwt,
-                &DecodingKey::from_secret(&JWT_KEY.token),
-                &Validation::new(Algorithm::HS512),
-            )
-            .map_err(|_| reject::custom(Error::JWTTokenError))?;
-            if role == Role::Admin && Role::from_str(&decoded.claims.role) != Role::Admin {
+            let mut validation: Validation = Validation::new(Algorithm::HS512);
+            validation.leeway = JWT_PARAMS.leeway_secs;
+            let decoded =
+                decode::<Claims>(&jwt, &DecodingKey::from_secret(&JWT_KEY.token), &validation)
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::ExpiredSignature => reject::custom(Error::JWTTokenExpiredError),
+                        _ => reject::custom(Error::JWTTokenError),
+                    })?;
+            let token_role: Role = Role::from_str(&decoded.claims.role);
+            // roles rank Spectator < User < Designer < Admin, so e.g. spectators
+            // are let in only where explicitly allowed
+            if !token_role.ge(&role) {
                 return Err(reject::custom(Error::NoPermissionError));
             }
+            match db.get_token_version(&decoded.claims.sub).await {
+                Ok(version) if version == decoded.claims.ver => (),
+                Ok(_) => return Err(reject::custom(Error::JWTTokenRevokedError)),
+                Err(Error::UserNotFoundError) => return Err(reject::custom(Error::JWTTokenError)),
+                Err(e) => return Err(reject::custom(e)),
+            }
             Ok(decoded.claims.sub)
         }
         Err(e) => return Err(reject::custom(e)),
     }
 }
 
+/// Adds a `Set-Cookie` header carrying `jwt` to `reply` if JWT cookies
+/// are enabled.
+pub fn with_jwt_cookie(reply: impl Reply, jwt: &Option<String>) -> Response {
+    let mut response: Response = reply.into_response();
+    if let (true, Some(jwt)) = (JWT_PARAMS.cookie, jwt) {
+        let cookie: String = format!(
+            "{}={}; Path=/; Max-Age={}; Secure; HttpOnly; SameSite=Strict",
+            JWT_COOKIE_NAME,
+            jwt,
+            jwt_lifetime().num_seconds()
+        );
+        match HeaderValue::from_str(&cookie) {
+            Ok(cookie) => {
+                response.headers_mut().insert(SET_COOKIE, cookie);
+            }
+            Err(e) => log::error!("cannot build JWT cookie: {:?}", e),
+        }
+    }
+    response
+}
+
+fn jwt_from_cookie(headers: &HeaderMap<HeaderValue>) -> Option<String> {
+    let prefix: String = format!("{}=", JWT_COOKIE_NAME);
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .map(|cookie| cookie.trim())
+        .find(|cookie| cookie.starts_with(&prefix))
+        .map(|cookie| cookie.trim_start_matches(&prefix).to_owned())
+}
+
 fn jwt_from_header(headers: &HeaderMap<HeaderValue>) -> Result<String> {
     let header: &warp::http::HeaderValue = match headers.get(AUTHORIZATION) {
         Some(v) => v,
-        None => return Err(Error::NoAuthHeaderError),
+        None => {
+            if JWT_PARAMS.cookie {
+                if let Some(jwt) = jwt_from_cookie(headers) {
+                    return Ok(jwt);
+                }
+            }
+            return Err(Error::NoAuthHeaderError);
+        }
     };
     let auth_header: &str = match std::str::from_utf8(header.as_bytes()) {
         Ok(v) => v,
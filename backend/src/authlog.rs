@@ -0,0 +1,122 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::config::EnvReader;
+use lazy_static::lazy_static;
+use log::Level;
+use serde::Serialize;
+use std::net::SocketAddr;
+use warp::{Filter, Rejection};
+
+lazy_static! {
+    /// If set, authentication events are logged as one JSON object per
+    /// line, for consumption by a SIEM.
+    static ref AUTH_LOG_JSON: bool = EnvReader::new().flag("AUTH_LOG_JSON", false);
+}
+
+/// Where a request came from, as far as the server can tell.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    /// claimed by the client or a proxy in between, not verified
+    pub forwarded_for: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// The address the request came from, or "" if unknown. Unlike
+    /// `forwarded_for`, it can't be chosen by the client.
+    pub fn ip(&self) -> &str {
+        self.ip.as_deref().unwrap_or("")
+    }
+}
+
+pub fn with_client_info() -> impl Filter<Extract = (ClientInfo,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(
+            |remote: Option<SocketAddr>,
+             forwarded_for: Option<String>,
+             user_agent: Option<String>| {
+                ClientInfo {
+                    ip: remote.map(|addr| addr.ip().to_string()),
+                    forwarded_for,
+                    user_agent,
+                }
+            },
+        )
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthEvent {
+    LoginSucceeded,
+    LoginFailed,
+    LoggedOut,
+    SecondFactorSucceeded,
+    SecondFactorFailed,
+    AccountLocked,
+    PasswordChanged,
+    RoleChanged,
+}
+
+impl AuthEvent {
+    fn level(&self) -> Level {
+        match self {
+            AuthEvent::LoginFailed | AuthEvent::SecondFactorFailed | AuthEvent::AccountLocked => {
+                Level::Warn
+            }
+            _ => Level::Info,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct AuthLogRecord<'a> {
+    event: AuthEvent,
+    username: &'a str,
+    #[serde(flatten)]
+    client: &'a ClientInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+}
+
+/// Logs an authentication outcome under the `auth` target. Neither
+/// `error_kind` nor `detail` may contain secrets.
+pub fn log_auth_event(
+    event: AuthEvent,
+    username: &str,
+    client: &ClientInfo,
+    error_kind: Option<&str>,
+    detail: Option<&str>,
+) {
+    let record = AuthLogRecord {
+        event,
+        username,
+        client,
+        error_kind,
+        detail,
+    };
+    match *AUTH_LOG_JSON {
+        true => match serde_json::to_string(&record) {
+            Ok(line) => log::log!(target: "auth", event.level(), "{}", line),
+            Err(e) => log::error!("Error: serializing auth event failed: {}", e),
+        },
+        false => log::log!(
+            target: "auth",
+            event.level(),
+            "{:?}: username = {}, ip = {}, forwarded_for = {}, user_agent = {}, error_kind = {}, detail = {}",
+            event,
+            username,
+            client.ip.as_deref().unwrap_or("-"),
+            client.forwarded_for.as_deref().unwrap_or("-"),
+            client.user_agent.as_deref().unwrap_or("-"),
+            error_kind.unwrap_or("-"),
+            detail.unwrap_or("-")
+        ),
+    }
+}
@@ -1,3 +1,4 @@
+use log;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 pub fn serialize<S: Serializer>(v: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
@@ -6,5 +7,30 @@ pub fn serialize<S: Serializer>(v: &Vec<u8>, s: S) -> Result<S::Ok, S::Error> {
 }
 pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
     let decoded: String = String::deserialize(d)?;
-    base64::decode(decoded.as_bytes()).map_err(|e| serde::de::Error::custom(e))
+    decode(&decoded).map_err(|e| serde::de::Error::custom(e))
+}
+
+/// Tells whether `encoded` looks like RFC 4648 base32 rather than base64.
+/// Keys have always been written as base64, which practically never
+/// consists of upper-case letters and the digits 2-7 only.
+pub fn is_base32(encoded: &str) -> bool {
+    !encoded.is_empty()
+        && encoded
+            .trim_end_matches('=')
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || ('2'..='7').contains(&c))
+}
+
+/// Decodes base64, falling back to base32 for values stored that way
+/// in the past.
+pub fn decode(encoded: &str) -> Result<Vec<u8>, String> {
+    if is_base32(encoded) {
+        log::warn!("decoding base32-encoded key, please run the migration");
+        return base32::decode(
+            base32::Alphabet::RFC4648 { padding: false },
+            encoded.trim_end_matches('='),
+        )
+        .ok_or("invalid base32".to_string());
+    }
+    base64::decode(encoded.as_bytes()).map_err(|e| e.to_string())
 }
@@ -0,0 +1,191 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::{error::Error, Result};
+use log;
+use serde::Deserialize;
+use std::env;
+
+const HCAPTCHA_VERIFY_URL: &str = "https://hcaptcha.com/siteverify";
+const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+/// Challenge a client has to pass before a registration is accepted.
+/// Selected via `REGISTRATION_CHALLENGE` (`none`, `hcaptcha`, `turnstile`
+/// or `pow`); disabled by default.
+#[derive(Debug, Clone)]
+pub enum RegistrationChallenge {
+    None,
+    Captcha { verify_url: String, secret: String },
+    ProofOfWork { difficulty: u32 },
+}
+
+#[derive(Deserialize, Debug)]
+struct CaptchaVerifyResponse {
+    success: bool,
+}
+
+impl RegistrationChallenge {
+    /// Reads the challenge from the environment, returning a description
+    /// of every problem, e.g. a captcha without `CAPTCHA_SECRET`.
+    pub fn from_env() -> std::result::Result<RegistrationChallenge, Vec<String>> {
+        let kind: String = env::var("REGISTRATION_CHALLENGE")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match kind.as_str() {
+            "hcaptcha" | "turnstile" => {
+                let verify_url: &str = match kind.as_str() {
+                    "hcaptcha" => HCAPTCHA_VERIFY_URL,
+                    _ => TURNSTILE_VERIFY_URL,
+                };
+                match env::var("CAPTCHA_SECRET") {
+                    Ok(secret) if !secret.is_empty() => Ok(RegistrationChallenge::Captcha {
+                        verify_url: verify_url.to_string(),
+                        secret,
+                    }),
+                    _ => Err(vec![format!(
+                        "CAPTCHA_SECRET has not been set, but REGISTRATION_CHALLENGE is {}",
+                        &kind
+                    )]),
+                }
+            }
+            "pow" => match env::var("POW_DIFFICULTY") {
+                Err(_) => Ok(RegistrationChallenge::ProofOfWork { difficulty: 20 }),
+                Ok(value) => match value.parse() {
+                    Ok(difficulty) if difficulty <= 128 => {
+                        Ok(RegistrationChallenge::ProofOfWork { difficulty })
+                    }
+                    _ => Err(vec![format!(
+                        "POW_DIFFICULTY = {:?} is not valid (0 to 128 bits)",
+                        &value
+                    )]),
+                },
+            },
+            "" | "none" => Ok(RegistrationChallenge::None),
+            _ => Err(vec![format!(
+                "REGISTRATION_CHALLENGE = {:?} is not one of none, hcaptcha, turnstile, pow",
+                &kind
+            )]),
+        }
+    }
+
+    /// Checks the `response` the client sent along with the registration
+    /// of `username` <`email`>.
+    pub async fn verify(
+        &self,
+        username: &String,
+        email: &String,
+        response: &Option<String>,
+    ) -> Result<()> {
+        let response: &String = match (self, response) {
+            (RegistrationChallenge::None, _) => return Ok(()),
+            (_, Some(response)) => response,
+            (_, None) => return Err(Error::ChallengeFailedError),
+        };
+        let passed: bool = match self {
+            RegistrationChallenge::None => true,
+            RegistrationChallenge::Captcha { verify_url, secret } => {
+                verify_captcha(verify_url, secret, response).await
+            }
+            RegistrationChallenge::ProofOfWork { difficulty } => {
+                verify_proof_of_work(username, email, response, *difficulty)
+            }
+        };
+        match passed {
+            true => Ok(()),
+            false => Err(Error::ChallengeFailedError),
+        }
+    }
+}
+
+async fn verify_captcha(verify_url: &String, secret: &String, token: &String) -> bool {
+    let client = reqwest::Client::new();
+    let result = client
+        .post(verify_url)
+        .form(&[("secret", secret), ("response", token)])
+        .send()
+        .await;
+    match result {
+        Ok(response) => match response.json::<CaptchaVerifyResponse>().await {
+            Ok(verification) => verification.success,
+            Err(e) => {
+                log::error!("cannot parse captcha verification: {:?}", e);
+                false
+            }
+        },
+        Err(e) => {
+            log::error!("captcha verification failed: {:?}", e);
+            false
+        }
+    }
+}
+
+/// The client has to find a `nonce` so that the MD5 hash of
+/// `"{username}:{email}:{nonce}"` starts with at least `difficulty` zero bits.
+fn verify_proof_of_work(
+    username: &String,
+    email: &String,
+    nonce: &String,
+    difficulty: u32,
+) -> bool {
+    let digest = md5::compute(format!("{}:{}:{}", username, email, nonce).as_bytes());
+    u128::from_be_bytes(digest.0).leading_zeros() >= difficulty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Finds a nonce the way a client would.
+    fn solve(username: &String, email: &String, difficulty: u32) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| verify_proof_of_work(username, email, nonce, difficulty))
+            .unwrap()
+    }
+
+    #[test]
+    fn proof_of_work_meets_the_difficulty() {
+        let username: String = "alice".to_string();
+        let email: String = "alice@example.com".to_string();
+        let nonce: String = solve(&username, &email, 8);
+        assert!(verify_proof_of_work(&username, &email, &nonce, 8));
+        assert!(verify_proof_of_work(&username, &email, &nonce, 0));
+        assert!(!verify_proof_of_work(&username, &email, &nonce, 129));
+    }
+
+    #[tokio::test]
+    async fn registration_without_valid_challenge_is_rejected() {
+        let challenge = RegistrationChallenge::ProofOfWork { difficulty: 8 };
+        let username: String = "alice".to_string();
+        let email: String = "alice@example.com".to_string();
+        assert!(matches!(
+            challenge.verify(&username, &email, &None).await,
+            Err(Error::ChallengeFailedError)
+        ));
+        let nonce: String = solve(&username, &email, 8);
+        // a nonce that doesn't meet the difficulty
+        let weak: String = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| !verify_proof_of_work(&username, &email, nonce, 8))
+            .unwrap();
+        assert!(matches!(
+            challenge.verify(&username, &email, &Some(weak)).await,
+            Err(Error::ChallengeFailedError)
+        ));
+        assert!(challenge
+            .verify(&username, &email, &Some(nonce))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn no_challenge_accepts_every_registration() {
+        let username: String = "alice".to_string();
+        let email: String = "alice@example.com".to_string();
+        assert!(RegistrationChallenge::None
+            .verify(&username, &email, &None)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,514 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::{
+    auth::{JwtParams, JWT_PARAMS, JWT_SECRET_KEY_FILE},
+    challenge::RegistrationChallenge,
+    email::EmailDomainCheck,
+    error::Error,
+    maintenance::MaintenanceMode,
+    passwd::{Argon2Params, ARGON2_PARAMS},
+    totp::{TotpParams, TOTP_PARAMS},
+    Result,
+};
+use bson::oid::ObjectId;
+use chrono::{DateTime, Utc};
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use warp::Filter;
+
+/// Settings the server cannot run without, read once at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub db_url: String,
+    pub db_name: String,
+    pub coll_users: String,
+    pub coll_riddles: String,
+    pub coll_rooms: String,
+    pub coll_games: String,
+    pub coll_room_visits: String,
+    pub coll_attempts: String,
+    pub coll_audit_log: String,
+    pub coll_refresh_tokens: String,
+    pub coll_email_outbox: String,
+    /// game entered on activation if the user didn't choose one
+    pub default_game_id: Option<ObjectId>,
+    pub max_concurrent_aggregations: usize,
+    pub aggregation_timeout: Duration,
+    pub user_cache: bool,
+    pub user_cache_ttl: Duration,
+    /// users cached at most
+    pub user_cache_size: usize,
+    pub api_host: SocketAddr,
+    /// address `/metrics` is served on, apart from the API so that it can
+    /// be kept internal; not served at all if `None`
+    pub metrics_host: Option<SocketAddr>,
+    pub rp_name: String,
+    pub rp_origin: String,
+    pub rp_id: String,
+    pub bad_passwords_md5: String,
+    pub recovery_rate_limit: usize,
+    pub recovery_rate_limit_window: Duration,
+    pub maintenance_mode: MaintenanceMode,
+    pub maintenance_notice: Option<String>,
+    /// where uploaded riddle files are served from, without trailing slash
+    pub upload_url: String,
+    /// directory served at `upload_url`; uploads are refused if unset
+    pub upload_dir: Option<String>,
+    /// largest riddle file accepted for upload, in bytes
+    pub max_upload_bytes: u64,
+    /// largest body accepted by the game and user imports, in bytes
+    pub max_import_bytes: u64,
+    /// largest body accepted by the batch solve, in bytes
+    pub max_solve_batch_bytes: u64,
+    /// solving is only possible from `event_start` until `event_end`
+    pub event_start: Option<DateTime<Utc>>,
+    pub event_end: Option<DateTime<Utc>>,
+    /// riddles have to be solved in order of their level in all games, not
+    /// only in those with `Game::level_gating`
+    pub level_gating: bool,
+    /// a riddle can only be solved from the room it was opened in, so that
+    /// riddles can't be fetched in one room and answered in another
+    pub strict_riddle_room: bool,
+    /// highest level a riddle may have; a user's level is clamped to it
+    pub max_riddle_level: u32,
+    /// deduction for wrong answers if neither the riddle nor its game
+    /// defines one
+    pub default_deduction: i32,
+    /// longest answer accepted for riddles that don't set their own limit
+    pub max_solution_length: usize,
+    /// solves within this many seconds of first seeing the riddle earn a
+    /// time bonus, see `score_for_attempt()`
+    pub time_bonus_window_secs: Option<i64>,
+    /// riddles at least this difficult count as medium in the score
+    /// breakdown
+    pub difficulty_tier_medium: i32,
+    /// riddles at least this difficult count as hard
+    pub difficulty_tier_hard: i32,
+    /// a revealed solution costs this many times the riddle's difficulty
+    pub reveal_penalty_factor: i32,
+    /// solutions accepted by a single batch solve at most
+    pub max_solve_batch_size: usize,
+    /// seconds a user has to wait after a wrong answer before trying the
+    /// same riddle again
+    pub solve_cooldown_secs: i64,
+    /// points deducted for each hint a user unlocks
+    pub hint_penalty: i32,
+    /// debriefings name the user who solved the riddle first
+    pub public_first_blood: bool,
+    /// number of solves shown to spectators
+    pub spectate_recent_solves: usize,
+    /// how long the mail worker waits when the outbox is empty
+    pub mail_poll_interval: Duration,
+    /// delivery attempts before a mail is marked as failed
+    pub mail_max_attempts: u32,
+    /// time after which a mail still in `Sending` is assumed to be stuck,
+    /// e.g. because the server stopped while sending it, and is tried again
+    pub mail_sending_timeout: Duration,
+    /// what a client has to pass to register, see `REGISTRATION_CHALLENGE`
+    pub registration_challenge: RegistrationChallenge,
+    pub email_domain_check: EmailDomainCheck,
+    /// login responses leave out the `status` discriminator for old clients
+    pub legacy_login_response: bool,
+    /// registration tells whether a username or email address is already
+    /// taken. Convenient in development, but lets strangers find out who
+    /// has an account.
+    pub reveal_taken_accounts: bool,
+    /// sessions (refresh token families) per user at most; only enforced if
+    /// refresh tokens are enabled, as plain JWTs are stateless
+    pub max_sessions: Option<usize>,
+    /// a login beyond `max_sessions` ends the least recently refreshed
+    /// session instead of being rejected
+    pub evict_oldest_session: bool,
+    /// minutes a mailed password reset token stays valid
+    pub password_reset_token_minutes: i64,
+    /// failed logins in a row after which an account is locked
+    pub login_max_failed_attempts: u32,
+    /// minutes an account stays locked after too many failed logins
+    pub login_lockout_minutes: i64,
+    /// minutes during which a retried activation with the same PIN gets the
+    /// same answer as the first one
+    pub activation_replay_minutes: i64,
+    pub jwt: JwtParams,
+    pub argon2: Argon2Params,
+    pub totp: TotpParams,
+}
+
+/// Collects all problems found while reading the environment, so that
+/// they can be reported in one go. Settings that live with the code they
+/// tune, like `JwtParams`, are read with it as well.
+#[derive(Default)]
+pub struct EnvReader {
+    problems: Vec<String>,
+}
+
+impl EnvReader {
+    pub fn new() -> EnvReader {
+        EnvReader::default()
+    }
+
+    /// Returns `value`, or all problems found while reading it.
+    pub fn finish<T>(self, value: T) -> std::result::Result<T, Vec<String>> {
+        match self.problems.is_empty() {
+            true => Ok(value),
+            false => Err(self.problems),
+        }
+    }
+
+    pub fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    fn required(&mut self, name: &str) -> String {
+        match env::var(name) {
+            Ok(value) if !value.is_empty() => value,
+            _ => {
+                self.problems.push(format!("{} has not been set", name));
+                String::new()
+            }
+        }
+    }
+
+    pub fn optional(&mut self, name: &str, default: &str) -> String {
+        match env::var(name) {
+            Ok(value) if !value.is_empty() => value,
+            _ => default.to_string(),
+        }
+    }
+
+    pub fn parsed<T: FromStr>(&mut self, name: &str, default: T) -> T {
+        match env::var(name) {
+            Ok(value) => match value.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    self.problems
+                        .push(format!("{} = {:?} is not valid", name, &value));
+                    default
+                }
+            },
+            Err(_) => default,
+        }
+    }
+
+    /// Reads a setting that is off unless given.
+    pub fn parsed_option<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        match env::var(name) {
+            Ok(value) if !value.is_empty() => match value.parse() {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    self.problems
+                        .push(format!("{} = {:?} is not valid", name, &value));
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Reads a switch, which is on if set to "true" or "1".
+    pub fn flag(&mut self, name: &str, default: bool) -> bool {
+        match env::var(name) {
+            Ok(value) if !value.is_empty() => value == "true" || value == "1",
+            _ => default,
+        }
+    }
+
+    /// Reads an RFC 3339 date like "2022-12-24T18:00:00+01:00".
+    fn time(&mut self, name: &str) -> Option<DateTime<Utc>> {
+        match env::var(name) {
+            Ok(value) if !value.is_empty() => match DateTime::parse_from_rfc3339(&value) {
+                Ok(time) => Some(time.with_timezone(&Utc)),
+                Err(_) => {
+                    self.problems
+                        .push(format!("{} = {:?} is not an RFC 3339 date", name, &value));
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    pub fn file(&mut self, what: &str, path: &str) {
+        if !path.is_empty() && !Path::new(path).is_file() {
+            self.problems
+                .push(format!("{} '{}' cannot be found", what, path));
+        }
+    }
+
+    fn dir(&mut self, what: &str, path: &str) {
+        if !path.is_empty() && !Path::new(path).is_dir() {
+            self.problems
+                .push(format!("{} '{}' is not a directory", what, path));
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config> {
+        let mut reader = EnvReader::new();
+        let db_url: String = reader.required("DB_URL");
+        let db_name: String = reader.required("DB_NAME");
+        let coll_users: String = reader.required("DB_COLL_USERS");
+        let coll_riddles: String = reader.required("DB_COLL_RIDDLES");
+        let coll_rooms: String = reader.required("DB_COLL_ROOMS");
+        let coll_games: String = reader.optional("DB_COLL_GAMES", "games");
+        let coll_room_visits: String = reader.optional("DB_COLL_ROOM_VISITS", "room_visits");
+        let coll_attempts: String = reader.optional("DB_COLL_ATTEMPTS", "attempts");
+        let coll_audit_log: String = reader.optional("DB_COLL_AUDIT_LOG", "audit_log");
+        let coll_refresh_tokens: String =
+            reader.optional("DB_COLL_REFRESH_TOKENS", "refresh_tokens");
+        let coll_email_outbox: String = reader.optional("DB_COLL_EMAIL_OUTBOX", "email_outbox");
+        let default_game_id: String = reader.optional("DEFAULT_GAME_ID", "");
+        let default_game_id: Option<ObjectId> = match default_game_id.as_str() {
+            "" => None,
+            id => match ObjectId::parse_str(id) {
+                Ok(id) => Some(id),
+                Err(_) => {
+                    reader.problems.push(format!(
+                        "DEFAULT_GAME_ID = {:?} is not a valid ObjectId",
+                        id
+                    ));
+                    None
+                }
+            },
+        };
+        let max_concurrent_aggregations: usize = reader.parsed("DB_MAX_CONCURRENT_AGGREGATIONS", 4);
+        let aggregation_timeout_ms: u64 = reader.parsed("DB_AGGREGATION_TIMEOUT_MS", 2000);
+        let user_cache: bool = reader.flag("USER_CACHE", false);
+        let user_cache_ttl_ms: u64 = reader.parsed("USER_CACHE_TTL_MS", 2000);
+        let user_cache_size: usize = reader.parsed("USER_CACHE_SIZE", 10000);
+        let api_host: String = reader.required("API_HOST");
+        let api_host: Option<SocketAddr> = match api_host.parse() {
+            Ok(addr) => Some(addr),
+            Err(_) => {
+                if !api_host.is_empty() {
+                    reader
+                        .problems
+                        .push(format!("API_HOST = {:?} is not a valid address", &api_host));
+                }
+                None
+            }
+        };
+        let metrics_host: String = reader.optional("METRICS_HOST", "");
+        let metrics_host: Option<SocketAddr> = match metrics_host.as_str() {
+            "" => None,
+            addr => match addr.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    reader
+                        .problems
+                        .push(format!("METRICS_HOST = {:?} is not a valid address", addr));
+                    None
+                }
+            },
+        };
+        let rp_name: String = reader.required("RP_NAME");
+        let rp_origin: String = reader.required("RP_ORIGIN");
+        if !rp_origin.is_empty() && Url::parse(&rp_origin).is_err() {
+            reader
+                .problems
+                .push(format!("RP_ORIGIN = {:?} is not a valid URL", &rp_origin));
+        }
+        let rp_id: String = reader.required("RP_ID");
+        let bad_passwords_md5: String = reader.required("BAD_PASSWORDS_MD5");
+        reader.file("BAD_PASSWORDS_MD5 file", &bad_passwords_md5);
+        reader.file("JWT secret key file", JWT_SECRET_KEY_FILE);
+        let recovery_rate_limit: usize = reader.parsed("RECOVERY_RATE_LIMIT", 5);
+        let recovery_rate_limit_window_secs: u64 =
+            reader.parsed("RECOVERY_RATE_LIMIT_WINDOW_SECS", 900);
+        let maintenance_mode: MaintenanceMode =
+            reader.parsed("MAINTENANCE_MODE", MaintenanceMode::Off);
+        let maintenance_notice: String = reader.optional("MAINTENANCE_NOTICE", "");
+        let upload_url: String = reader.optional("UPLOAD_URL", "/upload");
+        let upload_dir: String = reader.optional("UPLOAD_DIR", "");
+        reader.dir("UPLOAD_DIR", &upload_dir);
+        let max_upload_bytes: u64 = reader.parsed("MAX_UPLOAD_BYTES", 10 * 1024 * 1024);
+        let max_import_bytes: u64 = reader.parsed("MAX_IMPORT_BYTES", 16 * 1024 * 1024);
+        let max_solve_batch_bytes: u64 = reader.parsed("MAX_SOLVE_BATCH_BYTES", 1024 * 1024);
+        let event_start: Option<DateTime<Utc>> = reader.time("EVENT_START");
+        let event_end: Option<DateTime<Utc>> = reader.time("EVENT_END");
+        if let (Some(start), Some(end)) = (event_start, event_end) {
+            if start >= end {
+                reader
+                    .problems
+                    .push("EVENT_START must be before EVENT_END".to_string());
+            }
+        }
+        let level_gating: bool = reader.flag("LEVEL_GATING", false);
+        let strict_riddle_room: bool = reader.flag("STRICT_RIDDLE_ROOM", false);
+        let max_riddle_level: u32 = reader.parsed("MAX_RIDDLE_LEVEL", 1000);
+        let default_deduction: i32 = reader.parsed("DEFAULT_DEDUCTION", 0);
+        let max_solution_length: usize = reader.parsed("MAX_SOLUTION_LENGTH", 1024);
+        let time_bonus_window_secs: i64 = reader.parsed("TIME_BONUS_WINDOW_SECS", 0);
+        let difficulty_tier_medium: i32 = reader.parsed("DIFFICULTY_TIER_MEDIUM", 5);
+        let difficulty_tier_hard: i32 = reader.parsed("DIFFICULTY_TIER_HARD", 10);
+        let reveal_penalty_factor: i32 = reader.parsed("REVEAL_PENALTY_FACTOR", 2);
+        let max_solve_batch_size: usize = reader.parsed("MAX_SOLVE_BATCH_SIZE", 20);
+        let solve_cooldown_secs: i64 = reader.parsed("SOLVE_COOLDOWN_SECS", 5);
+        let hint_penalty: i32 = reader.parsed("HINT_PENALTY", 1);
+        let public_first_blood: bool = reader.flag("PUBLIC_FIRST_BLOOD", false);
+        let spectate_recent_solves: usize = reader.parsed("SPECTATE_RECENT_SOLVES", 10);
+        let mail_poll_interval_secs: u64 = reader.parsed("MAIL_POLL_INTERVAL_SECS", 5);
+        let mail_max_attempts: u32 = reader.parsed("MAIL_MAX_ATTEMPTS", 10);
+        let mail_sending_timeout_secs: u64 = reader.parsed("MAIL_SENDING_TIMEOUT_SECS", 600);
+        let registration_challenge: RegistrationChallenge = match RegistrationChallenge::from_env()
+        {
+            Ok(challenge) => challenge,
+            Err(problems) => {
+                reader.problems.extend(problems);
+                RegistrationChallenge::None
+            }
+        };
+        let email_domain_check: EmailDomainCheck = match EmailDomainCheck::from_env() {
+            Ok(check) => check,
+            Err(problems) => {
+                reader.problems.extend(problems);
+                EmailDomainCheck::default()
+            }
+        };
+        let legacy_login_response: bool = reader.flag("LEGACY_LOGIN_RESPONSE", false);
+        let reveal_taken_accounts: bool = reader.flag("REVEAL_TAKEN_ACCOUNTS", false);
+        let max_sessions: Option<usize> = reader.parsed_option("MAX_SESSIONS");
+        if max_sessions == Some(0) {
+            reader
+                .problems
+                .push("MAX_SESSIONS must be at least 1".to_string());
+        }
+        let evict_oldest_session: bool = reader.flag("EVICT_OLDEST_SESSION", true);
+        let password_reset_token_minutes: i64 = reader.parsed("PASSWORD_RESET_TOKEN_MINUTES", 60);
+        let login_max_failed_attempts: u32 = reader.parsed("LOGIN_MAX_FAILED_ATTEMPTS", 5);
+        let login_lockout_minutes: i64 = reader.parsed("LOGIN_LOCKOUT_MINUTES", 15);
+        let activation_replay_minutes: i64 = reader.parsed("ACTIVATION_REPLAY_MINUTES", 10);
+        if let Err(problems) = JwtParams::from_env() {
+            reader.problems.extend(problems);
+        }
+        if let Err(problems) = Argon2Params::from_env() {
+            reader.problems.extend(problems);
+        }
+        if let Err(problems) = TotpParams::from_env() {
+            reader.problems.extend(problems);
+        }
+        if !reader.problems.is_empty() {
+            return Err(Error::ConfigError(reader.problems));
+        }
+        Ok(Config {
+            db_url,
+            db_name,
+            coll_users,
+            coll_riddles,
+            coll_rooms,
+            coll_games,
+            coll_room_visits,
+            coll_attempts,
+            coll_audit_log,
+            coll_refresh_tokens,
+            coll_email_outbox,
+            default_game_id,
+            max_concurrent_aggregations,
+            aggregation_timeout: Duration::from_millis(aggregation_timeout_ms),
+            user_cache,
+            user_cache_ttl: Duration::from_millis(user_cache_ttl_ms),
+            user_cache_size,
+            api_host: api_host.unwrap(),
+            metrics_host,
+            rp_name,
+            rp_origin,
+            rp_id,
+            bad_passwords_md5,
+            recovery_rate_limit,
+            recovery_rate_limit_window: Duration::from_secs(recovery_rate_limit_window_secs),
+            maintenance_mode,
+            maintenance_notice: match maintenance_notice.is_empty() {
+                true => None,
+                false => Some(maintenance_notice),
+            },
+            upload_url: upload_url.trim_end_matches('/').to_string(),
+            upload_dir: match upload_dir.is_empty() {
+                true => None,
+                false => Some(upload_dir),
+            },
+            max_upload_bytes,
+            max_import_bytes,
+            max_solve_batch_bytes,
+            event_start,
+            event_end,
+            level_gating,
+            strict_riddle_room,
+            max_riddle_level,
+            default_deduction,
+            max_solution_length,
+            time_bonus_window_secs: match time_bonus_window_secs {
+                0 => None,
+                secs => Some(secs),
+            },
+            difficulty_tier_medium,
+            difficulty_tier_hard,
+            reveal_penalty_factor,
+            max_solve_batch_size,
+            solve_cooldown_secs,
+            hint_penalty,
+            public_first_blood,
+            spectate_recent_solves,
+            mail_poll_interval: Duration::from_secs(mail_poll_interval_secs),
+            mail_max_attempts,
+            mail_sending_timeout: Duration::from_secs(mail_sending_timeout_secs),
+            registration_challenge,
+            email_domain_check,
+            legacy_login_response,
+            reveal_taken_accounts,
+            max_sessions,
+            evict_oldest_session,
+            password_reset_token_minutes,
+            login_max_failed_attempts,
+            login_lockout_minutes,
+            activation_replay_minutes,
+            jwt: *JWT_PARAMS,
+            argon2: *ARGON2_PARAMS,
+            totp: *TOTP_PARAMS,
+        })
+    }
+}
+
+pub fn with_config(
+    config: Arc<Config>,
+) -> impl Filter<Extract = (Arc<Config>,), Error = Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_problems_are_reported_together() {
+        for name in ["DB_URL", "DB_NAME", "RP_ID"] {
+            env::remove_var(name);
+        }
+        env::set_var("LOGIN_MAX_FAILED_ATTEMPTS", "many");
+        let problems: Vec<String> = match Config::from_env() {
+            Err(Error::ConfigError(problems)) => problems,
+            other => panic!("expected ConfigError, got {:?}", other),
+        };
+        env::remove_var("LOGIN_MAX_FAILED_ATTEMPTS");
+        for expected in [
+            "DB_URL has not been set",
+            "DB_NAME has not been set",
+            "RP_ID has not been set",
+            "LOGIN_MAX_FAILED_ATTEMPTS = \"many\" is not valid",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem == expected),
+                "{:?} missing in {:?}",
+                expected,
+                &problems
+            );
+        }
+    }
+}
@@ -2,20 +2,33 @@
  * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
  * All rights reserved.
  */
-use crate::{auth::Role, b64, error::Error::*, passwd::Password, Result};
+use crate::{
+    auth::Role, b64, clock::SharedClock, config::Config, error::Error::*, import::GameImport,
+    passwd::Password, Result, OPPOSITE,
+};
 use bson::oid::ObjectId;
-use chrono::{serde::ts_seconds_option, DateTime, Utc};
+use chrono::{
+    serde::{ts_seconds, ts_seconds_option},
+    DateTime, Utc,
+};
 use futures::stream::{StreamExt, TryStreamExt};
 use log;
 use mongodb::bson::doc;
-use mongodb::options::{ClientOptions, FindOneOptions, FindOptions, UpdateOptions};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{
+    ClientOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions,
+    ReturnDocument, UpdateOptions,
+};
 use mongodb::results::UpdateResult;
-use mongodb::{Client, Collection, Database};
+use mongodb::{Client, ClientSession, Collection, Database, IndexModel};
 use rand::{distributions::Distribution, Rng};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::Infallible;
-use std::env;
 use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use warp::Filter;
 use webauthn_rs::proto::{Authentication, AuthenticatorData, Credential, CredentialID};
 use webauthn_rs::{AuthenticationState, RegistrationState};
@@ -59,6 +72,10 @@ pub struct Riddle {
     #[serde(default)]
     pub files: Option<Vec<UploadedFile>>,
     pub ignore_case: Option<bool>,
+    /// Language whose case rules apply if `ignore_case` is set, e.g. "tr";
+    /// see `fold_case()`.
+    #[serde(default)]
+    pub comparison_locale: Option<String>,
     #[serde(default)]
     pub solution: String,
     #[serde(default)]
@@ -71,6 +88,72 @@ pub struct Riddle {
     pub script: Option<String>,
     #[serde(default)]
     pub external_password_input: bool,
+    /// If set, wrong answers whose Levenshtein ratio to the solution
+    /// reaches this threshold are answered with a "very close" hint.
+    #[serde(default)]
+    pub close_answer_threshold: Option<f32>,
+    /// Time of the last authoring change to the riddle's metadata; falls
+    /// back to the creation time encoded in `_id`.
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Longest answer accepted for this riddle (in characters); overrides
+    /// `MAX_SOLUTION_LENGTH`.
+    #[serde(default)]
+    pub max_solution_length: Option<usize>,
+    /// If set, users may have the solution revealed (at a penalty) after
+    /// that many wrong answers.
+    #[serde(default)]
+    pub reveal_after_failures: Option<u32>,
+    /// If set, users may give at most that many wrong answers. After that
+    /// the riddle, and with it the doorway it guards, stays locked to them.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// Deducted (scaled by the game's score multiplier) when the last of
+    /// `max_attempts` fails.
+    #[serde(default)]
+    pub exhaustion_penalty: Option<i32>,
+    /// Revealed one by one on request, each at a penalty, see
+    /// `HINT_PENALTY`.
+    #[serde(default)]
+    pub hints: Option<Vec<String>>,
+}
+
+impl Riddle {
+    /// Whether answers are compared case-insensitively. Answers meant for
+    /// an external system are passed on as they are, so they must match
+    /// exactly, whatever `ignore_case` says.
+    pub fn ignores_case(&self) -> bool {
+        self.ignore_case.unwrap_or(false) && !self.external_password_input
+    }
+
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        match self.updated_at {
+            Some(updated_at) => updated_at,
+            None => self.id.timestamp().to_chrono(),
+        }
+    }
+}
+
+/// Changes to a riddle's authoring data; fields left out stay as they are.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RiddleUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deduction: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub solution: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_case: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credits: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debriefing: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -80,11 +163,61 @@ pub struct Direction {
     pub level: u32,
 }
 
+/// Lets admins freeze a game during a live competition.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GameState {
+    Open,
+    /// read-only: authoring edits are rejected, play goes on
+    Locked,
+    /// read-only, and solving neither awards nor deducts points
+    Frozen,
+}
+
+impl Default for GameState {
+    fn default() -> Self {
+        GameState::Open
+    }
+}
+
+impl GameState {
+    pub fn is_editable(&self) -> bool {
+        *self == GameState::Open
+    }
+    pub fn is_scoring(&self) -> bool {
+        *self != GameState::Frozen
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Game {
     #[serde(rename = "_id")]
     pub id: ObjectId,
     pub name: String,
+    /// Factor applied to points awarded and deducted in this game.
+    #[serde(default = "default_score_multiplier")]
+    pub score_multiplier: f32,
+    /// If set, users may only solve riddles up to one level above their own.
+    #[serde(default)]
+    pub level_gating: bool,
+    /// Deduction for wrong answers to riddles without a `deduction` of
+    /// their own. Overrides `DEFAULT_DEDUCTION`.
+    #[serde(default)]
+    pub default_deduction: Option<i32>,
+    #[serde(default)]
+    pub state: GameState,
+    /// solving before this time is rejected; overrides `EVENT_START`
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub starts_at: Option<DateTime<Utc>>,
+    /// solving from this time on is rejected; overrides `EVENT_END`
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+fn default_score_multiplier() -> f32 {
+    1.0
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -112,6 +245,26 @@ pub enum SecondFactor {
     Fido2,
 }
 
+/// Sums up a user's 2FA posture so that clients don't have to piece it
+/// together from several fields. Where more than one applies, the later
+/// state wins, e.g. a user with both TOTP and FIDO2 is `Fido2Registered`.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TwoFactorState {
+    /// neither TOTP nor FIDO2 is set up
+    None,
+    /// TOTP has been enabled but not yet confirmed with a code, so logins
+    /// don't ask for it
+    TotpPending,
+    /// logins require a TOTP
+    TotpActive,
+    /// logins require a FIDO2 authenticator (or a TOTP, if also active)
+    Fido2Registered,
+    /// the password has been accepted and the login waits for the second
+    /// factor
+    AwaitingSecondFactor,
+}
+
 impl SecondFactor {
     pub fn from_str(factor: &str) -> SecondFactor {
         match factor {
@@ -165,9 +318,257 @@ pub struct RiddleAttempt {
     pub t0: Option<DateTime<Utc>>,
     #[serde(default)]
     pub dt: Option<i64>,
+    /// wrong answers given while this was the current attempt
+    #[serde(default)]
+    pub failures: u32,
+    /// set if the solution was revealed instead of found
+    #[serde(default)]
+    pub revealed: bool,
+    /// room the user was in when last opening the riddle
+    #[serde(default)]
+    pub room_id: Option<ObjectId>,
+}
+
+/// Overview of the whole installation for operators.
+#[derive(Serialize, Debug, Clone)]
+pub struct AdminStats {
+    pub num_users: u64,
+    pub num_activated_users: u64,
+    /// users with a confirmed TOTP or a FIDO2 key
+    pub num_users_with_2fa: u64,
+    pub num_riddles: u64,
+    pub num_rooms: u64,
+    pub num_games: u64,
+    /// unexpired refresh tokens, i.e. logins that can still be renewed
+    pub num_active_sessions: u64,
+    pub num_signups_last_day: u64,
+    pub num_signups_last_week: u64,
+    #[serde(with = "ts_seconds")]
+    pub computed_at: DateTime<Utc>,
+}
+
+/// How many distinct users have opened vs. solved a riddle, and how long
+/// it took them.
+#[derive(Serialize, Debug, Clone)]
+pub struct RiddleStats {
+    pub riddle_id: ObjectId,
+    pub num_attempts: u32,
+    pub num_solved: u32,
+    /// users who had the solution revealed; not counted as solved
+    pub num_revealed: u32,
+    pub solve_rate: f32,
+    /// median solve time in seconds, `None` if nobody solved the riddle
+    pub median_solve_time: Option<i64>,
+}
+
+/// A hint a user has paid for; asking for it again is free.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct UnlockedHint {
+    pub riddle_id: ObjectId,
+    pub index: u32,
+}
+
+/// When a user last gave a wrong answer to a riddle.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WrongAnswer {
+    pub riddle_id: ObjectId,
+    #[serde(with = "ts_seconds")]
+    pub at: DateTime<Utc>,
+    /// wrong answers to the riddle so far
+    #[serde(default)]
+    pub count: u32,
+}
+
+/// The outcome of a solution submitted with an idempotency key, so that
+/// resubmitting it doesn't book it twice. Keys are scoped per riddle.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ProcessedSolve {
+    pub idempotency_key: String,
+    pub riddle_id: ObjectId,
+    pub solved: bool,
+}
+
+/// Records that a user has stepped through the exit of a game. Only the
+/// best completion per game is kept.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FinishedGame {
+    pub game_id: ObjectId,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    /// points earned in the game; missing in older records, which may
+    /// hold the user's whole score instead
+    #[serde(default)]
+    pub score: Option<i32>,
+    /// seconds from entering the game, or from its start if that was
+    /// later, to finishing it, if known
+    #[serde(default)]
+    pub elapsed: Option<i64>,
+}
+
+/// A riddle solved by a user. Solves live in a collection of their own,
+/// keyed by (`user_id`, `riddle_id`), to keep the user document small.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SolvedAttempt {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    #[serde(flatten)]
+    pub attempt: RiddleAttempt,
+}
+
+/// Difficulty and timing of a riddle a user has solved, see
+/// `get_solved_difficulties()`.
+#[derive(Debug, Clone)]
+pub struct SolvedDifficulty {
+    pub difficulty: i32,
+    /// seconds the user needed, `None` if no timing has been recorded
+    pub dt: Option<i64>,
+    /// set if the solution was revealed instead of found
+    pub revealed: bool,
+}
+
+/// How a user's solve of a riddle compares to everybody else's.
+#[derive(Serialize, Debug, Default)]
+pub struct SolveRanking {
+    /// seconds the user needed, `None` if no timing has been recorded
+    pub solve_time: Option<i64>,
+    /// rank among all timed solvers, fastest first
+    pub rank: Option<u32>,
+    pub num_solvers: u32,
+    /// user who solved the riddle first
+    pub first_blood: Option<String>,
+}
+
+/// One step of a user's path through a labyrinth. Visits are kept in a
+/// collection of their own so that the user document doesn't grow with
+/// every move. `rooms_entered` remains the de-duplicated "has visited" set.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoomVisit {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub room_id: ObjectId,
+    pub game_id: ObjectId,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    /// the user's score on entering the room; missing in older records
+    #[serde(default)]
+    pub score: Option<i32>,
+}
+
+/// An administrative action, kept for later reference.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditLogEntry {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    pub admin: String,
+    pub action: String,
+    pub target: String,
+    pub reason: String,
+    #[serde(default)]
+    pub details: bson::Document,
+}
+
+/// A TOTP authenticator app registered by the user.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TotpSecret {
+    pub name: String,
+    #[serde(with = "b64")]
+    pub key: Vec<u8>,
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub created: Option<DateTime<Utc>>,
+}
+
+impl TotpSecret {
+    pub fn new(name: &str, key: Vec<u8>, now: DateTime<Utc>) -> TotpSecret {
+        TotpSecret {
+            name: name.to_string(),
+            key,
+            created: Some(now),
+        }
+    }
 }
 
+/// A refresh token, stored by its digest. All tokens obtained from one
+/// login share a `family`; a token can only be exchanged once.
 #[derive(Deserialize, Serialize, Debug)]
+pub struct RefreshToken {
+    #[serde(rename = "_id")]
+    pub digest: String,
+    pub family: ObjectId,
+    pub username: String,
+    #[serde(with = "ts_seconds")]
+    pub issued: DateTime<Utc>,
+    /// a BSON date, so that the TTL index removes the token once expired
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires: DateTime<Utc>,
+    #[serde(default)]
+    pub used: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MailState {
+    Pending,
+    /// handed to the mail worker; taken again after a while if the
+    /// server dies while sending
+    Sending,
+    Sent,
+    /// gave up after too many attempts
+    Failed,
+}
+
+/// A mail waiting in the outbox, already rendered so that the worker
+/// doesn't need to know what it's about.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OutboxMail {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub from: String,
+    pub to: Vec<String>,
+    /// the complete RFC 5322 message; removed once the mail is sent or
+    /// given up on, as it may contain passwords, PINs or reset tokens
+    #[serde(default)]
+    pub raw: String,
+    #[serde(with = "ts_seconds")]
+    pub created: DateTime<Utc>,
+    pub state: MailState,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(with = "ts_seconds")]
+    pub next_attempt_at: DateTime<Utc>,
+    /// when a worker last moved the mail to `Sending`
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub claimed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub sent_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// when MongoDB removes the mail through the TTL index; a BSON date,
+    /// as TTL indexes ignore numbers
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_at: Option<bson::DateTime>,
+}
+
+/// A pending password reset. Only the digest of the mailed token is
+/// stored; the entry is kept after use so that a reused token can be told
+/// apart from a wrong one.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PasswordReset {
+    pub digest: String,
+    #[serde(with = "ts_seconds")]
+    pub expires: DateTime<Utc>,
+    #[serde(default)]
+    pub used: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
     #[serde(rename = "_id")]
     pub id: ObjectId,
@@ -178,6 +579,9 @@ pub struct User {
     #[serde(default)]
     pub pin: PinType,
     pub activated: bool,
+    /// set by an admin; keeps the PIN from activating the user again
+    #[serde(default)]
+    pub deactivated: bool,
     #[serde(default)]
     #[serde(with = "ts_seconds_option")]
     pub created: Option<DateTime<Utc>>,
@@ -187,6 +591,9 @@ pub struct User {
     #[serde(default)]
     #[serde(with = "ts_seconds_option")]
     pub last_login: Option<DateTime<Utc>>,
+    /// Filled from the attempts collection by `DB::get_user()`; never
+    /// written back to the user document.
+    #[serde(default, skip_serializing)]
     pub solved: Vec<RiddleAttempt>,
     pub current_riddle_attempt: Option<RiddleAttempt>,
     #[serde(default)]
@@ -199,12 +606,44 @@ pub struct User {
     #[serde(default)]
     pub awaiting_second_factor: bool,
     #[serde(default)]
-    #[serde(with = "b64")]
-    pub totp_key: Vec<u8>,
+    pub totp_keys: Vec<TotpSecret>,
+    /// set up but not yet confirmed with a code; replaces `totp_keys` once
+    /// confirmed, logins keep using the old ones until then
+    #[serde(default)]
+    pub pending_totp_key: Option<TotpSecret>,
     #[serde(default)]
     pub recovery_keys: Vec<String>,
     #[serde(default)]
     pub webauthn: WebauthnManagementData,
+    #[serde(default)]
+    pub finished: Vec<FinishedGame>,
+    /// game chosen at registration, entered on activation
+    #[serde(default)]
+    pub game_id: Option<ObjectId>,
+    #[serde(default)]
+    pub password_reset: Option<PasswordReset>,
+    #[serde(default)]
+    pub unlocked_hints: Vec<UnlockedHint>,
+    /// the last wrong answer per riddle, kept across riddle switches so
+    /// that the solve cooldown can't be dodged
+    #[serde(default)]
+    pub last_wrong_answers: Vec<WrongAnswer>,
+    /// the most recent solutions submitted in batches
+    #[serde(default)]
+    pub processed_solves: Vec<ProcessedSolve>,
+    /// JWTs carrying an older version are no longer accepted
+    #[serde(default)]
+    pub token_version: u32,
+    /// wrong passwords or TOTPs since the last login or lockout
+    #[serde(default)]
+    pub failed_logins: u32,
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub locked_until: Option<DateTime<Utc>>,
+    /// when the activation PIN was last mailed
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub last_pin_sent: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -219,11 +658,41 @@ pub struct UserFullScoreData {
     #[serde(default)]
     pub level: u32,
     #[serde(default)]
-    pub score: u32,
+    pub score: i32,
     #[serde(default)]
     pub in_room: Option<ObjectId>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct UserLeaderboardData {
+    pub username: String,
+    #[serde(default)]
+    pub score: i32,
+    #[serde(default)]
+    pub level: u32,
+    /// number of rooms of the game the user has entered
+    #[serde(default)]
+    pub rooms_entered_count: u32,
+    /// filled in by `get_leaderboard()`; users with the same score share a rank
+    #[serde(default)]
+    pub rank: u32,
+}
+
+/// One page of a game's leaderboard, see `get_leaderboard()`.
+#[derive(Debug)]
+pub struct Leaderboard {
+    pub users: Vec<UserLeaderboardData>,
+    /// number of players on the whole leaderboard
+    pub total: u64,
+    /// entry of the requesting user, wherever it is on the leaderboard
+    pub own: Option<UserLeaderboardData>,
+}
+
+/// Matches users who play, i.e. everyone but spectators.
+fn players_only() -> bson::Document {
+    doc! { "role": { "$ne": bson::to_bson(&Role::Spectator).unwrap() } }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct UserCompactScoreData {
     pub username: String,
@@ -236,6 +705,37 @@ pub struct UserCompactScoreData {
 }
 
 impl User {
+    pub fn has_totp(&self) -> bool {
+        !self.totp_keys.is_empty()
+    }
+
+    /// Returns the second factors set up, disregarding a login in progress.
+    pub fn two_factor_setup(&self) -> TwoFactorState {
+        if !self.webauthn.credentials.is_empty() {
+            TwoFactorState::Fido2Registered
+        } else if self.has_totp() {
+            TwoFactorState::TotpActive
+        } else if self.pending_totp_key.is_some() {
+            TwoFactorState::TotpPending
+        } else {
+            TwoFactorState::None
+        }
+    }
+
+    pub fn two_factor_state(&self) -> TwoFactorState {
+        match self.awaiting_second_factor {
+            true => TwoFactorState::AwaitingSecondFactor,
+            false => self.two_factor_setup(),
+        }
+    }
+
+    pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        match self.locked_until {
+            Some(locked_until) => locked_until > now,
+            None => false,
+        }
+    }
+
     pub fn new(
         username: &String,
         email: &String,
@@ -243,6 +743,7 @@ impl User {
         hash: String,
         pin: PinType,
         totp_key: Vec<u8>,
+        now: DateTime<Utc>,
     ) -> Self {
         User {
             id: ObjectId::new(),
@@ -252,7 +753,8 @@ impl User {
             hash: hash,
             pin: pin,
             activated: false,
-            created: Some(Utc::now()),
+            deactivated: false,
+            created: Some(now),
             registered: Option::default(),
             last_login: Option::default(),
             solved: Vec::new(),
@@ -262,9 +764,23 @@ impl User {
             score: 0,
             in_room: Option::default(),
             awaiting_second_factor: false,
-            totp_key: totp_key,
+            totp_keys: match totp_key.is_empty() {
+                true => Vec::new(),
+                false => vec![TotpSecret::new("default", totp_key, now)],
+            },
+            pending_totp_key: Option::default(),
             recovery_keys: Vec::new(),
             webauthn: WebauthnManagementData::new(),
+            finished: Vec::new(),
+            game_id: Option::default(),
+            password_reset: Option::default(),
+            unlocked_hints: Vec::new(),
+            last_wrong_answers: Vec::new(),
+            processed_solves: Vec::new(),
+            token_version: 0,
+            failed_logins: 0,
+            locked_until: Option::default(),
+            last_pin_sent: Option::default(),
         }
     }
 }
@@ -282,6 +798,215 @@ impl Distribution<u8> for KeyChars {
     }
 }
 
+/// Generates `count` distinct recovery keys of four dash-separated groups
+/// of `group_len` characters each.
+pub fn generate_recovery_keys(count: usize, group_len: usize) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::with_capacity(count);
+    let mut seen: HashSet<String> = HashSet::new();
+    while keys.len() < count {
+        let key: String = (0..4)
+            .map(|_| {
+                rand::thread_rng()
+                    .sample_iter(&KeyChars)
+                    .take(group_len)
+                    .map(char::from)
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>()
+            .join("-");
+        if seen.insert(key.clone()) {
+            keys.push(key);
+        }
+    }
+    keys
+}
+
+/// Users recently returned by `DB::get_user()`, keyed by username.
+#[derive(Debug)]
+pub struct UserCache {
+    users: HashMap<String, (Instant, User)>,
+    /// incremented by every invalidation, so that a user read before one
+    /// isn't cached after it
+    generation: u64,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl UserCache {
+    pub fn new(ttl: Duration, capacity: usize) -> UserCache {
+        UserCache {
+            users: HashMap::new(),
+            generation: 0,
+            ttl,
+            capacity,
+        }
+    }
+
+    pub fn get(&mut self, username: &str) -> Option<&User> {
+        match self.users.get(username) {
+            Some((cached_at, _)) if cached_at.elapsed() >= self.ttl => {
+                self.users.remove(username);
+                None
+            }
+            Some((_, user)) => Some(user),
+            None => None,
+        }
+    }
+
+    /// Returns the generation to pass to `insert()` for a user about to
+    /// be read from the database.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Caches `user` unless a user has been invalidated since `generation`
+    /// was taken, as `user` may have been read before that write. Expired
+    /// users are dropped if the cache is full, then the oldest one.
+    pub fn insert(&mut self, user: User, generation: u64) {
+        if generation != self.generation || self.capacity == 0 {
+            return;
+        }
+        if self.users.len() >= self.capacity {
+            let ttl: Duration = self.ttl;
+            self.users
+                .retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+        }
+        if self.users.len() >= self.capacity {
+            let oldest: Option<String> = self
+                .users
+                .iter()
+                .min_by_key(|(_, (cached_at, _))| *cached_at)
+                .map(|(username, _)| username.clone());
+            if let Some(oldest) = oldest {
+                self.users.remove(&oldest);
+            }
+        }
+        self.users
+            .insert(user.username.clone(), (Instant::now(), user));
+    }
+
+    pub fn invalidate(&mut self, username: &str) {
+        self.generation += 1;
+        self.users.remove(username);
+    }
+}
+
+/// Seconds `DB::get_admin_stats()` results are reused for.
+const ADMIN_STATS_MAX_AGE_SECS: i64 = 10;
+
+/// MongoDB's error code for a collection that already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
+/// MongoDB's error code for a write refused by a unique index.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Returns the code of the error the server reported, if `e` is one.
+fn server_error_code(e: &mongodb::error::Error) -> Option<i32> {
+    match *e.kind {
+        ErrorKind::Command(ref e) => Some(e.code),
+        ErrorKind::Write(WriteFailure::WriteError(ref e)) => Some(e.code),
+        ErrorKind::Write(WriteFailure::WriteConcernError(ref e)) => Some(e.code),
+        ErrorKind::BulkWrite(ref e) => e
+            .write_errors
+            .as_ref()
+            .and_then(|errors| errors.first())
+            .map(|e| e.code),
+        _ => None,
+    }
+}
+
+/// Tells whether `e` is a write refused by a unique index.
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    server_error_code(e) == Some(DUPLICATE_KEY)
+}
+
+/// Does the search of `DB::shortest_path()` on the rooms of one game.
+/// Doorways that lead into more than one room can't be walked through, as
+/// in `DB::get_room_behind()`; if the way is blocked by one of them, that's
+/// reported instead of `NoPathError`.
+fn find_path(
+    rooms: &[Room],
+    from: &ObjectId,
+    to: &ObjectId,
+    passable: &HashSet<ObjectId>,
+) -> Result<Vec<String>> {
+    let rooms_by_id: HashMap<ObjectId, &Room> = rooms.iter().map(|room| (room.id, room)).collect();
+    // a doorway leads into the room that has the same riddle on the
+    // opposite side
+    let mut entered_through: HashMap<(ObjectId, &String), Vec<ObjectId>> = HashMap::new();
+    for room in rooms.iter() {
+        for neighbor in room.neighbors.iter() {
+            entered_through
+                .entry((neighbor.riddle_id, &neighbor.direction))
+                .or_default()
+                .push(room.id);
+        }
+    }
+    let mut came_from: HashMap<ObjectId, (ObjectId, &String)> = HashMap::new();
+    let mut visited: HashSet<ObjectId> = HashSet::from([*from]);
+    let mut queue: VecDeque<ObjectId> = VecDeque::from([*from]);
+    let mut ambiguous: bool = false;
+    while let Some(room_id) = queue.pop_front() {
+        if room_id == *to {
+            let mut path: Vec<String> = Vec::new();
+            let mut current: ObjectId = room_id;
+            while let Some((previous, direction)) = came_from.get(&current) {
+                path.push(direction.to_string());
+                current = *previous;
+            }
+            path.reverse();
+            return Ok(path);
+        }
+        let room: &Room = match rooms_by_id.get(&room_id) {
+            Some(room) => room,
+            None => continue,
+        };
+        for neighbor in room
+            .neighbors
+            .iter()
+            .filter(|neighbor| passable.contains(&neighbor.riddle_id))
+        {
+            let opposite: &String = match OPPOSITE.get(&neighbor.direction) {
+                Some(opposite) => opposite,
+                None => continue,
+            };
+            let behind: Vec<ObjectId> = match entered_through.get(&(neighbor.riddle_id, opposite)) {
+                Some(behind) => behind
+                    .iter()
+                    .filter(|id| **id != room_id)
+                    .copied()
+                    .collect(),
+                None => continue,
+            };
+            let next: ObjectId = match behind.len() {
+                0 => continue,
+                1 => behind[0],
+                _ => {
+                    ambiguous = true;
+                    continue;
+                }
+            };
+            if visited.insert(next) {
+                came_from.insert(next, (room_id, &neighbor.direction));
+                queue.push_back(next);
+            }
+        }
+    }
+    match ambiguous {
+        true => Err(AmbiguousRoomBehindError),
+        false => Err(NoPathError),
+    }
+}
+
+/// The score `DB::adjust_user_score()` leaves: `score` plus `delta`, or
+/// `absolute` if given, but never below zero.
+fn adjusted_score(score: i32, delta: i32, absolute: Option<i32>) -> i32 {
+    match absolute {
+        Some(score) => score.max(0),
+        None => (score + delta).max(0),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DB {
     pub client: Client,
@@ -289,30 +1014,73 @@ pub struct DB {
     pub coll_rooms: String,
     pub coll_riddles: String,
     pub coll_users: String,
+    pub coll_games: String,
+    pub coll_room_visits: String,
+    pub coll_attempts: String,
+    pub coll_audit_log: String,
+    pub coll_refresh_tokens: String,
+    pub coll_email_outbox: String,
+    pub default_game_id: Option<ObjectId>,
+    pub aggregation_permits: Arc<Semaphore>,
+    pub aggregation_timeout: Duration,
+    /// `None` if caching users is disabled
+    pub user_cache: Option<Arc<Mutex<UserCache>>>,
+    pub admin_stats_cache: Arc<Mutex<Option<AdminStats>>>,
+    pub clock: SharedClock,
 }
 
 impl DB {
-    pub async fn init() -> Result<Self> {
-        let url: String = env::var("DB_URL").expect("DB_URL is not in .env file");
-        let name: String = env::var("DB_NAME").expect("DB_NAME is not in .env file");
-        let coll_users: String =
-            env::var("DB_COLL_USERS").expect("DB_COLL_USERS is not in .env file");
-        let coll_riddles: String =
-            env::var("DB_COLL_RIDDLES").expect("DB_COLL_RIDDLES is not in .env file");
-        let coll_rooms: String =
-            env::var("DB_COLL_ROOMS").expect("DB_COLL_ROOMS is not in .env file");
+    /// Connects to the database. All time-dependent code asks `clock` for
+    /// the current time.
+    pub async fn init(config: &Config, clock: SharedClock) -> Result<Self> {
         let mut client_options: mongodb::options::ClientOptions =
-            ClientOptions::parse(url).await.unwrap();
-        client_options.app_name = Some(name.to_string());
+            match ClientOptions::parse(&config.db_url).await {
+                Ok(client_options) => client_options,
+                Err(e) => return Err(MongoError(e)),
+            };
+        client_options.app_name = Some(config.db_name.to_string());
+        let client: Client = match Client::with_options(client_options) {
+            Ok(client) => client,
+            Err(e) => return Err(MongoError(e)),
+        };
         Ok(Self {
-            client: Client::with_options(client_options).unwrap(),
-            name: name.to_string(),
-            coll_users: coll_users.to_string(),
-            coll_riddles: coll_riddles.to_string(),
-            coll_rooms: coll_rooms.to_string(),
+            client,
+            name: config.db_name.to_string(),
+            coll_users: config.coll_users.to_string(),
+            coll_riddles: config.coll_riddles.to_string(),
+            coll_rooms: config.coll_rooms.to_string(),
+            coll_games: config.coll_games.to_string(),
+            coll_room_visits: config.coll_room_visits.to_string(),
+            coll_attempts: config.coll_attempts.to_string(),
+            coll_audit_log: config.coll_audit_log.to_string(),
+            coll_refresh_tokens: config.coll_refresh_tokens.to_string(),
+            coll_email_outbox: config.coll_email_outbox.to_string(),
+            default_game_id: config.default_game_id,
+            aggregation_permits: Arc::new(Semaphore::new(config.max_concurrent_aggregations)),
+            aggregation_timeout: config.aggregation_timeout,
+            user_cache: match config.user_cache {
+                true => Some(Arc::new(Mutex::new(UserCache::new(
+                    config.user_cache_ttl,
+                    config.user_cache_size,
+                )))),
+                false => None,
+            },
+            admin_stats_cache: Arc::new(Mutex::new(None)),
+            clock,
         })
     }
 
+    pub async fn ping(&self) -> Result<()> {
+        match self
+            .get_database()
+            .run_command(doc! { "ping": 1u32 }, None)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoError(e)),
+        }
+    }
+
     pub fn get_database(&self) -> Database {
         self.client.database(&self.name)
     }
@@ -329,6 +1097,70 @@ impl DB {
         self.get_database().collection::<Room>(&self.coll_rooms)
     }
 
+    pub fn get_games_coll(&self) -> Collection<Game> {
+        self.get_database().collection::<Game>(&self.coll_games)
+    }
+
+    pub fn get_attempts_coll(&self) -> Collection<SolvedAttempt> {
+        self.get_database()
+            .collection::<SolvedAttempt>(&self.coll_attempts)
+    }
+
+    pub fn get_room_visits_coll(&self) -> Collection<RoomVisit> {
+        self.get_database()
+            .collection::<RoomVisit>(&self.coll_room_visits)
+    }
+
+    pub fn get_audit_log_coll(&self) -> Collection<AuditLogEntry> {
+        self.get_database()
+            .collection::<AuditLogEntry>(&self.coll_audit_log)
+    }
+
+    pub fn get_refresh_tokens_coll(&self) -> Collection<RefreshToken> {
+        self.get_database()
+            .collection::<RefreshToken>(&self.coll_refresh_tokens)
+    }
+
+    pub fn get_email_outbox_coll(&self) -> Collection<OutboxMail> {
+        self.get_database()
+            .collection::<OutboxMail>(&self.coll_email_outbox)
+    }
+
+    /// Starts a session with a transaction, so that a change and the mail
+    /// about it are stored together or not at all. Needs MongoDB to run as
+    /// a replica set (see docker/docker-compose.yml).
+    async fn start_transaction(&self) -> Result<ClientSession> {
+        let mut session: ClientSession = match self.client.start_session(None).await {
+            Ok(session) => session,
+            Err(e) => return Err(MongoError(e)),
+        };
+        match session.start_transaction(None).await {
+            Ok(()) => Ok(session),
+            Err(e) => Err(MongoError(e)),
+        }
+    }
+
+    /// Waits for a slot to run one of the expensive aggregations
+    /// (e.g. `get_max_score_for_game()`, `get_num_riddles()`). If no slot
+    /// becomes available within `aggregation_timeout`, the caller is asked
+    /// to come back later instead of piling up more work on MongoDB.
+    pub async fn acquire_aggregation_permit(&self) -> Result<OwnedSemaphorePermit> {
+        let retry_after: u64 = self.aggregation_timeout.as_secs().max(1);
+        match tokio::time::timeout(
+            self.aggregation_timeout,
+            self.aggregation_permits.clone().acquire_owned(),
+        )
+        .await
+        {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => Err(ServiceBusyError(retry_after)),
+            Err(_) => {
+                log::warn!("no aggregation permit available, rejecting request");
+                Err(ServiceBusyError(retry_after))
+            }
+        }
+    }
+
     pub async fn get_num_rooms(&self, game_id: &ObjectId) -> Result<u32> {
         log::info!("get_num_rooms(); game_id = {}", game_id);
         match self
@@ -341,13 +1173,19 @@ impl DB {
         }
     }
 
+    /// Returns all activated players, highest score first. Ties are broken by
+    /// earliest `last_login` (users who never logged in come first, as
+    /// MongoDB sorts missing values lowest), then by `_id`, i.e. order of
+    /// registration, so that the result is deterministic.
     pub async fn get_full_user_scores(&self) -> Result<Vec<UserFullScoreData>> {
         log::info!("get_full_user_scores()");
+        let mut filter: bson::Document = players_only();
+        filter.insert("activated", true);
         let cursor: mongodb::Cursor<UserFullScoreData> = match self
             .get_database()
             .collection::<UserFullScoreData>(&self.coll_users)
             .find(
-                doc! { "activated": true },
+                filter,
                 FindOptions::builder()
                     .projection(doc! {
                         "username": 1u32,
@@ -358,7 +1196,9 @@ impl DB {
                         "in_room": 1u32,
                     })
                     .sort(doc! {
-                        "score": 1u32,
+                        "score": -1i32,
+                        "last_login": 1i32,
+                        "_id": 1i32,
                     })
                     .build(),
             )
@@ -374,17 +1214,166 @@ impl DB {
         Ok(users)
     }
 
-    pub async fn get_compact_user_scores(
+    /// Returns the requested page of the players who have entered at least
+    /// one room of the given game, ordered like `get_full_user_scores()`,
+    /// plus the entry of `username`. Spectators are left out.
+    pub async fn get_leaderboard(
         &self,
-        _game_id: &bson::oid::ObjectId, // TODO
-    ) -> Result<Vec<UserCompactScoreData>> {
-        log::info!("get_compact_user_scores()");
+        game_id: &ObjectId,
+        username: &String,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Leaderboard> {
+        log::info!(
+            "get_leaderboard(); game_id = {}, offset = {}, limit = {}",
+            game_id,
+            offset,
+            limit
+        );
+        let room_ids: Vec<ObjectId> = match self
+            .get_rooms_coll()
+            .distinct("_id", doc! { "game_id": game_id }, None)
+            .await
+        {
+            Ok(ids) => ids.iter().filter_map(|id| id.as_object_id()).collect(),
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let mut filter: bson::Document = players_only();
+        filter.insert("activated", true);
+        filter.insert("rooms_entered", doc! { "$in": &room_ids });
+        let project: bson::Document = doc! {
+            "$project": {
+                "_id": 0i32,
+                "username": 1i32,
+                "level": 1i32,
+                "score": 1i32,
+                "rooms_entered_count": {
+                    "$size": { "$setIntersection": [ "$rooms_entered", &room_ids ] }
+                },
+            }
+        };
+        let cursor: mongodb::Cursor<bson::Document> = match self
+            .get_database()
+            .collection::<bson::Document>(&self.coll_users)
+            .aggregate(
+                vec![
+                    doc! { "$match": &filter },
+                    doc! { "$sort": { "score": -1i32, "last_login": 1i32, "_id": 1i32 } },
+                    doc! {
+                        "$facet": {
+                            "page": [
+                                { "$skip": offset as i64 },
+                                { "$limit": limit as i64 },
+                                project.clone(),
+                            ],
+                            "total": [ { "$count": "n" } ],
+                        }
+                    },
+                ],
+                None,
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let docs: Vec<bson::Document> = match cursor.try_collect().await {
+            Ok(docs) => docs,
+            Err(e) => return Err(MongoError(e)),
+        };
+        let facets: bson::Document = docs.into_iter().next().unwrap_or_default();
+        let total: u64 = facets
+            .get_array("total")
+            .ok()
+            .and_then(|counts| counts.first())
+            .and_then(|count| count.as_document())
+            .map_or(0, |count| match count.get("n") {
+                Some(bson::Bson::Int32(n)) => *n as u64,
+                Some(bson::Bson::Int64(n)) => *n as u64,
+                _ => 0,
+            });
+        let page: Vec<bson::Bson> = facets.get_array("page").cloned().unwrap_or_default();
+        let mut users: Vec<UserLeaderboardData> = Vec::new();
+        for user in page.into_iter() {
+            match bson::from_bson::<UserLeaderboardData>(user) {
+                Ok(user) => users.push(user),
+                Err(e) => return Err(BsonDeError(e)),
+            }
+        }
+        // the first user on the page is ranked behind everyone with a higher
+        // score, the others behind everyone above them on the list unless
+        // they share the score of their predecessor
+        let mut previous: Option<(i32, u32)> = None;
+        for (i, user) in users.iter_mut().enumerate() {
+            user.rank = match previous {
+                Some((score, rank)) if score == user.score => rank,
+                Some(_) => offset + i as u32 + 1,
+                None => self.count_players_ahead(&filter, user.score).await? + 1,
+            };
+            previous = Some((user.score, user.rank));
+        }
+        let mut own_filter: bson::Document = filter.clone();
+        own_filter.insert("username", username);
+        let cursor: mongodb::Cursor<bson::Document> = match self
+            .get_database()
+            .collection::<bson::Document>(&self.coll_users)
+            .aggregate(vec![doc! { "$match": own_filter }, project], None)
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let docs: Vec<bson::Document> = match cursor.try_collect().await {
+            Ok(docs) => docs,
+            Err(e) => return Err(MongoError(e)),
+        };
+        let own: Option<UserLeaderboardData> = match docs.into_iter().next() {
+            Some(doc) => match bson::from_document::<UserLeaderboardData>(doc) {
+                Ok(mut own) => {
+                    own.rank = self.count_players_ahead(&filter, own.score).await? + 1;
+                    Some(own)
+                }
+                Err(e) => return Err(BsonDeError(e)),
+            },
+            None => None,
+        };
+        Ok(Leaderboard { users, total, own })
+    }
+
+    /// Counts the users matching `filter` who have scored more than `score`.
+    async fn count_players_ahead(&self, filter: &bson::Document, score: i32) -> Result<u32> {
+        let mut filter: bson::Document = filter.clone();
+        filter.insert("score", doc! { "$gt": score });
+        match self
+            .get_database()
+            .collection::<bson::Document>(&self.coll_users)
+            .count_documents(filter, None)
+            .await
+        {
+            Ok(n) => Ok(n as u32),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn get_compact_user_scores(
+        &self,
+        _game_id: &bson::oid::ObjectId, // TODO
+    ) -> Result<Vec<UserCompactScoreData>> {
+        log::info!("get_compact_user_scores()");
+        let mut filter: bson::Document = players_only();
+        filter.insert("activated", true);
         let cursor = match self
             .get_database()
             .collection::<bson::Document>(&self.coll_users)
             .aggregate(
                 vec![
-                    doc! { "$match": doc! {"activated": true }},
+                    doc! { "$match": filter },
+                    doc! { "$lookup": doc! {
+                        "from": &self.coll_attempts,
+                        "localField": "_id",
+                        "foreignField": "user_id",
+                        "as": "solved",
+                    }},
                     doc! { "$unwind": "$solved"},
                     doc! { "$group": doc! {
                         "_id": "$_id",
@@ -600,27 +1589,350 @@ impl DB {
         }
     }
 
+    /// Returns those of the given riddle ids that exist in the database.
+    pub async fn get_existing_riddle_ids(&self, ids: &Vec<ObjectId>) -> Result<HashSet<ObjectId>> {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct RiddleId {
+            _id: ObjectId,
+        }
+        let cursor: mongodb::Cursor<RiddleId> = match self
+            .get_database()
+            .collection::<RiddleId>(&self.coll_riddles)
+            .find(
+                doc! { "_id": { "$in": ids } },
+                FindOptions::builder()
+                    .projection(doc! { "_id": 1u32 })
+                    .build(),
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let riddles: Vec<RiddleId> = match cursor.try_collect().await {
+            Ok(riddles) => riddles,
+            Err(e) => return Err(MongoError(e)),
+        };
+        Ok(riddles.into_iter().map(|r| r._id).collect())
+    }
+
+    pub async fn get_game(&self, game_id: &ObjectId) -> Result<Option<Game>> {
+        log::info!("get_game(); game_id = {}", game_id);
+        match self
+            .get_games_coll()
+            .find_one(doc! { "_id": game_id }, None)
+            .await
+        {
+            Ok(game) => Ok(game),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Returns the games with a doorway guarded by the given riddle.
+    pub async fn get_games_of_riddle(&self, riddle_id: &ObjectId) -> Result<Vec<Game>> {
+        let game_ids: Vec<ObjectId> = match self
+            .get_rooms_coll()
+            .distinct("game_id", doc! { "neighbors.riddle_id": riddle_id }, None)
+            .await
+        {
+            Ok(ids) => ids.iter().filter_map(|id| id.as_object_id()).collect(),
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let cursor: mongodb::Cursor<Game> = match self
+            .get_games_coll()
+            .find(doc! { "_id": { "$in": &game_ids } }, None)
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match cursor.try_collect().await {
+            Ok(games) => Ok(games),
+            Err(e) => Err(MongoError(e)),
+        }
+    }
+
+    pub async fn create_riddle(&self, riddle: &Riddle) -> Result<()> {
+        log::info!(
+            "create_riddle(); id = {}, level = {}",
+            &riddle.id,
+            riddle.level
+        );
+        match self.get_riddles_coll().insert_one(riddle, None).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_key(&e) => Err(RiddleLevelTakenError(riddle.level)),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Makes riddle levels unique. As long as existing riddles share a
+    /// level, the index can't be built; that's logged, and the server runs
+    /// without it until the levels have been fixed.
+    pub async fn ensure_riddle_indexes(&self) -> Result<()> {
+        log::info!("ensure_riddle_indexes()");
+        let index: IndexModel = IndexModel::builder()
+            .keys(doc! { "level": 1u32 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        match self.get_riddles_coll().create_index(index, None).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_key(&e) => {
+                log::error!(
+                    "Error: riddles share a level, so levels are not kept unique until that is fixed: {}",
+                    e
+                );
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Appends `file` to the riddle's files and stamps `updated_at`.
+    pub async fn add_riddle_file(&self, riddle_id: &ObjectId, file: &UploadedFile) -> Result<()> {
+        log::info!(
+            "add_riddle_file(); riddle_id = {}, uploaded_name = {}",
+            riddle_id,
+            &file.uploaded_name
+        );
+        let file_doc: bson::Document = match bson::to_document(file) {
+            Ok(file_doc) => file_doc,
+            Err(e) => return Err(DatabaseQueryError(e.to_string())),
+        };
+        // `$push` fails on riddles whose `files` is null
+        if let Err(e) = self
+            .get_riddles_coll()
+            .update_one(
+                doc! { "_id": riddle_id, "files": null },
+                doc! { "$set": { "files": [] } },
+                None,
+            )
+            .await
+        {
+            return Err(MongoQueryError(e));
+        }
+        let result: UpdateResult = match self
+            .get_riddles_coll()
+            .update_one(
+                doc! { "_id": riddle_id },
+                doc! {
+                    "$push": { "files": file_doc },
+                    "$set": { "updated_at": self.clock.now().timestamp() },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        if result.matched_count == 0 {
+            return Err(RiddleNotFoundError);
+        }
+        Ok(())
+    }
+
+    /// Detaches the file stored as `uploaded_name` from the riddle and
+    /// returns it, or `None` if the riddle has no such file.
+    pub async fn remove_riddle_file(
+        &self,
+        riddle_id: &ObjectId,
+        uploaded_name: &String,
+    ) -> Result<Option<UploadedFile>> {
+        log::info!(
+            "remove_riddle_file(); riddle_id = {}, uploaded_name = {}",
+            riddle_id,
+            uploaded_name
+        );
+        let riddle: Option<Riddle> = match self
+            .get_riddles_coll()
+            .find_one_and_update(
+                doc! { "_id": riddle_id, "files.uploadedName": uploaded_name },
+                doc! {
+                    "$pull": { "files": { "uploadedName": uploaded_name } },
+                    "$set": { "updated_at": self.clock.now().timestamp() },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::Before)
+                    .build(),
+            )
+            .await
+        {
+            Ok(riddle) => riddle,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        Ok(riddle.and_then(|riddle| riddle.files).and_then(|files| {
+            files
+                .into_iter()
+                .find(|file| &file.uploaded_name == uploaded_name)
+        }))
+    }
+
+    /// Whether any riddle still refers to the stored file `uploaded_name`,
+    /// be it as a file, a variant or a retina version.
+    pub async fn is_file_referenced(&self, uploaded_name: &String) -> Result<bool> {
+        match self
+            .get_riddles_coll()
+            .count_documents(
+                doc! { "$or": [
+                    { "files.uploadedName": uploaded_name },
+                    { "files.variants.uploadedName": uploaded_name },
+                    { "files.retina": uploaded_name },
+                ] },
+                None,
+            )
+            .await
+        {
+            Ok(count) => Ok(count > 0),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Applies `update` to the riddle and stamps `updated_at`.
+    pub async fn update_riddle(&self, riddle_id: &ObjectId, update: &RiddleUpdate) -> Result<()> {
+        let mut fields: bson::Document = match bson::to_document(update) {
+            Ok(fields) => fields,
+            Err(e) => return Err(DatabaseQueryError(e.to_string())),
+        };
+        // the fields' values are left out, as they may contain the solution
+        log::info!(
+            "update_riddle(); riddle_id = {}, fields = {:?}",
+            riddle_id,
+            fields.keys().collect::<Vec<&String>>()
+        );
+        fields.insert("updated_at", self.clock.now().timestamp());
+        let result: UpdateResult = match self
+            .get_riddles_coll()
+            .update_one(doc! { "_id": riddle_id }, doc! { "$set": fields }, None)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) if is_duplicate_key(&e) => {
+                return Err(RiddleLevelTakenError(update.level.unwrap_or_default()))
+            }
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        if result.matched_count == 0 {
+            return Err(RiddleNotFoundError);
+        }
+        Ok(())
+    }
+
+    /// Returns the game the given room belongs to.
+    pub async fn get_game_of_room(&self, room_id: &ObjectId) -> Result<Option<Game>> {
+        let room: Room = match self.get_room(room_id).await {
+            Ok(room) => room,
+            Err(e) => return Err(e),
+        };
+        self.get_game(&room.game_id).await
+    }
+
+    pub async fn set_game_state(&self, game_id: &ObjectId, state: GameState) -> Result<()> {
+        log::info!(
+            "set_game_state(); game_id = {}, state = {:?}",
+            game_id,
+            state
+        );
+        let result: UpdateResult = match self
+            .get_games_coll()
+            .update_one(
+                doc! { "_id": game_id },
+                doc! { "$set": { "state": bson::to_bson(&state).unwrap() } },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match result.matched_count {
+            0 => Err(GameNotFoundError),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn game_exists(&self, game_id: &ObjectId) -> Result<bool> {
+        match self
+            .get_games_coll()
+            .count_documents(doc! { "_id": game_id }, None)
+            .await
+        {
+            Ok(count) => Ok(count > 0),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Stores a validated game import.
+    pub async fn import_game(&mut self, import: &GameImport) -> Result<()> {
+        log::info!(
+            "import_game(); game_id = {}, name = {}",
+            &import.game.id,
+            &import.game.name
+        );
+        match self.get_games_coll().insert_one(&import.game, None).await {
+            Ok(_) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        if !import.riddles.is_empty() {
+            match self
+                .get_riddles_coll()
+                .insert_many(&import.riddles, None)
+                .await
+            {
+                Ok(_) => (),
+                Err(e) if is_duplicate_key(&e) => {
+                    let level: u32 = match *e.kind {
+                        ErrorKind::BulkWrite(ref failure) => failure
+                            .write_errors
+                            .as_ref()
+                            .and_then(|errors| errors.first())
+                            .and_then(|error| import.riddles.get(error.index))
+                            .map(|riddle| riddle.level)
+                            .unwrap_or_default(),
+                        _ => 0,
+                    };
+                    return Err(RiddleLevelTakenError(level));
+                }
+                Err(e) => return Err(MongoQueryError(e)),
+            }
+        }
+        match self.get_rooms_coll().insert_many(&import.rooms, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
     pub async fn get_riddle_if_solved(
         &self,
         riddle_id: &ObjectId,
         username: &String,
         options: impl Into<Option<FindOneOptions>>,
     ) -> Result<Option<Riddle>> {
-        let user: Option<User> = match self
-            .get_users_coll()
-            .find_one(
-                doc! {
-                    "username": username,
-                    "solved.riddle_id": riddle_id,
-                },
-                options,
-            )
+        #[derive(Debug, Serialize, Deserialize)]
+        struct UserId {
+            _id: ObjectId,
+        }
+        let user: Option<UserId> = match self
+            .get_database()
+            .collection::<UserId>(&self.coll_users)
+            .find_one(doc! { "username": username }, options)
             .await
         {
             Ok(user) => user,
             Err(e) => return Err(MongoQueryError(e)),
         };
-        if user.is_none() {
+        let user_id: ObjectId = match user {
+            Some(user) => user._id,
+            None => return Ok(Option::default()),
+        };
+        let solved: Option<SolvedAttempt> = match self
+            .get_attempts_coll()
+            .find_one(doc! { "user_id": user_id, "riddle_id": riddle_id }, None)
+            .await
+        {
+            Ok(solved) => solved,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        if solved.is_none() {
             return Ok(Option::default());
         }
         let riddle: Option<Riddle> = match self.get_riddle_by_oid(riddle_id).await {
@@ -720,21 +2032,50 @@ impl DB {
         }
     }
 
-    pub async fn get_user_role(&self, username: &String) -> Result<Role> {
-        log::info!("get_user_role(); username = {}", username);
-        #[derive(Debug, Serialize, Deserialize)]
-        struct UserRole {
-            _id: ObjectId,
-            role: Role,
-        }
-        let user: Option<UserRole> = match self
-            .get_database()
-            .collection::<UserRole>(&self.coll_users)
-            .find_one(
-                doc! { "username": username },
-                FindOneOptions::builder()
-                    .projection(doc! { "role": 1u32 })
-                    .build(),
+    /// Returns the users with the given username or mail address; at most
+    /// two, as both are unique.
+    pub async fn get_users_by_username_or_email(
+        &self,
+        username: &String,
+        email: &String,
+    ) -> Result<Vec<User>> {
+        log::info!(
+            "get_users_by_username_or_email(); username = {}, email = {}",
+            username,
+            email
+        );
+        let cursor: mongodb::Cursor<User> = match self
+            .get_users_coll()
+            .find(
+                doc! { "$or": [ { "username": username }, { "email": email } ] },
+                None,
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match cursor.try_collect().await {
+            Ok(users) => Ok(users),
+            Err(e) => Err(MongoError(e)),
+        }
+    }
+
+    pub async fn get_user_role(&self, username: &String) -> Result<Role> {
+        log::info!("get_user_role(); username = {}", username);
+        #[derive(Debug, Serialize, Deserialize)]
+        struct UserRole {
+            _id: ObjectId,
+            role: Role,
+        }
+        let user: Option<UserRole> = match self
+            .get_database()
+            .collection::<UserRole>(&self.coll_users)
+            .find_one(
+                doc! { "username": username },
+                FindOneOptions::builder()
+                    .projection(doc! { "role": 1u32 })
+                    .build(),
             )
             .await
         {
@@ -750,8 +2091,81 @@ impl DB {
         }
     }
 
+    /// Drops `username` from the user cache. Every method writing to a user
+    /// document or to the user's solved attempts has to call this after
+    /// the write, so that subsequent reads see it.
+    pub fn invalidate_user(&self, username: &str) {
+        if let Some(ref cache) = self.user_cache {
+            cache.lock().unwrap().invalidate(username);
+        }
+    }
+
+    /// Returns the version JWTs of the user must carry. Looked up on every
+    /// authorized request, so only the one field is fetched unless the
+    /// user is cached anyway.
+    pub async fn get_token_version(&self, username: &String) -> Result<u32> {
+        if let Some(ref cache) = self.user_cache {
+            if let Some(user) = cache.lock().unwrap().get(username) {
+                return Ok(user.token_version);
+            }
+        }
+        let user: Option<bson::Document> = match self
+            .get_database()
+            .collection::<bson::Document>(&self.coll_users)
+            .find_one(
+                doc! { "username": username },
+                FindOneOptions::builder()
+                    .projection(doc! { "token_version": 1 })
+                    .build(),
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match user {
+            Some(user) => Ok(match user.get("token_version") {
+                Some(bson::Bson::Int32(version)) => *version as u32,
+                Some(bson::Bson::Int64(version)) => *version as u32,
+                _ => 0,
+            }),
+            None => Err(UserNotFoundError),
+        }
+    }
+
+    /// Invalidates all JWTs issued to the user so far.
+    pub async fn increment_token_version(&self, username: &String) -> Result<()> {
+        log::info!("increment_token_version(); username = {}", username);
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username },
+                doc! { "$inc": { "token_version": 1i64 } },
+                None,
+            )
+            .await
+        {
+            Ok(result) if result.matched_count == 1 => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Ok(_) => Err(UserNotFoundError),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
     pub async fn get_user(&self, username: &String) -> Result<User> {
         log::info!("get_user(); username = {}", username);
+        let generation: Option<u64> = match self.user_cache {
+            Some(ref cache) => {
+                let mut cache = cache.lock().unwrap();
+                if let Some(user) = cache.get(username) {
+                    return Ok(user.clone());
+                }
+                Some(cache.generation())
+            }
+            None => None,
+        };
         let user: Option<User> = match self
             .get_users_coll()
             .find_one(doc! { "username": username }, None)
@@ -763,233 +2177,2413 @@ impl DB {
                 return Err(MongoQueryError(e));
             }
         };
-        match user {
-            Some(user) => Ok(user),
-            None => Err(UserNotFoundError),
+        let mut user: User = match user {
+            Some(user) => user,
+            None => return Err(UserNotFoundError),
+        };
+        user.solved = match self.get_solved_attempts(&user.id).await {
+            Ok(solved) => solved,
+            Err(e) => return Err(e),
+        };
+        if let (Some(ref cache), Some(generation)) = (&self.user_cache, generation) {
+            cache.lock().unwrap().insert(user.clone(), generation);
         }
+        Ok(user)
     }
 
-    pub async fn get_room(&self, oid: &ObjectId) -> Result<Room> {
-        log::info!("get_room(); oid = {}", oid);
-        let room: Option<Room> = match self
-            .get_rooms_coll()
-            .find_one(doc! { "_id": oid }, None)
+    pub async fn get_solved_attempts(&self, user_id: &ObjectId) -> Result<Vec<RiddleAttempt>> {
+        log::info!("get_solved_attempts(); user_id = {}", user_id);
+        let cursor: mongodb::Cursor<SolvedAttempt> = match self
+            .get_attempts_coll()
+            .find(
+                doc! { "user_id": user_id },
+                FindOptions::builder().sort(doc! { "t0": 1u32 }).build(),
+            )
             .await
         {
-            Ok(room) => room,
+            Ok(cursor) => cursor,
             Err(e) => return Err(MongoQueryError(e)),
         };
-        match room {
-            Some(room) => Ok(room),
-            None => Err(RoomNotFoundError),
-        }
+        let solved: Vec<SolvedAttempt> = match cursor.try_collect().await {
+            Ok(solved) => solved,
+            Err(e) => return Err(MongoError(e)),
+        };
+        Ok(solved.into_iter().map(|s| s.attempt).collect())
     }
 
-    pub async fn get_room_behind(
+    /// Returns the difficulties of the given riddles, keyed by riddle id.
+    pub async fn get_riddle_difficulties(
         &self,
-        opposite: &String,
-        riddle_id: &bson::oid::ObjectId,
-    ) -> Result<Room> {
-        log::info!(
-            "get_room_behind(); opposite = {}, riddle_id = {}",
-            opposite,
-            riddle_id
-        );
-        let room: Option<Room> = match self
-            .get_rooms_coll()
-            .find_one(
-                doc! {
-                    "neighbors": {
-                        "$elemMatch": {
-                            "direction": opposite,
-                            "riddle_id": riddle_id,
+        riddle_ids: &Vec<ObjectId>,
+    ) -> Result<HashMap<ObjectId, i32>> {
+        log::info!("get_riddle_difficulties(); riddle_ids = {:?}", riddle_ids);
+        let cursor: mongodb::Cursor<bson::Document> = match self
+            .get_database()
+            .collection::<bson::Document>(&self.coll_riddles)
+            .find(
+                doc! { "_id": { "$in": riddle_ids } },
+                FindOptions::builder()
+                    .projection(doc! { "difficulty": 1 })
+                    .build(),
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let docs: Vec<bson::Document> = match cursor.try_collect().await {
+            Ok(docs) => docs,
+            Err(e) => return Err(MongoError(e)),
+        };
+        Ok(docs
+            .iter()
+            .filter_map(|doc| match doc.get_object_id("_id") {
+                Ok(id) => Some((id, doc.get_i32("difficulty").unwrap_or(0))),
+                Err(_) => None,
+            })
+            .collect())
+    }
+
+    /// Returns the difficulty of each riddle solved by the given user along
+    /// with how long solving took.
+    pub async fn get_solved_difficulties(
+        &self,
+        user_id: &ObjectId,
+    ) -> Result<Vec<SolvedDifficulty>> {
+        log::info!("get_solved_difficulties(); user_id = {}", user_id);
+        let cursor: mongodb::Cursor<bson::Document> = match self
+            .get_attempts_coll()
+            .aggregate(
+                vec![
+                    doc! {
+                        "$match": {
+                            "user_id": user_id,
                         }
-                    }
-                },
+                    },
+                    doc! {
+                       "$lookup": {
+                            "from": &self.coll_riddles,
+                            "localField": "riddle_id",
+                            "foreignField": "_id",
+                            "as": "riddle"
+                        }
+                    },
+                    doc! {
+                        "$project": {
+                            "difficulty": doc! { "$arrayElemAt": [ "$riddle.difficulty", 0u32 ] },
+                            "dt": 1u32,
+                            "revealed": 1u32,
+                        }
+                    },
+                ],
                 None,
             )
             .await
         {
-            Ok(room) => room,
-            Err(e) => return Err(MongoQueryError(e)),
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoError(e)),
         };
-        match room {
-            Some(room) => Ok(room),
-            None => Err(RoomBehindNotFoundError),
-        }
+        let docs: Vec<bson::Document> = match cursor.try_collect().await {
+            Ok(docs) => docs,
+            Err(e) => return Err(MongoError(e)),
+        };
+        Ok(docs
+            .iter()
+            .map(|doc| SolvedDifficulty {
+                difficulty: doc.get_i32("difficulty").unwrap_or(0),
+                dt: match doc.get("dt") {
+                    Some(bson::Bson::Int64(dt)) => Some(*dt),
+                    Some(bson::Bson::Int32(dt)) => Some(*dt as i64),
+                    _ => None,
+                },
+                revealed: doc.get_bool("revealed").unwrap_or(false),
+            })
+            .collect())
     }
 
-    pub async fn get_user_with_pin(&self, username: &String, pin: PinType) -> Result<User> {
-        log::info!("get_user_with_pin(\"{}\", \"{:06}\")", username, pin);
-        let result: Option<User> = match self
-            .get_users_coll()
-            .find_one(
-                doc! { "username": username, "pin": pin, "activated": false },
+    /// Returns the last result of `get_admin_stats()` if it is recent enough.
+    pub fn get_cached_admin_stats(&self) -> Option<AdminStats> {
+        let now: DateTime<Utc> = self.clock.now();
+        self.admin_stats_cache
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|stats| (now - stats.computed_at).num_seconds() < ADMIN_STATS_MAX_AGE_SECS)
+    }
+
+    /// Counts users, content and sessions. The user counts come from a
+    /// single aggregation, the others from index-backed counts.
+    pub async fn get_admin_stats(&self) -> Result<AdminStats> {
+        log::info!("get_admin_stats()");
+        let now: DateTime<Utc> = self.clock.now();
+        let count: bson::Document = doc! { "$count": "n" };
+        let cursor: mongodb::Cursor<bson::Document> = match self
+            .get_database()
+            .collection::<bson::Document>(&self.coll_users)
+            .aggregate(
+                vec![doc! {
+                    "$facet": {
+                        "users": [ count.clone() ],
+                        "activated": [ { "$match": { "activated": true } }, count.clone() ],
+                        "two_factor": [
+                            {
+                                "$match": {
+                                    "$or": [
+                                        { "totp_keys.0": { "$exists": true } },
+                                        { "webauthn.credentials.0": { "$exists": true } },
+                                    ]
+                                }
+                            },
+                            count.clone(),
+                        ],
+                        "last_day": [
+                            { "$match": { "created": { "$gte": (now - chrono::Duration::days(1)).timestamp() } } },
+                            count.clone(),
+                        ],
+                        "last_week": [
+                            { "$match": { "created": { "$gte": (now - chrono::Duration::days(7)).timestamp() } } },
+                            count.clone(),
+                        ],
+                    }
+                }],
                 None,
             )
             .await
         {
-            Ok(user) => user,
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoError(e)),
+        };
+        let docs: Vec<bson::Document> = match cursor.try_collect().await {
+            Ok(docs) => docs,
+            Err(e) => return Err(MongoError(e)),
+        };
+        // each facet yields `[{ "n": count }]`, or `[]` if nothing matched
+        let facet = |name: &str| -> u64 {
+            docs.first()
+                .and_then(|doc| doc.get_array(name).ok())
+                .and_then(|counts| counts.first())
+                .and_then(|count| count.as_document())
+                .map_or(0, |count| match count.get("n") {
+                    Some(bson::Bson::Int32(n)) => *n as u64,
+                    Some(bson::Bson::Int64(n)) => *n as u64,
+                    _ => 0,
+                })
+        };
+        let num_riddles: u64 = match self.get_riddles_coll().estimated_document_count(None).await {
+            Ok(n) => n,
             Err(e) => return Err(MongoQueryError(e)),
         };
-        match result {
-            Some(user) => {
-                log::info!("Found {} <{}>", &user.username, &user.email);
-                Ok(user)
-            }
-            None => {
-                log::info!("user not found");
-                Err(UserNotFoundError)
-            }
-        }
+        let num_rooms: u64 = match self.get_rooms_coll().estimated_document_count(None).await {
+            Ok(n) => n,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let num_games: u64 = match self.get_games_coll().estimated_document_count(None).await {
+            Ok(n) => n,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let num_active_sessions: u64 = match self
+            .get_refresh_tokens_coll()
+            .count_documents(
+                doc! { "used": false, "expires": { "$gt": bson::DateTime::from_chrono(now) } },
+                None,
+            )
+            .await
+        {
+            Ok(n) => n,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let stats: AdminStats = AdminStats {
+            num_users: facet("users"),
+            num_activated_users: facet("activated"),
+            num_users_with_2fa: facet("two_factor"),
+            num_riddles,
+            num_rooms,
+            num_games,
+            num_active_sessions,
+            num_signups_last_day: facet("last_day"),
+            num_signups_last_week: facet("last_week"),
+            computed_at: now,
+        };
+        *self.admin_stats_cache.lock().unwrap() = Some(stats.clone());
+        Ok(stats)
     }
 
-    pub async fn set_user_solved(
-        &mut self,
-        solutions: &Vec<RiddleAttempt>,
-        user: &User,
-    ) -> Result<()> {
-        match self
-            .get_users_coll()
-            .update_one(
-                doc! { "_id": user.id, "activated": true },
-                doc! {
-                    "$set": {
-                        "solved": bson::to_bson(solutions).unwrap(),
-                        "level": user.level,
-                        "score": user.score,
+    /// Counts users who solved the riddle (attempts collection) plus users
+    /// currently working on it (`current_riddle_attempt`).
+    pub async fn get_riddle_stats(&self, riddle_id: &ObjectId) -> Result<RiddleStats> {
+        log::info!("get_riddle_stats(); riddle_id = {}", riddle_id);
+        let cursor: mongodb::Cursor<bson::Document> = match self
+            .get_attempts_coll()
+            .aggregate(
+                vec![
+                    doc! {
+                        "$match": {
+                            "riddle_id": riddle_id,
+                        }
                     },
-                },
+                    doc! {
+                        "$group": {
+                            "_id": { "$eq": [ "$revealed", true ] },
+                            "user_ids": { "$push": "$user_id" },
+                            "dts": { "$push": "$dt" },
+                        }
+                    },
+                ],
                 None,
             )
             .await
         {
-            Ok(_) => Ok(()),
-            Err(e) => Err(MongoQueryError(e)),
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoError(e)),
+        };
+        let docs: Vec<bson::Document> = match cursor.try_collect().await {
+            Ok(docs) => docs,
+            Err(e) => return Err(MongoError(e)),
+        };
+        let mut user_ids: Vec<ObjectId> = Vec::new();
+        let mut revealed_user_ids: Vec<ObjectId> = Vec::new();
+        let mut dts: Vec<i64> = Vec::new();
+        for doc in docs.iter() {
+            let ids: Vec<ObjectId> = match doc.get_array("user_ids") {
+                Ok(ids) => ids.iter().filter_map(|id| id.as_object_id()).collect(),
+                Err(_) => Vec::new(),
+            };
+            if doc.get_bool("_id").unwrap_or(false) {
+                revealed_user_ids = ids;
+                continue;
+            }
+            user_ids = ids;
+            if let Ok(values) = doc.get_array("dts") {
+                dts = values
+                    .iter()
+                    .filter_map(|dt| match dt {
+                        bson::Bson::Int64(dt) => Some(*dt),
+                        bson::Bson::Int32(dt) => Some(*dt as i64),
+                        _ => None,
+                    })
+                    .collect();
+            }
         }
-    }
-
-    pub async fn update_webauthn_cred(
-        &self,
-        username: &String,
-        cred_id: &CredentialID,
-        auth_data: &AuthenticatorData<Authentication>,
-    ) -> Result<()> {
-        let update_options = UpdateOptions::builder()
-            .array_filters(vec![doc! {
-                "elem.cred_id": bson::to_bson(cred_id).unwrap(),
-            }])
-            .build();
-        match self
+        let num_open: u64 = match self
             .get_users_coll()
-            .update_one(
-                doc! { "username": username, "activated": true },
+            .count_documents(
                 doc! {
-                    "$set": {
-                        "webauthn.credentials.$[elem].counter": auth_data.counter,
-                        "webauthn.credentials.$[elem].verified": auth_data.user_verified,
-                    }
+                    "current_riddle_attempt.riddle_id": riddle_id,
+                    "_id": { "$nin": [user_ids.as_slice(), revealed_user_ids.as_slice()].concat() },
                 },
-                update_options,
+                None,
             )
             .await
         {
+            Ok(count) => count,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let num_solved: u32 = user_ids.len() as u32;
+        let num_revealed: u32 = revealed_user_ids.len() as u32;
+        let num_attempts: u32 = num_solved + num_revealed + num_open as u32;
+        dts.sort();
+        let median_solve_time: Option<i64> = match dts.len() {
+            0 => None,
+            n if n % 2 == 0 => Some((dts[n / 2 - 1] + dts[n / 2]) / 2),
+            n => Some(dts[n / 2]),
+        };
+        Ok(RiddleStats {
+            riddle_id: *riddle_id,
+            num_attempts,
+            num_solved,
+            num_revealed,
+            solve_rate: if num_attempts > 0 {
+                num_solved as f32 / num_attempts as f32
+            } else {
+                0.0
+            },
+            median_solve_time,
+        })
+    }
+
+    /// Ranks the user's solve of a riddle by solve time. Revealed solutions
+    /// neither get a rank nor count as solves.
+    pub async fn get_solve_ranking(
+        &self,
+        riddle_id: &ObjectId,
+        user_id: &ObjectId,
+    ) -> Result<SolveRanking> {
+        log::info!(
+            "get_solve_ranking(); riddle_id = {}, user_id = {}",
+            riddle_id,
+            user_id
+        );
+        let attempt: Option<SolvedAttempt> = match self
+            .get_attempts_coll()
+            .find_one(doc! { "user_id": user_id, "riddle_id": riddle_id }, None)
+            .await
+        {
+            Ok(attempt) => attempt,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let solve_time: Option<i64> = match attempt {
+            Some(attempt) if !attempt.attempt.revealed => attempt.attempt.dt,
+            _ => None,
+        };
+        let num_solvers: u64 = match self
+            .get_attempts_coll()
+            .count_documents(
+                doc! { "riddle_id": riddle_id, "revealed": { "$ne": true } },
+                None,
+            )
+            .await
+        {
+            Ok(count) => count,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let rank: Option<u32> = match solve_time {
+            Some(dt) => match self
+                .get_attempts_coll()
+                .count_documents(
+                    doc! {
+                        "riddle_id": riddle_id,
+                        "revealed": { "$ne": true },
+                        "dt": { "$lt": dt },
+                    },
+                    None,
+                )
+                .await
+            {
+                Ok(faster) => Some(faster as u32 + 1),
+                Err(e) => return Err(MongoQueryError(e)),
+            },
+            None => None,
+        };
+        let mut cursor: mongodb::Cursor<bson::Document> = match self
+            .get_attempts_coll()
+            .aggregate(
+                vec![
+                    doc! {
+                        "$match": {
+                            "riddle_id": riddle_id,
+                            "revealed": { "$ne": true },
+                            "t0": { "$type": "number" },
+                            "dt": { "$type": "number" },
+                        }
+                    },
+                    doc! { "$addFields": { "solved_at": { "$add": [ "$t0", "$dt" ] } } },
+                    doc! { "$sort": { "solved_at": 1 } },
+                    doc! { "$limit": 1 },
+                    doc! {
+                        "$lookup": {
+                            "from": &self.coll_users,
+                            "localField": "user_id",
+                            "foreignField": "_id",
+                            "as": "user",
+                        }
+                    },
+                    doc! {
+                        "$project": {
+                            "username": { "$arrayElemAt": [ "$user.username", 0 ] },
+                        }
+                    },
+                ],
+                None,
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoError(e)),
+        };
+        let first_blood: Option<String> = match cursor.next().await {
+            Some(Ok(doc)) => doc.get_str("username").ok().map(|s| s.to_string()),
+            Some(Err(e)) => return Err(MongoError(e)),
+            None => None,
+        };
+        Ok(SolveRanking {
+            solve_time,
+            rank,
+            num_solvers: num_solvers as u32,
+            first_blood,
+        })
+    }
+
+    /// Records that the user has finished the game. If the game has been
+    /// finished before, the new completion replaces the old one only if it
+    /// scores higher, or as high but faster.
+    pub async fn record_game_finished(
+        &self,
+        user: &User,
+        game_id: &ObjectId,
+        score: i32,
+        elapsed: Option<i64>,
+    ) -> Result<()> {
+        log::info!(
+            "record_game_finished(); username = {}, game_id = {}, score = {}, elapsed = {:?}",
+            &user.username,
+            game_id,
+            score,
+            elapsed
+        );
+        let finished_doc: bson::Document = match bson::to_document(&FinishedGame {
+            game_id: *game_id,
+            timestamp: self.clock.now(),
+            score: Some(score),
+            elapsed,
+        }) {
+            Ok(finished_doc) => finished_doc,
+            Err(e) => return Err(DatabaseQueryError(e.to_string())),
+        };
+        let elapsed: i64 = elapsed.unwrap_or(i64::MAX);
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "_id": user.id },
+                vec![
+                    doc! {
+                        "$set": {
+                            "_previous": {
+                                "$arrayElemAt": [
+                                    {
+                                        "$filter": {
+                                            "input": { "$ifNull": [ "$finished", [] ] },
+                                            "cond": { "$eq": [ "$$this.game_id", game_id ] },
+                                        }
+                                    },
+                                    0i32,
+                                ]
+                            }
+                        }
+                    },
+                    doc! {
+                        "$set": {
+                            "finished": {
+                                "$cond": [
+                                    {
+                                        "$or": [
+                                            { "$gt": [ score, { "$ifNull": [ "$_previous.score", i32::MIN ] } ] },
+                                            {
+                                                "$and": [
+                                                    { "$eq": [ score, "$_previous.score" ] },
+                                                    { "$lt": [ elapsed, { "$ifNull": [ "$_previous.elapsed", i64::MAX ] } ] },
+                                                ]
+                                            },
+                                        ]
+                                    },
+                                    {
+                                        "$concatArrays": [
+                                            {
+                                                "$filter": {
+                                                    "input": { "$ifNull": [ "$finished", [] ] },
+                                                    "cond": { "$ne": [ "$$this.game_id", game_id ] },
+                                                }
+                                            },
+                                            [ &finished_doc ],
+                                        ]
+                                    },
+                                    "$finished",
+                                ]
+                            }
+                        }
+                    },
+                    doc! { "$unset": "_previous" },
+                ],
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(&user.username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Returns the games the user has finished, most recent first. Older
+    /// records may list a game more than once, in which case only the first
+    /// completion counts.
+    pub async fn get_finished_games(&self, username: &String) -> Result<Vec<FinishedGame>> {
+        log::info!("get_finished_games(); username = {}", username);
+        let user: User = match self.get_user(username).await {
+            Ok(user) => user,
+            Err(e) => return Err(e),
+        };
+        let mut finished: Vec<FinishedGame> = user.finished;
+        finished.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        let mut seen: HashSet<ObjectId> = HashSet::new();
+        finished.retain(|f| seen.insert(f.game_id));
+        finished.reverse();
+        Ok(finished)
+    }
+
+    /// Returns the user's position on the leaderboard, using the same
+    /// order as `get_full_user_scores()`: score descending, then earliest
+    /// `last_login` (missing first), then `_id`. `None` for users who have
+    /// not been activated and for spectators.
+    pub async fn get_user_rank(&self, user: &User) -> Result<Option<u32>> {
+        log::info!("get_user_rank(); username = {}", &user.username);
+        if !user.activated || user.role == Role::Spectator {
+            return Ok(None);
+        }
+        let mut ahead: Vec<bson::Document> = vec![doc! { "score": { "$gt": user.score } }];
+        match user.last_login {
+            Some(last_login) => {
+                ahead.push(doc! { "score": user.score, "last_login": bson::Bson::Null });
+                ahead.push(doc! {
+                    "score": user.score,
+                    "last_login": { "$lt": last_login.timestamp() },
+                });
+                ahead.push(doc! {
+                    "score": user.score,
+                    "last_login": last_login.timestamp(),
+                    "_id": { "$lt": user.id },
+                });
+            }
+            None => ahead.push(doc! {
+                "score": user.score,
+                "last_login": bson::Bson::Null,
+                "_id": { "$lt": user.id },
+            }),
+        }
+        let mut filter: bson::Document = players_only();
+        filter.insert("activated", true);
+        filter.insert("$or", ahead);
+        match self.get_users_coll().count_documents(filter, None).await {
+            Ok(count) => Ok(Some(count as u32 + 1)),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn get_room(&self, oid: &ObjectId) -> Result<Room> {
+        log::info!("get_room(); oid = {}", oid);
+        let room: Option<Room> = match self
+            .get_rooms_coll()
+            .find_one(doc! { "_id": oid }, None)
+            .await
+        {
+            Ok(room) => room,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match room {
+            Some(room) => Ok(room),
+            None => Err(RoomNotFoundError),
+        }
+    }
+
+    /// Looks a room up by the number it is known by within a game.
+    pub async fn get_room_by_number(&self, game_id: &ObjectId, number: u32) -> Result<Room> {
+        log::info!(
+            "get_room_by_number(); game_id = {}, number = {}",
+            game_id,
+            number
+        );
+        let room: Option<Room> = match self
+            .get_rooms_coll()
+            .find_one(doc! { "game_id": game_id, "number": number }, None)
+            .await
+        {
+            Ok(room) => room,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match room {
+            Some(room) => Ok(room),
+            None => Err(RoomNotFoundError),
+        }
+    }
+
+    /// Puts the user into `room_id` without going through any doorway. The
+    /// riddle they were working on is abandoned, as it belongs to the room
+    /// they leave. Returns the updated user.
+    pub async fn teleport_user(&self, username: &String, room_id: &ObjectId) -> Result<User> {
+        log::info!(
+            "teleport_user(); username = {}, room_id = {}",
+            username,
+            room_id
+        );
+        let user: Option<User> = match self
+            .get_users_coll()
+            .find_one_and_update(
+                doc! { "username": username },
+                doc! {
+                    "$set": { "in_room": room_id },
+                    "$addToSet": { "rooms_entered": room_id },
+                    "$unset": { "current_riddle_attempt": "" },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        match user {
+            Some(user) => Ok(user),
+            None => Err(UserNotFoundError),
+        }
+    }
+
+    /// Searches breadth-first for the shortest way from room `from` to room
+    /// `to` of the same game, only passing through doorways whose riddle is
+    /// in `passable`. Returns the directions to take in order.
+    pub async fn shortest_path(
+        &self,
+        from: &ObjectId,
+        to: &ObjectId,
+        passable: &HashSet<ObjectId>,
+    ) -> Result<Vec<String>> {
+        log::info!("shortest_path(); from = {}, to = {}", from, to);
+        let start: Room = match self.get_room(from).await {
+            Ok(room) => room,
+            Err(e) => return Err(e),
+        };
+        let cursor: mongodb::Cursor<Room> = match self
+            .get_rooms_coll()
+            .find(doc! { "game_id": start.game_id }, None)
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let rooms: Vec<Room> = match cursor.try_collect().await {
+            Ok(rooms) => rooms,
+            Err(e) => return Err(MongoError(e)),
+        };
+        find_path(&rooms, from, to, passable)
+    }
+
+    /// Finds the room on the other side of the doorway secured by
+    /// `riddle_id` that leaves `from` in the direction opposite to
+    /// `opposite`. Only rooms of the same game (other than `from` itself)
+    /// are considered. If a riddle secures several doorways so that more
+    /// than one room qualifies, the move is refused rather than resolved
+    /// arbitrarily.
+    pub async fn get_room_behind(
+        &self,
+        from: &Room,
+        opposite: &String,
+        riddle_id: &bson::oid::ObjectId,
+    ) -> Result<Room> {
+        log::info!(
+            "get_room_behind(); from = {}, opposite = {}, riddle_id = {}",
+            &from.id,
+            opposite,
+            riddle_id
+        );
+        let cursor: mongodb::Cursor<Room> = match self
+            .get_rooms_coll()
+            .find(
+                doc! {
+                    "_id": { "$ne": from.id },
+                    "game_id": from.game_id,
+                    "neighbors": {
+                        "$elemMatch": {
+                            "direction": opposite,
+                            "riddle_id": riddle_id,
+                        }
+                    }
+                },
+                FindOptions::builder().limit(2).build(),
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let mut rooms: Vec<Room> = match cursor.try_collect().await {
+            Ok(rooms) => rooms,
+            Err(e) => return Err(MongoError(e)),
+        };
+        match rooms.len() {
+            0 => Err(RoomBehindNotFoundError),
+            1 => Ok(rooms.remove(0)),
+            _ => {
+                log::error!(
+                    "riddle {} secures more than one doorway facing '{}' in game {}",
+                    riddle_id,
+                    opposite,
+                    &from.game_id
+                );
+                Err(AmbiguousRoomBehindError)
+            }
+        }
+    }
+
+    pub async fn get_user_with_pin(&self, username: &String, pin: PinType) -> Result<User> {
+        log::info!("get_user_with_pin(\"{}\", \"{:06}\")", username, pin);
+        let result: Option<User> = match self
+            .get_users_coll()
+            .find_one(
+                doc! {
+                    "username": username,
+                    "pin": pin,
+                    "activated": false,
+                    "deactivated": { "$ne": true },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match result {
+            Some(user) => {
+                log::info!("Found {} <{}>", &user.username, &user.email);
+                Ok(user)
+            }
+            None => {
+                log::info!("user not found");
+                Err(UserNotFoundError)
+            }
+        }
+    }
+
+    /// Finds the not yet activated user with the given username or email
+    /// address whose PIN was last mailed before `sent_before`, and marks
+    /// the PIN as mailed now. Returns `None` if there is no such user.
+    /// The mail rendered by `mail` is queued in the same transaction.
+    pub async fn claim_pin_resend(
+        &self,
+        username: &String,
+        email: &String,
+        sent_before: DateTime<Utc>,
+        mail: impl FnOnce(&User) -> Result<OutboxMail>,
+    ) -> Result<Option<User>> {
+        log::info!(
+            "claim_pin_resend(); username = {}, email = {}",
+            username,
+            email
+        );
+        let mut session: ClientSession = self.start_transaction().await?;
+        let user: Option<User> = match self
+            .get_users_coll()
+            .find_one_and_update_with_session(
+                doc! {
+                    "activated": false,
+                    "deactivated": { "$ne": true },
+                    "$and": [
+                        { "$or": [ { "username": username }, { "email": email } ] },
+                        { "$or": [ { "last_pin_sent": null }, { "last_pin_sent": { "$lt": sent_before.timestamp() } } ] },
+                    ],
+                },
+                doc! { "$set": { "last_pin_sent": self.clock.now().timestamp() } },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+                &mut session,
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let user: User = match user {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        self.enqueue_mail_with_session(&mail(&user)?, &mut session)
+            .await?;
+        match session.commit_transaction().await {
+            Ok(()) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        self.invalidate_user(&user.username);
+        Ok(Some(user))
+    }
+
+    /// Finds a user who has been activated with `pin` no earlier than
+    /// `since`, so that a retried activation can be answered again.
+    pub async fn get_activated_user_with_pin(
+        &self,
+        username: &String,
+        pin: PinType,
+        since: DateTime<Utc>,
+    ) -> Result<User> {
+        log::info!("get_activated_user_with_pin(); username = {}", username);
+        let result: Option<User> = match self
+            .get_users_coll()
+            .find_one(
+                doc! {
+                    "username": username,
+                    "pin": pin,
+                    "activated": true,
+                    "registered": { "$gte": since.timestamp() },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match result {
+            Some(user) => Ok(user),
+            None => {
+                log::info!("user not found");
+                Err(UserNotFoundError)
+            }
+        }
+    }
+
+    /// Records `attempt` as solved by `user`, raises the user's level to
+    /// `user.level` and adds `points` to their score, which never drops
+    /// below zero. Solving the same riddle again keeps the first record and
+    /// doesn't score. Returns the user's new score.
+    pub async fn set_user_solved(
+        &mut self,
+        attempt: &RiddleAttempt,
+        user: &User,
+        points: i32,
+    ) -> Result<i32> {
+        let solved = SolvedAttempt {
+            id: ObjectId::new(),
+            user_id: user.id,
+            attempt: attempt.clone(),
+        };
+        let points: i32 = match self
+            .get_attempts_coll()
+            .update_one(
+                doc! { "user_id": user.id, "riddle_id": attempt.riddle_id },
+                doc! { "$setOnInsert": bson::to_document(&solved).unwrap() },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+        {
+            Ok(result) if result.upserted_id.is_some() => points,
+            // solved before
+            Ok(_) => 0,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let updated: Option<User> = match self
+            .get_users_coll()
+            .find_one_and_update(
+                doc! { "_id": user.id, "activated": true },
+                vec![doc! {
+                    "$set": {
+                        "level": { "$max": [ { "$ifNull": [ "$level", 0i32 ] }, user.level ] },
+                        "score": { "$max": [ 0i32, { "$add": [ { "$ifNull": [ "$score", 0i32 ] }, points ] } ] },
+                    },
+                }],
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+        {
+            Ok(updated) => updated,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(&user.username);
+        match updated {
+            Some(updated) => Ok(updated.score),
+            None => Err(UserNotFoundError),
+        }
+    }
+
+    /// Rewrites TOTP keys stored as base32 in the canonical base64 form.
+    pub async fn migrate_totp_keys(&self) -> Result<()> {
+        log::info!("migrate_totp_keys()");
+        #[derive(Debug, Deserialize)]
+        struct StoredTotpKey {
+            _id: ObjectId,
+            totp_key: String,
+        }
+        let mut cursor: mongodb::Cursor<StoredTotpKey> = match self
+            .get_database()
+            .collection::<StoredTotpKey>(&self.coll_users)
+            .find(
+                doc! { "totp_key": { "$regex": "^[A-Z2-7]+=*$" } },
+                FindOptions::builder()
+                    .projection(doc! { "totp_key": 1u32 })
+                    .build(),
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let mut num_migrated: u32 = 0;
+        while let Some(stored) = cursor.next().await {
+            let stored: StoredTotpKey = match stored {
+                Ok(stored) => stored,
+                Err(e) => return Err(MongoError(e)),
+            };
+            let totp_key: Vec<u8> = match b64::decode(&stored.totp_key) {
+                Ok(totp_key) => totp_key,
+                Err(e) => {
+                    log::error!("cannot decode TOTP key of user {}: {}", &stored._id, e);
+                    continue;
+                }
+            };
+            match self
+                .get_users_coll()
+                .update_one(
+                    doc! { "_id": stored._id },
+                    doc! { "$set": { "totp_key": base64::encode(&totp_key) } },
+                    None,
+                )
+                .await
+            {
+                Ok(_) => num_migrated += 1,
+                Err(e) => return Err(MongoQueryError(e)),
+            }
+        }
+        if num_migrated > 0 {
+            log::info!("Migrated TOTP keys of {} users to base64.", num_migrated);
+        }
+        Ok(())
+    }
+
+    /// Moves unconfirmed TOTP keys, which older user documents kept in
+    /// `totp_keys` flagged with `totp_pending`, to `pending_totp_key`.
+    pub async fn migrate_pending_totp(&self) -> Result<()> {
+        log::info!("migrate_pending_totp()");
+        match self
+            .get_users_coll()
+            .update_many(
+                doc! { "totp_pending": { "$exists": true } },
+                vec![
+                    doc! {
+                        "$set": {
+                            "pending_totp_key": {
+                                "$cond": [
+                                    { "$eq": [ "$totp_pending", true ] },
+                                    { "$arrayElemAt": [ { "$ifNull": [ "$totp_keys", [] ] }, 0 ] },
+                                    null,
+                                ]
+                            },
+                            "totp_keys": {
+                                "$cond": [ { "$eq": [ "$totp_pending", true ] }, [], "$totp_keys" ]
+                            },
+                        }
+                    },
+                    doc! { "$unset": "totp_pending" },
+                ],
+                None,
+            )
+            .await
+        {
+            Ok(result) => {
+                if result.modified_count > 0 {
+                    log::info!(
+                        "Migrated pending TOTP keys of {} users.",
+                        result.modified_count
+                    );
+                }
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Turns the single `totp_key` of older user documents into a one-element
+    /// `totp_keys` list.
+    pub async fn migrate_totp_key_lists(&self) -> Result<()> {
+        log::info!("migrate_totp_key_lists()");
+        match self
+            .get_users_coll()
+            .update_many(
+                doc! { "totp_key": { "$exists": true } },
+                vec![
+                    doc! {
+                        "$set": {
+                            "totp_keys": {
+                                "$cond": [
+                                    { "$eq": [ "$totp_key", "" ] },
+                                    [],
+                                    [ { "name": "default", "key": "$totp_key" } ],
+                                ]
+                            }
+                        }
+                    },
+                    doc! { "$unset": "totp_key" },
+                ],
+                None,
+            )
+            .await
+        {
+            Ok(result) => {
+                if result.modified_count > 0 {
+                    log::info!(
+                        "Migrated TOTP keys of {} users to lists.",
+                        result.modified_count
+                    );
+                }
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Stores a TOTP authenticator that replaces the user's authenticators
+    /// once confirmed via `confirm_totp()`.
+    pub async fn set_pending_totp(&self, username: &String, secret: &TotpSecret) -> Result<()> {
+        log::info!("set_pending_totp(); username = {}", username);
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                doc! { "$set": { "pending_totp_key": bson::to_bson(secret).unwrap() } },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Makes TOTP set up by `user_totp_enable_handler` live, replacing all
+    /// authenticators the user had before. Returns `false` if `secret` is
+    /// no longer the pending one, e.g. because the user has started over.
+    pub async fn confirm_totp(&self, username: &String, secret: &TotpSecret) -> Result<bool> {
+        log::info!("confirm_totp(); username = {}", username);
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "username": username,
+                    "activated": true,
+                    "pending_totp_key": bson::to_bson(secret).unwrap(),
+                },
+                doc! {
+                    "$set": { "totp_keys": [bson::to_bson(secret).unwrap()] },
+                    "$unset": { "pending_totp_key": "" },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => {
+                self.invalidate_user(username);
+                Ok(result.modified_count == 1)
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Adds a TOTP authenticator unless the user already has one of the
+    /// same name. Returns `false` in that case.
+    pub async fn add_totp_secret(&self, username: &String, secret: &TotpSecret) -> Result<bool> {
+        log::info!(
+            "add_totp_secret(); username = {}, name = {}",
+            username,
+            &secret.name
+        );
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "username": username,
+                    "activated": true,
+                    "totp_keys.name": { "$ne": &secret.name },
+                },
+                doc! { "$push": { "totp_keys": bson::to_bson(secret).unwrap() } },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        Ok(result.modified_count == 1)
+    }
+
+    /// Removes the named TOTP authenticator. Returns `false` if there was
+    /// none of that name.
+    pub async fn remove_totp_secret(&self, username: &String, name: &String) -> Result<bool> {
+        log::info!(
+            "remove_totp_secret(); username = {}, name = {}",
+            username,
+            name
+        );
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                doc! { "$pull": { "totp_keys": { "name": name } } },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        Ok(result.modified_count == 1)
+    }
+
+    /// Moves `solved` arrays still embedded in user documents into the
+    /// attempts collection. Safe to run on every start.
+    pub async fn migrate_embedded_solved(&self) -> Result<()> {
+        log::info!("migrate_embedded_solved()");
+        let index: IndexModel = IndexModel::builder()
+            .keys(doc! { "user_id": 1u32, "riddle_id": 1u32 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        match self.get_attempts_coll().create_index(index, None).await {
+            Ok(_) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        #[derive(Debug, Deserialize)]
+        struct EmbeddedSolved {
+            _id: ObjectId,
+            #[serde(default)]
+            solved: Vec<RiddleAttempt>,
+        }
+        let mut cursor: mongodb::Cursor<EmbeddedSolved> = match self
+            .get_database()
+            .collection::<EmbeddedSolved>(&self.coll_users)
+            .find(
+                doc! { "solved": { "$exists": true } },
+                FindOptions::builder()
+                    .projection(doc! { "solved": 1u32 })
+                    .build(),
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let mut num_migrated: u32 = 0;
+        while let Some(user) = cursor.next().await {
+            let user: EmbeddedSolved = match user {
+                Ok(user) => user,
+                Err(e) => return Err(MongoError(e)),
+            };
+            for attempt in user.solved.iter() {
+                let solved = SolvedAttempt {
+                    id: ObjectId::new(),
+                    user_id: user._id,
+                    attempt: attempt.clone(),
+                };
+                match self
+                    .get_attempts_coll()
+                    .update_one(
+                        doc! { "user_id": user._id, "riddle_id": attempt.riddle_id },
+                        doc! { "$setOnInsert": bson::to_document(&solved).unwrap() },
+                        UpdateOptions::builder().upsert(true).build(),
+                    )
+                    .await
+                {
+                    Ok(_) => (),
+                    Err(e) => return Err(MongoQueryError(e)),
+                }
+            }
+            match self
+                .get_users_coll()
+                .update_one(
+                    doc! { "_id": user._id },
+                    doc! { "$unset": { "solved": 0u32 } },
+                    None,
+                )
+                .await
+            {
+                Ok(_) => num_migrated += 1,
+                Err(e) => return Err(MongoQueryError(e)),
+            }
+        }
+        log::info!("Migrated solved riddles of {} user(s).", num_migrated);
+        Ok(())
+    }
+
+    /// Stores the credential's new signature counter. A counter that
+    /// doesn't increase hints at a cloned authenticator and is rejected
+    /// with `CredentialCounterRegressionError`, unless the authenticator
+    /// doesn't implement counters at all (stored and asserted both zero).
+    pub async fn update_webauthn_cred(
+        &self,
+        username: &String,
+        cred_id: &CredentialID,
+        auth_data: &AuthenticatorData<Authentication>,
+    ) -> Result<()> {
+        let counter_filter: bson::Document = match auth_data.counter {
+            0 => doc! { "$eq": 0u32 },
+            counter => doc! { "$lt": counter },
+        };
+        let update_options = UpdateOptions::builder()
+            .array_filters(vec![doc! {
+                "elem.cred_id": bson::to_bson(cred_id).unwrap(),
+            }])
+            .build();
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "username": username,
+                    "activated": true,
+                    "webauthn.credentials": {
+                        "$elemMatch": {
+                            "cred_id": bson::to_bson(cred_id).unwrap(),
+                            "counter": counter_filter,
+                        }
+                    },
+                },
+                doc! {
+                    "$set": {
+                        "webauthn.credentials.$[elem].counter": auth_data.counter,
+                        "webauthn.credentials.$[elem].verified": auth_data.user_verified,
+                    }
+                },
+                update_options,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        match result.matched_count {
+            0 => {
+                log::warn!(
+                    "credential counter of user {} did not increase (asserted {})",
+                    username,
+                    auth_data.counter
+                );
+                Err(CredentialCounterRegressionError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn set_user_awaiting_2fa(&mut self, user: &User, awaiting: bool) -> Result<()> {
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "_id": user.id, "activated": true },
+                doc! {
+                    "$set": { "awaiting_second_factor": awaiting },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(&user.username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn save_webauthn_registration_state(
+        &self,
+        username: &String,
+        rs: &RegistrationState,
+    ) -> Result<()> {
+        log::info!(
+            "save_webauthn_registration_state(); username = {}, rs = {:?}",
+            username,
+            rs
+        );
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                doc! {
+                    "$set": {
+                        "webauthn.registrationState": Some(bson::to_bson(rs).unwrap()),
+                    },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn save_webauthn_registration(
+        &self,
+        username: &String,
+        creds: &Vec<Credential>,
+    ) -> Result<()> {
+        log::info!("save_webauthn_registration(); username = {}", username);
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                doc! {
+                    "$set": {
+                        "webauthn.credentials": Some(bson::to_bson(creds).unwrap()),
+                    },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn save_webauthn_authentication_state(
+        &self,
+        username: &String,
+        st: &AuthenticationState,
+    ) -> Result<()> {
+        log::info!(
+            "save_webauthn_authentication_state(); username = {}, as = {:?}",
+            username,
+            st
+        );
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                doc! {
+                    "$set": {
+                        "webauthn.authenticationState": Some(bson::to_bson(st).unwrap()),
+                    },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn clear_webauthn_authentication_state(&self, username: &String) -> Result<()> {
+        log::info!(
+            "clear_webauthn_authentication_state(); username = {}",
+            username
+        );
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                doc! { "$unset": { "webauthn.authenticationState": 0u32 } },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Counts a wrong answer to the user's current riddle.
+    pub async fn add_failed_attempt(&self, user: &User, riddle_id: &ObjectId) -> Result<()> {
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "_id": user.id,
+                    "activated": true,
+                    "current_riddle_attempt.riddle_id": riddle_id,
+                },
+                doc! { "$inc": { "current_riddle_attempt.failures": 1u32 } },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(&user.username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Stamps `now` as the user's last wrong answer to `riddle_id`,
+    /// replacing an earlier one, and counts it. This is done before the
+    /// answer is checked, and only if the last wrong answer is at least
+    /// `cooldown_secs` old and fewer than `max_attempts` wrong answers have
+    /// been given, so that answers sent at the same time can't slip past
+    /// the cooldown or the attempt limit. Returns the user as before the
+    /// update, or `None` if the update was refused.
+    pub async fn reserve_attempt(
+        &self,
+        user: &User,
+        riddle_id: &ObjectId,
+        cooldown_secs: i64,
+        max_attempts: Option<u32>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<User>> {
+        let mut refused: Vec<bson::Document> =
+            vec![doc! { "at": { "$gt": now.timestamp() - cooldown_secs } }];
+        if let Some(max_attempts) = max_attempts {
+            refused.push(doc! { "count": { "$gte": max_attempts } });
+        }
+        let previous_count: bson::Document = doc! {
+            "$arrayElemAt": [
+                {
+                    "$map": {
+                        "input": {
+                            "$filter": {
+                                "input": { "$ifNull": [ "$last_wrong_answers", [] ] },
+                                "cond": { "$eq": [ "$$this.riddle_id", riddle_id ] },
+                            }
+                        },
+                        "in": "$$this.count",
+                    }
+                },
+                0i32,
+            ]
+        };
+        let wrong_answer_doc: bson::Document = doc! {
+            "riddle_id": riddle_id,
+            "at": now.timestamp(),
+            "count": { "$add": [ { "$ifNull": [ previous_count, 0i64 ] }, 1i64 ] },
+        };
+        match self
+            .get_users_coll()
+            .find_one_and_update(
+                doc! {
+                    "_id": user.id,
+                    "activated": true,
+                    "last_wrong_answers": {
+                        "$not": {
+                            "$elemMatch": { "riddle_id": riddle_id, "$or": refused }
+                        }
+                    },
+                },
+                vec![doc! {
+                    "$set": {
+                        "last_wrong_answers": {
+                            "$concatArrays": [
+                                {
+                                    "$filter": {
+                                        "input": { "$ifNull": [ "$last_wrong_answers", [] ] },
+                                        "cond": { "$ne": [ "$$this.riddle_id", riddle_id ] },
+                                    }
+                                },
+                                [ &wrong_answer_doc ],
+                            ]
+                        }
+                    }
+                }],
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::Before)
+                    .build(),
+            )
+            .await
+        {
+            Ok(before) => {
+                self.invalidate_user(&user.username);
+                Ok(before)
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Undoes `reserve_attempt()` made at `reserved_at` for an answer that
+    /// turned out to be right, putting back `previous`, the user's last
+    /// wrong answer to `riddle_id` before the reservation.
+    pub async fn release_attempt(
+        &self,
+        user: &User,
+        riddle_id: &ObjectId,
+        reserved_at: DateTime<Utc>,
+        previous: Option<&WrongAnswer>,
+    ) -> Result<()> {
+        let restored: Vec<bson::Bson> = match previous {
+            Some(previous) => vec![bson::to_bson(previous).unwrap()],
+            None => Vec::new(),
+        };
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "_id": user.id,
+                    "last_wrong_answers": {
+                        "$elemMatch": { "riddle_id": riddle_id, "at": reserved_at.timestamp() }
+                    },
+                },
+                vec![doc! {
+                    "$set": {
+                        "last_wrong_answers": {
+                            "$concatArrays": [
+                                {
+                                    "$filter": {
+                                        "input": "$last_wrong_answers",
+                                        "cond": { "$ne": [ "$$this.riddle_id", riddle_id ] },
+                                    }
+                                },
+                                { "$literal": restored },
+                            ]
+                        }
+                    }
+                }],
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(&user.username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Records that the item with `idempotency_key` for `riddle_id` is
+    /// being processed, keeping only the `keep` most recent items. Returns
+    /// `false` if it has been recorded before, e.g. by a concurrent request,
+    /// in which case it must not be booked again.
+    pub async fn claim_processed_solve(
+        &self,
+        username: &String,
+        riddle_id: &ObjectId,
+        idempotency_key: &String,
+        keep: i32,
+    ) -> Result<bool> {
+        let processed_doc: bson::Document = match bson::to_document(&ProcessedSolve {
+            idempotency_key: idempotency_key.clone(),
+            riddle_id: *riddle_id,
+            solved: false,
+        }) {
+            Ok(processed_doc) => processed_doc,
+            Err(e) => return Err(DatabaseQueryError(e.to_string())),
+        };
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "username": username,
+                    "processed_solves": { "$not": { "$elemMatch": {
+                        "idempotency_key": idempotency_key,
+                        "riddle_id": riddle_id,
+                    } } },
+                },
+                doc! {
+                    "$push": {
+                        "processed_solves": { "$each": [ processed_doc ], "$slice": -keep }
+                    }
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        Ok(result.modified_count == 1)
+    }
+
+    /// Stores the outcome of an item claimed with `claim_processed_solve()`.
+    pub async fn settle_processed_solve(
+        &self,
+        username: &String,
+        riddle_id: &ObjectId,
+        idempotency_key: &String,
+        solved: bool,
+    ) -> Result<()> {
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "username": username,
+                    "processed_solves": { "$elemMatch": {
+                        "idempotency_key": idempotency_key,
+                        "riddle_id": riddle_id,
+                    } },
+                },
+                doc! { "$set": { "processed_solves.$.solved": solved } },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Forgets an item claimed with `claim_processed_solve()` that couldn't
+    /// be booked, so that the client may submit it again.
+    pub async fn release_processed_solve(
+        &self,
+        username: &String,
+        riddle_id: &ObjectId,
+        idempotency_key: &String,
+    ) -> Result<()> {
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username },
+                doc! { "$pull": { "processed_solves": {
+                    "idempotency_key": idempotency_key,
+                    "riddle_id": riddle_id,
+                } } },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Ok(())
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn promote_user(&mut self, username: &String, role: &Role) -> Result<()> {
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                // JWTs carry the role, so they're revoked if it changes
+                vec![doc! {
+                    "$set": {
+                        "token_version": {
+                            "$cond": [
+                                { "$eq": [ "$role", bson::to_bson(role).unwrap() ] },
+                                "$token_version",
+                                { "$add": [ { "$ifNull": [ "$token_version", 0i64 ] }, 1i64 ] },
+                            ]
+                        },
+                        "role": bson::to_bson(role).unwrap(),
+                    },
+                }],
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        match result {
+            result if result.matched_count == 0 => Err(UserNotFoundError),
+            result if result.matched_count == 1 && result.modified_count == 0 => {
+                Err(CannotPromoteUserError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts the activated admins.
+    pub async fn count_admins(&self) -> Result<u64> {
+        match self
+            .get_users_coll()
+            .count_documents(
+                doc! { "role": bson::to_bson(&Role::Admin).unwrap(), "activated": true },
+                None,
+            )
+            .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Deactivates the user and invalidates their JWTs. Neither the PIN
+    /// nor a resent one activates them again. The last active admin can't
+    /// be deactivated: the admins are counted after the update, which is
+    /// undone if none is left. Of two admins deactivated at the same time
+    /// at least the second one to be counted is restored that way, so there
+    /// is always an admin left.
+    pub async fn deactivate_user(&self, username: &String) -> Result<()> {
+        log::info!("deactivate_user(); username = {}", username);
+        let user: User = match self
+            .get_users_coll()
+            .find_one_and_update(
+                doc! { "username": username },
+                doc! {
+                    "$set": { "activated": false, "deactivated": true },
+                    "$inc": { "token_version": 1i64 },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::Before)
+                    .build(),
+            )
+            .await
+        {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(UserNotFoundError),
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        if user.role != Role::Admin || !user.activated {
+            return Ok(());
+        }
+        match self.count_admins().await {
+            Ok(0) => (),
+            Ok(_) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        log::warn!("{} is the last admin, activating again", username);
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username },
+                doc! { "$set": { "activated": true, "deactivated": user.deactivated } },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.invalidate_user(username);
+                Err(CannotRemoveLastAdminError)
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Deletes the user along with their solved riddles, room visits and
+    /// refresh tokens. Audit log entries concerning them are kept. Like
+    /// `deactivate_user()`, refused for the last active admin.
+    pub async fn delete_user(&self, username: &String) -> Result<()> {
+        log::info!("delete_user(); username = {}", username);
+        // refused for the last admin
+        match self.deactivate_user(username).await {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        }
+        let user: User = match self
+            .get_users_coll()
+            .find_one_and_delete(doc! { "username": username }, None)
+            .await
+        {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(UserNotFoundError),
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        match self
+            .get_attempts_coll()
+            .delete_many(doc! { "user_id": user.id }, None)
+            .await
+        {
+            Ok(_) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        match self
+            .get_room_visits_coll()
+            .delete_many(doc! { "user_id": user.id }, None)
+            .await
+        {
+            Ok(_) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        self.revoke_refresh_tokens_of_user(username).await
+    }
+
+    /// Inserts `user` and queues `mail` in one transaction.
+    pub async fn create_user(&mut self, user: &User, mail: &OutboxMail) -> Result<()> {
+        log::info!("create_user(); username = {}", &user.username);
+        let mut session: ClientSession = self.start_transaction().await?;
+        match self
+            .get_users_coll()
+            .insert_one_with_session(user, None, &mut session)
+            .await
+        {
+            Ok(_) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        self.enqueue_mail_with_session(mail, &mut session).await?;
+        match session.commit_transaction().await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Inserts all `users` with a single `insert_many()` and queues `mails`
+    /// in the same transaction; used by the bulk import, which has checked
+    /// the users for conflicts beforehand.
+    pub async fn create_users(&mut self, users: &Vec<User>, mails: &Vec<OutboxMail>) -> Result<()> {
+        log::info!("create_users(); count = {}", users.len());
+        if users.is_empty() {
+            return Ok(());
+        }
+        let mut session: ClientSession = self.start_transaction().await?;
+        match self
+            .get_users_coll()
+            .insert_many_with_session(users, None, &mut session)
+            .await
+        {
+            Ok(_) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        if !mails.is_empty() {
+            match self
+                .get_email_outbox_coll()
+                .insert_many_with_session(mails, None, &mut session)
+                .await
+            {
+                Ok(_) => (),
+                Err(e) => return Err(MongoQueryError(e)),
+            }
+        }
+        match session.commit_transaction().await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn login_user(&mut self, user: &User) -> Result<()> {
+        match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": user.username.clone(), "activated": true },
+                doc! {
+                    "$set": {
+                        "last_login": Some(self.clock.now().timestamp()),
+                        "awaiting_second_factor": false,
+                        "failed_logins": 0u32,
+                        "locked_until": bson::Bson::Null,
+                    },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(_) => {
+                log::info!("Updated {}.", &user.username);
+                self.invalidate_user(&user.username);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Error: update failed ({:?})", &e);
+                Err(MongoQueryError(e))
+            }
+        }
+    }
+
+    /// Records that the user has unlocked `hint` and deducts `penalty`,
+    /// never letting the score drop below zero. Both happen in one update
+    /// that only matches if the hint hasn't been unlocked yet, so a hint is
+    /// paid for once. Returns the user's new score, or `None` if the hint
+    /// had been unlocked before.
+    pub async fn unlock_hint(
+        &self,
+        username: &String,
+        hint: &UnlockedHint,
+        penalty: i32,
+    ) -> Result<Option<i32>> {
+        log::info!(
+            "unlock_hint(); username = {}, hint = {:?}, penalty = {}",
+            username,
+            hint,
+            penalty
+        );
+        let hint_doc: bson::Document = match bson::to_document(hint) {
+            Ok(hint_doc) => hint_doc,
+            Err(e) => return Err(DatabaseQueryError(e.to_string())),
+        };
+        let user: Option<User> = match self
+            .get_users_coll()
+            .find_one_and_update(
+                doc! {
+                    "username": username,
+                    "unlocked_hints": { "$not": { "$elemMatch": &hint_doc } },
+                },
+                vec![doc! {
+                    "$set": {
+                        "score": { "$max": [ 0i32, { "$subtract": [ { "$ifNull": [ "$score", 0i32 ] }, penalty ] } ] },
+                        "unlocked_hints": { "$concatArrays": [ { "$ifNull": [ "$unlocked_hints", [] ] }, [ &hint_doc ] ] },
+                    }
+                }],
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        Ok(user.map(|user| user.score))
+    }
+
+    /// Counts a failed login. The `max_failed`-th failure in a row locks
+    /// the account for `lockout` and starts counting anew. Returns `true`
+    /// if this failure has locked the account.
+    pub async fn register_failed_login(
+        &self,
+        username: &String,
+        max_failed: u32,
+        lockout: chrono::Duration,
+    ) -> Result<bool> {
+        log::info!("register_failed_login(); username = {}", username);
+        let locked_until: i64 = (self.clock.now() + lockout).timestamp();
+        match self
+            .get_users_coll()
+            .find_one_and_update(
+                doc! { "username": username },
+                vec![
+                    doc! {
+                        "$set": {
+                            "failed_logins": { "$add": [ { "$ifNull": [ "$failed_logins", 0i64 ] }, 1i64 ] }
+                        }
+                    },
+                    doc! {
+                        "$set": {
+                            "locked_until": {
+                                "$cond": [ { "$gte": [ "$failed_logins", max_failed ] }, locked_until, "$locked_until" ]
+                            },
+                            "failed_logins": {
+                                "$cond": [ { "$gte": [ "$failed_logins", max_failed ] }, 0i64, "$failed_logins" ]
+                            },
+                        }
+                    },
+                ],
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+        {
+            Ok(user) => {
+                self.invalidate_user(username);
+                Ok(user.map_or(false, |user| user.is_locked(self.clock.now())))
+            }
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Removes `recovery_key` from the user's recovery keys. Returns `false`
+    /// if the user has no such key. Keys are stored in lower case.
+    pub async fn consume_recovery_key(
+        &self,
+        username: &String,
+        recovery_key: &String,
+    ) -> Result<bool> {
+        log::info!("consume_recovery_key(); username = {}", username);
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "username": username,
+                    "activated": true,
+                    "recovery_keys": recovery_key,
+                },
+                doc! { "$pull": { "recovery_keys": recovery_key } },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        Ok(result.modified_count == 1)
+    }
+
+    /// Stores a password reset for the activated user with the given
+    /// email address, replacing any pending one, and queues the mail
+    /// rendered by `mail` in the same transaction. Returns the user, or
+    /// `None` if there is no such user.
+    pub async fn set_password_reset(
+        &self,
+        email: &String,
+        reset: &PasswordReset,
+        mail: impl FnOnce(&User) -> Result<OutboxMail>,
+    ) -> Result<Option<User>> {
+        log::info!("set_password_reset(); email = {}", email);
+        let reset: bson::Document = match bson::to_document(reset) {
+            Ok(reset) => reset,
+            Err(e) => return Err(DatabaseQueryError(e.to_string())),
+        };
+        let mut session: ClientSession = self.start_transaction().await?;
+        let user: Option<User> = match self
+            .get_users_coll()
+            .find_one_and_update_with_session(
+                doc! { "email": email, "activated": true },
+                doc! { "$set": { "password_reset": reset } },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+                &mut session,
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        let user: User = match user {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        self.enqueue_mail_with_session(&mail(&user)?, &mut session)
+            .await?;
+        match session.commit_transaction().await {
+            Ok(()) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        self.invalidate_user(&user.username);
+        Ok(Some(user))
+    }
+
+    pub async fn get_user_by_password_reset(&self, digest: &String) -> Result<Option<User>> {
+        log::info!("get_user_by_password_reset()");
+        match self
+            .get_users_coll()
+            .find_one(
+                doc! { "password_reset.digest": digest, "activated": true },
+                None,
+            )
+            .await
+        {
+            Ok(user) => Ok(user),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Marks the password reset as used. Returns `false` if it has been
+    /// used already, so that concurrent requests cannot both succeed.
+    pub async fn use_password_reset(&self, username: &String, digest: &String) -> Result<bool> {
+        log::info!("use_password_reset(); username = {}", username);
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! {
+                    "username": username,
+                    "password_reset.digest": digest,
+                    "password_reset.used": false,
+                },
+                doc! { "$set": { "password_reset.used": true } },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        self.invalidate_user(username);
+        Ok(result.modified_count == 1)
+    }
+
+    /// Sets a new password and revokes the user's JWTs.
+    pub async fn set_user_password(&mut self, username: &String, password: &String) -> Result<()> {
+        let hash = match Password::hash(password) {
+            Ok(hash) => hash,
+            Err(e) => return Err(e),
+        };
+        let result: UpdateResult = match self
+            .get_users_coll()
+            .update_one(
+                doc! { "username": username, "activated": true },
+                doc! {
+                    "$set": {
+                        "hash": hash,
+                    },
+                    "$inc": { "token_version": 1i64 },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                log::error!("Error: update failed ({:?})", &e);
+                return Err(MongoQueryError(e));
+            }
+        };
+        self.invalidate_user(username);
+        if result.matched_count != 1 || result.modified_count != 1 {
+            return Err(UserNotFoundError);
+        }
+        log::info!("Password of user {} updated.", username);
+        Ok(())
+    }
+
+    pub async fn add_audit_log_entry(&self, entry: &AuditLogEntry) -> Result<()> {
+        log::info!(
+            "add_audit_log_entry(); admin = {}, action = {}, target = {}",
+            &entry.admin,
+            &entry.action,
+            &entry.target
+        );
+        match self.get_audit_log_coll().insert_one(entry, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn enqueue_mail(&self, mail: &OutboxMail) -> Result<()> {
+        log::info!("enqueue_mail(); to = {:?}", &mail.to);
+        match self.get_email_outbox_coll().insert_one(mail, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    async fn enqueue_mail_with_session(
+        &self,
+        mail: &OutboxMail,
+        session: &mut ClientSession,
+    ) -> Result<()> {
+        log::info!("enqueue_mail_with_session(); to = {:?}", &mail.to);
+        match self
+            .get_email_outbox_coll()
+            .insert_one_with_session(mail, None, session)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Creates the collections written to in transactions, which MongoDB
+    /// 4.0 can't create inside one, and the TTL index that removes sent and
+    /// failed mails from the outbox. Safe to run on every start.
+    pub async fn ensure_outbox_indexes(&self) -> Result<()> {
+        log::info!("ensure_outbox_indexes()");
+        for name in [&self.coll_users, &self.coll_email_outbox] {
+            match self.get_database().create_collection(name, None).await {
+                Ok(()) => (),
+                Err(e) if server_error_code(&e) == Some(NAMESPACE_EXISTS) => (),
+                Err(e) => return Err(MongoQueryError(e)),
+            }
+        }
+        let index: IndexModel = IndexModel::builder()
+            .keys(doc! { "expire_at": 1u32 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        match self.get_email_outbox_coll().create_index(index, None).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Takes the oldest pending mail that is due out of the queue by
+    /// moving it to `Sending`, so that no other worker picks it up. A mail
+    /// claimed before `stuck_before` and still in `Sending` is taken again.
+    pub async fn claim_due_mail(
+        &self,
+        now: DateTime<Utc>,
+        stuck_before: DateTime<Utc>,
+    ) -> Result<Option<OutboxMail>> {
+        match self
+            .get_email_outbox_coll()
+            .find_one_and_update(
+                doc! {
+                    "$or": [
+                        {
+                            "state": "pending",
+                            "next_attempt_at": { "$lte": now.timestamp() },
+                        },
+                        {
+                            "state": "sending",
+                            // also matches mails claimed before `claimed_at` was recorded
+                            "claimed_at": { "$not": { "$gte": stuck_before.timestamp() } },
+                        },
+                    ],
+                },
+                doc! {
+                    "$set": { "state": "sending", "claimed_at": now.timestamp() },
+                    "$inc": { "attempts": 1u32 },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .sort(doc! { "next_attempt_at": 1i32 })
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+        {
+            Ok(mail) => Ok(mail),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Records a successful delivery and drops the message itself. The
+    /// entry is removed at `expire_at`.
+    pub async fn mark_mail_sent(&self, id: &ObjectId, expire_at: DateTime<Utc>) -> Result<()> {
+        log::info!("mark_mail_sent(); id = {}", id);
+        match self
+            .get_email_outbox_coll()
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": {
+                        "state": "sent",
+                        "sent_at": self.clock.now().timestamp(),
+                        "last_error": null,
+                        "expire_at": bson::DateTime::from_chrono(expire_at),
+                    },
+                    "$unset": { "raw": "" },
+                },
+                None,
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Records a failed delivery. The mail is retried at `retry_at`, or
+    /// given up on if that is `None`, in which case the entry is removed
+    /// at `expire_at`.
+    pub async fn mark_mail_failed(
+        &self,
+        id: &ObjectId,
+        error: &String,
+        retry_at: Option<DateTime<Utc>>,
+        expire_at: DateTime<Utc>,
+    ) -> Result<()> {
+        log::info!(
+            "mark_mail_failed(); id = {}, retry_at = {:?}",
+            id,
+            &retry_at
+        );
+        let update: bson::Document = match retry_at {
+            Some(retry_at) => doc! { "$set": {
+                "state": "pending",
+                "next_attempt_at": retry_at.timestamp(),
+                "last_error": error,
+            } },
+            None => doc! {
+                "$set": {
+                    "state": "failed",
+                    "last_error": error,
+                    "expire_at": bson::DateTime::from_chrono(expire_at),
+                },
+                "$unset": { "raw": "" },
+            },
+        };
+        match self
+            .get_email_outbox_coll()
+            .update_one(doc! { "_id": id }, update, None)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Counts mails a previous run was in the middle of sending.
+    pub async fn count_mails_in_flight(&self) -> Result<u64> {
+        match self
+            .get_email_outbox_coll()
+            .count_documents(doc! { "state": "sending" }, None)
+            .await
+        {
+            Ok(count) => Ok(count),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Drops refresh tokens from before their expiry became a BSON date
+    /// and their digest SHA-256, which can't be used anymore, and creates
+    /// the TTL index that removes expired tokens. Safe to run on every
+    /// start.
+    pub async fn migrate_refresh_tokens(&self) -> Result<()> {
+        log::info!("migrate_refresh_tokens()");
+        match self
+            .get_refresh_tokens_coll()
+            .delete_many(doc! { "expires": { "$type": "number" } }, None)
+            .await
+        {
+            Ok(result) if result.deleted_count > 0 => log::info!(
+                "Dropped {} refresh token(s) of the old format.",
+                result.deleted_count
+            ),
+            Ok(_) => (),
+            Err(e) => return Err(MongoQueryError(e)),
+        }
+        let index: IndexModel = IndexModel::builder()
+            .keys(doc! { "expires": 1u32 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        match self
+            .get_refresh_tokens_coll()
+            .create_index(index, None)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    pub async fn add_refresh_token(&self, token: &RefreshToken) -> Result<()> {
+        log::info!("add_refresh_token(); username = {}", &token.username);
+        match self.get_refresh_tokens_coll().insert_one(token, None).await {
             Ok(_) => Ok(()),
             Err(e) => Err(MongoQueryError(e)),
         }
     }
 
-    pub async fn set_user_awaiting_2fa(&mut self, user: &User, awaiting: bool) -> Result<()> {
+    pub async fn get_refresh_token(&self, digest: &String) -> Result<Option<RefreshToken>> {
         match self
-            .get_users_coll()
-            .update_one(
-                doc! { "_id": user.id, "activated": true },
-                doc! {
-                    "$set": { "awaiting_second_factor": awaiting },
-                },
-                None,
-            )
+            .get_refresh_tokens_coll()
+            .find_one(doc! { "_id": digest }, None)
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(token) => Ok(token),
             Err(e) => Err(MongoQueryError(e)),
         }
     }
 
-    pub async fn save_webauthn_registration_state(
-        &self,
-        username: &String,
-        rs: &RegistrationState,
-    ) -> Result<()> {
-        log::info!(
-            "save_webauthn_registration_state(); username = {}, rs = {:?}",
-            username,
-            rs
-        );
+    /// Marks the refresh token as used. Returns `false` if it had already
+    /// been used, e.g. by a concurrent request.
+    pub async fn use_refresh_token(&self, digest: &String) -> Result<bool> {
         match self
-            .get_users_coll()
+            .get_refresh_tokens_coll()
             .update_one(
-                doc! { "username": username, "activated": true },
-                doc! {
-                    "$set": {
-                        "webauthn.registrationState": Some(bson::to_bson(rs).unwrap()),
-                    },
-                },
+                doc! { "_id": digest, "used": false },
+                doc! { "$set": { "used": true } },
                 None,
             )
             .await
         {
-            Ok(_) => Ok(()),
+            Ok(result) => Ok(result.modified_count == 1),
             Err(e) => Err(MongoQueryError(e)),
         }
     }
 
-    pub async fn save_webauthn_registration(
-        &self,
-        username: &String,
-        creds: &Vec<Credential>,
-    ) -> Result<()> {
-        log::info!("save_webauthn_registration(); username = {}", username);
-        dbg!(&creds);
-        match self
-            .get_users_coll()
-            .update_one(
-                doc! { "username": username, "activated": true },
+    /// Returns the unused, unexpired refresh tokens of the user, one per
+    /// session, least recently refreshed first.
+    pub async fn get_active_sessions(&self, username: &String) -> Result<Vec<RefreshToken>> {
+        let cursor: mongodb::Cursor<RefreshToken> = match self
+            .get_refresh_tokens_coll()
+            .find(
                 doc! {
-                    "$set": {
-                        "webauthn.credentials": Some(bson::to_bson(creds).unwrap()),
-                    },
+                    "username": username,
+                    "used": false,
+                    "expires": { "$gt": bson::DateTime::from_chrono(self.clock.now()) },
                 },
-                None,
+                FindOptions::builder().sort(doc! { "issued": 1u32 }).build(),
             )
             .await
         {
-            Ok(_) => Ok(()),
-            Err(e) => Err(MongoQueryError(e)),
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match cursor.try_collect().await {
+            Ok(sessions) => Ok(sessions),
+            Err(e) => Err(MongoError(e)),
         }
     }
 
-    pub async fn save_webauthn_authentication_state(
-        &self,
-        username: &String,
-        st: &AuthenticationState,
-    ) -> Result<()> {
-        log::info!(
-            "save_webauthn_authentication_state(); username = {}, as = {:?}",
-            username,
-            st
-        );
+    /// Deletes all refresh tokens of the user.
+    pub async fn revoke_refresh_tokens_of_user(&self, username: &String) -> Result<()> {
+        log::info!("revoke_refresh_tokens_of_user(); username = {}", username);
         match self
-            .get_users_coll()
-            .update_one(
-                doc! { "username": username, "activated": true },
-                doc! {
-                    "$set": {
-                        "webauthn.authenticationState": Some(bson::to_bson(st).unwrap()),
-                    },
-                },
-                None,
-            )
+            .get_refresh_tokens_coll()
+            .delete_many(doc! { "username": username }, None)
             .await
         {
             Ok(_) => Ok(()),
@@ -997,16 +4591,12 @@ impl DB {
         }
     }
 
-    pub async fn rewrite_user_score(&mut self, user: &User) -> Result<()> {
+    /// Deletes all refresh tokens descending from the same login.
+    pub async fn revoke_refresh_token_family(&self, family: &ObjectId) -> Result<()> {
+        log::info!("revoke_refresh_token_family(); family = {}", family);
         match self
-            .get_users_coll()
-            .update_one(
-                doc! { "_id": user.id, "activated": true },
-                doc! {
-                    "$set": { "score": user.score },
-                },
-                None,
-            )
+            .get_refresh_tokens_coll()
+            .delete_many(doc! { "family": family }, None)
             .await
         {
             Ok(_) => Ok(()),
@@ -1014,178 +4604,236 @@ impl DB {
         }
     }
 
-    pub async fn promote_user(&mut self, username: &String, role: &Role) -> Result<()> {
-        let result: UpdateResult = match self
+    /// Adds `delta` to the user's score, or sets it to `absolute` if given,
+    /// in a single update. The score never drops below zero. Returns the
+    /// user's previous and new score.
+    pub async fn adjust_user_score(
+        &self,
+        username: &String,
+        delta: i32,
+        absolute: Option<i32>,
+    ) -> Result<(i32, i32)> {
+        log::info!(
+            "adjust_user_score(); username = {}, delta = {}, absolute = {:?}",
+            username,
+            delta,
+            absolute
+        );
+        let new_score: bson::Document = match absolute {
+            Some(score) => doc! { "$literal": score.max(0) },
+            None => {
+                doc! { "$max": [ 0i32, { "$add": [ { "$ifNull": [ "$score", 0i32 ] }, delta ] } ] }
+            }
+        };
+        let user: Option<User> = match self
             .get_users_coll()
-            .update_one(
-                doc! { "username": username, "activated": true },
-                doc! {
-                    "$set": { "role": bson::to_bson(role).unwrap() },
-                },
-                None,
+            .find_one_and_update(
+                doc! { "username": username },
+                vec![doc! { "$set": { "score": new_score } }],
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::Before)
+                    .build(),
             )
             .await
         {
-            Ok(result) => result,
+            Ok(user) => user,
             Err(e) => return Err(MongoQueryError(e)),
         };
-        match result {
-            result if result.matched_count == 0 => Err(UserNotFoundError),
-            result if result.matched_count == 1 && result.modified_count == 0 => {
-                Err(CannotPromoteUserError)
-            }
-            _ => Ok(()),
+        self.invalidate_user(username);
+        match user {
+            Some(user) => Ok((user.score, adjusted_score(user.score, delta, absolute))),
+            None => Err(UserNotFoundError),
+        }
+    }
+
+    /// Returns all audit log entries concerning `target`, oldest first.
+    pub async fn get_audit_log_entries(&self, target: &String) -> Result<Vec<AuditLogEntry>> {
+        log::info!("get_audit_log_entries(); target = {}", target);
+        let cursor: mongodb::Cursor<AuditLogEntry> = match self
+            .get_audit_log_coll()
+            .find(
+                doc! { "target": target },
+                FindOptions::builder()
+                    .sort(doc! { "timestamp": 1u32, "_id": 1u32 })
+                    .build(),
+            )
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(MongoQueryError(e)),
+        };
+        match cursor.try_collect().await {
+            Ok(entries) => Ok(entries),
+            Err(e) => Err(MongoError(e)),
         }
     }
 
-    pub async fn create_user(&mut self, user: &User) -> Result<()> {
-        log::info!("create_user({:?})", user);
-        match self.get_users_coll().insert_one(user, None).await {
+    pub async fn record_room_visit(&self, user: &User, room: &Room) -> Result<()> {
+        log::info!(
+            "record_room_visit(); username = {}, room_id = {}",
+            &user.username,
+            &room.id
+        );
+        let visit = RoomVisit {
+            id: ObjectId::new(),
+            user_id: user.id,
+            room_id: room.id,
+            game_id: room.game_id,
+            timestamp: self.clock.now(),
+            score: Some(user.score),
+        };
+        match self.get_room_visits_coll().insert_one(visit, None).await {
             Ok(_) => Ok(()),
             Err(e) => Err(MongoQueryError(e)),
         }
     }
 
-    pub async fn login_user(&mut self, user: &User) -> Result<()> {
+    /// Returns when the user first entered a room of the given game.
+    pub async fn get_first_room_visit(
+        &self,
+        user_id: &ObjectId,
+        game_id: &ObjectId,
+    ) -> Result<Option<RoomVisit>> {
+        log::info!(
+            "get_first_room_visit(); user_id = {}, game_id = {}",
+            user_id,
+            game_id
+        );
         match self
-            .get_users_coll()
-            .update_one(
-                doc! { "username": user.username.clone(), "activated": true },
-                doc! {
-                    "$set": {
-                        "last_login": Some(Utc::now().timestamp()),
-                        "awaiting_second_factor": false
-                    },
-                },
-                None,
+            .get_room_visits_coll()
+            .find_one(
+                doc! { "user_id": user_id, "game_id": game_id },
+                FindOneOptions::builder()
+                    .sort(doc! { "timestamp": 1u32, "_id": 1u32 })
+                    .build(),
             )
             .await
         {
-            Ok(_) => {
-                log::info!("Updated {}.", &user.username);
-                Ok(())
-            }
-            Err(e) => {
-                log::error!("Error: update failed ({:?})", &e);
-                Err(MongoQueryError(e))
-            }
+            Ok(visit) => Ok(visit),
+            Err(e) => Err(MongoQueryError(e)),
         }
     }
 
-    pub async fn set_user_password(&mut self, username: &String, password: &String) -> Result<()> {
-        let hash = match Password::hash(password) {
-            Ok(hash) => hash,
-            Err(e) => return Err(e),
-        };
-        let result: UpdateResult = match self
-            .get_users_coll()
-            .update_one(
-                doc! { "username": username, "activated": true },
-                doc! {
-                    "$set": {
-                        "hash": hash,
-                    },
-                },
-                None,
+    /// Returns the user's room visits, oldest first, as a cursor, as there
+    /// may be too many of them to hold in memory.
+    pub async fn get_room_visits(&self, user_id: &ObjectId) -> Result<mongodb::Cursor<RoomVisit>> {
+        log::info!("get_room_visits(); user_id = {}", user_id);
+        match self
+            .get_room_visits_coll()
+            .find(
+                doc! { "user_id": user_id },
+                FindOptions::builder()
+                    .sort(doc! { "timestamp": 1u32, "_id": 1u32 })
+                    .build(),
             )
             .await
         {
-            Ok(result) => result,
-            Err(e) => {
-                log::error!("Error: update failed ({:?})", &e);
-                return Err(MongoQueryError(e));
-            }
-        };
-        if result.matched_count != 1 || result.modified_count != 1 {
-            return Err(UserNotFoundError);
+            Ok(cursor) => Ok(cursor),
+            Err(e) => Err(MongoQueryError(e)),
+        }
+    }
+
+    /// Returns the entry room of the given game, or of the default game
+    /// (`DEFAULT_GAME_ID`) if `game_id` is `None`. Without a default game,
+    /// the entry room of any game is returned.
+    pub async fn get_entry_room(&self, game_id: &Option<ObjectId>) -> Result<Option<Room>> {
+        let mut filter: bson::Document = doc! { "entry": true };
+        if let Some(game_id) = game_id.or(self.default_game_id) {
+            filter.insert("game_id", game_id);
+        }
+        match self.get_rooms_coll().find_one(filter, None).await {
+            Ok(entrance) => Ok(entrance),
+            Err(e) => Err(MongoQueryError(e)),
         }
-        log::info!("Password of user {} updated.", username);
-        Ok(())
     }
 
+    /// Activates the user and places them in the entry room of their game.
+    /// Spectators don't play, so they are activated without entering a
+    /// room. Nothing is changed, neither in the database nor in `user`, if
+    /// that fails, e.g. because the game has no entry room.
     pub async fn activate_user(&mut self, user: &mut User) -> Result<()> {
-        let entrance: Option<Room> = match self
-            .get_rooms_coll()
-            .find_one(
-                doc! {
-                    "entry": true,
-                    /* XXX: choose a game_id */
-                },
-                None,
-            )
-            .await
-        {
-            Ok(entrance) => entrance,
-            Err(e) => return Err(MongoQueryError(e)),
+        let entrance: Option<Room> = if user.role == Role::Spectator {
+            None
+        } else {
+            match self.get_entry_room(&user.game_id).await {
+                Ok(Some(room)) => {
+                    log::info!("Found room {}", &room.id);
+                    Some(room)
+                }
+                Ok(None) => {
+                    log::error!(
+                        "Error: cannot activate {}, game {:?} has no entry room",
+                        &user.username,
+                        user.game_id.or(self.default_game_id)
+                    );
+                    return Err(NoEntryRoomError);
+                }
+                Err(e) => return Err(e),
+            }
         };
-        let first_room_id: bson::oid::ObjectId = match entrance {
-            Some(room) => {
-                log::info!("Found room {}", &room.id);
-                room.id
+        let now: DateTime<Utc> = self.clock.now();
+        let mut rooms_entered: Vec<ObjectId> = user.rooms_entered.clone();
+        if let Some(entrance) = &entrance {
+            rooms_entered.push(entrance.id);
+        }
+        let recovery_keys: Vec<String> = loop {
+            let keys: Vec<String> = generate_recovery_keys(10, 4);
+            match self
+                .get_users_coll()
+                .count_documents(doc! { "recovery_keys": { "$in": &keys } }, None)
+                .await
+            {
+                Ok(0) => break keys,
+                Ok(_) => log::warn!("recovery key collision, generating new keys"),
+                Err(e) => return Err(MongoQueryError(e)),
             }
-            None => return Err(RoomNotFoundError),
         };
-        user.activated = true;
-        user.registered = Some(Utc::now());
-        user.last_login = Some(Utc::now());
-        user.in_room = Some(first_room_id);
-        user.rooms_entered.push(first_room_id);
-        user.pin = 0;
-        user.recovery_keys = (0..10)
-            .map(|_| {
-                let a: String = rand::thread_rng()
-                    .sample_iter(&KeyChars)
-                    .take(4)
-                    .map(char::from)
-                    .collect();
-                let b: String = rand::thread_rng()
-                    .sample_iter(&KeyChars)
-                    .take(4)
-                    .map(char::from)
-                    .collect();
-                let c: String = rand::thread_rng()
-                    .sample_iter(&KeyChars)
-                    .take(4)
-                    .map(char::from)
-                    .collect();
-                let d: String = rand::thread_rng()
-                    .sample_iter(&KeyChars)
-                    .take(4)
-                    .map(char::from)
-                    .collect();
-                a + "-" + &b + "-" + &c + "-" + &d
-            })
-            .collect();
-        let modification: bson::Document = doc! {
-            "$set": {
-                "activated": user.activated,
-                "registered": Utc::now().timestamp() as u32,
-                "last_login": Utc::now().timestamp() as u32,
-                "in_room": first_room_id,
-                "rooms_entered": &user.rooms_entered,
-                "recovery_keys": &user.recovery_keys,
-            },
-            "$unset": {
-                "pin": 0 as u32,
-            },
+        let mut fields: bson::Document = doc! {
+            "activated": true,
+            "registered": now.timestamp() as u32,
+            "last_login": now.timestamp() as u32,
+            "recovery_keys": &recovery_keys,
         };
+        if let Some(entrance) = &entrance {
+            fields.insert("in_room", entrance.id);
+            fields.insert("rooms_entered", &rooms_entered);
+        }
         match self
             .get_users_coll()
             .update_one(
-                doc! { "username": user.username.clone(), "activated": false },
-                modification,
+                doc! {
+                    "username": user.username.clone(),
+                    "activated": false,
+                    "deactivated": { "$ne": true },
+                },
+                doc! { "$set": fields },
                 None,
             )
             .await
         {
-            Ok(_) => {
+            Ok(result) if result.matched_count == 1 => {
                 log::info!("Updated {}.", &user.username);
+                self.invalidate_user(&user.username);
             }
+            // activated concurrently
+            Ok(_) => return Err(UserNotFoundError),
             Err(e) => {
                 log::error!("Error: update failed ({:?})", &e);
                 return Err(MongoQueryError(e));
             }
         }
+        user.activated = true;
+        user.registered = Some(now);
+        user.last_login = Some(now);
+        user.recovery_keys = recovery_keys;
+        if let Some(entrance) = entrance {
+            user.in_room = Some(entrance.id);
+            user.rooms_entered = rooms_entered;
+            // the game starts here, see `get_first_room_visit()`
+            if let Err(e) = self.record_room_visit(user, &entrance).await {
+                log::error!("Error: recording room visit failed: {}", &e);
+            }
+        }
         Ok(())
     }
 }
@@ -1193,3 +4841,191 @@ impl DB {
 pub fn with_db(db: DB) -> impl Filter<Extract = (DB,), Error = Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(neighbors: &[(&str, ObjectId)]) -> Room {
+        Room {
+            id: ObjectId::new(),
+            number: 0,
+            coords: None,
+            neighbors: neighbors
+                .iter()
+                .map(|(direction, riddle_id)| Direction {
+                    direction: direction.to_string(),
+                    riddle_id: *riddle_id,
+                    level: 1,
+                })
+                .collect(),
+            game_id: ObjectId::new(),
+            entry: None,
+            exit: None,
+        }
+    }
+
+    /// Three rooms in a row from west to east, returned with the riddles
+    /// guarding the two doorways.
+    fn corridor() -> (Vec<Room>, ObjectId, ObjectId) {
+        let (first, second): (ObjectId, ObjectId) = (ObjectId::new(), ObjectId::new());
+        let rooms: Vec<Room> = vec![
+            room(&[("e", first)]),
+            room(&[("w", first), ("e", second)]),
+            room(&[("w", second)]),
+        ];
+        (rooms, first, second)
+    }
+
+    #[test]
+    fn path_leads_through_solved_doorways() {
+        let (rooms, first, second) = corridor();
+        let passable: HashSet<ObjectId> = HashSet::from([first, second]);
+        let path: Vec<String> = find_path(&rooms, &rooms[2].id, &rooms[0].id, &passable).unwrap();
+        assert_eq!(path, vec!["w".to_string(), "w".to_string()]);
+        let path: Vec<String> = find_path(&rooms, &rooms[0].id, &rooms[0].id, &passable).unwrap();
+        assert!(path.is_empty());
+    }
+
+    #[test]
+    fn unsolved_doorways_block_the_path() {
+        let (rooms, first, _) = corridor();
+        let passable: HashSet<ObjectId> = HashSet::from([first]);
+        assert!(matches!(
+            find_path(&rooms, &rooms[2].id, &rooms[0].id, &passable),
+            Err(NoPathError)
+        ));
+    }
+
+    #[test]
+    fn ambiguous_doorways_are_reported() {
+        let (mut rooms, first, second) = corridor();
+        // a second room behind the eastern doorway of the middle room
+        rooms.push(room(&[("w", second)]));
+        let passable: HashSet<ObjectId> = HashSet::from([first, second]);
+        assert!(matches!(
+            find_path(&rooms, &rooms[0].id, &rooms[2].id, &passable),
+            Err(AmbiguousRoomBehindError)
+        ));
+    }
+
+    fn test_user(username: &str) -> User {
+        User::new(
+            &username.to_string(),
+            &format!("{}@example.com", username),
+            Role::User,
+            String::new(),
+            0,
+            Vec::new(),
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn user_read_before_an_invalidation_is_not_cached() {
+        let mut cache: UserCache = UserCache::new(Duration::from_secs(60), 10);
+        let generation: u64 = cache.generation();
+        cache.invalidate("alice");
+        cache.insert(test_user("alice"), generation);
+        assert!(cache.get("alice").is_none());
+        cache.insert(test_user("alice"), cache.generation());
+        assert!(cache.get("alice").is_some());
+        cache.invalidate("alice");
+        assert!(cache.get("alice").is_none());
+    }
+
+    #[test]
+    fn full_user_cache_drops_the_oldest_user() {
+        let mut cache: UserCache = UserCache::new(Duration::from_secs(60), 2);
+        for username in ["alice", "bob", "carol"] {
+            cache.insert(test_user(username), cache.generation());
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert!(cache.get("alice").is_none());
+        assert!(cache.get("bob").is_some());
+        assert!(cache.get("carol").is_some());
+    }
+
+    #[test]
+    fn expired_users_are_not_returned() {
+        let mut cache: UserCache = UserCache::new(Duration::ZERO, 10);
+        cache.insert(test_user("alice"), cache.generation());
+        assert!(cache.get("alice").is_none());
+    }
+
+    #[test]
+    fn recovery_keys_have_the_requested_count_and_format() {
+        let keys: Vec<String> = generate_recovery_keys(10, 4);
+        assert_eq!(keys.len(), 10);
+        for key in keys.iter() {
+            let groups: Vec<&str> = key.split('-').collect();
+            assert_eq!(groups.len(), 4, "{}", key);
+            assert!(groups.iter().all(|group| group.len() == 4), "{}", key);
+        }
+        assert!(generate_recovery_keys(0, 4).is_empty());
+    }
+
+    #[test]
+    fn recovery_keys_use_unambiguous_characters() {
+        for key in generate_recovery_keys(50, 8) {
+            assert!(
+                key.chars().all(|c| c == '-'
+                    || (c.is_ascii_lowercase() && c != 'l')
+                    || c.is_ascii_digit()),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn recovery_keys_within_a_set_are_distinct() {
+        // with one character per group there are only 35^4 keys, so that
+        // duplicates are drawn every now and then
+        let keys: Vec<String> = generate_recovery_keys(2000, 1);
+        let distinct: HashSet<&String> = keys.iter().collect();
+        assert_eq!(distinct.len(), keys.len());
+    }
+
+    #[test]
+    fn adjusted_score_never_drops_below_zero() {
+        assert_eq!(adjusted_score(10, -3, None), 7);
+        assert_eq!(adjusted_score(3, -5, None), 0);
+        assert_eq!(adjusted_score(0, 4, None), 4);
+        assert_eq!(adjusted_score(10, -3, Some(12)), 12);
+        assert_eq!(adjusted_score(10, 0, Some(-4)), 0);
+    }
+
+    /// `user` as stored, with the key of its only authenticator encoded
+    /// as `encoded_key`.
+    fn stored_with_totp_key(user: &User, encoded_key: &str) -> bson::Document {
+        let mut stored: bson::Document = bson::to_document(user).unwrap();
+        stored.insert(
+            "totp_keys",
+            vec![doc! { "name": "phone", "key": encoded_key, "created": 0i64 }],
+        );
+        stored
+    }
+
+    #[test]
+    fn totp_key_stored_as_base64_is_read() {
+        let key: Vec<u8> = b"12345678901234567890".to_vec();
+        let stored: bson::Document =
+            stored_with_totp_key(&test_user("alice"), &base64::encode(&key));
+        let user: User = bson::from_document(stored).unwrap();
+        assert_eq!(user.totp_keys[0].key, key);
+    }
+
+    #[test]
+    fn totp_key_stored_as_base32_is_read() {
+        let key: Vec<u8> = b"12345678901234567890".to_vec();
+        let encoded: String = base32::encode(base32::Alphabet::RFC4648 { padding: true }, &key);
+        assert!(b64::is_base32(&encoded));
+        let stored: bson::Document = stored_with_totp_key(&test_user("alice"), &encoded);
+        let user: User = bson::from_document(stored).unwrap();
+        assert_eq!(user.totp_keys[0].key, key);
+        // and written back as base64
+        let written: bson::Document = bson::to_document(&user.totp_keys[0]).unwrap();
+        assert_eq!(written.get_str("key").unwrap(), base64::encode(&key));
+    }
+}
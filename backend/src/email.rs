@@ -0,0 +1,106 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::{config::EnvReader, error::Error, Result};
+use log;
+use std::collections::HashSet;
+use std::fs;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Checks the domain part of a mail address before a registration is
+/// accepted. Disabled unless `EMAIL_DOMAIN_CHECK` is set; domains listed
+/// in `DISPOSABLE_EMAIL_DOMAINS` (comma separated) or in the file named by
+/// `DISPOSABLE_EMAIL_DOMAINS_FILE` (one per line) are rejected. With
+/// `EMAIL_MX_CHECK` set the domain must also have an MX record.
+#[derive(Debug, Clone, Default)]
+pub struct EmailDomainCheck {
+    pub enabled: bool,
+    pub mx_lookup: bool,
+    pub blocklist: HashSet<String>,
+}
+
+/// Lowercases the domain part of `email`, leaving the local part as is.
+pub fn normalize(email: &String) -> String {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, domain.to_lowercase()),
+        None => email.clone(),
+    }
+}
+
+impl EmailDomainCheck {
+    /// Reads the check from the environment, returning a description of
+    /// every problem, e.g. a blocklist file that can't be read.
+    pub fn from_env() -> std::result::Result<EmailDomainCheck, Vec<String>> {
+        let mut reader: EnvReader = EnvReader::new();
+        let mut blocklist: HashSet<String> = reader
+            .optional("DISPOSABLE_EMAIL_DOMAINS", "")
+            .split(',')
+            .map(|d| d.trim().to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+        let filename: String = reader.optional("DISPOSABLE_EMAIL_DOMAINS_FILE", "");
+        if !filename.is_empty() {
+            match fs::read_to_string(&filename) {
+                Ok(contents) => blocklist.extend(
+                    contents
+                        .lines()
+                        .map(|d| d.trim().to_lowercase())
+                        .filter(|d| !d.is_empty() && !d.starts_with('#')),
+                ),
+                Err(e) => reader.problem(format!(
+                    "DISPOSABLE_EMAIL_DOMAINS_FILE '{}' cannot be read: {}",
+                    &filename, e
+                )),
+            }
+        }
+        let check = EmailDomainCheck {
+            enabled: reader.flag("EMAIL_DOMAIN_CHECK", false),
+            mx_lookup: reader.flag("EMAIL_MX_CHECK", false),
+            blocklist,
+        };
+        reader.finish(check)
+    }
+
+    /// Returns `Error::InvalidEmailError` if the domain of the (normalized)
+    /// `email` is blocklisted or, if enabled, has no MX record.
+    pub async fn check(&self, email: &String) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let domain: &str = match email.rsplit_once('@') {
+            Some((_, domain)) => domain,
+            None => return Err(Error::InvalidEmailError("missing domain".to_string())),
+        };
+        let blocked: bool = self.blocklist.contains(domain)
+            || self
+                .blocklist
+                .iter()
+                .any(|b| domain.ends_with(&format!(".{}", b)));
+        if blocked {
+            return Err(Error::InvalidEmailError(format!(
+                "disposable domain {}",
+                domain
+            )));
+        }
+        if self.mx_lookup {
+            let resolver: TokioAsyncResolver = match TokioAsyncResolver::tokio_from_system_conf() {
+                Ok(resolver) => resolver,
+                Err(e) => {
+                    log::warn!("cannot create DNS resolver: {:?}", e);
+                    return Ok(()); // soft fail
+                }
+            };
+            match resolver.mx_lookup(format!("{}.", domain)).await {
+                Ok(mx) if mx.iter().next().is_some() => (),
+                Ok(_) | Err(_) => {
+                    return Err(Error::InvalidEmailError(format!(
+                        "domain {} does not accept mail",
+                        domain
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
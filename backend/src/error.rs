@@ -2,11 +2,17 @@
  * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
  * All rights reserved.
  */
+use crate::auth::Role;
+use crate::metrics::METRICS;
+use chrono::{DateTime, Utc};
 use log;
 use serde::Serialize;
 use std::convert::Infallible;
 use thiserror::Error;
-use warp::{http::StatusCode, Rejection, Reply};
+use warp::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    Rejection, Reply,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -16,6 +22,8 @@ pub enum Error {
     MongoQueryError(mongodb::error::Error),
     #[error("could not access field in document: {0}")]
     MongoDataError(#[from] bson::document::ValueAccessError),
+    #[error("could not deserialize document: {0}")]
+    BsonDeError(#[from] bson::de::Error),
     #[error("could not parse ObjectID {0}")]
     BsonOidError(#[from] bson::oid::Error),
     #[error("invalid id used: {0}")]
@@ -34,6 +42,8 @@ pub enum Error {
     TotpKeyMissingError,
     #[error("TOTP QR code generation error")]
     TotpQrCodeGenerationError,
+    #[error("otpauth URI too long to be encoded as QR code")]
+    TotpUriTooLongError,
     #[error("user not found")]
     UserNotFoundError,
     #[error("username is not valid")]
@@ -42,12 +52,10 @@ pub enum Error {
     UsernameOrEmailNotAvailableError,
     #[error("combination of username and mail address is not valid")]
     MalformedAddressError,
-    #[error("mail address is not valid")]
-    InvalidEmailError,
+    #[error("mail address is not valid: {0}")]
+    InvalidEmailError(String),
     #[error("building mail failed")]
     MailBuilderError,
-    #[error("sending mail failed")]
-    SmtpTransportError,
     #[error("user update failed")]
     UserUpdateError,
     #[error("user is no admin")]
@@ -66,26 +74,62 @@ pub enum Error {
     NeighborNotFoundError,
     #[error("room behind not found")]
     RoomBehindNotFoundError,
+    #[error("doorway leads to more than one room")]
+    AmbiguousRoomBehindError,
+    #[error("no way through solved riddles leads there")]
+    NoPathError,
+    #[error("riddle was opened in another room, open it again from here")]
+    RiddleOpenedElsewhereError,
     #[error("riddle not solved")]
     RiddleNotSolvedError,
+    #[error("riddle level is too high for user")]
+    LevelLockedError,
+    #[error("no attempts left for this riddle")]
+    AttemptsExhaustedError,
     #[error("wrong credentials")]
     WrongCredentialsError,
+    #[error("account has not been activated yet, please enter the PIN we have mailed to you")]
+    AccountNotActivatedError,
+    #[error("too many failed logins, account is locked until {0}")]
+    AccountLockedError(DateTime<Utc>),
     #[error("unsufficient rights")]
     UnsufficentRightsError,
     #[error("cannot change user's role")]
     CannotPromoteUserError,
     #[error("user cannot change own role")]
     UserCannotChangeOwnRoleError,
-    #[error("cannot change to same or lower-ranked role")]
-    CannotChangeToSameRole,
+    #[error("user cannot deactivate or delete own account")]
+    UserCannotRemoveOwnAccountError,
+    #[error("cannot deactivate or delete the last admin")]
+    CannotRemoveLastAdminError,
+    #[error("user already has role {0}")]
+    CannotChangeToSameRole(Role),
+    #[error("user has role {0}, promoting cannot lower it")]
+    CannotPromoteToLowerRoleError(Role),
     #[error("pointless FIDO2")]
     PointlessFido2Error,
     #[error("pointless TOTP")]
     PointlessTotpError,
     #[error("TOTP missing")]
     TotpMissingError,
+    #[error("TOTP is already enabled, pass force=true to replace it")]
+    TotpAlreadyEnabledError,
+    #[error("no TOTP setup is awaiting confirmation")]
+    TotpNotPendingError,
+    #[error("an authenticator of that name already exists")]
+    TotpNameTakenError,
+    #[error("no authenticator of that name")]
+    TotpNotFoundError,
+    #[error("TOTP is not configured for this user, log in with FIDO2 instead")]
+    TotpNotConfiguredError,
+    #[error("FIDO2 is not configured for this user, log in with TOTP instead")]
+    Fido2NotConfiguredError,
     #[error("jwt token not valid")]
     JWTTokenError,
+    #[error("jwt token has expired")]
+    JWTTokenExpiredError,
+    #[error("jwt token has been revoked")]
+    JWTTokenRevokedError,
     #[error("jwt token creation error")]
     JWTTokenCreationError,
     #[error("no auth header")]
@@ -98,6 +142,64 @@ pub enum Error {
     CheatError,
     #[error("WebAuthn error")]
     WebauthnError,
+    #[error("{0}")]
+    MaintenanceError(String),
+    #[error("service busy, retry after {0} seconds")]
+    ServiceBusyError(u64),
+    #[error("too many requests")]
+    TooManyRequestsError,
+    #[error("registration challenge missing or invalid")]
+    ChallengeFailedError,
+    #[error("wrong answer given too recently, retry in {0} seconds")]
+    SolveCooldownError(u64),
+    #[error("batch exceeds {0} solutions")]
+    SolveBatchTooLargeError(usize),
+    #[error("solution exceeds {0} characters")]
+    SolutionTooLongError(usize),
+    #[error("credential counter did not increase, authenticator may have been cloned")]
+    CredentialCounterRegressionError,
+    #[error("game not found")]
+    GameNotFoundError,
+    #[error(
+        "game has no entry room, an operator has to mark one of its rooms with \"entry\": true"
+    )]
+    NoEntryRoomError,
+    #[error("event has not started yet, it starts at {0}")]
+    EventNotStartedError(DateTime<Utc>),
+    #[error("event has ended at {0}")]
+    EventEndedError(DateTime<Utc>),
+    #[error("game is locked")]
+    GameLockedError,
+    #[error("riddle is not valid: {0}")]
+    InvalidRiddleError(String),
+    #[error("level {0} is already taken by another riddle")]
+    RiddleLevelTakenError(u32),
+    #[error("file uploads are not configured on this server")]
+    UploadsDisabledError,
+    #[error("upload failed: {0}")]
+    UploadError(String),
+    #[error("riddle has no file {0}")]
+    RiddleFileNotFoundError(String),
+    #[error("cannot access file: {0}")]
+    FileAccessError(String),
+    #[error("file name is not valid: {0}")]
+    InvalidFileNameError(String),
+    #[error("no such hint, riddle has {0} hints")]
+    HintNotFoundError(usize),
+    #[error("solution cannot be revealed")]
+    SolutionNotRevealableError,
+    #[error("too many sessions, at most {0} allowed")]
+    TooManySessionsError(usize),
+    #[error("refresh token has already been used")]
+    RefreshTokenReusedError,
+    #[error("password reset token has expired")]
+    PasswordResetTokenExpiredError,
+    #[error("password reset token has already been used")]
+    PasswordResetTokenUsedError,
+    #[error("invalid configuration:\n  {}", .0.join("\n  "))]
+    ConfigError(Vec<String>),
+    #[error("score adjustment needs either a delta or an absolute score, and a reason")]
+    InvalidScoreAdjustmentError,
 }
 
 #[derive(Serialize, Debug)]
@@ -106,26 +208,81 @@ struct ErrorResponse {
     code: u16,
     status: String,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<&'static str>,
+}
+
+impl Error {
+    /// Machine-readable kind for errors clients are expected to tell apart.
+    fn kind(&self) -> Option<&'static str> {
+        match self {
+            Error::CannotChangeToSameRole(_) => Some("same_role"),
+            Error::CannotPromoteToLowerRoleError(_) => Some("lower_role"),
+            _ => None,
+        }
+    }
 }
 
 impl warp::reject::Reject for Error {}
 
 pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
-    dbg!(&err);
     let (code, message) = if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not Found".to_string())
     } else if let Some(e) = err.find::<Error>() {
         match e {
             Error::CheatError => (StatusCode::PAYMENT_REQUIRED, e.to_string()),
             Error::RoomBehindNotFoundError => (StatusCode::CONFLICT, e.to_string()),
+            Error::AmbiguousRoomBehindError => (StatusCode::CONFLICT, e.to_string()),
             Error::NeighborNotFoundError => (StatusCode::CONFLICT, e.to_string()),
+            Error::NoPathError => (StatusCode::CONFLICT, e.to_string()),
+            Error::RiddleOpenedElsewhereError => (StatusCode::CONFLICT, e.to_string()),
             Error::UnsafePasswordError => (StatusCode::CONFLICT, e.to_string()),
-            Error::InvalidEmailError => (StatusCode::CONFLICT, e.to_string()),
+            Error::InvalidEmailError(_) => (StatusCode::CONFLICT, e.to_string()),
             Error::InvalidUsernameError => (StatusCode::CONFLICT, e.to_string()),
             Error::UsernameOrEmailNotAvailableError => (StatusCode::CONFLICT, e.to_string()),
             Error::WrongCredentialsError => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::AccountLockedError(_) => (StatusCode::LOCKED, e.to_string()),
+            Error::AccountNotActivatedError => (StatusCode::LOCKED, e.to_string()),
             Error::NoPermissionError => (StatusCode::UNAUTHORIZED, e.to_string()),
             Error::JWTTokenError => (StatusCode::UNAUTHORIZED, e.to_string()),
+            Error::JWTTokenExpiredError => (StatusCode::UNAUTHORIZED, e.to_string()),
+            Error::JWTTokenRevokedError => (StatusCode::UNAUTHORIZED, e.to_string()),
+            Error::RefreshTokenReusedError => (StatusCode::UNAUTHORIZED, e.to_string()),
+            Error::PasswordResetTokenExpiredError => (StatusCode::GONE, e.to_string()),
+            Error::PasswordResetTokenUsedError => (StatusCode::GONE, e.to_string()),
+            Error::TooManySessionsError(_) => (StatusCode::CONFLICT, e.to_string()),
+            Error::ServiceBusyError(_) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+            Error::MaintenanceError(_) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+            Error::TooManyRequestsError => (StatusCode::TOO_MANY_REQUESTS, e.to_string()),
+            Error::SolveCooldownError(_) => (StatusCode::TOO_MANY_REQUESTS, e.to_string()),
+            Error::SolveBatchTooLargeError(_) => (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()),
+            Error::ChallengeFailedError => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::LevelLockedError => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::UserCannotRemoveOwnAccountError => (StatusCode::CONFLICT, e.to_string()),
+            Error::CannotRemoveLastAdminError => (StatusCode::CONFLICT, e.to_string()),
+            Error::AttemptsExhaustedError => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::TotpNotConfiguredError => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::TotpNameTakenError => (StatusCode::CONFLICT, e.to_string()),
+            Error::TotpAlreadyEnabledError => (StatusCode::CONFLICT, e.to_string()),
+            Error::TotpNotPendingError => (StatusCode::CONFLICT, e.to_string()),
+            Error::TotpNotFoundError => (StatusCode::NOT_FOUND, e.to_string()),
+            Error::Fido2NotConfiguredError => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::CannotChangeToSameRole(_) => (StatusCode::CONFLICT, e.to_string()),
+            Error::CannotPromoteToLowerRoleError(_) => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::SolutionNotRevealableError => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::HintNotFoundError(_) => (StatusCode::NOT_FOUND, e.to_string()),
+            Error::GameNotFoundError => (StatusCode::NOT_FOUND, e.to_string()),
+            Error::NoEntryRoomError => (StatusCode::CONFLICT, e.to_string()),
+            Error::GameLockedError => (StatusCode::LOCKED, e.to_string()),
+            Error::InvalidRiddleError(_) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+            Error::RiddleLevelTakenError(_) => (StatusCode::CONFLICT, e.to_string()),
+            Error::UploadsDisabledError => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+            Error::RiddleFileNotFoundError(_) => (StatusCode::NOT_FOUND, e.to_string()),
+            Error::FileAccessError(_) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            Error::InvalidFileNameError(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            Error::EventNotStartedError(_) => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::EventEndedError(_) => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::CredentialCounterRegressionError => (StatusCode::FORBIDDEN, e.to_string()),
             Error::JWTTokenCreationError => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Internal Server Error".to_string(),
@@ -137,6 +294,13 @@ pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply,
         .is_some()
     {
         (StatusCode::BAD_REQUEST, "BodyDeserializeError".to_string())
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Payload Too Large".to_string(),
+        )
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        (StatusCode::LENGTH_REQUIRED, "Length Required".to_string())
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         (
             StatusCode::METHOD_NOT_ALLOWED,
@@ -149,11 +313,22 @@ pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply,
             "Internal Server Error".to_string(),
         )
     };
+    let error_kind: Option<&'static str> = err.find::<Error>().and_then(|e| e.kind());
+    METRICS.count_error(code.as_u16(), error_kind.unwrap_or(""));
     let json = warp::reply::json(&ErrorResponse {
         ok: false,
         code: code.as_u16(),
         status: code.to_string(),
         message: message,
+        error_kind,
     });
-    Ok(warp::reply::with_status(json, code))
+    let mut response = warp::reply::with_status(json, code).into_response();
+    if let Some(Error::ServiceBusyError(retry_after))
+    | Some(Error::SolveCooldownError(retry_after)) = err.find::<Error>()
+    {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(*retry_after));
+    }
+    Ok(response)
 }
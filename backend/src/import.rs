@@ -0,0 +1,397 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::db::{Game, Riddle, Room};
+use crate::upload;
+use crate::OPPOSITE;
+use bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// A complete game as uploaded by a designer: the game itself plus all of
+/// its rooms and the riddles securing their doorways.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GameImport {
+    pub game: Game,
+    pub rooms: Vec<Room>,
+    #[serde(default)]
+    pub riddles: Vec<Riddle>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct GameImportReport {
+    pub num_rooms: u32,
+    pub num_riddles: u32,
+    pub num_doorways: u32,
+    pub problems: Vec<String>,
+}
+
+/// Checks the riddle fields a designer sets, be it through an import or
+/// the riddle endpoints. Fields that are `None` aren't checked. Levels
+/// may go up to `max_level`. Returns what is wrong, worded to follow
+/// "riddle <id> ".
+pub fn riddle_field_problems(
+    max_level: u32,
+    level: Option<u32>,
+    difficulty: Option<i32>,
+    solution: Option<&str>,
+) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    if let Some(level) = level {
+        if level > max_level {
+            problems.push(format!("has level {} (maximum is {})", level, max_level));
+        }
+    }
+    if let Some(difficulty) = difficulty {
+        if difficulty <= 0 {
+            problems.push(format!(
+                "has difficulty {} (must be greater than 0)",
+                difficulty
+            ));
+        }
+    }
+    if let Some(solution) = solution {
+        if solution.trim().is_empty() {
+            problems.push("has an empty solution".to_string());
+        }
+    }
+    problems
+}
+
+impl GameImport {
+    /// Ids of all riddles the import refers to, be it as part of the
+    /// import or from a doorway.
+    pub fn referenced_riddle_ids(&self) -> Vec<ObjectId> {
+        let mut ids: HashSet<ObjectId> = self.riddles.iter().map(|r| r.id).collect();
+        for room in self.rooms.iter() {
+            for neighbor in room.neighbors.iter() {
+                ids.insert(neighbor.riddle_id);
+            }
+        }
+        ids.into_iter().collect()
+    }
+
+    /// Checks the import for referential integrity and a consistent layout.
+    /// `existing_riddles` contains the ids of riddles already stored in the
+    /// database, `game_exists` tells if a game with the same id is present.
+    /// Riddle levels may go up to `max_level`. Files the riddles refer to
+    /// have to be present in `upload_dir`, if there is one. The live import
+    /// and the dry run share this check.
+    pub fn validate(
+        &self,
+        existing_riddles: &HashSet<ObjectId>,
+        game_exists: bool,
+        max_level: u32,
+        upload_dir: Option<&Path>,
+    ) -> GameImportReport {
+        let mut report = GameImportReport {
+            num_rooms: self.rooms.len() as u32,
+            num_riddles: self.riddles.len() as u32,
+            num_doorways: self.rooms.iter().map(|r| r.neighbors.len() as u32).sum(),
+            problems: Vec::new(),
+        };
+        if game_exists {
+            report
+                .problems
+                .push(format!("game {} already exists", &self.game.id));
+        }
+        if self.rooms.is_empty() {
+            report.problems.push("game has no rooms".to_string());
+        }
+        if !self.rooms.iter().any(|r| r.entry.unwrap_or(false)) {
+            report.problems.push("game has no entry room".to_string());
+        }
+        let mut room_ids: HashSet<ObjectId> = HashSet::new();
+        for room in self.rooms.iter() {
+            if !room_ids.insert(room.id) {
+                report
+                    .problems
+                    .push(format!("room {} is defined more than once", &room.id));
+            }
+            if room.game_id != self.game.id {
+                report.problems.push(format!(
+                    "room {} belongs to game {}, not {}",
+                    &room.id, &room.game_id, &self.game.id
+                ));
+            }
+        }
+        let mut riddle_ids: HashSet<ObjectId> = HashSet::new();
+        let mut levels: HashMap<u32, ObjectId> = HashMap::new();
+        for riddle in self.riddles.iter() {
+            if !riddle_ids.insert(riddle.id) {
+                report
+                    .problems
+                    .push(format!("riddle {} is defined more than once", &riddle.id));
+            }
+            for problem in riddle_field_problems(
+                max_level,
+                Some(riddle.level),
+                Some(riddle.difficulty),
+                // scripted riddles work out their solution themselves
+                riddle.script.is_none().then(|| riddle.solution.as_str()),
+            ) {
+                report
+                    .problems
+                    .push(format!("riddle {} {}", &riddle.id, problem));
+            }
+            if let Some(other) = levels.insert(riddle.level, riddle.id) {
+                report.problems.push(format!(
+                    "riddle {} has level {} like riddle {}",
+                    &riddle.id, riddle.level, &other
+                ));
+            }
+            if existing_riddles.contains(&riddle.id) {
+                report
+                    .problems
+                    .push(format!("riddle {} already exists", &riddle.id));
+            }
+            let mut missing_files: Vec<&str> = Vec::new();
+            let mut unsafe_names: Vec<&str> = Vec::new();
+            let mut absent_files: Vec<&str> = Vec::new();
+            for file in riddle.files.iter().flatten() {
+                let mut uploaded_names: Vec<(&str, &str)> =
+                    vec![(&file.uploaded_name, &file.original_name)];
+                if let Some(ref retina) = file.retina {
+                    uploaded_names.push((retina, &file.original_name));
+                }
+                for variant in file.variants.iter().flatten() {
+                    uploaded_names.push((&variant.uploaded_name, &variant.original_name));
+                }
+                for (uploaded_name, original_name) in uploaded_names {
+                    if uploaded_name.trim().is_empty() {
+                        missing_files.push(original_name);
+                    } else if !upload::is_safe_name(uploaded_name) {
+                        unsafe_names.push(uploaded_name);
+                    } else if upload_dir.map_or(false, |dir| !dir.join(uploaded_name).is_file()) {
+                        absent_files.push(uploaded_name);
+                    }
+                }
+            }
+            if !missing_files.is_empty() {
+                report.problems.push(format!(
+                    "riddle {} refers to files without an uploaded name: {}",
+                    &riddle.id,
+                    missing_files.join(", ")
+                ));
+            }
+            if !unsafe_names.is_empty() {
+                report.problems.push(format!(
+                    "riddle {} refers to files outside the upload folder: {}",
+                    &riddle.id,
+                    unsafe_names.join(", ")
+                ));
+            }
+            if !absent_files.is_empty() {
+                report.problems.push(format!(
+                    "riddle {} refers to files missing from the upload folder: {}",
+                    &riddle.id,
+                    absent_files.join(", ")
+                ));
+            }
+        }
+        for room in self.rooms.iter() {
+            for neighbor in room.neighbors.iter() {
+                let opposite: &String = match OPPOSITE.get(&neighbor.direction) {
+                    Some(opposite) => opposite,
+                    None => {
+                        report.problems.push(format!(
+                            "room {} has a doorway with unknown direction '{}'",
+                            &room.id, &neighbor.direction
+                        ));
+                        continue;
+                    }
+                };
+                if !riddle_ids.contains(&neighbor.riddle_id)
+                    && !existing_riddles.contains(&neighbor.riddle_id)
+                {
+                    report.problems.push(format!(
+                        "doorway '{}' of room {} refers to unknown riddle {}",
+                        &neighbor.direction, &room.id, &neighbor.riddle_id
+                    ));
+                }
+                let num_rooms_behind: usize = self
+                    .rooms
+                    .iter()
+                    .filter(|other| {
+                        other.id != room.id
+                            && other.neighbors.iter().any(|n| {
+                                &n.direction == opposite && n.riddle_id == neighbor.riddle_id
+                            })
+                    })
+                    .count();
+                match num_rooms_behind {
+                    1 => (),
+                    0 => report.problems.push(format!(
+                        "doorway '{}' of room {} leads nowhere",
+                        &neighbor.direction, &room.id
+                    )),
+                    _ => report.problems.push(format!(
+                        "doorway '{}' of room {} leads to {} rooms",
+                        &neighbor.direction, &room.id, num_rooms_behind
+                    )),
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    /// Two rooms of one game, joined by a doorway to the east guarded by
+    /// a single riddle.
+    fn two_rooms() -> GameImport {
+        let game_id: ObjectId = ObjectId::new();
+        let riddle_id: ObjectId = ObjectId::new();
+        bson::from_document(doc! {
+            "game": {"_id": game_id, "name": "test"},
+            "rooms": [
+                {
+                    "_id": ObjectId::new(),
+                    "game_id": game_id,
+                    "entry": true,
+                    "neighbors": [{"direction": "e", "riddle_id": riddle_id, "level": 1}],
+                },
+                {
+                    "_id": ObjectId::new(),
+                    "game_id": game_id,
+                    "neighbors": [{"direction": "w", "riddle_id": riddle_id, "level": 1}],
+                },
+            ],
+            "riddles": [{"_id": riddle_id, "level": 1, "difficulty": 5, "solution": "42"}],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn riddle_fields_within_bounds_pass() {
+        assert!(riddle_field_problems(10, Some(10), Some(1), Some("42")).is_empty());
+        assert!(riddle_field_problems(10, None, None, None).is_empty());
+    }
+
+    #[test]
+    fn riddle_fields_out_of_bounds_are_reported() {
+        let problems: Vec<String> = riddle_field_problems(10, Some(11), Some(0), Some("  "));
+        assert_eq!(
+            problems,
+            vec![
+                "has level 11 (maximum is 10)".to_string(),
+                "has difficulty 0 (must be greater than 0)".to_string(),
+                "has an empty solution".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn consistent_import_passes() {
+        let import: GameImport = two_rooms();
+        let report: GameImportReport = import.validate(&HashSet::new(), false, 10, None);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(report.num_rooms, 2);
+        assert_eq!(report.num_riddles, 1);
+        assert_eq!(report.num_doorways, 2);
+    }
+
+    #[test]
+    fn existing_game_and_riddles_are_reported() {
+        let import: GameImport = two_rooms();
+        let existing: HashSet<ObjectId> = HashSet::from([import.riddles[0].id]);
+        let report: GameImportReport = import.validate(&existing, true, 10, None);
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(report.problems[0].ends_with("already exists"));
+        assert!(report.problems[1].ends_with("already exists"));
+    }
+
+    #[test]
+    fn one_sided_doorway_leads_nowhere() {
+        let mut import: GameImport = two_rooms();
+        import.rooms[1].neighbors.clear();
+        let report: GameImportReport = import.validate(&HashSet::new(), false, 10, None);
+        assert_eq!(
+            report.problems,
+            vec![format!(
+                "doorway 'e' of room {} leads nowhere",
+                &import.rooms[0].id
+            )]
+        );
+    }
+
+    #[test]
+    fn doorway_to_unknown_riddle_is_reported() {
+        let mut import: GameImport = two_rooms();
+        import.riddles.clear();
+        let report: GameImportReport = import.validate(&HashSet::new(), false, 10, None);
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(report
+            .problems
+            .iter()
+            .all(|p| p.contains("refers to unknown riddle")));
+    }
+
+    #[test]
+    fn missing_entry_room_and_duplicate_levels_are_reported() {
+        let mut import: GameImport = two_rooms();
+        import.rooms[0].entry = None;
+        let twin: Riddle = bson::from_document(doc! {
+            "_id": ObjectId::new(), "level": 1, "difficulty": 5, "solution": "43",
+        })
+        .unwrap();
+        import.riddles.push(twin);
+        let report: GameImportReport = import.validate(&HashSet::new(), false, 10, None);
+        assert!(report
+            .problems
+            .contains(&"game has no entry room".to_string()));
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| p.contains("has level 1 like riddle")));
+    }
+
+    #[test]
+    fn files_outside_the_upload_folder_are_reported() {
+        let mut import: GameImport = two_rooms();
+        import.riddles[0] = bson::from_document(doc! {
+            "_id": import.riddles[0].id, "level": 1, "difficulty": 5, "solution": "42",
+            "files": [{"originalName": "map.png", "uploadedName": "../secret", "mimeType": "image/png"}],
+        })
+        .unwrap();
+        let report: GameImportReport = import.validate(&HashSet::new(), false, 10, None);
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert!(report.problems[0].ends_with("outside the upload folder: ../secret"));
+    }
+
+    #[test]
+    fn files_missing_from_the_upload_folder_are_reported() {
+        let upload_dir = std::env::temp_dir().join(format!("labyrinth-{}", ObjectId::new()));
+        std::fs::create_dir(&upload_dir).unwrap();
+        std::fs::write(upload_dir.join("map.png"), b"").unwrap();
+        let mut import: GameImport = two_rooms();
+        import.riddles[0] = bson::from_document(doc! {
+            "_id": import.riddles[0].id, "level": 1, "difficulty": 5, "solution": "42",
+            "files": [{
+                "originalName": "map.png", "uploadedName": "map.png", "mimeType": "image/png",
+                "retina": "map@2x.png",
+                "variants": [{"originalName": "map.png", "uploadedName": "map-small.png", "scale": 1}],
+            }],
+        })
+        .unwrap();
+        let report: GameImportReport =
+            import.validate(&HashSet::new(), false, 10, Some(&upload_dir));
+        std::fs::remove_dir_all(&upload_dir).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![format!(
+                "riddle {} refers to files missing from the upload folder: map@2x.png, map-small.png",
+                &import.riddles[0].id
+            )]
+        );
+        // without an upload folder there is nothing to check against
+        let report: GameImportReport = import.validate(&HashSet::new(), false, 10, None);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+}
@@ -3,64 +3,98 @@
  * All rights reserved.
  */
 use crate::error::Error;
-use auth::{with_auth, Role};
+use auth::{with_auth, with_jwt_cookie, Role};
+use authlog::{log_auth_event, with_client_info, AuthEvent, ClientInfo};
 use base32;
 use bson::oid::ObjectId;
-use chrono::{serde::ts_seconds_option, DateTime, TimeZone, Utc};
+use chrono::{
+    serde::{ts_seconds, ts_seconds_option},
+    DateTime, TimeZone, Utc,
+};
+use clock::SystemClock;
+use config::{with_config, Config};
 use db::{
-    with_db, Direction, PinType, Riddle, RiddleAttempt, Room, SecondFactor, User,
-    UserCompactScoreData, DB,
+    with_db, AdminStats, AuditLogEntry, Direction, FinishedGame, Game, GameState, Leaderboard,
+    OutboxMail, PasswordReset, PinType, ProcessedSolve, RefreshToken, Riddle, RiddleAttempt,
+    RiddleStats, RiddleUpdate, Room, RoomVisit, SecondFactor, SolveRanking, SolvedDifficulty,
+    TotpSecret, TwoFactorState, UnlockedHint, UploadedFile, User, UserCompactScoreData,
+    UserLeaderboardData, WrongAnswer, DB,
 };
 use dotenv::dotenv;
+use futures::stream::StreamExt;
+use import::{GameImport, GameImportReport};
 use lazy_static::lazy_static;
-use lettre::{Message, SmtpTransport, Transport};
+use lettre::Message;
 use log;
+use maintenance::{with_maintenance, Access, Maintenance, MaintenanceMode, SharedMaintenance};
 use mongodb::bson::doc;
-use passwd::Password;
+use passwd::{Argon2Params, Password, ARGON2_PARAMS};
 use qrcode_generator::QrCodeEcc;
 use rand::Rng;
 use rand_core::{OsRng, RngCore};
+use ratelimit::{with_rate_limiter, RateLimiter, SharedRateLimiter};
 use regex::Regex;
 use rlua;
 use scripting::{with_script_env, ScriptEnv, ScriptEnvMap};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::From;
-use std::env;
 use std::fs;
 use std::io::{Read, Seek, SeekFrom};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use totp_lite::{totp_custom, Sha1};
+use std::sync::{Arc, Mutex, RwLock};
+use totp::{verify_totp, TOTP_PARAMS};
 use url_escape;
-use warp::{http::StatusCode, reject, reply::WithStatus, Filter, Rejection, Reply};
+use warp::{
+    http::{
+        header::{
+            CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, LAST_MODIFIED,
+            X_CONTENT_TYPE_OPTIONS,
+        },
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    reject,
+    reply::{Response, WithStatus},
+    Filter, Rejection, Reply,
+};
 use webauthn_rs::proto::{
     CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
     RequestChallengeResponse,
 };
 
 mod auth;
+mod authlog;
 mod b64;
+mod challenge;
+mod clock;
+mod config;
 mod db;
+mod email;
 mod error;
+mod import;
+mod maintenance;
+mod metrics;
+mod outbox;
 mod passwd;
+mod ratelimit;
 mod scripting;
+mod totp;
+mod upload;
 mod webauthn;
+mod webhook;
 
 type Result<T> = std::result::Result<T, error::Error>;
 type WebResult<T> = std::result::Result<T, Rejection>;
 type OidString = String;
 
-pub fn webauthn_default_config() -> webauthn::WebauthnVolatileConfig {
-    let rp_name: String =
-        env::var("RP_NAME").expect("environment variable RP_NAME has not been set");
-    let rp_origin: String =
-        env::var("RP_ORIGIN").expect("environment variable RP_ORIGIN has not been set");
-    let rp_id: String = env::var("RP_ID").expect("environment variable RP_ID has not been set");
-    let wa_config =
-        webauthn::WebauthnVolatileConfig::new(&rp_name, &rp_origin, &rp_id, Option::default());
+pub fn webauthn_default_config(config: &Config) -> webauthn::WebauthnVolatileConfig {
+    let wa_config = webauthn::WebauthnVolatileConfig::new(
+        &config.rp_name,
+        &config.rp_origin,
+        &config.rp_id,
+        Option::default(),
+    );
     wa_config
 }
 
@@ -70,11 +104,12 @@ lazy_static! {
         (String::from("e"), String::from("w")),
         (String::from("s"), String::from("n")),
         (String::from("w"), String::from("e")),
-        //(String::from("u"), String::from("d")),
-        //(String::from("d"), String::from("u")),
+        (String::from("u"), String::from("d")),
+        (String::from("d"), String::from("u")),
     ]);
     static ref RE_USERNAME: Regex = Regex::new(r"^\w+$").unwrap();
-    static ref RE_MAIL: Regex = Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$").unwrap();
+    static ref RE_MAIL: Regex =
+        Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$").unwrap();
 }
 
 #[repr(C)]
@@ -83,14 +118,15 @@ union MD5Hash {
     value: u128,
 }
 
-fn is_bad_password(password: &String) -> std::result::Result<bool, std::io::Error> {
+fn is_bad_password(
+    password: &String,
+    md5_filename: &String,
+) -> std::result::Result<bool, std::io::Error> {
     let hash = MD5Hash {
         hash: md5::compute(password.as_bytes()),
     };
     let given_hash_raw = unsafe { MD5Hash { hash: hash.hash } };
     let given_hash = unsafe { u128::from_be(given_hash_raw.value) };
-    let md5_filename = env::var("BAD_PASSWORDS_MD5")
-        .expect("environment variable BAD_PASSWORDS_MD5 has not been set");
     let metadata = fs::metadata(&md5_filename).expect(&format!(
         "cannot read metadata of MD5 hash file '{}'",
         &md5_filename
@@ -126,11 +162,107 @@ fn is_bad_password(password: &String) -> std::result::Result<bool, std::io::Erro
     Ok(false)
 }
 
+/// Minimum number of characters a solution must have before a "very close"
+/// hint may be given. For shorter solutions the hint would narrow down the
+/// search space too much.
+const CLOSE_ANSWER_MIN_LENGTH: usize = 5;
+
+/// Lower bound for the per-riddle similarity threshold, so that a sloppily
+/// configured riddle can't turn the hint into a brute-force oracle.
+const CLOSE_ANSWER_MIN_THRESHOLD: f32 = 0.75;
+
+fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        curr[0] = i;
+        for j in 1..=b.len() {
+            let cost: usize = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            curr[j] = (prev[j] + 1).min(curr[j - 1] + 1).min(prev[j - 1] + cost);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+/// Lower-cases `s` for case-insensitive comparison. Rust's `to_lowercase()`
+/// follows the language-independent Unicode rules, which get the dotted
+/// and dotless I wrong for Turkish and Azerbaijani: there, "I" lowers to
+/// "ı" and "İ" to "i". Those two languages are handled here if `locale`
+/// names them ("tr", "tr-TR", "az", ...). Other language-specific rules,
+/// e.g. Lithuanian accented i, are not, so such locales fall back to the
+/// default behavior.
+fn fold_case(s: &str, locale: &Option<String>) -> String {
+    let dotted_i: bool = match locale {
+        Some(locale) => {
+            let language: String = locale
+                .split(|c| c == '-' || c == '_')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            language == "tr" || language == "az"
+        }
+        None => false,
+    };
+    match dotted_i {
+        true => s
+            .chars()
+            .map(|c| match c {
+                'I' => 'ı'.to_string(),
+                'İ' => 'i'.to_string(),
+                c => c.to_lowercase().to_string(),
+            })
+            .collect(),
+        false => s.to_lowercase(),
+    }
+}
+
+/// Brings an answer into the form it is compared in when solving a
+/// riddle; both the solution and the player's answer go through this.
+fn normalize_answer(answer: &str, ignore_case: bool, locale: &Option<String>) -> String {
+    match ignore_case {
+        true => fold_case(answer, locale),
+        false => answer.to_string(),
+    }
+}
+
+/// Checks whether a wrong `guess` is similar enough to `solution` to tell
+/// the player they're "very close". Riddles opt in by setting
+/// `close_answer_threshold` (a Levenshtein ratio between 0 and 1).
+fn is_close_answer(
+    solution: &String,
+    guess: &String,
+    ignore_case: bool,
+    locale: &Option<String>,
+    threshold: Option<f32>,
+) -> bool {
+    let threshold: f32 = match threshold {
+        Some(threshold) => threshold.max(CLOSE_ANSWER_MIN_THRESHOLD).min(1.0),
+        None => return false,
+    };
+    let (solution, guess) = match ignore_case {
+        true => (
+            fold_case(solution.trim(), locale),
+            fold_case(guess.trim(), locale),
+        ),
+        false => (solution.trim().to_string(), guess.trim().to_string()),
+    };
+    let len: usize = solution.chars().count().max(guess.chars().count());
+    if solution.chars().count() < CLOSE_ANSWER_MIN_LENGTH || len == 0 {
+        return false;
+    }
+    let ratio: f32 = 1.0 - levenshtein_distance(&solution, &guess) as f32 / len as f32;
+    ratio >= threshold && ratio < 1.0
+}
+
 #[derive(Serialize, Debug)]
 pub struct PingResponse {
     pub ok: bool,
     pub message: Option<String>,
     pub version: String,
+    pub argon2: Argon2Params,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -142,6 +274,12 @@ pub struct UserRegistrationRequest {
     pub locale: String,
     #[serde(rename = "secondFactorMethod")]
     pub second_factor: Option<SecondFactor>,
+    /// captcha token or proof-of-work nonce, see `Config::registration_challenge`
+    #[serde(default)]
+    pub challenge: Option<String>,
+    /// game to enter after activation; any game with an entry room if unset
+    #[serde(default)]
+    pub game_id: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -162,6 +300,12 @@ pub struct UserActivationRequest {
     pub pin: PinType,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct UserActivationResendRequest {
+    /// username or email address
+    pub username: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct UserLoginRequest {
     pub username: String,
@@ -176,11 +320,83 @@ pub struct UserTotpRequest {
     pub totp: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct UserTotpConfirmRequest {
+    pub totp: String,
+}
+
+/// Sets up or turns off TOTP; requires the password and, if the user has
+/// TOTP configured, a current code.
+#[derive(Deserialize, Debug)]
+pub struct UserTotpStepUpRequest {
+    pub password: String,
+    pub totp: Option<String>,
+}
+
+/// Adds or removes a named TOTP authenticator; requires the password
+/// and, if the user has TOTP configured, a current code.
+#[derive(Deserialize)]
+pub struct UserTotpChangeRequest {
+    pub name: String,
+    pub password: String,
+    pub totp: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserRefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserPasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserPasswordResetConfirmRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserRecoveryRequest {
+    pub username: String,
+    pub recovery_key: String,
+    /// not needed if the password was checked already and the login only
+    /// awaits the second factor
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserRecoveryValidateRequest {
+    pub username: String,
+    pub recovery_key: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct RiddleSolveRequest {
     pub solution: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct BatchSolveItem {
+    pub riddle_id: OidString,
+    pub solution: String,
+    /// chosen by the client; resubmitting an item with a known key returns
+    /// the earlier outcome without booking it again
+    pub idempotency_key: String,
+}
+
+/// A door of the room, with just enough about the riddle behind it for
+/// clients to order doors by how hard they are.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Neighbor {
+    pub direction: String,
+    pub riddle_id: ObjectId,
+    pub level: u32,
+    pub difficulty: Option<i32>,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct RoomResponse {
     pub ok: bool,
@@ -188,7 +404,7 @@ pub struct RoomResponse {
     pub id: ObjectId,
     pub number: u32,
     pub coords: Option<String>,
-    pub neighbors: Vec<Direction>,
+    pub neighbors: Vec<Neighbor>,
     pub game_id: ObjectId,
     pub entry: Option<bool>,
     pub exit: Option<bool>,
@@ -229,8 +445,8 @@ impl TotpResponseRaw {
             qrcode,
             secret,
             hash: "SHA1".to_string(),
-            interval: 30,
-            digits: 6,
+            interval: TOTP_PARAMS.interval,
+            digits: TOTP_PARAMS.digits,
         }
     }
 }
@@ -261,13 +477,18 @@ pub struct UserWhoamiResponse {
     pub last_login: Option<DateTime<Utc>>,
     pub level: u32,
     pub score: i32,
-    pub in_room: RoomResponse,
+    /// `None` for spectators, who don't enter rooms
+    pub in_room: Option<RoomResponse>,
     pub solved: Vec<RiddleAttempt>,
     pub rooms_entered: Vec<ObjectId>,
     pub jwt: Option<String>,
+    pub refresh_token: Option<String>,
+    /// number of sessions holding a valid refresh token, if tracked
+    pub active_sessions: Option<u32>,
     pub totp: Option<TotpResponseRaw>,
     pub recovery_keys: Option<Vec<String>>,
     pub configured_2fa: Vec<SecondFactor>,
+    pub two_factor_state: TwoFactorState,
 }
 
 #[derive(Serialize, Debug)]
@@ -298,6 +519,39 @@ pub struct FileResponse {
     pub variants: Option<Vec<FileVariantResponse>>,
 }
 
+#[derive(Serialize, Debug)]
+pub struct FileVariantMetadataResponse {
+    #[serde(rename = "originalName")]
+    pub original_name: String,
+    #[serde(rename = "uploadedName")]
+    pub uploaded_name: String,
+    pub scale: u32,
+    pub url: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileMetadataResponse {
+    #[serde(rename = "originalName")]
+    pub original_name: String,
+    #[serde(rename = "uploadedName")]
+    pub uploaded_name: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub scale: Option<u32>,
+    pub url: String,
+    pub variants: Vec<FileVariantMetadataResponse>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RiddleFilesResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub id: ObjectId,
+    pub files: Vec<FileMetadataResponse>,
+}
+
 #[derive(Serialize, Debug)]
 pub struct RiddleResponse {
     pub ok: bool,
@@ -310,6 +564,27 @@ pub struct RiddleResponse {
     pub deduction: i32,
     pub ignore_case: bool,
     pub credits: Option<String>,
+    /// number of hints available via `POST /riddle/{id}/hint/{index}`
+    pub num_hints: usize,
+    /// `true` if the user had opened the riddle before but not solved it yet
+    pub opened: bool,
+    /// wrong answers the user may still give, if the riddle limits them
+    pub attempts_left: Option<u32>,
+    #[serde(default)]
+    #[serde(with = "ts_seconds_option")]
+    pub t0: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RiddleHintResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub riddle_id: ObjectId,
+    pub index: u32,
+    pub hint: String,
+    /// points deducted for this request; 0 if the hint had been unlocked
+    pub penalty: i32,
+    pub score: i32,
 }
 
 #[derive(Serialize, Debug)]
@@ -317,6 +592,12 @@ pub struct DebriefingResponse {
     pub ok: bool,
     pub message: Option<String>,
     pub debriefing: Option<String>,
+    /// seconds the user needed to solve the riddle, if known
+    pub solve_time: Option<i64>,
+    /// rank among all solvers by solve time, if known
+    pub rank: Option<u32>,
+    pub num_solvers: u32,
+    pub first_blood: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -328,6 +609,34 @@ pub struct RiddleSolvedResponse {
     pub level: u32,
     pub message: Option<String>,
     pub feedback: Option<String>,
+    pub attempts_left: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchSolveResult {
+    pub idempotency_key: String,
+    pub riddle_id: OidString,
+    pub ok: bool,
+    pub solved: bool,
+    /// the item had been processed before and wasn't booked again
+    pub duplicate: bool,
+    pub message: Option<String>,
+    pub feedback: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchSolveResponse {
+    pub ok: bool,
+    pub score: i32,
+    pub level: u32,
+    pub results: Vec<BatchSolveResult>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PathHomeResponse {
+    pub ok: bool,
+    /// directions leading from the user's room to the entry room
+    pub path: Vec<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -344,107 +653,525 @@ pub struct GameStatsResponse {
     pub num_rooms: u32,
     pub num_riddles: u32,
     pub max_score: u32,
+    pub state: GameState,
 }
 
 #[derive(Serialize, Debug)]
-pub struct SecondFactorRequiredResponse {
+pub struct DirectionPair {
+    pub direction: String,
+    pub opposite: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DirectionsResponse {
     pub ok: bool,
-    pub message: String,
-    pub second_factors: Vec<SecondFactor>,
+    pub message: Option<String>,
+    pub game_id: ObjectId,
+    pub directions: Vec<DirectionPair>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct WebAuthnRegisterStartRequest {
+#[derive(Serialize, Debug, Default)]
+pub struct ScoreBreakdown {
+    pub easy: i32,
+    pub medium: i32,
+    pub hard: i32,
+    /// Points lost to deductions for wrong answers, so that
+    /// `easy + medium + hard - penalties` equals the user's score.
+    pub penalties: i32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserStatsResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub score: i32,
+    pub level: u32,
+    pub num_solved: u32,
+    pub score_breakdown: ScoreBreakdown,
+    /// position on the leaderboard, 1 being the best
+    pub rank: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct UserExportRequest {
+    pub password: String,
+    pub totp: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserExportProfile {
     pub username: String,
+    pub email: String,
+    pub role: Role,
+    pub activated: bool,
+    #[serde(with = "ts_seconds_option")]
+    pub created: Option<DateTime<Utc>>,
+    #[serde(with = "ts_seconds_option")]
+    pub registered: Option<DateTime<Utc>>,
+    #[serde(with = "ts_seconds_option")]
+    pub last_login: Option<DateTime<Utc>>,
+    pub level: u32,
+    pub score: i32,
+    pub in_room: Option<ObjectId>,
+    pub configured_2fa: Vec<SecondFactor>,
 }
 
+/// Everything stored about a user, minus secrets (password hash, TOTP
+/// key, recovery keys, WebAuthn credentials). The room visits are not part
+/// of the struct but streamed after it, see `json_with_streamed_array()`.
 #[derive(Serialize, Debug)]
-pub struct WebAuthnRegisterFinishResponse {
+pub struct UserExportResponse {
     pub ok: bool,
     pub message: Option<String>,
+    #[serde(with = "ts_seconds")]
+    pub exported_at: DateTime<Utc>,
+    pub profile: UserExportProfile,
+    pub solved: Vec<RiddleAttempt>,
+    pub current_riddle_attempt: Option<RiddleAttempt>,
+    pub rooms_entered: Vec<ObjectId>,
+    pub finished: Vec<FinishedGame>,
+    pub audit_log: Vec<AuditLogEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserImportRow {
+    pub username: String,
+    pub email: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserImportQuery {
+    /// activate the accounts right away instead of mailing a PIN
+    #[serde(default)]
+    pub activate: bool,
+    /// return the initial passwords in the response instead of mailing them
+    #[serde(default)]
+    pub return_passwords: bool,
 }
 
 #[derive(Serialize, Debug)]
-struct WebAuthnRegisterStartResponse {
+pub struct UserImportResult {
+    pub username: String,
     pub ok: bool,
     pub message: Option<String>,
-    pub ccr: CreationChallengeResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
-struct WebAuthnLoginStartResponse {
+pub struct UserImportResponse {
     pub ok: bool,
     pub message: Option<String>,
-    pub rcr: RequestChallengeResponse,
+    pub num_created: u32,
+    pub results: Vec<UserImportResult>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MaintenanceRequest {
+    pub mode: MaintenanceMode,
+    pub notice: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
-struct WebAuthnLoginFinishResponse {
+pub struct MaintenanceResponse {
     pub ok: bool,
     pub message: Option<String>,
-    pub jwt: String,
+    pub mode: MaintenanceMode,
+    pub notice: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GameStateRequest {
+    pub state: GameState,
 }
 
 #[derive(Serialize, Debug)]
-struct MFARequiredResponse {
+pub struct GameStateResponse {
     pub ok: bool,
     pub message: Option<String>,
-    #[serde(rename = "mfaMethods")]
-    pub configured_2fa: Vec<SecondFactor>,
+    pub game_id: ObjectId,
+    pub state: GameState,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminScoreRequest {
+    pub delta: Option<i32>,
+    pub score: Option<i32>,
+    pub reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TeleportQuery {
+    pub game_id: OidString,
 }
 
 #[derive(Serialize, Debug)]
-struct PromoteUserResponse {
+pub struct TeleportResponse {
     pub ok: bool,
     pub message: Option<String>,
     pub username: String,
-    pub role: Role,
+    pub room_id: ObjectId,
+    pub number: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TotpEnableQuery {
+    /// replace existing authenticators
+    #[serde(default)]
+    pub force: bool,
 }
 
 #[derive(Serialize, Debug)]
-struct UserScoreResponse {
+pub struct AdminScoreResponse {
+    pub ok: bool,
+    pub message: Option<String>,
     pub username: String,
-    #[serde(rename = "absScore")]
-    pub abs_score: i64,
-    #[serde(rename = "relScore")]
-    pub rel_score: f32,
+    pub previous_score: i32,
+    pub score: i32,
 }
 
 #[derive(Serialize, Debug)]
-struct HighscoresResponse {
+pub struct RiddleSolutionResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub riddle_id: ObjectId,
+    pub solution: String,
+    /// points deducted for revealing the solution
+    pub penalty: i32,
+    pub score: i32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RiddleStatsResponse {
     pub ok: bool,
     pub message: Option<String>,
-    pub highscores: Vec<UserScoreResponse>,
+    #[serde(flatten)]
+    pub stats: RiddleStats,
 }
 
-#[derive(Debug)]
-pub struct ScriptResult {
-    pub solution: Option<String>,
+#[derive(Serialize, Debug)]
+pub struct AdminStatsResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub stats: AdminStats,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RiddleCreateRequest {
+    pub level: u32,
+    pub difficulty: i32,
+    pub deduction: Option<i32>,
+    pub solution: String,
+    pub ignore_case: Option<bool>,
     pub task: Option<String>,
-    pub feedback: Option<String>,
-    pub name: Option<String>,
-    pub mime_type: Option<String>,
+    pub credits: Option<String>,
+    pub debriefing: Option<String>,
 }
 
-pub fn evaluate_script(
-    username: &String,
-    script: &String,
-    guess: Option<String>,
-    env: Arc<Mutex<ScriptEnvMap>>,
-    load: bool,
-) -> ScriptResult {
-    dbg!(&guess);
-    let mut env = env.lock().unwrap();
-    if !env.contains_key(username) {
-        env.insert(username.clone(), ScriptEnv::new());
-        log::info!("inserted {} into script_env", username);
-    }
-    let env = env.get(username).unwrap();
-    log::info!("fetched {} from script_env", username);
-    let (solution, task, feedback, name, mime_type) = env.lua.context(|lua_ctx| {
-        if load {
-            match lua_ctx.load(&script).exec() {
-                Ok(()) => (),
+#[derive(Serialize, Debug)]
+pub struct RiddleCreateResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub riddle_id: ObjectId,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RiddleFileUploadResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub file: FileMetadataResponse,
+}
+
+/// The riddle settings that affect how answers are compared.
+#[derive(Deserialize, Debug, Default)]
+pub struct NormalizePreviewOptions {
+    #[serde(default)]
+    pub ignore_case: Option<bool>,
+    #[serde(default)]
+    pub comparison_locale: Option<String>,
+    #[serde(default)]
+    pub external_password_input: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NormalizePreviewRequest {
+    pub raw: String,
+    #[serde(default)]
+    pub options: NormalizePreviewOptions,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NormalizePreviewResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub normalized: String,
+    /// whether the options make the comparison case-insensitive
+    pub ignore_case: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FinishedGameResponse {
+    pub game_id: ObjectId,
+    pub name: Option<String>,
+    #[serde(with = "ts_seconds")]
+    pub finished_at: DateTime<Utc>,
+    pub score: Option<i32>,
+    /// seconds from entering the game to finishing it
+    pub elapsed: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FinishedGamesResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub finished: Paginated<FinishedGameResponse>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SecondFactorRequiredResponse {
+    pub ok: bool,
+    pub message: String,
+    pub second_factors: Vec<SecondFactor>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WebAuthnRegisterStartRequest {
+    pub username: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WebAuthnRegisterFinishResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct WebAuthnRegisterStartResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub ccr: CreationChallengeResponse,
+}
+
+#[derive(Serialize, Debug)]
+struct WebAuthnLoginStartResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub rcr: RequestChallengeResponse,
+}
+
+#[derive(Serialize, Debug)]
+struct WebAuthnLoginFinishResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub jwt: String,
+}
+
+#[derive(Serialize, Debug)]
+struct MFARequiredResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    #[serde(rename = "mfaMethods")]
+    pub configured_2fa: Vec<SecondFactor>,
+    pub two_factor_state: TwoFactorState,
+}
+
+#[derive(Serialize, Debug)]
+struct UserRefreshResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub jwt: Option<String>,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct RecoveryKeyValidResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub valid: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RiddleGetQuery {
+    /// embed the riddle's files base64-encoded in the response, like before
+    /// they were served from GET /riddle/{oid}/file/{name}
+    #[serde(default)]
+    pub inline_files: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GameImportQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct GameImportResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub dry_run: bool,
+    pub game_id: ObjectId,
+    pub report: GameImportReport,
+}
+
+/// Response of `/user/login`. Clients branch on the `status` field
+/// (`"ok"` or `"mfa_required"`) instead of probing for the presence of `jwt`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum LoginResponse {
+    Ok(UserWhoamiResponse),
+    MfaRequired(MFARequiredResponse),
+}
+
+/// Serializes a `LoginResponse`. With `LEGACY_LOGIN_RESPONSE=true` the
+/// `status` discriminator is left out for old clients.
+fn login_reply(response: LoginResponse, config: &Config) -> warp::reply::Json {
+    match (config.legacy_login_response, response) {
+        (false, response) => warp::reply::json(&json!(&response)),
+        (true, LoginResponse::Ok(response)) => warp::reply::json(&json!(&response)),
+        (true, LoginResponse::MfaRequired(response)) => warp::reply::json(&json!(&response)),
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PromoteUserResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub username: String,
+    pub role: Role,
+}
+
+#[derive(Serialize, Debug)]
+struct UserScoreResponse {
+    pub username: String,
+    #[serde(rename = "absScore")]
+    pub abs_score: i64,
+    #[serde(rename = "relScore")]
+    pub rel_score: f32,
+}
+
+#[derive(Serialize, Debug)]
+struct HighscoresResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub highscores: Paginated<UserScoreResponse>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct LeaderboardEntry {
+    pub rank: u32,
+    pub username: String,
+    pub score: i32,
+    pub level: u32,
+    pub rooms_entered_count: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct LeaderboardResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub leaderboard: Paginated<LeaderboardEntry>,
+    /// the requesting user's entry, even if it is not on the page
+    pub own: Option<LeaderboardEntry>,
+}
+
+#[derive(Serialize, Debug)]
+struct SpectateResponse {
+    pub ok: bool,
+    pub message: Option<String>,
+    pub username: String,
+    pub level: u32,
+    pub score: i32,
+    pub in_room: RoomResponse,
+    /// most recent first
+    pub recent_solves: Vec<RiddleAttempt>,
+}
+
+/// Common shape of all list responses.
+#[derive(Serialize, Debug)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+    pub limit: u32,
+    pub offset: u32,
+    pub has_more: bool,
+}
+
+impl<T> Paginated<T> {
+    pub fn new(items: Vec<T>, total: u64, page: &PageQuery) -> Paginated<T> {
+        let has_more: bool = (page.offset as u64 + items.len() as u64) < total;
+        Paginated {
+            items,
+            total,
+            limit: page.limit(),
+            offset: page.offset,
+            has_more,
+        }
+    }
+    /// Cuts the requested page out of a list that has been loaded completely.
+    pub fn from_vec(all: Vec<T>, page: &PageQuery) -> Paginated<T> {
+        let total: u64 = all.len() as u64;
+        let items: Vec<T> = all
+            .into_iter()
+            .skip(page.offset as usize)
+            .take(page.limit() as usize)
+            .collect();
+        Paginated::new(items, total, page)
+    }
+}
+
+const DEFAULT_PAGE_LIMIT: u32 = 50;
+const MAX_PAGE_LIMIT: u32 = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct PageQuery {
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+impl PageQuery {
+    pub fn limit(&self) -> u32 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .max(1)
+            .min(MAX_PAGE_LIMIT)
+    }
+}
+
+#[derive(Debug)]
+pub struct ScriptResult {
+    pub solution: Option<String>,
+    pub task: Option<String>,
+    pub feedback: Option<String>,
+    pub name: Option<String>,
+    pub mime_type: Option<String>,
+}
+
+pub fn evaluate_script(
+    username: &String,
+    script: &String,
+    guess: Option<String>,
+    env: Arc<Mutex<ScriptEnvMap>>,
+    load: bool,
+) -> ScriptResult {
+    let mut env = env.lock().unwrap();
+    if !env.contains_key(username) {
+        env.insert(username.clone(), ScriptEnv::new());
+        log::info!("inserted {} into script_env", username);
+    }
+    let env = env.get(username).unwrap();
+    log::info!("fetched {} from script_env", username);
+    let (solution, task, feedback, name, mime_type) = env.lua.context(|lua_ctx| {
+        if load {
+            match lua_ctx.load(&script).exec() {
+                Ok(()) => (),
                 Err(e) => {
                     log::error!("{:?}", e);
                     return (
@@ -545,22 +1272,55 @@ fn err_response(message: Option<String>) -> WithStatus<warp::reply::Json> {
     warp::reply::with_status(reply, StatusCode::OK)
 }
 
+/// Adds the difficulty of the riddle behind each door.
+async fn get_neighbors(neighbors: Vec<Direction>, db: &DB) -> Result<Vec<Neighbor>> {
+    let riddle_ids: Vec<ObjectId> = neighbors.iter().map(|n| n.riddle_id).collect();
+    let difficulties: HashMap<ObjectId, i32> = match db.get_riddle_difficulties(&riddle_ids).await {
+        Ok(difficulties) => difficulties,
+        Err(e) => return Err(e),
+    };
+    Ok(neighbors
+        .into_iter()
+        .map(|n| Neighbor {
+            difficulty: difficulties.get(&n.riddle_id).copied(),
+            direction: n.direction,
+            riddle_id: n.riddle_id,
+            level: n.level,
+        })
+        .collect())
+}
+
+/// Returns the room the user is in, or `None` for spectators.
+async fn get_user_room(user: &User, db: &DB) -> Result<Option<RoomResponse>> {
+    if user.role == Role::Spectator {
+        return Ok(None);
+    }
+    match user.in_room {
+        Some(in_room) => get_room_by_id(&in_room, db).await.map(Some),
+        None => Err(Error::UserIsInNoRoom),
+    }
+}
+
 async fn get_room_by_id(room_id: &ObjectId, db: &DB) -> Result<RoomResponse> {
-    let room_response = match db.get_room(room_id).await {
-        Ok(room) => RoomResponse {
-            ok: true,
-            message: Option::default(),
-            id: room.id,
-            number: room.number,
-            coords: room.coords,
-            neighbors: room.neighbors,
-            game_id: room.game_id,
-            entry: room.entry,
-            exit: room.exit,
-        },
+    let room: Room = match db.get_room(room_id).await {
+        Ok(room) => room,
+        Err(e) => return Err(e),
+    };
+    let neighbors: Vec<Neighbor> = match get_neighbors(room.neighbors, db).await {
+        Ok(neighbors) => neighbors,
         Err(e) => return Err(e),
     };
-    Ok(room_response)
+    Ok(RoomResponse {
+        ok: true,
+        message: Option::default(),
+        id: room.id,
+        number: room.number,
+        coords: room.coords,
+        neighbors,
+        game_id: room.game_id,
+        entry: room.entry,
+        exit: room.exit,
+    })
 }
 
 pub async fn ping_handler() -> WebResult<impl Reply> {
@@ -569,21 +1329,52 @@ pub async fn ping_handler() -> WebResult<impl Reply> {
         ok: true,
         message: Option::default(),
         version: env!("CARGO_PKG_VERSION").to_string(),
+        argon2: *ARGON2_PARAMS,
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
-pub async fn go_handler(direction_str: String, username: String, db: DB) -> WebResult<impl Reply> {
-    log::info!(
-        "go_handler(); direction = {}; username = {}",
-        &direction_str,
-        &username
-    );
-    let mut user: User = match db.get_user(&username).await {
+/// Liveness probe: succeeds as long as the process can serve requests.
+pub async fn healthz_handler() -> WebResult<impl Reply> {
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Latency histograms and error counters in the Prometheus text format.
+pub async fn metrics_handler() -> WebResult<impl Reply> {
+    Ok(warp::reply::with_header(
+        metrics::METRICS.render(),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// Readiness probe: succeeds only if the database can be reached.
+pub async fn readyz_handler(db: DB) -> WebResult<impl Reply> {
+    let (status, message) = match db.ping().await {
+        Ok(()) => (StatusCode::OK, Option::default()),
+        Err(e) => {
+            log::error!("readiness check failed: {}", &e);
+            (StatusCode::SERVICE_UNAVAILABLE, Some(e.to_string()))
+        }
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: status == StatusCode::OK,
+        message,
+    }));
+    Ok(warp::reply::with_status(reply, status))
+}
+
+pub async fn path_home_handler(username: String, db: DB) -> WebResult<impl Reply> {
+    log::info!("path_home_handler(); username = {}", &username);
+    let user: User = match db.get_user(&username).await {
         Ok(user) => user,
         Err(e) => return Err(reject::custom(e)),
     };
-    let in_room = match &user.in_room {
+    let in_room: ObjectId = match user.in_room {
         Some(in_room) => in_room,
         None => return Err(reject::custom(Error::UserIsInNoRoom)),
     };
@@ -591,24 +1382,63 @@ pub async fn go_handler(direction_str: String, username: String, db: DB) -> WebR
         Ok(room) => room,
         Err(e) => return Err(reject::custom(e)),
     };
-    let direction: &Direction = match room
-        .neighbors
-        .iter()
-        .find(|&neighbor| neighbor.direction == direction_str)
-    {
-        Some(direction) => direction,
-        None => return Err(reject::custom(Error::NeighborNotFoundError)),
+    let entry_room: Room = match db.get_entry_room(&Some(room.game_id)).await {
+        Ok(Some(entry_room)) => entry_room,
+        Ok(None) => return Err(reject::custom(Error::NoEntryRoomError)),
+        Err(e) => return Err(reject::custom(e)),
     };
-    let riddle_id: bson::oid::ObjectId = match user
+    let solved: HashSet<ObjectId> = user
         .solved
         .iter()
-        .find(|&s| s.riddle_id == direction.riddle_id)
-    {
+        .map(|riddle_attempt| riddle_attempt.riddle_id)
+        .collect();
+    let path: Vec<String> = match db.shortest_path(&in_room, &entry_room.id, &solved).await {
+        Ok(path) => path,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&PathHomeResponse { ok: true, path }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn go_handler(direction_str: String, username: String, db: DB) -> WebResult<impl Reply> {
+    log::info!(
+        "go_handler(); direction = {}; username = {}",
+        &direction_str,
+        &username
+    );
+    let mut user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let in_room = match &user.in_room {
+        Some(in_room) => in_room,
+        None => return Err(reject::custom(Error::UserIsInNoRoom)),
+    };
+    let room: Room = match db.get_room(&in_room).await {
+        Ok(room) => room,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let direction: &Direction = match room
+        .neighbors
+        .iter()
+        .find(|&neighbor| neighbor.direction == direction_str)
+    {
+        Some(direction) => direction,
+        None => return Err(reject::custom(Error::NeighborNotFoundError)),
+    };
+    let riddle_id: bson::oid::ObjectId = match user
+        .solved
+        .iter()
+        .find(|&s| s.riddle_id == direction.riddle_id)
+    {
         Some(riddle_attempt) => riddle_attempt.riddle_id,
         None => return Err(reject::custom(Error::RiddleNotSolvedError)),
     };
-    let opposite: &String = &OPPOSITE[&direction.direction];
-    let room_behind: Room = match db.get_room_behind(&opposite, &riddle_id).await {
+    let opposite: &String = match OPPOSITE.get(&direction.direction) {
+        Some(opposite) => opposite,
+        None => return Err(reject::custom(Error::RoomBehindNotFoundError)),
+    };
+    let room_behind: Room = match db.get_room_behind(&room, &opposite, &riddle_id).await {
         Ok(room_behind) => room_behind,
         Err(e) => return Err(reject::custom(e)),
     };
@@ -619,97 +1449,462 @@ pub async fn go_handler(direction_str: String, username: String, db: DB) -> WebR
         &room_behind.id
     );
     user.in_room = Some(room_behind.id);
+    let finished: bool = room.exit.is_some() && room.exit.unwrap();
+    let finished_at: DateTime<Utc> = db.clock.now();
     // TODO: move all code accessing the database to db.rs
-    let update_doc: bson::Document = match room.exit.is_some() && room.exit.unwrap() {
-        true => doc! {
-            "$set": {
-                "in_room": user.in_room,
-            },
-            "$addToSet": { "rooms_entered": user.in_room },
-            "$addToSet": {
-                "finished": {
-                    "game_id": room.game_id,
-                    "timestamp": Utc::now().timestamp() as u32,
-                }
-            }
-        },
-        false => doc! {
-            "$set": {
-                "in_room": user.in_room,
-            },
-            "$addToSet": { "rooms_entered": user.in_room },
+    let update_doc: bson::Document = doc! {
+        "$set": {
+            "in_room": user.in_room,
         },
+        "$addToSet": { "rooms_entered": user.in_room },
     };
     match db
         .get_users_coll()
         .update_one(doc! { "_id": user.id, "activated": true }, update_doc, None)
         .await
     {
-        Ok(_) => {}
+        Ok(_) => db.invalidate_user(&user.username),
         Err(e) => return Ok(err_response(Some(e.to_string()))),
     };
+    if finished {
+        let first_visit: Option<RoomVisit> =
+            match db.get_first_room_visit(&user.id, &room.game_id).await {
+                Ok(visit) => visit,
+                Err(e) => return Ok(err_response(Some(e.to_string()))),
+            };
+        let starts_at: Option<DateTime<Utc>> = match db.get_game(&room.game_id).await {
+            Ok(game) => game.and_then(|game| game.starts_at),
+            Err(e) => return Ok(err_response(Some(e.to_string()))),
+        };
+        // time spent in the game, not counting any waiting for it to start
+        let elapsed: Option<i64> = first_visit.as_ref().map(|visit| {
+            let started_at: DateTime<Utc> = match starts_at {
+                Some(starts_at) => starts_at.max(visit.timestamp),
+                None => visit.timestamp,
+            };
+            finished_at.signed_duration_since(started_at).num_seconds()
+        });
+        // points earned since entering the game; older visits don't know
+        // the score, so the whole score counts then
+        let score: i32 = match first_visit.and_then(|visit| visit.score) {
+            Some(score_at_start) => user.score - score_at_start,
+            None => user.score,
+        };
+        match db
+            .record_game_finished(&user, &room.game_id, score, elapsed)
+            .await
+        {
+            Ok(()) => (),
+            Err(e) => return Ok(err_response(Some(e.to_string()))),
+        }
+        webhook::notify_game_completed(webhook::GameCompletedPayload {
+            username: user.username.clone(),
+            game_id: room.game_id,
+            score,
+            finished_at,
+        });
+    }
+    match db.record_room_visit(&user, &room_behind).await {
+        Ok(()) => (),
+        Err(e) => log::error!("Error: recording room visit failed: {}", &e),
+    }
     let in_room = match &user.in_room {
         Some(in_room) => in_room,
         None => return Err(reject::custom(Error::UserIsInNoRoom)),
     };
-    let room: Room = match db.get_room(&in_room).await {
+    let room: RoomResponse = match get_room_by_id(&in_room, &db).await {
         Ok(room) => room,
         Err(e) => return Ok(err_response(Some(e.to_string()))),
     };
     let reply: warp::reply::Json = warp::reply::json(&json!(&SteppedThroughResponse {
         ok: true,
         message: Option::default(),
-        room: RoomResponse {
-            ok: true,
-            message: Option::default(),
-            id: room.id,
-            number: room.number,
-            coords: room.coords,
-            entry: room.entry,
-            exit: room.exit,
-            game_id: room.game_id,
-            neighbors: room.neighbors,
-        },
+        room,
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
+/// Points deducted for a wrong answer to `riddle`: the riddle's own
+/// deduction if set, else the game's default, else `default_deduction`
+/// (`DEFAULT_DEDUCTION`).
+fn effective_deduction(riddle: &Riddle, game: &Option<Game>, default_deduction: i32) -> i32 {
+    riddle
+        .deduction
+        .or(game.as_ref().and_then(|game| game.default_deduction))
+        .unwrap_or(default_deduction)
+}
+
+/// The single policy for wrong answers: returns the riddle's effective
+/// deduction (see `effective_deduction()`), scaled by the game's score
+/// multiplier. Frozen games don't deduct anything. The score never drops
+/// below zero, see `DB::adjust_user_score()`.
+fn wrong_answer_deduction(riddle: &Riddle, game: &Option<Game>, default_deduction: i32) -> i32 {
+    let (scoring, score_multiplier) = match game {
+        Some(game) => (game.state.is_scoring(), game.score_multiplier),
+        None => (true, 1.0),
+    };
+    if !scoring {
+        return 0;
+    }
+    apply_score_multiplier(
+        effective_deduction(riddle, game, default_deduction),
+        score_multiplier,
+    )
+}
+
+/// Scales `points` by a game's score multiplier, rounding to the nearest
+/// integer.
+fn apply_score_multiplier(points: i32, multiplier: f32) -> i32 {
+    (points as f32 * multiplier).round() as i32
+}
+
+/// Points for solving a riddle of the given difficulty after `elapsed`.
+/// Without a time bonus window (`TIME_BONUS_WINDOW_SECS`) this is the
+/// difficulty. With it, a bonus is added that decays linearly from the
+/// difficulty for an immediate solve down to zero at the end of the window:
+///
+/// ```text
+/// points = difficulty + difficulty * (1 - min(1, elapsed / window))
+/// ```
+///
+/// so solves never earn less than the difficulty and at most twice as
+/// much. Negative durations, i.e. a `t0` in the future due to clock skew,
+/// count as zero.
+fn score_for_attempt(
+    difficulty: i32,
+    elapsed: chrono::Duration,
+    time_bonus_window: Option<i64>,
+) -> u32 {
+    let difficulty: u32 = difficulty.max(0) as u32;
+    let window: i64 = match time_bonus_window {
+        Some(window) if window > 0 => window,
+        _ => return difficulty,
+    };
+    // share of the window used up, from 0 for an immediate solve to 1
+    let progress: f64 = (elapsed.num_seconds() as f64 / window as f64).clamp(0.0, 1.0);
+    let bonus: u32 = (difficulty as f64 * (1.0 - progress)).floor() as u32;
+    difficulty + bonus
+}
+
 pub async fn riddle_solve_handler(
     riddle_id_str: OidString,
     body: RiddleSolveRequest,
     username: String,
     mut db: DB,
     script_env: Arc<Mutex<ScriptEnvMap>>,
+    config: Arc<Config>,
 ) -> WebResult<impl Reply> {
     let solution = url_escape::decode(&body.solution).into_owned();
     log::info!(
-        "riddle_solve_handler(); riddle_id = {}, solution = {}",
+        "riddle_solve_handler(); riddle_id = {}, username = {}",
         &riddle_id_str,
-        &solution
+        &username
+    );
+    let response: RiddleSolvedResponse = match solve_riddle(
+        riddle_id_str,
+        solution,
+        &username,
+        &mut db,
+        script_env,
+        &config,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&response));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Number of batch-submitted solutions remembered per user for
+/// deduplication.
+const PROCESSED_SOLVES_KEPT: i32 = 200;
+
+/// Solves the items one by one, each exactly as `riddle_solve_handler`
+/// would, so that one failing item doesn't undo the others. Items for a
+/// riddle that is solved already or answered earlier in the batch are
+/// reported as duplicates without being booked.
+pub async fn riddle_solve_batch_handler(
+    body: Vec<BatchSolveItem>,
+    username: String,
+    mut db: DB,
+    script_env: Arc<Mutex<ScriptEnvMap>>,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_solve_batch_handler(); username = {}, items = {}",
+        &username,
+        body.len()
     );
+    if body.len() > config.max_solve_batch_size {
+        return Err(reject::custom(Error::SolveBatchTooLargeError(
+            config.max_solve_batch_size,
+        )));
+    }
+    // riddles solved so far, and riddles answered in this batch: each
+    // riddle is booked at most once per batch, and not at all once solved,
+    // whatever the idempotency keys
+    let mut solved_riddles: HashSet<ObjectId> = match db.get_user(&username).await {
+        Ok(user) => user.solved.iter().map(|s| s.riddle_id).collect(),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let mut answered_riddles: HashSet<ObjectId> = HashSet::new();
+    let mut results: Vec<BatchSolveResult> = Vec::with_capacity(body.len());
+    for item in body {
+        let riddle_id: ObjectId = match ObjectId::parse_str(&item.riddle_id) {
+            Ok(oid) => oid,
+            Err(e) => {
+                results.push(BatchSolveResult {
+                    idempotency_key: item.idempotency_key,
+                    riddle_id: item.riddle_id,
+                    ok: false,
+                    solved: false,
+                    duplicate: false,
+                    message: Some(Error::BsonOidError(e).to_string()),
+                    feedback: Option::default(),
+                });
+                continue;
+            }
+        };
+        if solved_riddles.contains(&riddle_id) || !answered_riddles.insert(riddle_id) {
+            results.push(BatchSolveResult {
+                idempotency_key: item.idempotency_key,
+                riddle_id: item.riddle_id,
+                ok: true,
+                solved: solved_riddles.contains(&riddle_id),
+                duplicate: true,
+                message: Option::default(),
+                feedback: Option::default(),
+            });
+            continue;
+        }
+        // claimed before booking, so that a concurrent resubmission of the
+        // same item finds it
+        match db
+            .claim_processed_solve(
+                &username,
+                &riddle_id,
+                &item.idempotency_key,
+                PROCESSED_SOLVES_KEPT,
+            )
+            .await
+        {
+            Ok(true) => (),
+            Ok(false) => {
+                let processed: Option<ProcessedSolve> = match db.get_user(&username).await {
+                    Ok(user) => user.processed_solves.into_iter().find(|processed| {
+                        processed.idempotency_key == item.idempotency_key
+                            && processed.riddle_id == riddle_id
+                    }),
+                    Err(e) => return Err(reject::custom(e)),
+                };
+                results.push(BatchSolveResult {
+                    idempotency_key: item.idempotency_key,
+                    riddle_id: item.riddle_id,
+                    ok: true,
+                    solved: processed.map_or(false, |processed| processed.solved),
+                    duplicate: true,
+                    message: Option::default(),
+                    feedback: Option::default(),
+                });
+                continue;
+            }
+            Err(e) => return Err(reject::custom(e)),
+        }
+        let solution = url_escape::decode(&item.solution).into_owned();
+        match solve_riddle(
+            item.riddle_id.clone(),
+            solution,
+            &username,
+            &mut db,
+            script_env.clone(),
+            &config,
+        )
+        .await
+        {
+            Ok(response) => {
+                if let Err(e) = db
+                    .settle_processed_solve(
+                        &username,
+                        &riddle_id,
+                        &item.idempotency_key,
+                        response.solved,
+                    )
+                    .await
+                {
+                    log::error!("Error: recording processed solve failed: {}", &e);
+                }
+                if response.solved {
+                    solved_riddles.insert(riddle_id);
+                }
+                results.push(BatchSolveResult {
+                    idempotency_key: item.idempotency_key,
+                    riddle_id: item.riddle_id,
+                    ok: true,
+                    solved: response.solved,
+                    duplicate: false,
+                    message: response.message,
+                    feedback: response.feedback,
+                });
+            }
+            Err(e) => {
+                if let Err(e) = db
+                    .release_processed_solve(&username, &riddle_id, &item.idempotency_key)
+                    .await
+                {
+                    log::error!("Error: releasing processed solve failed: {}", &e);
+                }
+                results.push(BatchSolveResult {
+                    idempotency_key: item.idempotency_key,
+                    riddle_id: item.riddle_id,
+                    ok: false,
+                    solved: false,
+                    duplicate: false,
+                    message: Some(e.to_string()),
+                    feedback: Option::default(),
+                });
+            }
+        }
+    }
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&BatchSolveResponse {
+        ok: true,
+        score: user.score,
+        level: user.level,
+        results,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Checks `solution` against the riddle and books the outcome for the
+/// user. Shared by the single and the batch solve handlers.
+async fn solve_riddle(
+    riddle_id_str: OidString,
+    solution: String,
+    username: &String,
+    db: &mut DB,
+    script_env: Arc<Mutex<ScriptEnvMap>>,
+    config: &Config,
+) -> Result<RiddleSolvedResponse> {
     let oid: bson::oid::ObjectId = match ObjectId::parse_str(riddle_id_str) {
         Ok(oid) => oid,
-        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+        Err(e) => return Err(Error::BsonOidError(e)),
     };
-    let (riddle_id, user, _msg) = db.riddle_accessibility(&oid, &username).await;
+    let (riddle_id, user, _msg) = db.riddle_accessibility(&oid, username).await;
     let riddle_id = match riddle_id {
         Some(in_room) => in_room,
-        None => return Err(reject::custom(Error::RiddleNotFoundError)),
+        None => return Err(Error::RiddleNotFoundError),
     };
     let riddle: Option<Riddle> = match db.get_riddle_by_oid(&riddle_id).await {
         Ok(riddle) => riddle,
-        Err(e) => return Err(reject::custom(e)),
+        Err(e) => return Err(e),
     };
     let riddle: Riddle = match riddle {
         Some(riddle) => riddle,
-        None => return Err(reject::custom(Error::RiddleNotFoundError)),
+        None => return Err(Error::RiddleNotFoundError),
+    };
+    // Reject oversized answers before they reach the script or any
+    // comparison.
+    let max_solution_length: usize = riddle
+        .max_solution_length
+        .unwrap_or(config.max_solution_length);
+    if solution.chars().count() > max_solution_length {
+        return Err(Error::SolutionTooLongError(max_solution_length));
+    }
+    let mut user: User = match user {
+        Some(user) => user,
+        None => return Err(Error::UserNotFoundError),
+    };
+    let game: Option<Game> = match user.in_room {
+        Some(ref room_id) => match db.get_game_of_room(room_id).await {
+            Ok(game) => game,
+            Err(e) => return Err(e),
+        },
+        None => Option::default(),
+    };
+    if user.role != Role::Admin {
+        match check_event_window(config, &game, db.clock.now()) {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    if config.strict_riddle_room {
+        if let Some(ref attempt) = user.current_riddle_attempt {
+            if attempt.riddle_id == riddle.id
+                && attempt.room_id.is_some()
+                && attempt.room_id != user.in_room
+            {
+                return Err(Error::RiddleOpenedElsewhereError);
+            }
+        }
+    }
+    let cooldown_left: i64 = solve_cooldown_left(
+        &user,
+        &riddle.id,
+        db.clock.now(),
+        config.solve_cooldown_secs,
+    );
+    if cooldown_left > 0 {
+        return Err(Error::SolveCooldownError(cooldown_left as u64));
+    }
+    let already_solved: bool = user.solved.iter().any(|s| s.riddle_id == riddle.id);
+    if attempts_left(&user, &riddle) == Some(0) && !already_solved {
+        return Err(Error::AttemptsExhaustedError);
+    }
+    let score_multiplier: f32 = match game {
+        Some(ref game) => game.score_multiplier,
+        None => 1.0,
+    };
+    let level_gating: bool = config.level_gating || game.as_ref().map_or(false, |g| g.level_gating);
+    let scoring: bool = game.as_ref().map_or(true, |g| g.state.is_scoring());
+    if level_gating && riddle.level > user.level.saturating_add(1) {
+        return Err(Error::LevelLockedError);
+    }
+    // counted as wrong until found right
+    let now: DateTime<Utc> = db.clock.now();
+    let max_attempts: Option<u32> = match already_solved {
+        true => None,
+        false => riddle.max_attempts,
+    };
+    let before: User = match db
+        .reserve_attempt(
+            &user,
+            &riddle.id,
+            config.solve_cooldown_secs,
+            max_attempts,
+            now,
+        )
+        .await
+    {
+        Ok(Some(before)) => before,
+        // another answer has been counted in the meantime
+        Ok(None) => {
+            return Err(match db.get_user(username).await {
+                Ok(current)
+                    if max_attempts.is_some() && attempts_left(&current, &riddle) == Some(0) =>
+                {
+                    Error::AttemptsExhaustedError
+                }
+                Ok(current) => Error::SolveCooldownError(
+                    solve_cooldown_left(&current, &riddle.id, now, config.solve_cooldown_secs)
+                        .max(1) as u64,
+                ),
+                Err(e) => e,
+            })
+        }
+        Err(e) => return Err(e),
     };
-    let script_env_present = script_env.lock().unwrap().contains_key(&username);
+    let attempts_left: Option<u32> = attempts_left(&before, &riddle);
+    let script_env_present = script_env.lock().unwrap().contains_key(username);
     let (calculated_solution, feedback) = match script_env_present && riddle.script.is_some() {
         true => {
             let result: ScriptResult = evaluate_script(
-                &username,
-                &riddle.script.unwrap(),
+                username,
+                riddle.script.as_ref().unwrap(),
                 Some(solution.clone()),
                 script_env,
                 false,
@@ -718,72 +1913,208 @@ pub async fn riddle_solve_handler(
         }
         false => (Some(riddle.solution.clone()), Option::default()),
     };
-    let solved: bool = match riddle.ignore_case.unwrap_or(false) {
-        true => calculated_solution.unwrap_or_default().to_lowercase() == solution.to_lowercase(),
-        false => calculated_solution.unwrap_or_default() == solution,
-    };
-    let mut user: User = match user {
-        Some(user) => user,
-        None => return Err(reject::custom(Error::UserNotFoundError)),
+    let calculated_solution: String = calculated_solution.unwrap_or_default();
+    let ignore_case: bool = riddle.ignores_case();
+    let solved: bool =
+        normalize_answer(&calculated_solution, ignore_case, &riddle.comparison_locale)
+            == normalize_answer(&solution, ignore_case, &riddle.comparison_locale);
+    let message: Option<String> = match !solved
+        && is_close_answer(
+            &calculated_solution,
+            &solution,
+            ignore_case,
+            &riddle.comparison_locale,
+            riddle.close_answer_threshold,
+        ) {
+        true => Some("very close".to_string()),
+        false => Option::default(),
     };
     if solved {
-        let mut solutions: Vec<RiddleAttempt> = user.solved.clone();
+        let previous: Option<&WrongAnswer> = before
+            .last_wrong_answers
+            .iter()
+            .find(|wrong_answer| wrong_answer.riddle_id == riddle.id);
+        match db.release_attempt(&user, &riddle.id, now, previous).await {
+            Ok(()) => (),
+            Err(e) => return Err(e),
+        }
         let riddle_attempt = match user.current_riddle_attempt {
             Some(ref riddle_attempt) => riddle_attempt,
-            None => return Err(reject::custom(Error::RiddleHasNotBeenSeenByUser)),
+            None => return Err(Error::RiddleHasNotBeenSeenByUser),
         };
         if riddle_attempt.t0.is_none() {
-            return Err(reject::custom(Error::RiddleHasNotBeenSeenByUser));
+            return Err(Error::RiddleHasNotBeenSeenByUser);
         }
         let t0 = riddle_attempt
             .t0
             .unwrap_or(Utc.ymd(1970, 1, 1).and_hms(0, 0, 0));
-        solutions.push(RiddleAttempt {
+        let elapsed: chrono::Duration = db.clock.now().signed_duration_since(t0);
+        let attempt = RiddleAttempt {
             riddle_id: riddle.id,
             t0: riddle_attempt.t0,
-            dt: Some(Utc::now().signed_duration_since(t0).num_seconds()),
-        });
-        user.level = riddle.level.max(user.level);
-        user.score += riddle.difficulty;
-        match db.set_user_solved(&solutions, &user).await {
-            Ok(()) => {
+            dt: Some(elapsed.num_seconds()),
+            failures: riddle_attempt.failures,
+            revealed: false,
+            room_id: riddle_attempt.room_id,
+        };
+        if riddle.level > config.max_riddle_level {
+            log::warn!(
+                "riddle {} has level {} beyond maximum {}, clamping",
+                &riddle.id,
+                riddle.level,
+                config.max_riddle_level
+            );
+        }
+        user.level = riddle.level.min(config.max_riddle_level).max(user.level);
+        // solving a riddle again doesn't score
+        let points: i32 = match scoring && !already_solved {
+            true => apply_score_multiplier(
+                score_for_attempt(riddle.difficulty, elapsed, config.time_bonus_window_secs) as i32,
+                score_multiplier,
+            ),
+            false => 0,
+        };
+        match db.set_user_solved(&attempt, &user, points).await {
+            Ok(score) => {
+                user.score = score;
                 log::info!("User {} updated.", &username);
             }
             Err(e) => {
                 log::error!("Error: update failed: {}", &e);
-                return Err(reject::custom(Error::RiddleNotSolvedError));
+                return Err(Error::RiddleNotSolvedError);
             }
         }
     } else {
-        let deduction = riddle.deduction.unwrap_or(0);
-        user.score = 0.max(user.score - deduction);
-        match db.rewrite_user_score(&user).await {
-            Ok(()) => {
+        let mut deduction: i32 = wrong_answer_deduction(&riddle, &game, config.default_deduction);
+        if attempts_left == Some(1) && scoring {
+            deduction +=
+                apply_score_multiplier(riddle.exhaustion_penalty.unwrap_or(0), score_multiplier);
+        }
+        match db.add_failed_attempt(&user, &riddle.id).await {
+            Ok(()) => (),
+            Err(e) => log::error!("Error: counting failed attempt failed: {}", &e),
+        }
+        // applied as a difference, so that concurrent solves don't
+        // overwrite each other's score changes
+        match db.adjust_user_score(username, -deduction, None).await {
+            Ok((_, score)) => {
+                user.score = score;
                 log::info!("User updated.");
             }
             Err(e) => {
                 log::error!("Error: update failed: {}", &e);
-                return Err(reject::custom(Error::RiddleNotSolvedError));
+                return Err(Error::RiddleNotSolvedError);
             }
         }
     }
-    let reply: warp::reply::Json = warp::reply::json(&json!(&RiddleSolvedResponse {
+    Ok(RiddleSolvedResponse {
         ok: true,
         riddle_id: riddle.id,
         solved,
         score: user.score,
         level: riddle.level,
-        message: Option::default(),
+        message,
         feedback,
-    }));
-    Ok(warp::reply::with_status(reply, StatusCode::OK))
+        attempts_left: match solved {
+            true => attempts_left,
+            false => attempts_left.map(|n| n.saturating_sub(1)),
+        },
+    })
+}
+
+/// Rejects solving outside the event window. A game's own window takes
+/// precedence over `EVENT_START` and `EVENT_END`.
+fn check_event_window(config: &Config, game: &Option<Game>, now: DateTime<Utc>) -> Result<()> {
+    let starts_at: Option<DateTime<Utc>> = game
+        .as_ref()
+        .and_then(|game| game.starts_at)
+        .or(config.event_start);
+    let ends_at: Option<DateTime<Utc>> = game
+        .as_ref()
+        .and_then(|game| game.ends_at)
+        .or(config.event_end);
+    if let Some(starts_at) = starts_at {
+        if now < starts_at {
+            return Err(Error::EventNotStartedError(starts_at));
+        }
+    }
+    if let Some(ends_at) = ends_at {
+        if now >= ends_at {
+            return Err(Error::EventEndedError(ends_at));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of wrong answers `user` may still give to `riddle`,
+/// or `None` if the riddle doesn't limit them.
+fn attempts_left(user: &User, riddle: &Riddle) -> Option<u32> {
+    let wrong_answers: u32 = user
+        .last_wrong_answers
+        .iter()
+        .find(|wrong_answer| wrong_answer.riddle_id == riddle.id)
+        .map_or(0, |wrong_answer| wrong_answer.count);
+    riddle
+        .max_attempts
+        .map(|max_attempts| max_attempts.saturating_sub(wrong_answers))
+}
+
+/// Returns the number of seconds `user` still has to wait before
+/// answering `riddle_id` again after a wrong answer, given that the
+/// cooldown lasts `cooldown_secs`, or 0 if there's no cooldown.
+fn solve_cooldown_left(
+    user: &User,
+    riddle_id: &ObjectId,
+    now: DateTime<Utc>,
+    cooldown_secs: i64,
+) -> i64 {
+    match user
+        .last_wrong_answers
+        .iter()
+        .find(|wrong_answer| &wrong_answer.riddle_id == riddle_id)
+    {
+        Some(wrong_answer) => {
+            let passed: i64 = now.signed_duration_since(wrong_answer.at).num_seconds();
+            (cooldown_secs - passed).max(0)
+        }
+        None => 0,
+    }
+}
+
+/// Formats `dt` as an HTTP date, e.g. "Wed, 21 Oct 2015 07:28:00 GMT".
+fn http_date(dt: &DateTime<Utc>) -> String {
+    dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Tells whether something last changed at `last_modified` has changed
+/// since the client's `If-Modified-Since` date. Missing or unparsable
+/// dates count as modified.
+fn is_modified_since(if_modified_since: &Option<String>, last_modified: &DateTime<Utc>) -> bool {
+    let since: DateTime<Utc> = match if_modified_since
+        .as_ref()
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+    {
+        Some(since) => since.with_timezone(&Utc),
+        None => return true,
+    };
+    last_modified.timestamp() > since.timestamp()
+}
+
+fn with_last_modified(mut response: Response, last_modified: &DateTime<Utc>) -> Response {
+    if let Ok(value) = HeaderValue::from_str(&http_date(last_modified)) {
+        response.headers_mut().insert(LAST_MODIFIED, value);
+    }
+    response
 }
 
 pub async fn riddle_get_oid_handler(
     riddle_id_str: String,
     username: String,
+    if_modified_since: Option<String>,
+    query: RiddleGetQuery,
     db: DB,
     script_env: Arc<Mutex<ScriptEnvMap>>,
+    config: Arc<Config>,
 ) -> WebResult<impl Reply> {
     log::info!("riddle_get_oid_handler(); riddle_id = {}", &riddle_id_str);
     let oid = match ObjectId::parse_str(riddle_id_str) {
@@ -793,7 +2124,7 @@ pub async fn riddle_get_oid_handler(
     let (riddle_id, user, message) = db.riddle_accessibility(&oid, &username).await;
     let riddle_id: bson::oid::ObjectId = match riddle_id {
         Some(riddle_id) => riddle_id,
-        None => return Ok(err_response(message)),
+        None => return Ok(err_response(message).into_response()),
     };
     let riddle: Option<Riddle> = match db.get_riddle_by_oid(&riddle_id).await {
         Ok(riddle) => riddle,
@@ -807,10 +2138,30 @@ pub async fn riddle_get_oid_handler(
         Some(user) => user,
         None => return Err(reject::custom(Error::UserNotAssociatedWithRiddle)),
     };
+    let game: Option<Game> = match user.in_room {
+        Some(ref room_id) => match db.get_game_of_room(room_id).await {
+            Ok(game) => game,
+            Err(e) => return Err(reject::custom(e)),
+        },
+        None => Option::default(),
+    };
+    let deduction: i32 = effective_deduction(&riddle, &game, config.default_deduction);
+    // Keep the time the riddle was first opened, so that fetching it again
+    // neither resets the solve timer nor hides that it's in progress.
+    let (t0, failures): (Option<DateTime<Utc>>, u32) = match user.current_riddle_attempt {
+        Some(ref attempt) if attempt.riddle_id == riddle_id => (attempt.t0, attempt.failures),
+        _ => (Option::default(), 0),
+    };
+    let opened: bool = t0.is_some() && !user.solved.iter().any(|s| s.riddle_id == riddle_id);
+    let attempts_left: Option<u32> = attempts_left(&user, &riddle);
+    let t0: Option<DateTime<Utc>> = Some(t0.unwrap_or(db.clock.now()));
     let riddle_attempt = RiddleAttempt {
         riddle_id,
-        t0: Some(Utc::now()),
+        t0,
         dt: Option::default(),
+        failures,
+        revealed: false,
+        room_id: user.in_room,
     };
     user.current_riddle_attempt = Some(riddle_attempt);
     match db
@@ -828,12 +2179,22 @@ pub async fn riddle_get_oid_handler(
     {
         Ok(_) => {
             log::info!("Updated current_riddle_attempt of user '{}'.", &username);
+            db.invalidate_user(&username);
         }
         Err(e) => {
             log::error!("Error: update failed ({:?})", &e);
             return Err(reject::custom(Error::MongoQueryError(e)));
         }
     }
+    // The attempt has been recorded above, so a 304 doesn't skip it. Note
+    // that `opened` in the client's cached copy may be stale then.
+    let last_modified: DateTime<Utc> = riddle.last_modified().max(t0.unwrap());
+    if riddle.script.is_none() && !is_modified_since(&if_modified_since, &last_modified) {
+        return Ok(with_last_modified(
+            StatusCode::NOT_MODIFIED.into_response(),
+            &last_modified,
+        ));
+    }
     let mut found_files: Vec<FileResponse> = Vec::new();
     if let Some(ref script) = riddle.script {
         let result: ScriptResult = evaluate_script(&username, script, None, script_env, true);
@@ -863,13 +2224,22 @@ pub async fn riddle_get_oid_handler(
                     });
                 }
             }
+            let data: Vec<u8> = match config.upload_dir {
+                Some(ref dir) if query.inline_files => {
+                    match upload::read_file(dir, &file.uploaded_name).await {
+                        Ok(data) => data,
+                        Err(e) => return Err(reject::custom(e)),
+                    }
+                }
+                _ => Vec::new(),
+            };
             found_files.push(FileResponse {
                 ok: true,
                 message: Option::default(),
                 original_name: Some(file.original_name.clone()),
                 uploaded_name: Some(file.uploaded_name.clone()),
                 mime_type: file.mime_type.clone(),
-                data: Vec::new(),
+                data,
                 scale: file.scale,
                 width: file.width,
                 height: file.height,
@@ -883,37 +2253,180 @@ pub async fn riddle_get_oid_handler(
         id: riddle.id,
         level: riddle.level,
         difficulty: riddle.difficulty,
-        deduction: riddle.deduction.unwrap_or(0),
-        ignore_case: riddle.ignore_case.unwrap_or(false),
+        deduction,
+        ignore_case: riddle.ignores_case(),
         files: Option::from(found_files),
         task: riddle.task,
         credits: riddle.credits,
+        num_hints: riddle.hints.as_ref().map_or(0, |hints| hints.len()),
+        opened,
+        attempts_left,
+        t0,
     }));
-    Ok(warp::reply::with_status(reply, StatusCode::OK))
+    Ok(with_last_modified(
+        warp::reply::with_status(reply, StatusCode::OK).into_response(),
+        &last_modified,
+    ))
 }
 
-// This function is needed for manual debugging.
-pub async fn riddle_get_by_level_handler(
-    level: u32,
+fn upload_url(config: &Config, uploaded_name: &String) -> String {
+    format!(
+        "{}/{}",
+        config.upload_url,
+        url_escape::encode_component(uploaded_name)
+    )
+}
+
+/// Lists the files of a riddle with the URLs to download them from, but
+/// without their contents. Unlike fetching the riddle, this doesn't open it.
+pub async fn riddle_files_handler(
+    riddle_id_str: String,
     username: String,
     db: DB,
-    script_env: Arc<Mutex<ScriptEnvMap>>,
+    config: Arc<Config>,
 ) -> WebResult<impl Reply> {
-    log::info!("riddle_get_by_level_handler(); level = {}", level);
-    let riddle: Option<Riddle> = match db.get_riddle_by_level(level).await {
-        Ok(riddle) => riddle,
-        Err(e) => return Err(reject::custom(e)),
+    log::info!("riddle_files_handler(); riddle_id = {}", &riddle_id_str);
+    let oid = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
     };
-    let riddle: Riddle = match riddle {
-        Some(riddle) => riddle,
-        None => return Err(reject::custom(Error::RiddleNotFoundError)),
+    let (riddle_id, _user, message) = db.riddle_accessibility(&oid, &username).await;
+    let riddle_id: bson::oid::ObjectId = match riddle_id {
+        Some(riddle_id) => riddle_id,
+        None => return Ok(err_response(message).into_response()),
     };
-    log::info!("got riddle w/ level = {}", riddle.level);
-    let mut found_files: Vec<FileResponse> = Vec::new();
-    if let Some(files) = riddle.files {
-        for file in files.iter() {
-            log::info!("trying to load file {:?}", &file);
-            let mut file_variants: Vec<FileVariantResponse> = Vec::new();
+    let riddle: Riddle = match db.get_riddle_by_oid(&riddle_id).await {
+        Ok(Some(riddle)) => riddle,
+        Ok(None) => return Err(reject::custom(Error::RiddleNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let files: Vec<FileMetadataResponse> = riddle
+        .files
+        .unwrap_or_default()
+        .iter()
+        .map(|file| FileMetadataResponse {
+            original_name: file.original_name.clone(),
+            uploaded_name: file.uploaded_name.clone(),
+            mime_type: file.mime_type.clone(),
+            width: file.width,
+            height: file.height,
+            scale: file.scale,
+            url: upload_url(&config, &file.uploaded_name),
+            variants: file
+                .variants
+                .iter()
+                .flatten()
+                .map(|variant| FileVariantMetadataResponse {
+                    original_name: variant.original_name.clone(),
+                    uploaded_name: variant.uploaded_name.clone(),
+                    scale: variant.scale,
+                    url: upload_url(&config, &variant.uploaded_name),
+                })
+                .collect(),
+        })
+        .collect();
+    let reply: warp::reply::Json = warp::reply::json(&json!(&RiddleFilesResponse {
+        ok: true,
+        message: Option::default(),
+        id: riddle.id,
+        files,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK).into_response())
+}
+
+/// Streams a file of the riddle, or one of its variants or retina
+/// versions, from `UPLOAD_DIR`. Like the file list, it's available to
+/// users who may access the riddle.
+pub async fn riddle_file_get_handler(
+    riddle_id_str: String,
+    uploaded_name: String,
+    username: String,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_file_get_handler(); riddle_id = {}, uploaded_name = {}",
+        &riddle_id_str,
+        &uploaded_name
+    );
+    let upload_dir: &String = match config.upload_dir {
+        Some(ref upload_dir) => upload_dir,
+        None => return Err(reject::custom(Error::UploadsDisabledError)),
+    };
+    let oid = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    let (riddle_id, _user, message) = db.riddle_accessibility(&oid, &username).await;
+    let riddle_id: bson::oid::ObjectId = match riddle_id {
+        Some(riddle_id) => riddle_id,
+        None => return Ok(err_response(message).into_response()),
+    };
+    let riddle: Riddle = match db.get_riddle_by_oid(&riddle_id).await {
+        Ok(Some(riddle)) => riddle,
+        Ok(None) => return Err(reject::custom(Error::RiddleNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let attached: bool = riddle.files.unwrap_or_default().iter().any(|file| {
+        file.uploaded_name == uploaded_name
+            || file.retina.as_ref() == Some(&uploaded_name)
+            || file
+                .variants
+                .iter()
+                .flatten()
+                .any(|variant| variant.uploaded_name == uploaded_name)
+    });
+    if !attached {
+        return Err(reject::custom(Error::RiddleFileNotFoundError(
+            uploaded_name,
+        )));
+    }
+    // by the name served rather than the stored `mimeType`, which variants
+    // don't have and which older uploads took from the client
+    let mime_type: &'static str =
+        upload::mime_type_of(&uploaded_name).unwrap_or("application/octet-stream");
+    let (body, len): (hyper::Body, u64) = match upload::open_file(upload_dir, &uploaded_name).await
+    {
+        Ok(file) => file,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let mut response: Response = Response::new(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_LENGTH, HeaderValue::from(len));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(mime_type));
+    response
+        .headers_mut()
+        .insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+// This function is needed for manual debugging.
+pub async fn riddle_get_by_level_handler(
+    level: u32,
+    username: String,
+    db: DB,
+    script_env: Arc<Mutex<ScriptEnvMap>>,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!("riddle_get_by_level_handler(); level = {}", level);
+    let riddle: Option<Riddle> = match db.get_riddle_by_level(level).await {
+        Ok(riddle) => riddle,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let riddle: Riddle = match riddle {
+        Some(riddle) => riddle,
+        None => return Err(reject::custom(Error::RiddleNotFoundError)),
+    };
+    log::info!("got riddle w/ level = {}", riddle.level);
+    let deduction: i32 = effective_deduction(&riddle, &None, config.default_deduction);
+    let mut found_files: Vec<FileResponse> = Vec::new();
+    if let Some(files) = riddle.files {
+        for file in files.iter() {
+            log::info!("trying to load file {:?}", &file);
+            let mut file_variants: Vec<FileVariantResponse> = Vec::new();
             if let Some(variants) = &file.variants {
                 for variant in variants {
                     file_variants.push(FileVariantResponse {
@@ -1032,11 +2545,15 @@ pub async fn riddle_get_by_level_handler(
         id: riddle.id,
         level: riddle.level,
         difficulty: riddle.difficulty,
-        deduction: riddle.deduction.unwrap_or(0),
-        ignore_case: riddle.ignore_case.unwrap_or(false),
+        deduction,
+        ignore_case: riddle.ignores_case(),
         files: Option::from(found_files),
         task: riddle.task,
         credits: riddle.credits,
+        num_hints: riddle.hints.as_ref().map_or(0, |hints| hints.len()),
+        opened: false,
+        attempts_left: riddle.max_attempts,
+        t0: Option::default(),
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
@@ -1045,6 +2562,7 @@ pub async fn debriefing_get_by_riddle_id_handler(
     riddle_id_str: String,
     username: String,
     db: DB,
+    config: Arc<Config>,
 ) -> WebResult<impl Reply> {
     log::info!(
         "debriefing_get_by_riddle_id_handler(); riddle_id = {}, username = {}",
@@ -1064,10 +2582,274 @@ pub async fn debriefing_get_by_riddle_id_handler(
         None => return Err(reject::custom(Error::RiddleNotFoundError)),
     };
     log::info!("got riddle {}", riddle.level);
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let ranking: SolveRanking = match db.get_solve_ranking(&riddle.id, &user.id).await {
+        Ok(ranking) => ranking,
+        Err(e) => return Err(reject::custom(e)),
+    };
     let reply: warp::reply::Json = warp::reply::json(&json!(&DebriefingResponse {
         ok: true,
         message: Option::default(),
         debriefing: riddle.debriefing,
+        solve_time: ranking.solve_time,
+        rank: ranking.rank,
+        num_solvers: ranking.num_solvers,
+        first_blood: match config.public_first_blood {
+            true => ranking.first_blood,
+            false => None,
+        },
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Reveals the solution of a riddle once the user has given enough wrong
+/// answers (see `Riddle::reveal_after_failures`). The riddle then counts as
+/// solved, but marked as revealed, and the user loses `REVEAL_PENALTY_FACTOR`
+/// times its difficulty. Solutions computed by scripts cannot be revealed.
+pub async fn riddle_solution_handler(
+    riddle_id_str: OidString,
+    username: String,
+    mut db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_solution_handler(); riddle_id = {}, username = {}",
+        &riddle_id_str,
+        &username
+    );
+    let oid: bson::oid::ObjectId = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    let (riddle_id, user, _msg) = db.riddle_accessibility(&oid, &username).await;
+    let riddle_id: bson::oid::ObjectId = match riddle_id {
+        Some(riddle_id) => riddle_id,
+        None => return Err(reject::custom(Error::RiddleNotFoundError)),
+    };
+    let riddle: Riddle = match db.get_riddle_by_oid(&riddle_id).await {
+        Ok(Some(riddle)) => riddle,
+        Ok(None) => return Err(reject::custom(Error::RiddleNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let mut user: User = match user {
+        Some(user) => user,
+        None => return Err(reject::custom(Error::UserNotFoundError)),
+    };
+    let mut penalty: i32 = 0;
+    if !user.solved.iter().any(|s| s.riddle_id == riddle.id) {
+        let riddle_attempt: RiddleAttempt = match user.current_riddle_attempt {
+            Some(ref attempt) if attempt.riddle_id == riddle.id => attempt.clone(),
+            _ => return Err(reject::custom(Error::RiddleHasNotBeenSeenByUser)),
+        };
+        let revealable: bool = riddle.script.is_none()
+            && riddle
+                .reveal_after_failures
+                .map_or(false, |n| riddle_attempt.failures >= n);
+        if !revealable {
+            return Err(reject::custom(Error::SolutionNotRevealableError));
+        }
+        // revealing counts as solving, which the attempt limit rules out
+        if attempts_left(&user, &riddle) == Some(0) {
+            return Err(reject::custom(Error::AttemptsExhaustedError));
+        }
+        let game: Option<Game> = match user.in_room {
+            Some(ref room_id) => match db.get_game_of_room(room_id).await {
+                Ok(game) => game,
+                Err(e) => return Err(reject::custom(e)),
+            },
+            None => Option::default(),
+        };
+        let (scoring, score_multiplier) = match game {
+            Some(ref game) => (game.state.is_scoring(), game.score_multiplier),
+            None => (true, 1.0),
+        };
+        if scoring {
+            penalty = apply_score_multiplier(
+                riddle.difficulty * config.reveal_penalty_factor,
+                score_multiplier,
+            );
+        }
+        user.level = riddle.level.min(config.max_riddle_level).max(user.level);
+        let attempt = RiddleAttempt {
+            riddle_id: riddle.id,
+            t0: riddle_attempt.t0,
+            dt: riddle_attempt
+                .t0
+                .map(|t0| db.clock.now().signed_duration_since(t0).num_seconds()),
+            failures: riddle_attempt.failures,
+            revealed: true,
+            room_id: riddle_attempt.room_id,
+        };
+        user.score = match db.set_user_solved(&attempt, &user, -penalty).await {
+            Ok(score) => score,
+            Err(e) => return Err(reject::custom(e)),
+        };
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&RiddleSolutionResponse {
+        ok: true,
+        message: Option::default(),
+        riddle_id: riddle.id,
+        solution: riddle.solution,
+        penalty,
+        score: user.score,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Reveals hint `index` of a riddle the user can see from their room.
+/// The first request for each hint costs `HINT_PENALTY` points, scaled
+/// by the game's score multiplier; frozen games don't charge.
+pub async fn riddle_hint_handler(
+    riddle_id_str: OidString,
+    index: u32,
+    username: String,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_hint_handler(); riddle_id = {}, index = {}, username = {}",
+        &riddle_id_str,
+        index,
+        &username
+    );
+    let oid: bson::oid::ObjectId = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    let (riddle_id, user, _msg) = db.riddle_accessibility(&oid, &username).await;
+    let riddle_id: bson::oid::ObjectId = match riddle_id {
+        Some(riddle_id) => riddle_id,
+        None => return Err(reject::custom(Error::RiddleNotFoundError)),
+    };
+    let riddle: Riddle = match db.get_riddle_by_oid(&riddle_id).await {
+        Ok(Some(riddle)) => riddle,
+        Ok(None) => return Err(reject::custom(Error::RiddleNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let user: User = match user {
+        Some(user) => user,
+        None => return Err(reject::custom(Error::UserNotFoundError)),
+    };
+    let hints: Vec<String> = riddle.hints.unwrap_or_default();
+    let hint: String = match hints.get(index as usize) {
+        Some(hint) => hint.clone(),
+        None => return Err(reject::custom(Error::HintNotFoundError(hints.len()))),
+    };
+    let game: Option<Game> = match user.in_room {
+        Some(ref room_id) => match db.get_game_of_room(room_id).await {
+            Ok(game) => game,
+            Err(e) => return Err(reject::custom(e)),
+        },
+        None => Option::default(),
+    };
+    let penalty: i32 = match game {
+        Some(ref game) if !game.state.is_scoring() => 0,
+        Some(ref game) => apply_score_multiplier(config.hint_penalty, game.score_multiplier),
+        None => config.hint_penalty,
+    };
+    let unlocked: UnlockedHint = UnlockedHint { riddle_id, index };
+    let (penalty, score): (i32, i32) = match db.unlock_hint(&username, &unlocked, penalty).await {
+        Ok(Some(score)) => (penalty, score),
+        Ok(None) => (0, user.score),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&RiddleHintResponse {
+        ok: true,
+        message: Option::default(),
+        riddle_id,
+        index,
+        hint,
+        penalty,
+        score,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn riddle_stats_handler(
+    riddle_id_str: String,
+    username: String,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_stats_handler(); riddle_id = {}, username = {}",
+        &riddle_id_str,
+        &username
+    );
+    let riddle_id: bson::oid::ObjectId = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    let _permit = match db.acquire_aggregation_permit().await {
+        Ok(permit) => permit,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let stats: RiddleStats = match db.get_riddle_stats(&riddle_id).await {
+        Ok(stats) => stats,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&RiddleStatsResponse {
+        ok: true,
+        message: Option::default(),
+        stats,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Returns an overview for operators. The numbers may be a few seconds
+/// old, see `DB::get_cached_admin_stats()`.
+pub async fn admin_stats_handler(username: String, db: DB) -> WebResult<impl Reply> {
+    log::info!("admin_stats_handler(); username = {}", &username);
+    let stats: AdminStats = match db.get_cached_admin_stats() {
+        Some(stats) => stats,
+        None => {
+            let _permit = match db.acquire_aggregation_permit().await {
+                Ok(permit) => permit,
+                Err(e) => return Err(reject::custom(e)),
+            };
+            match db.get_admin_stats().await {
+                Ok(stats) => stats,
+                Err(e) => return Err(reject::custom(e)),
+            }
+        }
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&AdminStatsResponse {
+        ok: true,
+        message: Option::default(),
+        stats,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn game_directions_handler(
+    game_id_str: String,
+    username: String,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "game_directions_handler(); game_id = {}, username = {}",
+        &game_id_str,
+        &username
+    );
+    let game_id: bson::oid::ObjectId = match ObjectId::parse_str(game_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    // All games share the same topology for now.
+    let mut directions: Vec<DirectionPair> = OPPOSITE
+        .iter()
+        .map(|(direction, opposite)| DirectionPair {
+            direction: direction.clone(),
+            opposite: opposite.clone(),
+        })
+        .collect();
+    directions.sort_by(|a, b| a.direction.cmp(&b.direction));
+    let reply: warp::reply::Json = warp::reply::json(&json!(&DirectionsResponse {
+        ok: true,
+        message: Option::default(),
+        game_id,
+        directions,
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
@@ -1086,6 +2868,16 @@ pub async fn game_stats_handler(
         Ok(oid) => oid,
         Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
     };
+    // unknown games would otherwise look like games without any rooms
+    let state: GameState = match db.get_game(&game_id).await {
+        Ok(Some(game)) => game.state,
+        Ok(None) => return Err(reject::custom(Error::GameNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let _permit = match db.acquire_aggregation_permit().await {
+        Ok(permit) => permit,
+        Err(e) => return Err(reject::custom(e)),
+    };
     let num_rooms: u32 = match db.get_num_rooms(&game_id).await {
         Ok(num_rooms) => num_rooms,
         Err(e) => return Err(reject::custom(e)),
@@ -1104,6 +2896,7 @@ pub async fn game_stats_handler(
         num_rooms: num_rooms,
         num_riddles: num_riddles,
         max_score: max_score,
+        state,
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
@@ -1113,6 +2906,7 @@ pub async fn promote_user_handler(
     role: String,
     username: String,
     mut db: DB,
+    client: ClientInfo,
 ) -> WebResult<impl Reply> {
     let user_to_promote = url_escape::decode(&user_to_promote).into_owned();
     let role = Role::from_str(&url_escape::decode(&role).into_owned());
@@ -1133,8 +2927,13 @@ pub async fn promote_user_handler(
         Ok(user) => user,
         Err(e) => return Err(reject::custom(e)),
     };
-    if role <= current_role {
-        return Err(reject::custom(Error::CannotChangeToSameRole));
+    if role == current_role {
+        return Err(reject::custom(Error::CannotChangeToSameRole(current_role)));
+    }
+    if role < current_role {
+        return Err(reject::custom(Error::CannotPromoteToLowerRoleError(
+            current_role,
+        )));
     }
     if user.role != Role::Admin {
         return Err(reject::custom(Error::UnsufficentRightsError));
@@ -1143,6 +2942,13 @@ pub async fn promote_user_handler(
         Ok(()) => (),
         Err(e) => return Err(reject::custom(e)),
     };
+    log_auth_event(
+        AuthEvent::RoleChanged,
+        &user_to_promote,
+        &client,
+        None,
+        Some(&format!("{} -> {} by {}", current_role, role, username)),
+    );
     let reply: warp::reply::Json = warp::reply::json(&json!(&PromoteUserResponse {
         ok: true,
         message: Option::default(),
@@ -1152,40 +2958,852 @@ pub async fn promote_user_handler(
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
-pub async fn user_authentication_handler(username: String) -> WebResult<impl Reply> {
-    log::info!("user_authentication_handler(); username = {}", &username);
-    Ok(StatusCode::OK)
-}
+/// Renders the mail with the initial password of an imported user, with
+/// the activation PIN if the user is not going to be activated by the
+/// import.
+fn initial_password_mail(
+    db: &DB,
+    user: &User,
+    password: &String,
+    activated: bool,
+) -> Result<OutboxMail> {
+    let to = match format!("{} <{}>", user.username, user.email).parse() {
+        Ok(to) => to,
+        Err(_) => return Err(Error::MalformedAddressError),
+    };
+    let activation: String = match activated {
+        true => String::new(),
+        false => format!(
+            "\nDeine PIN zur Aktivierung des Accounts: {:06}\n",
+            user.pin
+        ),
+    };
+    let email: lettre::Message = match Message::builder()
+        .header(lettre::message::header::ContentType::TEXT_PLAIN)
+        .from(
+            "Labyrinth Mailer <nirwana@raetselonkel.de>"
+                .parse()
+                .unwrap(),
+        )
+        .to(to)
+        .date_now()
+        .subject("Dein Zugang zu Labyrinth")
+        .body(format!(
+            r#"Moin {}!
 
-pub async fn cheat_handler(username: String) -> WebResult<impl Reply> {
-    log::info!("cheat_handler(); username = {}", username);
-    if true {
-        return Err(reject::custom(Error::CheatError));
-    }
-    Ok(StatusCode::PAYMENT_REQUIRED)
+Für dich wurde ein Account bei Labyrinth angelegt.
+
+Dein Benutzername: {}
+Dein Passwort: {}
+{}
+Bitte ändere das Passwort nach der ersten Anmeldung.
+
+Viele Grüße,
+Dein Rätselonkel
+"#,
+            user.username, user.username, password, activation
+        )) {
+        Ok(email) => email,
+        Err(_) => return Err(Error::MailBuilderError),
+    };
+    outbox::render(&email, db.clock.now())
 }
 
-pub async fn highscores_handler(
-    game_id_str: String,
+pub async fn user_import_handler(
     username: String,
-    db: DB,
+    query: UserImportQuery,
+    rows: Vec<UserImportRow>,
+    mut db: DB,
 ) -> WebResult<impl Reply> {
     log::info!(
-        "highscores_handler(); game_id = {}, username = {}",
-        &game_id_str,
-        &username
+        "user_import_handler(); username = {}, rows = {}, activate = {}",
+        &username,
+        rows.len(),
+        query.activate
     );
-    let game_id: bson::oid::ObjectId = match ObjectId::parse_str(game_id_str) {
-        Ok(oid) => oid,
-        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
-    };
-    let scores: Vec<UserCompactScoreData> = match db.get_compact_user_scores(&game_id).await {
-        Ok(scores) => scores,
-        Err(e) => return Err(reject::custom(Error::DatabaseQueryError(e.to_string()))),
-    };
-    let highscores: Vec<UserScoreResponse> = scores
-        .iter()
-        .map(|s| {
+    let mut results: Vec<UserImportResult> = Vec::new();
+    let mut users: Vec<User> = Vec::new();
+    let mut passwords: Vec<String> = Vec::new();
+    let mut mails: Vec<OutboxMail> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    for row in rows.into_iter() {
+        let email: String = email::normalize(&row.email);
+        let problem: Option<String> = if !RE_USERNAME.is_match(&row.username) {
+            Some(Error::InvalidUsernameError.to_string())
+        } else if !RE_MAIL.is_match(&email) {
+            Some(Error::InvalidEmailError("malformed address".to_string()).to_string())
+        } else if !seen.insert(row.username.to_lowercase()) || !seen.insert(email.to_lowercase()) {
+            Some("duplicate username or email in import".to_string())
+        } else {
+            match db.is_username_or_email_taken(&row.username, &email).await {
+                Ok(true) => Some(Error::UsernameOrEmailNotAvailableError.to_string()),
+                Ok(false) => Option::default(),
+                Err(e) => return Err(reject::custom(e)),
+            }
+        };
+        if problem.is_some() {
+            results.push(UserImportResult {
+                username: row.username,
+                ok: false,
+                message: problem,
+                password: Option::default(),
+            });
+            continue;
+        }
+        let password: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let hash: String = match Password::hash(&password) {
+            Ok(hash) => hash,
+            Err(e) => return Err(reject::custom(e)),
+        };
+        let mut pin: PinType = 0;
+        while pin == 0 {
+            pin = OsRng.next_u32() % 1000000;
+        }
+        let user: User = User::new(
+            &row.username,
+            &email,
+            row.role,
+            hash,
+            pin,
+            Vec::new(),
+            db.clock.now(),
+        );
+        if !query.return_passwords {
+            match initial_password_mail(&db, &user, &password, query.activate) {
+                Ok(mail) => mails.push(mail),
+                Err(e) => {
+                    results.push(UserImportResult {
+                        username: user.username,
+                        ok: false,
+                        message: Some(e.to_string()),
+                        password: Option::default(),
+                    });
+                    continue;
+                }
+            }
+        }
+        users.push(user);
+        passwords.push(password);
+    }
+    match db.create_users(&users, &mails).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    for (user, password) in users.iter_mut().zip(passwords.into_iter()) {
+        let mut message: Option<String> = Option::default();
+        if query.activate {
+            if let Err(e) = db.activate_user(user).await {
+                message = Some(e.to_string());
+            }
+        }
+        let password: Option<String> = match query.return_passwords {
+            true => Some(password),
+            false => Option::default(),
+        };
+        results.push(UserImportResult {
+            username: user.username.clone(),
+            ok: message.is_none(),
+            message,
+            password,
+        });
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&UserImportResponse {
+        ok: results.iter().all(|r| r.ok),
+        message: Option::default(),
+        num_created: users.len() as u32,
+        results,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn admin_score_handler(
+    user_to_adjust: String,
+    username: String,
+    body: AdminScoreRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
+    let user_to_adjust = url_escape::decode(&user_to_adjust).into_owned();
+    log::info!(
+        "admin_score_handler(); username = {}, user_to_adjust = {}, body = {:?}",
+        &username,
+        &user_to_adjust,
+        &body
+    );
+    if body.reason.trim().is_empty() || body.delta.is_some() == body.score.is_some() {
+        return Err(reject::custom(Error::InvalidScoreAdjustmentError));
+    }
+    let (previous_score, score) = match db
+        .adjust_user_score(&user_to_adjust, body.delta.unwrap_or(0), body.score)
+        .await
+    {
+        Ok(scores) => scores,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    match db
+        .add_audit_log_entry(&AuditLogEntry {
+            id: ObjectId::new(),
+            timestamp: db.clock.now(),
+            admin: username,
+            action: "adjust_score".to_string(),
+            target: user_to_adjust.clone(),
+            reason: body.reason,
+            details: doc! {
+                "delta": body.delta,
+                "score": body.score,
+                "previous_score": previous_score,
+                "new_score": score,
+            },
+        })
+        .await
+    {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&AdminScoreResponse {
+        ok: true,
+        message: Option::default(),
+        username: user_to_adjust,
+        previous_score,
+        score,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Checks that `user_to_remove` may be deactivated or deleted by `username`:
+/// admins can't remove themselves. That there's always an admin left is
+/// ensured by `DB::deactivate_user()`.
+fn check_user_removable(user_to_remove: &String, username: &String) -> Result<()> {
+    if user_to_remove == username {
+        return Err(Error::UserCannotRemoveOwnAccountError);
+    }
+    Ok(())
+}
+
+pub async fn deactivate_user_handler(
+    user_to_deactivate: String,
+    username: String,
+    db: DB,
+) -> WebResult<impl Reply> {
+    let user_to_deactivate = url_escape::decode(&user_to_deactivate).into_owned();
+    log::info!(
+        "deactivate_user_handler(); username = {}, user_to_deactivate = {}",
+        &username,
+        &user_to_deactivate
+    );
+    match check_user_removable(&user_to_deactivate, &username) {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.deactivate_user(&user_to_deactivate).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.revoke_refresh_tokens_of_user(&user_to_deactivate).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db
+        .add_audit_log_entry(&AuditLogEntry {
+            id: ObjectId::new(),
+            timestamp: db.clock.now(),
+            admin: username,
+            action: "deactivate_user".to_string(),
+            target: user_to_deactivate,
+            reason: String::new(),
+            details: doc! {},
+        })
+        .await
+    {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn delete_user_handler(
+    user_to_delete: String,
+    username: String,
+    db: DB,
+) -> WebResult<impl Reply> {
+    let user_to_delete = url_escape::decode(&user_to_delete).into_owned();
+    log::info!(
+        "delete_user_handler(); username = {}, user_to_delete = {}",
+        &username,
+        &user_to_delete
+    );
+    match check_user_removable(&user_to_delete, &username) {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.delete_user(&user_to_delete).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db
+        .add_audit_log_entry(&AuditLogEntry {
+            id: ObjectId::new(),
+            timestamp: db.clock.now(),
+            admin: username,
+            action: "delete_user".to_string(),
+            target: user_to_delete,
+            reason: String::new(),
+            details: doc! {},
+        })
+        .await
+    {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn teleport_by_number_handler(
+    user_to_move: String,
+    number: u32,
+    username: String,
+    query: TeleportQuery,
+    db: DB,
+) -> WebResult<impl Reply> {
+    let user_to_move = url_escape::decode(&user_to_move).into_owned();
+    log::info!(
+        "teleport_by_number_handler(); username = {}, user_to_move = {}, game_id = {}, number = {}",
+        &username,
+        &user_to_move,
+        &query.game_id,
+        number
+    );
+    let game_id: bson::oid::ObjectId = match ObjectId::parse_str(&query.game_id) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    match db.get_game(&game_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return Err(reject::custom(Error::GameNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let room: Room = match db.get_room_by_number(&game_id, number).await {
+        Ok(room) => room,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let user: User = match db.teleport_user(&user_to_move, &room.id).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    // like entering the room through a doorway, see `go_handler()`
+    match db.record_room_visit(&user, &room).await {
+        Ok(()) => (),
+        Err(e) => log::error!("Error: recording room visit failed: {}", &e),
+    }
+    match db
+        .add_audit_log_entry(&AuditLogEntry {
+            id: ObjectId::new(),
+            timestamp: db.clock.now(),
+            admin: username,
+            action: "teleport_user".to_string(),
+            target: user_to_move.clone(),
+            reason: String::new(),
+            details: doc! { "game_id": game_id, "room_id": room.id, "number": room.number },
+        })
+        .await
+    {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&TeleportResponse {
+        ok: true,
+        message: Option::default(),
+        username: user_to_move,
+        room_id: room.id,
+        number: room.number,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn game_state_handler(
+    game_id_str: String,
+    username: String,
+    body: GameStateRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "game_state_handler(); game_id = {}, username = {}, state = {:?}",
+        &game_id_str,
+        &username,
+        &body.state
+    );
+    let game_id: bson::oid::ObjectId = match ObjectId::parse_str(game_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    match db.set_game_state(&game_id, body.state).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&GameStateResponse {
+        ok: true,
+        message: Option::default(),
+        game_id,
+        state: body.state,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn maintenance_get_handler(
+    username: String,
+    maintenance: SharedMaintenance,
+) -> WebResult<impl Reply> {
+    log::info!("maintenance_get_handler(); username = {}", &username);
+    let maintenance = maintenance.read().unwrap();
+    let reply: warp::reply::Json = warp::reply::json(&json!(&MaintenanceResponse {
+        ok: true,
+        message: Option::default(),
+        mode: maintenance.mode,
+        notice: maintenance.notice.clone(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Switches maintenance mode at runtime. The setting is not persisted, so
+/// a restart falls back to `MAINTENANCE_MODE`.
+pub async fn maintenance_set_handler(
+    username: String,
+    body: MaintenanceRequest,
+    maintenance: SharedMaintenance,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "maintenance_set_handler(); username = {}, body = {:?}",
+        &username,
+        &body
+    );
+    let mut maintenance = maintenance.write().unwrap();
+    *maintenance = Maintenance::new(body.mode, body.notice);
+    let reply: warp::reply::Json = warp::reply::json(&json!(&MaintenanceResponse {
+        ok: true,
+        message: Option::default(),
+        mode: maintenance.mode,
+        notice: maintenance.notice.clone(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn game_import_handler(
+    username: String,
+    query: GameImportQuery,
+    mut body: GameImport,
+    mut db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "game_import_handler(); username = {}, game = {}, dry_run = {}",
+        &username,
+        &body.game.name,
+        query.dry_run
+    );
+    match db.get_game(&body.game.id).await {
+        Ok(Some(game)) if !game.state.is_editable() => {
+            return Err(reject::custom(Error::GameLockedError))
+        }
+        Ok(_) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let existing_riddles = match db
+        .get_existing_riddle_ids(&body.referenced_riddle_ids())
+        .await
+    {
+        Ok(existing_riddles) => existing_riddles,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let game_exists: bool = match db.game_exists(&body.game.id).await {
+        Ok(game_exists) => game_exists,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let report: GameImportReport = body.validate(
+        &existing_riddles,
+        game_exists,
+        config.max_riddle_level,
+        config.upload_dir.as_deref().map(std::path::Path::new),
+    );
+    let valid: bool = report.problems.is_empty();
+    if valid && !query.dry_run {
+        let now: DateTime<Utc> = db.clock.now();
+        for riddle in body.riddles.iter_mut() {
+            riddle.updated_at = Some(now);
+        }
+        match db.import_game(&body).await {
+            Ok(()) => (),
+            Err(e) => return Err(reject::custom(e)),
+        }
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&GameImportResponse {
+        ok: valid,
+        message: match valid {
+            true => Option::default(),
+            false => Some("game has not been imported".to_string()),
+        },
+        dry_run: query.dry_run,
+        game_id: body.game.id,
+        report,
+    }));
+    let status: StatusCode = match valid || query.dry_run {
+        true => StatusCode::OK,
+        false => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+    Ok(warp::reply::with_status(reply, status))
+}
+
+/// Stores an uploaded file in `UPLOAD_DIR` and attaches it to the riddle.
+pub async fn riddle_file_upload_handler(
+    riddle_id_str: String,
+    username: String,
+    form: warp::multipart::FormData,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_file_upload_handler(); riddle_id = {}, username = {}",
+        &riddle_id_str,
+        &username
+    );
+    let upload_dir: &String = match config.upload_dir {
+        Some(ref upload_dir) => upload_dir,
+        None => return Err(reject::custom(Error::UploadsDisabledError)),
+    };
+    let riddle_id: ObjectId = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    match db.get_riddle_by_oid(&riddle_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return Err(reject::custom(Error::RiddleNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.get_games_of_riddle(&riddle_id).await {
+        Ok(games) if games.iter().any(|game| !game.state.is_editable()) => {
+            return Err(reject::custom(Error::GameLockedError))
+        }
+        Ok(_) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let file: UploadedFile = match upload::store_file(upload_dir, form).await {
+        Ok(file) => file,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    if let Err(e) = db.add_riddle_file(&riddle_id, &file).await {
+        if let Err(e) = upload::remove_file(upload_dir, &file.uploaded_name).await {
+            log::warn!("cannot remove {}: {}", &file.uploaded_name, e);
+        }
+        return Err(reject::custom(e));
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&RiddleFileUploadResponse {
+        ok: true,
+        message: Option::default(),
+        file: FileMetadataResponse {
+            url: upload_url(&config, &file.uploaded_name),
+            original_name: file.original_name,
+            uploaded_name: file.uploaded_name,
+            mime_type: file.mime_type,
+            width: file.width,
+            height: file.height,
+            scale: file.scale,
+            variants: Vec::new(),
+        },
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+}
+
+/// Detaches a file from the riddle and deletes it from `UPLOAD_DIR`,
+/// together with its variants and retina version. Only files stored by
+/// the upload endpoint are deleted, and only if no other riddle still
+/// refers to them.
+pub async fn riddle_file_delete_handler(
+    riddle_id_str: String,
+    uploaded_name: String,
+    username: String,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_file_delete_handler(); riddle_id = {}, uploaded_name = {}, username = {}",
+        &riddle_id_str,
+        &uploaded_name,
+        &username
+    );
+    let upload_dir: &String = match config.upload_dir {
+        Some(ref upload_dir) => upload_dir,
+        None => return Err(reject::custom(Error::UploadsDisabledError)),
+    };
+    let riddle_id: ObjectId = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    match db.get_games_of_riddle(&riddle_id).await {
+        Ok(games) if games.iter().any(|game| !game.state.is_editable()) => {
+            return Err(reject::custom(Error::GameLockedError))
+        }
+        Ok(_) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let file: UploadedFile = match db.remove_riddle_file(&riddle_id, &uploaded_name).await {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            return Err(reject::custom(Error::RiddleFileNotFoundError(
+                uploaded_name,
+            )))
+        }
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let mut stored_names: Vec<String> = vec![file.uploaded_name];
+    stored_names.extend(file.retina);
+    stored_names.extend(
+        file.variants
+            .unwrap_or_default()
+            .into_iter()
+            .map(|variant| variant.uploaded_name),
+    );
+    for name in stored_names.iter() {
+        if !upload::is_generated_name(name) {
+            log::info!("{} has not been uploaded via the API, keeping it", name);
+            continue;
+        }
+        match db.is_file_referenced(name).await {
+            Ok(true) => {
+                log::info!("{} is still referenced, keeping it", name);
+                continue;
+            }
+            Ok(false) => (),
+            Err(e) => return Err(reject::custom(e)),
+        }
+        if let Err(e) = upload::remove_file(upload_dir, name).await {
+            log::warn!("cannot remove {}: {}", name, e);
+        }
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Shows designers what an answer looks like after the normalization
+/// applied when solving a riddle with the given settings.
+pub async fn normalize_preview_handler(
+    username: String,
+    body: NormalizePreviewRequest,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "normalize_preview_handler(); username = {}, options = {:?}",
+        &username,
+        &body.options
+    );
+    // same rule as `Riddle::ignores_case()`
+    let ignore_case: bool =
+        body.options.ignore_case.unwrap_or(false) && !body.options.external_password_input;
+    let reply: warp::reply::Json = warp::reply::json(&json!(&NormalizePreviewResponse {
+        ok: true,
+        message: Option::default(),
+        normalized: normalize_answer(&body.raw, ignore_case, &body.options.comparison_locale),
+        ignore_case,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn riddle_create_handler(
+    username: String,
+    body: RiddleCreateRequest,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "riddle_create_handler(); username = {}, level = {}",
+        &username,
+        body.level
+    );
+    let problems: Vec<String> = import::riddle_field_problems(
+        config.max_riddle_level,
+        Some(body.level),
+        Some(body.difficulty),
+        Some(&body.solution),
+    );
+    if !problems.is_empty() {
+        return Err(reject::custom(Error::InvalidRiddleError(format!(
+            "riddle {}",
+            problems.join(", ")
+        ))));
+    }
+    match db.get_riddle_by_level(body.level).await {
+        Ok(Some(_)) => return Err(reject::custom(Error::RiddleLevelTakenError(body.level))),
+        Ok(None) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let riddle: Riddle = Riddle {
+        id: ObjectId::new(),
+        difficulty: body.difficulty,
+        deduction: body.deduction,
+        level: body.level,
+        files: Option::default(),
+        ignore_case: body.ignore_case,
+        comparison_locale: Option::default(),
+        solution: body.solution,
+        debriefing: body.debriefing,
+        task: body.task,
+        credits: body.credits,
+        script: Option::default(),
+        external_password_input: false,
+        close_answer_threshold: Option::default(),
+        updated_at: Some(db.clock.now()),
+        max_solution_length: Option::default(),
+        reveal_after_failures: Option::default(),
+        max_attempts: Option::default(),
+        exhaustion_penalty: Option::default(),
+        hints: Option::default(),
+    };
+    match db.create_riddle(&riddle).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&RiddleCreateResponse {
+        ok: true,
+        message: Option::default(),
+        riddle_id: riddle.id,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+}
+
+/// Changes a riddle's authoring data. Refused while any game the riddle
+/// appears in is locked or frozen.
+pub async fn riddle_update_handler(
+    riddle_id_str: String,
+    username: String,
+    body: RiddleUpdate,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    let fields: Vec<String> = match bson::to_document(&body) {
+        Ok(fields) => fields.keys().cloned().collect(),
+        Err(e) => return Err(reject::custom(Error::DatabaseQueryError(e.to_string()))),
+    };
+    log::info!(
+        "riddle_update_handler(); riddle_id = {}, username = {}, fields = {:?}",
+        &riddle_id_str,
+        &username,
+        &fields
+    );
+    let riddle_id: ObjectId = match ObjectId::parse_str(riddle_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    let riddle: Riddle = match db.get_riddle_by_oid(&riddle_id).await {
+        Ok(Some(riddle)) => riddle,
+        Ok(None) => return Err(reject::custom(Error::RiddleNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let problems: Vec<String> = import::riddle_field_problems(
+        config.max_riddle_level,
+        body.level,
+        body.difficulty,
+        // scripted riddles work out their solution themselves
+        body.solution.as_deref().filter(|_| riddle.script.is_none()),
+    );
+    if !problems.is_empty() {
+        return Err(reject::custom(Error::InvalidRiddleError(format!(
+            "riddle {}",
+            problems.join(", ")
+        ))));
+    }
+    if let Some(level) = body.level {
+        match db.get_riddle_by_level(level).await {
+            Ok(Some(other)) if other.id != riddle_id => {
+                return Err(reject::custom(Error::RiddleLevelTakenError(level)))
+            }
+            Ok(_) => (),
+            Err(e) => return Err(reject::custom(e)),
+        }
+    }
+    match db.get_games_of_riddle(&riddle_id).await {
+        Ok(games) if games.iter().any(|game| !game.state.is_editable()) => {
+            return Err(reject::custom(Error::GameLockedError))
+        }
+        Ok(_) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.update_riddle(&riddle_id, &body).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let mut details: bson::Document = doc! { "fields": &fields };
+    if let Some(level) = body.level {
+        details.insert("previous_level", riddle.level);
+        details.insert("level", level);
+    }
+    match db
+        .add_audit_log_entry(&AuditLogEntry {
+            id: ObjectId::new(),
+            timestamp: db.clock.now(),
+            admin: username,
+            action: "update_riddle".to_string(),
+            target: riddle_id.to_hex(),
+            reason: String::new(),
+            details,
+        })
+        .await
+    {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn user_authentication_handler(username: String) -> WebResult<impl Reply> {
+    log::info!("user_authentication_handler(); username = {}", &username);
+    Ok(StatusCode::OK)
+}
+
+pub async fn cheat_handler(username: String) -> WebResult<impl Reply> {
+    log::info!("cheat_handler(); username = {}", username);
+    if true {
+        return Err(reject::custom(Error::CheatError));
+    }
+    Ok(StatusCode::PAYMENT_REQUIRED)
+}
+
+pub async fn highscores_handler(
+    game_id_str: String,
+    username: String,
+    page: PageQuery,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "highscores_handler(); game_id = {}, username = {}",
+        &game_id_str,
+        &username
+    );
+    let game_id: bson::oid::ObjectId = match ObjectId::parse_str(game_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    let scores: Vec<UserCompactScoreData> = match db.get_compact_user_scores(&game_id).await {
+        Ok(scores) => scores,
+        Err(e) => return Err(reject::custom(Error::DatabaseQueryError(e.to_string()))),
+    };
+    let mut highscores: Vec<UserScoreResponse> = scores
+        .iter()
+        .map(|s| {
             let rel_score: f32 = match s.total_time {
                 0 => 0.0,
                 _ => 3.6e3 * (s.score as f32 / s.total_time as f32),
@@ -1197,36 +3815,443 @@ pub async fn highscores_handler(
             }
         })
         .collect();
+    highscores.sort_by(|a, b| {
+        b.rel_score
+            .partial_cmp(&a.rel_score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.abs_score.cmp(&a.abs_score))
+            .then(a.username.cmp(&b.username))
+    });
     let reply: warp::reply::Json = warp::reply::json(&json!(&HighscoresResponse {
         ok: true,
         message: Option::default(),
-        highscores
+        highscores: Paginated::from_vec(highscores, &page),
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
-pub async fn user_whoami_handler(username: String, db: DB) -> WebResult<impl Reply> {
-    log::info!("user_whoami_handler() {}", &username);
-    let user: User = match db.get_user(&username).await {
+/// Lets spectators (and admins) follow a player: where they are and what
+/// they solved last. Spectators can't use any of the playing routes, see
+/// `auth::authorize()`.
+pub async fn spectate_handler(
+    player: String,
+    username: String,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    let player: String = url_escape::decode(&player).into_owned();
+    log::info!(
+        "spectate_handler(); player = {}, username = {}",
+        &player,
+        &username
+    );
+    match db.get_user_role(&username).await {
+        Ok(Role::Spectator) | Ok(Role::Admin) => (),
+        Ok(_) => return Err(reject::custom(Error::UnsufficentRightsError)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let user: User = match db.get_user(&player).await {
         Ok(user) => user,
         Err(e) => return Err(reject::custom(e)),
     };
-    log::info!("got user {} <{}>", &user.username, &user.email);
     let in_room: ObjectId = match user.in_room {
         Some(room) => room,
-        None => return Err(reject::custom(Error::RoomNotFoundError)),
+        None => return Err(reject::custom(Error::UserIsInNoRoom)),
     };
     let room_response: RoomResponse = match get_room_by_id(&in_room, &db).await {
         Ok(room_response) => room_response,
         Err(e) => return Err(reject::custom(e)),
     };
+    let mut recent_solves: Vec<RiddleAttempt> = user.solved;
+    recent_solves.sort_by_key(|attempt| {
+        std::cmp::Reverse(
+            attempt
+                .t0
+                .map(|t0| t0.timestamp() + attempt.dt.unwrap_or(0)),
+        )
+    });
+    recent_solves.truncate(config.spectate_recent_solves);
+    let reply: warp::reply::Json = warp::reply::json(&json!(&SpectateResponse {
+        ok: true,
+        message: Option::default(),
+        username: user.username,
+        level: user.level,
+        score: user.score,
+        in_room: room_response,
+        recent_solves,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Ranks the players of a game by score. Users with the same score share
+/// a rank.
+pub async fn leaderboard_handler(
+    game_id_str: String,
+    username: String,
+    page: PageQuery,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "leaderboard_handler(); game_id = {}, username = {}",
+        &game_id_str,
+        &username
+    );
+    let game_id: bson::oid::ObjectId = match ObjectId::parse_str(game_id_str) {
+        Ok(oid) => oid,
+        Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+    };
+    match db.get_game(&game_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return Err(reject::custom(Error::GameNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let leaderboard: Leaderboard = match db
+        .get_leaderboard(&game_id, &username, page.offset, page.limit())
+        .await
+    {
+        Ok(leaderboard) => leaderboard,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let entry = |user: UserLeaderboardData| LeaderboardEntry {
+        rank: user.rank,
+        username: user.username,
+        score: user.score,
+        level: user.level,
+        rooms_entered_count: user.rooms_entered_count,
+    };
+    let own: Option<LeaderboardEntry> = leaderboard.own.map(entry);
+    let entries: Vec<LeaderboardEntry> = leaderboard.users.into_iter().map(entry).collect();
+    let reply: warp::reply::Json = warp::reply::json(&json!(&LeaderboardResponse {
+        ok: true,
+        message: Option::default(),
+        leaderboard: Paginated::new(entries, leaderboard.total, &page),
+        own,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Sorts the points a user has earned by solving riddles into tiers by
+/// difficulty. Riddles with a difficulty below `tier_medium` count as easy,
+/// those below `tier_hard` as medium, all others as hard. Points are those
+/// of `score_for_attempt()`, so they include the time bonus; revealed
+/// solutions earn nothing. Whatever the user has lost on top of that, e.g.
+/// for wrong answers or hints, ends up in `penalties`.
+fn score_breakdown(
+    solved: &Vec<SolvedDifficulty>,
+    score: i32,
+    tier_medium: i32,
+    tier_hard: i32,
+    time_bonus_window: Option<i64>,
+) -> ScoreBreakdown {
+    let mut breakdown = ScoreBreakdown::default();
+    for solve in solved.iter().filter(|solve| !solve.revealed) {
+        // solves without timing can't have earned a bonus
+        let points: i32 = match solve.dt {
+            Some(dt) => score_for_attempt(
+                solve.difficulty,
+                chrono::Duration::seconds(dt),
+                time_bonus_window,
+            ),
+            None => score_for_attempt(solve.difficulty, chrono::Duration::zero(), None),
+        } as i32;
+        if solve.difficulty < tier_medium {
+            breakdown.easy += points;
+        } else if solve.difficulty < tier_hard {
+            breakdown.medium += points;
+        } else {
+            breakdown.hard += points;
+        }
+    }
+    breakdown.penalties = 0.max(breakdown.easy + breakdown.medium + breakdown.hard - score);
+    breakdown
+}
+
+pub async fn user_stats_handler(
+    username: String,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!("user_stats_handler(); username = {}", &username);
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let solved: Vec<SolvedDifficulty> = match db.get_solved_difficulties(&user.id).await {
+        Ok(solved) => solved,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let rank: Option<u32> = match db.get_user_rank(&user).await {
+        Ok(rank) => rank,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&UserStatsResponse {
+        ok: true,
+        message: Option::default(),
+        score: user.score,
+        level: user.level,
+        num_solved: user.solved.len() as u32,
+        score_breakdown: score_breakdown(
+            &solved,
+            user.score,
+            config.difficulty_tier_medium,
+            config.difficulty_tier_hard,
+            config.time_bonus_window_secs,
+        ),
+        rank,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Checks `totp` against each of the user's authenticators, allowing for
+/// `TOTP_SKEW_STEPS` intervals of clock skew either way.
+fn totp_matches(totp_keys: &Vec<TotpSecret>, totp: &String, now: DateTime<Utc>) -> bool {
+    let seconds: u64 = now.timestamp() as u64;
+    totp_keys
+        .iter()
+        .any(|secret| verify_totp(&secret.key, totp, TOTP_PARAMS.skew_steps as i64, seconds))
+}
+
+/// Makes the user re-enter the password, and a TOTP if configured, before
+/// sensitive operations even though the request carries a valid JWT.
+fn verify_step_up(
+    user: &User,
+    password: &String,
+    totp: &Option<String>,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    match Password::matches(&user.hash, password) {
+        Ok(true) => (),
+        Ok(false) => return Err(Error::WrongCredentialsError),
+        Err(e) => return Err(e),
+    }
+    if user.has_totp() {
+        match totp {
+            Some(totp) if totp_matches(&user.totp_keys, totp, now) => (),
+            _ => return Err(Error::WrongCredentialsError),
+        }
+    }
+    Ok(())
+}
+
+/// Streams the JSON object `head` with the items of `cursor` appended as
+/// the array `key`, so that the items are serialized one by one instead of
+/// being collected in memory first. An error while reading the cursor
+/// aborts the response, leaving the client with truncated JSON.
+fn json_with_streamed_array<T>(
+    head: &serde_json::Value,
+    key: &str,
+    cursor: mongodb::Cursor<T>,
+) -> hyper::Body
+where
+    T: Serialize + serde::de::DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    let mut prefix: String = head.to_string();
+    prefix.pop(); // the closing brace
+    if prefix.len() > 1 {
+        prefix.push(',');
+    }
+    prefix.push_str(&format!("{}:[", json!(key)));
+    let items = cursor.enumerate().map(|(i, item)| {
+        let item: T = match item {
+            Ok(item) => item,
+            Err(e) => {
+                log::error!("Error: reading cursor for streamed response failed: {}", e);
+                return Err(e.to_string());
+            }
+        };
+        match serde_json::to_vec(&item) {
+            Ok(mut bytes) => {
+                if i > 0 {
+                    bytes.insert(0, b',');
+                }
+                Ok(bytes)
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    });
+    let stream = futures::stream::once(async move { Ok(prefix.into_bytes()) })
+        .chain(items)
+        .chain(futures::stream::once(async { Ok(b"]}".to_vec()) }));
+    hyper::Body::wrap_stream(stream)
+}
+
+/// Exports the user's personal data. As the export contains personal
+/// data, the user has to re-enter the password (and TOTP, if configured)
+/// even though the request carries a valid JWT.
+pub async fn user_export_handler(
+    username: String,
+    body: UserExportRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!("user_export_handler(); username = {}", &username);
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    match verify_step_up(&user, &body.password, &body.totp, db.clock.now()) {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let mut configured_2fa: Vec<SecondFactor> = Vec::new();
+    if user.has_totp() {
+        configured_2fa.push(SecondFactor::Totp);
+    }
+    if !user.webauthn.credentials.is_empty() {
+        configured_2fa.push(SecondFactor::Fido2);
+    }
+    let room_visits: mongodb::Cursor<RoomVisit> = match db.get_room_visits(&user.id).await {
+        Ok(room_visits) => room_visits,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let audit_log: Vec<AuditLogEntry> = match db.get_audit_log_entries(&user.username).await {
+        Ok(audit_log) => audit_log,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let filename: String = format!("labyrinth-export-{}.json", &user.username);
+    let head: serde_json::Value = json!(&UserExportResponse {
+        ok: true,
+        message: Option::default(),
+        exported_at: db.clock.now(),
+        profile: UserExportProfile {
+            username: user.username,
+            email: user.email,
+            role: user.role,
+            activated: user.activated,
+            created: user.created,
+            registered: user.registered,
+            last_login: user.last_login,
+            level: user.level,
+            score: user.score,
+            in_room: user.in_room,
+            configured_2fa,
+        },
+        solved: user.solved,
+        current_riddle_attempt: user.current_riddle_attempt,
+        rooms_entered: user.rooms_entered,
+        finished: user.finished,
+        audit_log,
+    });
+    let mut response: Response =
+        Response::new(json_with_streamed_array(&head, "room_visits", room_visits));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    match HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)) {
+        Ok(value) => {
+            response.headers_mut().insert(CONTENT_DISPOSITION, value);
+        }
+        Err(e) => log::warn!("cannot set Content-Disposition for {}: {}", &filename, e),
+    }
+    Ok(response)
+}
+
+pub async fn user_finished_handler(
+    username: String,
+    page: PageQuery,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!("user_finished_handler(); username = {}", &username);
+    let finished: Vec<FinishedGame> = match db.get_finished_games(&username).await {
+        Ok(finished) => finished,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let mut games: Vec<FinishedGameResponse> = Vec::new();
+    for f in finished.into_iter() {
+        let name: Option<String> = match db.get_game(&f.game_id).await {
+            Ok(game) => game.map(|game| game.name),
+            Err(e) => return Err(reject::custom(e)),
+        };
+        games.push(FinishedGameResponse {
+            game_id: f.game_id,
+            name,
+            finished_at: f.timestamp,
+            score: f.score,
+            elapsed: f.elapsed,
+        });
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&FinishedGamesResponse {
+        ok: true,
+        message: Option::default(),
+        finished: Paginated::from_vec(games, &page),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Issues a new refresh token for `username` if refresh tokens are
+/// enabled. A fresh login starts a new token family, a refresh continues
+/// the given one. New logins are subject to `MAX_SESSIONS`.
+async fn issue_refresh_token(
+    db: &DB,
+    username: &String,
+    family: Option<ObjectId>,
+    config: &Config,
+) -> Result<Option<String>> {
+    if !auth::refresh_tokens_enabled() {
+        return Ok(None);
+    }
+    if let (None, Some(max_sessions)) = (family, config.max_sessions) {
+        let sessions: Vec<RefreshToken> = match db.get_active_sessions(username).await {
+            Ok(sessions) => sessions,
+            Err(e) => return Err(e),
+        };
+        if sessions.len() >= max_sessions {
+            if !config.evict_oldest_session {
+                return Err(Error::TooManySessionsError(max_sessions));
+            }
+            let num_evicted: usize = sessions.len() + 1 - max_sessions;
+            for session in sessions.iter().take(num_evicted) {
+                log::info!("evicting session {} of user {}", &session.family, username);
+                match db.revoke_refresh_token_family(&session.family).await {
+                    Ok(()) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+    let (token, digest) = auth::create_refresh_token();
+    let now: DateTime<Utc> = db.clock.now();
+    match db
+        .add_refresh_token(&RefreshToken {
+            digest,
+            family: family.unwrap_or_else(ObjectId::new),
+            username: username.clone(),
+            issued: now,
+            expires: now + chrono::Duration::minutes(config.jwt.refresh_token_minutes),
+            used: false,
+        })
+        .await
+    {
+        Ok(()) => Ok(Some(token)),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn user_whoami_handler(username: String, db: DB) -> WebResult<impl Reply> {
+    log::info!("user_whoami_handler() {}", &username);
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    log::info!("got user {} <{}>", &user.username, &user.email);
+    let room_response: Option<RoomResponse> = match get_user_room(&user, &db).await {
+        Ok(room_response) => room_response,
+        Err(e) => return Err(reject::custom(e)),
+    };
     let mut configured_2fa: Vec<SecondFactor> = Vec::new();
-    if user.totp_key.len() > 0 {
+    if user.has_totp() {
         configured_2fa.push(SecondFactor::Totp);
     }
     if user.webauthn.credentials.len() > 0 {
         configured_2fa.push(SecondFactor::Fido2);
     }
+    let active_sessions: Option<u32> = match auth::refresh_tokens_enabled() {
+        true => match db.get_active_sessions(&user.username).await {
+            Ok(sessions) => Some(sessions.len() as u32),
+            Err(e) => return Err(reject::custom(e)),
+        },
+        false => None,
+    };
+    let two_factor_state: TwoFactorState = user.two_factor_state();
     let reply: warp::reply::Json = warp::reply::json(&json!(&UserWhoamiResponse {
         ok: true,
         message: Option::default(),
@@ -1243,65 +4268,92 @@ pub async fn user_whoami_handler(username: String, db: DB) -> WebResult<impl Rep
         solved: user.solved,
         rooms_entered: user.rooms_entered,
         jwt: Option::default(),
+        refresh_token: Option::default(),
+        active_sessions,
         totp: Option::default(),
         recovery_keys: Option::default(),
         configured_2fa,
+        two_factor_state,
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
-pub async fn user_totp_login_handler(body: UserTotpRequest, mut db: DB) -> WebResult<impl Reply> {
-    log::info!(
-        "user_totp_login_handler(); username = {}, totp = {}",
-        &body.username,
-        &body.totp
-    );
+pub async fn user_totp_login_handler(
+    body: UserTotpRequest,
+    mut db: DB,
+    client: ClientInfo,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!("user_totp_login_handler(); username = {}", &body.username);
     let user: User = match db.get_user(&body.username).await {
         Ok(user) => user,
         Err(e) => return Err(reject::custom(e)),
     };
-    log::info!("got user {:?}", &user);
+    log::info!("got user {}", &user.username);
     if !user.awaiting_second_factor {
         return Err(reject::custom(Error::PointlessTotpError));
     }
+    if !user.has_totp() {
+        return Err(reject::custom(Error::TotpNotConfiguredError));
+    }
+    match check_not_locked(&user, db.clock.now()) {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
     let mut configured_2fa: Vec<SecondFactor> = Vec::new();
     if user.webauthn.credentials.len() > 0 {
         configured_2fa.push(SecondFactor::Fido2);
     }
-    if user.totp_key.len() > 0 {
-        configured_2fa.push(SecondFactor::Totp);
-        let seconds: u64 = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        match body.totp == totp_custom::<Sha1>(30, 6, &user.totp_key, seconds) {
-            true => log::info!("TOTPs match"),
-            false => {
-                if body.totp == totp_custom::<Sha1>(30, 6, &user.totp_key, seconds - 30) {
-                    log::info!("TOTPs match (after going back 30 secs)");
-                } else {
-                    return Err(reject::custom(Error::WrongCredentialsError));
-                }
-            }
+    configured_2fa.push(SecondFactor::Totp);
+    match totp_matches(&user.totp_keys, &body.totp, db.clock.now()) {
+        true => log_auth_event(
+            AuthEvent::SecondFactorSucceeded,
+            &user.username,
+            &client,
+            None,
+            Some("TOTP"),
+        ),
+        false => {
+            return Err(failed_login(
+                &db,
+                &user.username,
+                AuthEvent::SecondFactorFailed,
+                &client,
+                &config,
+            )
+            .await)
         }
     }
     match db.login_user(&user).await {
         Ok(()) => (),
         Err(e) => return Err(reject::custom(e)),
     }
-    let jwt: Option<String> = match auth::create_jwt(&user.username, &user.role) {
+    log_auth_event(
+        AuthEvent::LoginSucceeded,
+        &user.username,
+        &client,
+        None,
+        None,
+    );
+    let jwt: Option<String> = match auth::create_jwt(
+        &user.username,
+        &user.role,
+        user.token_version,
+        db.clock.now(),
+    ) {
         Ok(jwt) => Some(jwt),
         Err(e) => return Err(reject::custom(e)),
     };
-    let in_room: bson::oid::ObjectId = match user.in_room {
-        Some(room) => room,
-        None => return Err(reject::custom(Error::UserIsInNoRoom)),
-    };
-    // TODO: extract as function (see)
-    let room_response: RoomResponse = match get_room_by_id(&in_room, &db).await {
+    let refresh_token: Option<String> =
+        match issue_refresh_token(&db, &user.username, None, &config).await {
+            Ok(refresh_token) => refresh_token,
+            Err(e) => return Err(reject::custom(e)),
+        };
+    let room_response: Option<RoomResponse> = match get_user_room(&user, &db).await {
         Ok(room_response) => room_response,
         Err(e) => return Err(reject::custom(e)),
     };
+    let two_factor_state: TwoFactorState = user.two_factor_setup();
     let reply: warp::reply::Json = warp::reply::json(&json!(&UserWhoamiResponse {
         ok: true,
         message: Option::default(),
@@ -1317,44 +4369,146 @@ pub async fn user_totp_login_handler(body: UserTotpRequest, mut db: DB) -> WebRe
         in_room: room_response,
         solved: user.solved,
         rooms_entered: user.rooms_entered,
-        jwt,
+        jwt: jwt.clone(),
+        refresh_token,
+        active_sessions: Option::default(),
         totp: Option::default(),
         recovery_keys: Option::default(),
         configured_2fa,
+        two_factor_state,
     }));
-    Ok(warp::reply::with_status(reply, StatusCode::OK))
+    Ok(with_jwt_cookie(
+        warp::reply::with_status(reply, StatusCode::OK),
+        &jwt,
+    ))
+}
+
+/// Rejects logins to accounts locked after too many failed attempts.
+fn check_not_locked(user: &User, now: DateTime<Utc>) -> Result<()> {
+    match user.locked_until {
+        Some(locked_until) if user.is_locked(now) => Err(Error::AccountLockedError(locked_until)),
+        _ => Ok(()),
+    }
+}
+
+/// Counts a wrong password or TOTP towards the lockout, logs it as `event`
+/// and returns the rejection for it.
+async fn failed_login(
+    db: &DB,
+    username: &String,
+    event: AuthEvent,
+    client: &ClientInfo,
+    config: &Config,
+) -> Rejection {
+    let error_kind: &str = match event {
+        AuthEvent::SecondFactorFailed => "wrong_totp",
+        _ => "wrong_credentials",
+    };
+    log_auth_event(event, username, client, Some(error_kind), None);
+    match db
+        .register_failed_login(
+            username,
+            config.login_max_failed_attempts,
+            chrono::Duration::minutes(config.login_lockout_minutes),
+        )
+        .await
+    {
+        Ok(true) => log_auth_event(AuthEvent::AccountLocked, username, client, None, None),
+        Ok(false) => (),
+        Err(e) => log::error!("Error: recording failed login failed: {}", &e),
+    }
+    reject::custom(Error::WrongCredentialsError)
 }
 
-pub async fn user_login_handler(body: UserLoginRequest, mut db: DB) -> WebResult<impl Reply> {
+pub async fn user_login_handler(
+    body: UserLoginRequest,
+    mut db: DB,
+    client: ClientInfo,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
     log::info!("user_login_handler(); username = {}", &body.username);
     let user: User = match db.get_user(&body.username).await {
         Ok(user) => user,
+        Err(Error::UserNotFoundError) => {
+            // indistinguishable from a wrong password, also in timing
+            Password::dummy_verify(&body.password);
+            log_auth_event(
+                AuthEvent::LoginFailed,
+                &body.username,
+                &client,
+                Some("unknown_user"),
+                None,
+            );
+            return Err(reject::custom(Error::WrongCredentialsError));
+        }
         Err(e) => return Err(reject::custom(e)),
     };
-    log::info!("got user: {:?}", &user);
+    log::info!("got user {}", &user.username);
+    match check_not_locked(&user, db.clock.now()) {
+        Ok(()) => (),
+        Err(e) => {
+            log_auth_event(
+                AuthEvent::LoginFailed,
+                &user.username,
+                &client,
+                Some("account_locked"),
+                None,
+            );
+            return Err(reject::custom(e));
+        }
+    }
     let matches: bool = match Password::matches(&user.hash, &body.password) {
         Ok(matches) => matches,
         Err(_) => return Err(reject::custom(Error::HashingError)),
     };
     if !matches {
-        return Err(reject::custom(Error::WrongCredentialsError));
+        return Err(failed_login(
+            &db,
+            &user.username,
+            AuthEvent::LoginFailed,
+            &client,
+            &config,
+        )
+        .await);
     }
     log::info!("Hashes match.");
+    // checked after the password so as not to tell strangers about the account
+    if !user.activated {
+        log_auth_event(
+            AuthEvent::LoginFailed,
+            &user.username,
+            &client,
+            Some("not_activated"),
+            None,
+        );
+        return Err(reject::custom(Error::AccountNotActivatedError));
+    }
     let mut configured_2fa: Vec<SecondFactor> = Vec::new();
     let mut authenticated = true;
-    if user.totp_key.len() > 0 {
+    if user.has_totp() {
         // if the TOTP is sent along the usual credentials, check if TOTP is correct
         if let Some(totp) = body.totp {
-            let seconds: u64 = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            authenticated = match totp == totp_custom::<Sha1>(30, 6, &user.totp_key, seconds) {
+            authenticated = match totp_matches(&user.totp_keys, &totp, db.clock.now()) {
                 true => {
-                    log::info!("TOTPs match");
+                    log_auth_event(
+                        AuthEvent::SecondFactorSucceeded,
+                        &user.username,
+                        &client,
+                        None,
+                        Some("TOTP"),
+                    );
                     true
                 }
-                false => return Err(reject::custom(Error::WrongCredentialsError)),
+                false => {
+                    return Err(failed_login(
+                        &db,
+                        &user.username,
+                        AuthEvent::SecondFactorFailed,
+                        &client,
+                        &config,
+                    )
+                    .await)
+                }
             }
         } else {
             authenticated = false;
@@ -1378,46 +4532,73 @@ pub async fn user_login_handler(body: UserLoginRequest, mut db: DB) -> WebResult
             Ok(()) => (),
             Err(e) => return Err(reject::custom(e)),
         }
-        let jwt: Option<String> = match auth::create_jwt(&user.username, &user.role) {
+        log_auth_event(
+            AuthEvent::LoginSucceeded,
+            &user.username,
+            &client,
+            None,
+            None,
+        );
+        let jwt: Option<String> = match auth::create_jwt(
+            &user.username,
+            &user.role,
+            user.token_version,
+            db.clock.now(),
+        ) {
             Ok(jwt) => Some(jwt),
             Err(e) => return Err(reject::custom(e)),
         };
-        let in_room: bson::oid::ObjectId = match user.in_room {
-            Some(room) => room,
-            None => return Err(reject::custom(Error::UserIsInNoRoom)),
-        };
-        let room_response: RoomResponse = match get_room_by_id(&in_room, &db).await {
+        let refresh_token: Option<String> =
+            match issue_refresh_token(&db, &user.username, None, &config).await {
+                Ok(refresh_token) => refresh_token,
+                Err(e) => return Err(reject::custom(e)),
+            };
+        let room_response: Option<RoomResponse> = match get_user_room(&user, &db).await {
             Ok(room_response) => room_response,
             Err(e) => return Err(reject::custom(e)),
         };
-        let reply: warp::reply::Json = warp::reply::json(&json!(&UserWhoamiResponse {
-            ok: true,
-            message: Option::default(),
-            username: user.username.clone(),
-            email: user.email.clone(),
-            role: user.role.clone(),
-            activated: user.activated,
-            created: user.created,
-            registered: user.registered,
-            last_login: user.last_login,
-            level: user.level,
-            score: user.score,
-            in_room: room_response,
-            solved: user.solved,
-            rooms_entered: user.rooms_entered,
-            jwt,
-            totp: Option::default(),
-            recovery_keys: Option::default(),
-            configured_2fa,
-        }));
-        Ok(warp::reply::with_status(reply, StatusCode::OK))
+        let two_factor_state: TwoFactorState = user.two_factor_setup();
+        let reply: warp::reply::Json = login_reply(
+            LoginResponse::Ok(UserWhoamiResponse {
+                ok: true,
+                message: Option::default(),
+                username: user.username.clone(),
+                email: user.email.clone(),
+                role: user.role.clone(),
+                activated: user.activated,
+                created: user.created,
+                registered: user.registered,
+                last_login: user.last_login,
+                level: user.level,
+                score: user.score,
+                in_room: room_response,
+                solved: user.solved,
+                rooms_entered: user.rooms_entered,
+                jwt: jwt.clone(),
+                refresh_token,
+                active_sessions: Option::default(),
+                totp: Option::default(),
+                recovery_keys: Option::default(),
+                configured_2fa,
+                two_factor_state,
+            }),
+            &config,
+        );
+        Ok(with_jwt_cookie(
+            warp::reply::with_status(reply, StatusCode::OK),
+            &jwt,
+        ))
     } else {
-        let reply: warp::reply::Json = warp::reply::json(&json!(&MFARequiredResponse {
-            ok: false,
-            message: Some("second factor required".to_string()),
-            configured_2fa
-        }));
-        Ok(warp::reply::with_status(reply, StatusCode::OK))
+        let reply: warp::reply::Json = login_reply(
+            LoginResponse::MfaRequired(MFARequiredResponse {
+                ok: false,
+                message: Some("second factor required".to_string()),
+                configured_2fa,
+                two_factor_state: TwoFactorState::AwaitingSecondFactor,
+            }),
+            &config,
+        );
+        Ok(warp::reply::with_status(reply, StatusCode::OK).into_response())
     }
 }
 
@@ -1425,30 +4606,57 @@ fn generate_otp_qrcode(username: &String, totp_key: &Vec<u8>) -> Result<(String,
     let b32_otp_secret: String =
         base32::encode(base32::Alphabet::RFC4648 { padding: false }, totp_key);
     let otp_str = format!(
-        "otpauth://totp/{}: {}?secret={}&issuer={}",
+        "otpauth://totp/{}: {}?secret={}&issuer={}&period={}&digits={}",
         env!("CARGO_PKG_NAME"),
         username,
         b32_otp_secret,
         env!("CARGO_PKG_NAME"),
+        TOTP_PARAMS.interval,
+        TOTP_PARAMS.digits,
     );
-    let totp_qrcode: Vec<u8> =
-        match qrcode_generator::to_png_to_vec(&otp_str, QrCodeEcc::Medium, 256) {
-            Ok(code) => code,
-            Err(_) => return Err(Error::TotpQrCodeGenerationError),
-        };
+    // Byte-mode capacity of the largest QR code version (40) per error
+    // correction level. Fall back to a lower error correction level (and a
+    // larger image) before giving up on overly long URIs.
+    const QR_MAX_BYTES_ECC_MEDIUM: usize = 2331;
+    const QR_MAX_BYTES_ECC_LOW: usize = 2953;
+    let (ecc, size) = match otp_str.len() {
+        len if len <= QR_MAX_BYTES_ECC_MEDIUM => (QrCodeEcc::Medium, 256),
+        len if len <= QR_MAX_BYTES_ECC_LOW => (QrCodeEcc::Low, 512),
+        len => {
+            log::error!("otpauth URI too long for a QR code ({} bytes)", len);
+            return Err(Error::TotpUriTooLongError);
+        }
+    };
+    let totp_qrcode: Vec<u8> = match qrcode_generator::to_png_to_vec(&otp_str, ecc, size) {
+        Ok(code) => code,
+        Err(_) => return Err(Error::TotpQrCodeGenerationError),
+    };
     Ok((b32_otp_secret, totp_qrcode))
 }
 
-pub async fn user_totp_disable_handler(username: String, db: DB) -> WebResult<impl Reply> {
+/// Removes all TOTP authenticators. Requires the password and a current
+/// code even though the request carries a valid JWT.
+pub async fn user_totp_disable_handler(
+    username: String,
+    body: UserTotpStepUpRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
     log::info!("user_totp_disable_handler(); username = {}", &username);
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    match verify_step_up(&user, &body.password, &body.totp, db.clock.now()) {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
     match db
         .get_users_coll()
         .update_one(
             doc! { "username": username.clone(), "activated": true },
             doc! {
-                "$unset": {
-                    "totp_key": 0,
-                },
+                "$set": { "totp_keys": [] },
+                "$unset": { "pending_totp_key": "" },
             },
             None,
         )
@@ -1456,6 +4664,7 @@ pub async fn user_totp_disable_handler(username: String, db: DB) -> WebResult<im
     {
         Ok(_) => {
             log::info!("Updated {}.", &username);
+            db.invalidate_user(&username);
         }
         Err(e) => {
             log::info!("Error: update failed ({:?})", &e);
@@ -1469,29 +4678,117 @@ pub async fn user_totp_disable_handler(username: String, db: DB) -> WebResult<im
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
-pub async fn user_totp_enable_handler(username: String, db: DB) -> WebResult<impl Reply> {
-    log::info!("user_totp_enable_handler(); username = {}", &username);
+/// Replaces all TOTP authenticators with a new one. Requires the password
+/// and, if the user has TOTP configured, a current code. Refused if the
+/// user already has an authenticator, unless forced. The new authenticator only takes effect once confirmed
+/// via `user_totp_confirm_handler`; until then the old ones stay live.
+pub async fn user_totp_enable_handler(
+    username: String,
+    query: TotpEnableQuery,
+    body: UserTotpStepUpRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "user_totp_enable_handler(); username = {}, force = {}",
+        &username,
+        query.force
+    );
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    if user.has_totp() && !query.force {
+        return Err(reject::custom(Error::TotpAlreadyEnabledError));
+    }
+    match verify_step_up(&user, &body.password, &body.totp, db.clock.now()) {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let totp_key: Vec<u8> = rand::thread_rng().gen::<[u8; 32]>().to_vec();
+    let secret: TotpSecret = TotpSecret::new("default", totp_key.clone(), db.clock.now());
+    match db.set_pending_totp(&username, &secret).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let (secret, totp_qrcode) = match generate_otp_qrcode(&username, &totp_key) {
+        Ok((secret, qrcode)) => (secret, qrcode),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&TotpResponse {
+        ok: true,
+        message: Option::default(),
+        totp: TotpResponseRaw::new(totp_qrcode, secret),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Proves that the authenticator set up by `user_totp_enable_handler` works
+/// before making it live.
+pub async fn user_totp_confirm_handler(
+    username: String,
+    mut body: UserTotpConfirmRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
+    let totp: String = body.totp.clone();
+    body.totp = "******".to_string();
+    log::info!(
+        "user_totp_confirm_handler(); username = {}, body = {:?}",
+        &username,
+        &body
+    );
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let pending: TotpSecret = match user.pending_totp_key {
+        Some(pending) => pending,
+        None => return Err(reject::custom(Error::TotpNotPendingError)),
+    };
+    if !totp_matches(&vec![pending.clone()], &totp, db.clock.now()) {
+        return Err(reject::custom(Error::WrongCredentialsError));
+    }
+    match db.confirm_totp(&username, &pending).await {
+        Ok(true) => (),
+        Ok(false) => return Err(reject::custom(Error::TotpNotPendingError)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Registers an additional TOTP authenticator under the given name.
+pub async fn user_totp_add_handler(
+    username: String,
+    body: UserTotpChangeRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "user_totp_add_handler(); username = {}, name = {}",
+        &username,
+        &body.name
+    );
+    let user: User = match db.get_user(&username).await {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    match verify_step_up(&user, &body.password, &body.totp, db.clock.now()) {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
     let totp_key: Vec<u8> = rand::thread_rng().gen::<[u8; 32]>().to_vec();
     match db
-        .get_users_coll()
-        .update_one(
-            doc! { "username": username.clone() },
-            doc! {
-                "$set": {
-                    "totp_key": base64::encode(&totp_key),
-                },
-            },
-            None,
+        .add_totp_secret(
+            &username,
+            &TotpSecret::new(&body.name, totp_key.clone(), db.clock.now()),
         )
         .await
     {
-        Ok(_) => {
-            log::info!("Updated {}.", &username);
-        }
-        Err(e) => {
-            log::info!("Error: update failed ({:?})", &e);
-            return Err(reject::custom(Error::MongoQueryError(e)));
-        }
+        Ok(true) => (),
+        Ok(false) => return Err(reject::custom(Error::TotpNameTakenError)),
+        Err(e) => return Err(reject::custom(e)),
     }
     let (secret, totp_qrcode) = match generate_otp_qrcode(&username, &totp_key) {
         Ok((secret, qrcode)) => (secret, qrcode),
@@ -1505,47 +4802,330 @@ pub async fn user_totp_enable_handler(username: String, db: DB) -> WebResult<imp
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
-pub async fn user_activation_handler(
-    body: UserActivationRequest,
-    mut db: DB,
+/// Removes one of the user's TOTP authenticators, leaving the others.
+pub async fn user_totp_remove_handler(
+    username: String,
+    body: UserTotpChangeRequest,
+    db: DB,
 ) -> WebResult<impl Reply> {
     log::info!(
-        "user_activation_handler(); username = {}; pin = {}",
-        &body.username,
-        &body.pin
+        "user_totp_remove_handler(); username = {}, name = {}",
+        &username,
+        &body.name
     );
-    let mut user: User = match db.get_user_with_pin(&body.username, body.pin).await {
+    let user: User = match db.get_user(&username).await {
         Ok(user) => user,
         Err(e) => return Err(reject::custom(e)),
     };
-    match db.activate_user(&mut user).await {
+    match verify_step_up(&user, &body.password, &body.totp, db.clock.now()) {
         Ok(()) => (),
         Err(e) => return Err(reject::custom(e)),
+    }
+    match db.remove_totp_secret(&username, &body.name).await {
+        Ok(true) => (),
+        Ok(false) => return Err(reject::custom(Error::TotpNotFoundError)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Minimum number of seconds between two mails with the same activation PIN.
+const PIN_RESEND_INTERVAL_SECS: i64 = 60;
+
+/// Mails the activation PIN of a not yet activated account again. Replies
+/// the same whether or not there is such an account, and whether or not
+/// the mail has been held back because the last one went out too recently.
+pub async fn user_activation_resend_handler(
+    body: UserActivationResendRequest,
+    db: DB,
+) -> WebResult<impl Reply> {
+    log::info!("user_activation_resend_handler(); body = {:?}", &body);
+    let email: String = email::normalize(&body.username);
+    let sent_before: DateTime<Utc> =
+        db.clock.now() - chrono::Duration::seconds(PIN_RESEND_INTERVAL_SECS);
+    let user: Option<User> = match db
+        .claim_pin_resend(&body.username, &email, sent_before, |user| {
+            pin_mail(&db, user)
+        })
+        .await
+    {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
     };
-    let in_room: bson::oid::ObjectId = match user.in_room {
-        Some(room) => room,
-        None => return Err(reject::custom(Error::UserIsInNoRoom)),
-    };
-    let room_response: RoomResponse = match get_room_by_id(&in_room, &db).await {
+    match user {
+        Some(user) => log::info!(
+            "Mail with PIN queued again for {} <{}>.",
+            user.username,
+            user.email
+        ),
+        None => log::info!(
+            "No pending activation for {} or PIN sent too recently.",
+            &body.username
+        ),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn user_activation_handler(
+    body: UserActivationRequest,
+    mut db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!(
+        "user_activation_handler(); username = {}; pin = {}",
+        &body.username,
+        &body.pin
+    );
+    let (mut user, replayed): (User, bool) =
+        match db.get_user_with_pin(&body.username, body.pin).await {
+            Ok(user) => (user, false),
+            // the response to an earlier activation may have been lost
+            Err(Error::UserNotFoundError) => match db
+                .get_activated_user_with_pin(
+                    &body.username,
+                    body.pin,
+                    db.clock.now() - chrono::Duration::minutes(config.activation_replay_minutes),
+                )
+                .await
+            {
+                Ok(user) => (user, true),
+                Err(e) => return Err(reject::custom(e)),
+            },
+            Err(e) => return Err(reject::custom(e)),
+        };
+    if replayed {
+        log::info!("{} has already been activated, replaying.", &user.username);
+    } else {
+        match db.activate_user(&mut user).await {
+            Ok(()) => (),
+            Err(e) => return Err(reject::custom(e)),
+        };
+    }
+    let room_response: Option<RoomResponse> = match get_user_room(&user, &db).await {
         Ok(room_response) => room_response,
         Err(e) => return Err(reject::custom(e)),
     };
     let mut configured_2fa: Vec<SecondFactor> = Vec::new();
-    let jwt: Option<String> = match auth::create_jwt(&user.username, &user.role) {
+    let jwt: Option<String> = match auth::create_jwt(
+        &user.username,
+        &user.role,
+        user.token_version,
+        db.clock.now(),
+    ) {
         Ok(jwt) => Some(jwt),
         Err(e) => return Err(reject::custom(e)),
     };
-    let totp = match user.totp_key.is_empty() {
-        true => Option::default(),
-        false => {
+    let refresh_token: Option<String> =
+        match issue_refresh_token(&db, &user.username, None, &config).await {
+            Ok(refresh_token) => refresh_token,
+            Err(e) => return Err(reject::custom(e)),
+        };
+    let totp = match user.totp_keys.first().filter(|_| user.has_totp()) {
+        None => Option::default(),
+        Some(totp_secret) => {
             configured_2fa.push(SecondFactor::Totp);
-            let (secret, totp_qrcode) = match generate_otp_qrcode(&user.username, &user.totp_key) {
+            let (secret, totp_qrcode) = match generate_otp_qrcode(&user.username, &totp_secret.key)
+            {
                 Ok((secret, qrcode)) => (secret, qrcode),
                 Err(e) => return Err(reject::custom(e)),
             };
             Some(TotpResponseRaw::new(totp_qrcode, secret))
         }
     };
+    let two_factor_state: TwoFactorState = user.two_factor_setup();
+    let reply: warp::reply::Json = warp::reply::json(&json!(&UserWhoamiResponse {
+        ok: true,
+        message: Option::default(),
+        username: user.username.clone(),
+        email: user.email.clone(),
+        role: user.role.clone(),
+        activated: user.activated,
+        created: user.created,
+        registered: user.registered,
+        last_login: user.last_login,
+        level: user.level,
+        score: user.score,
+        in_room: room_response,
+        solved: user.solved,
+        rooms_entered: user.rooms_entered,
+        jwt: jwt.clone(),
+        refresh_token,
+        active_sessions: Option::default(),
+        totp,
+        // recovery keys are handed out only once
+        recovery_keys: match replayed {
+            true => Option::default(),
+            false => Some(user.recovery_keys),
+        },
+        configured_2fa,
+        two_factor_state,
+    }));
+    Ok(with_jwt_cookie(
+        warp::reply::with_status(reply, StatusCode::OK),
+        &jwt,
+    ))
+}
+
+/// Checks if `recovery_key` is one of the user's unused recovery keys
+/// without consuming it. Unknown users get the same negative answer as
+/// wrong keys so that the endpoint can't be used to enumerate accounts.
+pub async fn user_recovery_validate_handler(
+    mut body: UserRecoveryValidateRequest,
+    db: DB,
+    limiter: SharedRateLimiter,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    let recovery_key: String = body.recovery_key.trim().to_lowercase();
+    body.recovery_key = "******".to_string();
+    log::info!("user_recovery_validate_handler(); body = {:?}", &body);
+    if !limiter.lock().unwrap().check(client.ip(), &body.username) {
+        return Err(reject::custom(Error::TooManyRequestsError));
+    }
+    let recovery_keys: Vec<String> = match db.get_user(&body.username).await {
+        Ok(user) => user.recovery_keys,
+        Err(Error::UserNotFoundError) => Vec::new(),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let valid: bool = recovery_keys.iter().fold(false, |found, key| {
+        found | (key.to_lowercase() == recovery_key)
+    });
+    let reply: warp::reply::Json = warp::reply::json(&json!(&RecoveryKeyValidResponse {
+        ok: true,
+        message: Option::default(),
+        valid,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Logs the user in with one of the recovery keys handed out on
+/// activation, e.g. after losing the TOTP device or FIDO2 key. The key
+/// stands in for the second factor only: the password must be sent along
+/// unless it was checked already by a login that awaits the second factor.
+/// The key is removed in the same update that checks it, so it can't be
+/// used twice.
+pub async fn user_recovery_handler(
+    mut body: UserRecoveryRequest,
+    mut db: DB,
+    limiter: SharedRateLimiter,
+    client: ClientInfo,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    let recovery_key: String = body.recovery_key.trim().to_lowercase();
+    body.recovery_key = "******".to_string();
+    let password: Option<String> = body.password.take();
+    log::info!("user_recovery_handler(); body = {:?}", &body);
+    if !limiter.lock().unwrap().check(client.ip(), &body.username) {
+        return Err(reject::custom(Error::TooManyRequestsError));
+    }
+    let user: User = match db.get_user(&body.username).await {
+        Ok(user) => user,
+        Err(Error::UserNotFoundError) => {
+            if let Some(password) = &password {
+                Password::dummy_verify(password);
+            }
+            log_auth_event(
+                AuthEvent::LoginFailed,
+                &body.username,
+                &client,
+                Some("unknown_user"),
+                None,
+            );
+            return Err(reject::custom(Error::WrongCredentialsError));
+        }
+        Err(e) => return Err(reject::custom(e)),
+    };
+    match check_not_locked(&user, db.clock.now()) {
+        Ok(()) => (),
+        Err(e) => {
+            log_auth_event(
+                AuthEvent::LoginFailed,
+                &user.username,
+                &client,
+                Some("account_locked"),
+                None,
+            );
+            return Err(reject::custom(e));
+        }
+    }
+    let first_factor_passed: bool = match &password {
+        Some(password) => match Password::matches(&user.hash, password) {
+            Ok(matches) => matches,
+            Err(_) => return Err(reject::custom(Error::HashingError)),
+        },
+        None => user.awaiting_second_factor,
+    };
+    if !first_factor_passed {
+        return Err(failed_login(
+            &db,
+            &user.username,
+            AuthEvent::LoginFailed,
+            &client,
+            &config,
+        )
+        .await);
+    }
+    if !user.activated {
+        return Err(reject::custom(Error::AccountNotActivatedError));
+    }
+    match db.consume_recovery_key(&user.username, &recovery_key).await {
+        Ok(true) => (),
+        Ok(false) => {
+            return Err(failed_login(
+                &db,
+                &user.username,
+                AuthEvent::SecondFactorFailed,
+                &client,
+                &config,
+            )
+            .await)
+        }
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.login_user(&user).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    log_auth_event(
+        AuthEvent::LoginSucceeded,
+        &user.username,
+        &client,
+        None,
+        Some("recovery key"),
+    );
+    let jwt: Option<String> = match auth::create_jwt(
+        &user.username,
+        &user.role,
+        user.token_version,
+        db.clock.now(),
+    ) {
+        Ok(jwt) => Some(jwt),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let refresh_token: Option<String> =
+        match issue_refresh_token(&db, &user.username, None, &config).await {
+            Ok(refresh_token) => refresh_token,
+            Err(e) => return Err(reject::custom(e)),
+        };
+    let room_response: Option<RoomResponse> = match get_user_room(&user, &db).await {
+        Ok(room_response) => room_response,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let mut configured_2fa: Vec<SecondFactor> = Vec::new();
+    if user.has_totp() {
+        configured_2fa.push(SecondFactor::Totp);
+    }
+    if !user.webauthn.credentials.is_empty() {
+        configured_2fa.push(SecondFactor::Fido2);
+    }
+    let two_factor_state: TwoFactorState = user.two_factor_setup();
     let reply: warp::reply::Json = warp::reply::json(&json!(&UserWhoamiResponse {
         ok: true,
         message: Option::default(),
@@ -1561,19 +5141,247 @@ pub async fn user_activation_handler(
         in_room: room_response,
         solved: user.solved,
         rooms_entered: user.rooms_entered,
-        jwt,
-        totp,
-        recovery_keys: Some(user.recovery_keys),
-        configured_2fa
+        jwt: jwt.clone(),
+        refresh_token,
+        active_sessions: Option::default(),
+        totp: Option::default(),
+        recovery_keys: Option::default(),
+        configured_2fa,
+        two_factor_state,
+    }));
+    Ok(with_jwt_cookie(
+        warp::reply::with_status(reply, StatusCode::OK),
+        &jwt,
+    ))
+}
+
+/// Tells which second factors a user has configured, so that login
+/// forms can ask for the right one up front. Unknown users get an empty
+/// list, just like users without 2FA, to prevent account enumeration.
+pub async fn user_2fa_methods_handler(
+    username: String,
+    db: DB,
+    limiter: SharedRateLimiter,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    log::info!("user_2fa_methods_handler(); username = {}", &username);
+    if !limiter.lock().unwrap().check(client.ip(), &username) {
+        return Err(reject::custom(Error::TooManyRequestsError));
+    }
+    let mut configured_2fa: Vec<SecondFactor> = Vec::new();
+    let mut two_factor_state: TwoFactorState = TwoFactorState::None;
+    match db.get_user(&username).await {
+        Ok(user) => {
+            // a pending TOTP isn't used for logging in, and telling it
+            // apart from no 2FA would give away that the account exists
+            two_factor_state = match user.two_factor_setup() {
+                TwoFactorState::TotpPending => TwoFactorState::None,
+                state => state,
+            };
+            if user.has_totp() {
+                configured_2fa.push(SecondFactor::Totp);
+            }
+            if !user.webauthn.credentials.is_empty() {
+                configured_2fa.push(SecondFactor::Fido2);
+            }
+        }
+        Err(Error::UserNotFoundError) => (),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reply: warp::reply::Json = warp::reply::json(&json!(&MFARequiredResponse {
+        ok: true,
+        message: Option::default(),
+        configured_2fa,
+        two_factor_state,
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+/// Upper bound of the body of `POST /user/refresh`, which carries at most
+/// a refresh token.
+const REFRESH_BODY_LIMIT: u64 = 4 * 1024;
+
+/// Issues a fresh JWT, so that long sessions don't lapse mid-game. If
+/// refresh tokens are enabled, the body must carry one (see
+/// `refresh_with_token()`); otherwise the request must carry a valid JWT
+/// (see `refresh_with_jwt()`). Both share a single route, so that which one
+/// applies doesn't depend on the order routes are tried in.
+pub async fn user_refresh_handler(
+    headers: HeaderMap<HeaderValue>,
+    body: hyper::body::Bytes,
+    db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!("user_refresh_handler()");
+    let result: Result<UserRefreshResponse> = match auth::refresh_tokens_enabled() {
+        true => match serde_json::from_slice::<UserRefreshRequest>(&body) {
+            Ok(body) => refresh_with_token(&body, &db, &config).await,
+            Err(_) => Err(Error::JWTTokenError),
+        },
+        false => {
+            let username: String = match auth::authorize((Role::User, headers, db.clone())).await {
+                Ok(username) => username,
+                Err(e) => return Err(e),
+            };
+            refresh_with_jwt(&username, &db).await
+        }
+    };
+    let response: UserRefreshResponse = match result {
+        Ok(response) => response,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let jwt: Option<String> = response.jwt.clone();
+    let reply: warp::reply::Json = warp::reply::json(&json!(&response));
+    Ok(with_jwt_cookie(
+        warp::reply::with_status(reply, StatusCode::OK),
+        &jwt,
+    ))
+}
+
+/// Issues a fresh JWT to a user holding a valid one.
+async fn refresh_with_jwt(username: &String, db: &DB) -> Result<UserRefreshResponse> {
+    let user: User = match db.get_user(username).await {
+        Ok(user) => user,
+        Err(e) => return Err(e),
+    };
+    if !user.activated {
+        return Err(Error::UserNotFoundError);
+    }
+    let jwt: String = match auth::create_jwt(
+        &user.username,
+        &user.role,
+        user.token_version,
+        db.clock.now(),
+    ) {
+        Ok(jwt) => jwt,
+        Err(e) => return Err(e),
+    };
+    Ok(UserRefreshResponse {
+        ok: true,
+        message: Option::default(),
+        jwt: Some(jwt),
+        refresh_token: Option::default(),
+    })
+}
+
+/// Revokes all JWTs and refresh tokens of the user, on every device.
+pub async fn user_logout_handler(
+    username: String,
+    db: DB,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    log::info!("user_logout_handler(); username = {}", &username);
+    match db.increment_token_version(&username).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.revoke_refresh_tokens_of_user(&username).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    log_auth_event(AuthEvent::LoggedOut, &username, &client, None, None);
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
     }));
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
+/// Exchanges a refresh token for a new JWT and a new refresh token.
+/// Presenting a refresh token a second time means that it has been
+/// stolen, so all tokens of its family are revoked.
+/// How `refresh_with_token()` treats a presented refresh token.
+#[derive(Debug, PartialEq)]
+enum RefreshOutcome {
+    /// issue a new JWT and the next token of the family
+    Rotate,
+    /// the token had been used before, so it has been stolen or replayed;
+    /// the whole family is revoked
+    Reused,
+    Expired,
+}
+
+/// Decides about a refresh token. `claimed` tells if this request was the
+/// one to mark it as used. Reuse is checked first, so that replaying even
+/// an expired token revokes its family.
+fn refresh_outcome(token: &RefreshToken, claimed: bool, now: DateTime<Utc>) -> RefreshOutcome {
+    if !claimed {
+        RefreshOutcome::Reused
+    } else if token.expires < now {
+        RefreshOutcome::Expired
+    } else {
+        RefreshOutcome::Rotate
+    }
+}
+
+async fn refresh_with_token(
+    body: &UserRefreshRequest,
+    db: &DB,
+    config: &Config,
+) -> Result<UserRefreshResponse> {
+    let digest: String = auth::refresh_token_digest(&body.refresh_token);
+    let token: RefreshToken = match db.get_refresh_token(&digest).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return Err(Error::JWTTokenError),
+        Err(e) => return Err(e),
+    };
+    let claimed: bool = match token.used {
+        true => false,
+        false => match db.use_refresh_token(&digest).await {
+            Ok(claimed) => claimed,
+            Err(e) => return Err(e),
+        },
+    };
+    match refresh_outcome(&token, claimed, db.clock.now()) {
+        RefreshOutcome::Rotate => (),
+        RefreshOutcome::Reused => {
+            log::warn!(
+                "refresh token of user {} has been reused, revoking family {}",
+                &token.username,
+                &token.family
+            );
+            match db.revoke_refresh_token_family(&token.family).await {
+                Ok(()) => (),
+                Err(e) => return Err(e),
+            }
+            return Err(Error::RefreshTokenReusedError);
+        }
+        RefreshOutcome::Expired => return Err(Error::JWTTokenError),
+    }
+    let user: User = match db.get_user(&token.username).await {
+        Ok(user) => user,
+        Err(e) => return Err(e),
+    };
+    let jwt: String = match auth::create_jwt(
+        &user.username,
+        &user.role,
+        user.token_version,
+        db.clock.now(),
+    ) {
+        Ok(jwt) => jwt,
+        Err(e) => return Err(e),
+    };
+    let refresh_token: Option<String> =
+        match issue_refresh_token(db, &user.username, Some(token.family), config).await {
+            Ok(refresh_token) => refresh_token,
+            Err(e) => return Err(e),
+        };
+    Ok(UserRefreshResponse {
+        ok: true,
+        message: Option::default(),
+        jwt: Some(jwt),
+        refresh_token,
+    })
+}
+
 pub async fn user_password_change_handler(
     username: String,
     mut body: UserPasswordChangeRequest,
     mut db: DB,
+    config: Arc<Config>,
+    client: ClientInfo,
 ) -> WebResult<impl Reply> {
+    let requester: String = username.clone();
     let password: String = body.password;
     let username: String = match body.username.clone() {
         Some(username) => username,
@@ -1584,7 +5392,7 @@ pub async fn user_password_change_handler(
     if password.len() < 8 {
         return Err(reject::custom(Error::PasswordTooShortError));
     }
-    let password_is_bad = match is_bad_password(&password) {
+    let password_is_bad = match is_bad_password(&password, &config.bad_passwords_md5) {
         Ok(bad) => bad,
         Err(_) => false, // soft fail
     };
@@ -1602,6 +5410,171 @@ pub async fn user_password_change_handler(
         Ok(()) => (),
         Err(e) => return Err(reject::custom(e)),
     }
+    match db.revoke_refresh_tokens_of_user(&username).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    log_auth_event(
+        AuthEvent::PasswordChanged,
+        &username,
+        &client,
+        None,
+        Some(&format!("by {}", requester)),
+    );
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+fn password_reset_mail(
+    db: &DB,
+    user: &User,
+    token: &String,
+    expires: &DateTime<Utc>,
+) -> Result<OutboxMail> {
+    let to = match format!("{} <{}>", user.username, user.email).parse() {
+        Ok(to) => to,
+        Err(_) => return Err(Error::MalformedAddressError),
+    };
+    let email: lettre::Message = match Message::builder()
+        .header(lettre::message::header::ContentType::TEXT_PLAIN)
+        .from(
+            "Labyrinth Mailer <nirwana@raetselonkel.de>"
+                .parse()
+                .unwrap(),
+        )
+        .to(to)
+        .date_now()
+        .subject("Dein neues Passwort für Labyrinth")
+        .body(format!(
+            r#"Moin {}!
+
+Jemand (hoffentlich du) möchte das Passwort deines Labyrinth-Accounts zurücksetzen.
+
+Dein Code zum Zurücksetzen: {}
+
+Der Code ist gültig bis {} UTC und kann nur einmal verwendet werden.
+
+Viele Grüße,
+Dein Rätselonkel
+
+
+*** Falls du dein Passwort nicht zurücksetzen möchtest, kannst du diese Mail getrost ignorieren ;-)"#,
+            user.username,
+            token,
+            expires.format("%d.%m.%Y %H:%M")
+        )) {
+        Ok(email) => email,
+        Err(_) => return Err(Error::MailBuilderError),
+    };
+    outbox::render(&email, db.clock.now())
+}
+
+/// Mails a password reset token to the given address. Replies the same
+/// whether or not there is an account with that address.
+pub async fn user_password_reset_request_handler(
+    mut body: UserPasswordResetRequest,
+    db: DB,
+    limiter: SharedRateLimiter,
+    client: ClientInfo,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
+    log::info!("user_password_reset_request_handler(); body = {:?}", &body);
+    body.email = email::normalize(&body.email);
+    if !limiter.lock().unwrap().check(client.ip(), &body.email) {
+        return Err(reject::custom(Error::TooManyRequestsError));
+    }
+    let (token, digest) = auth::create_password_reset_token();
+    let reset: PasswordReset = PasswordReset {
+        digest,
+        expires: db.clock.now() + chrono::Duration::minutes(config.password_reset_token_minutes),
+        used: false,
+    };
+    let user: Option<User> = match db
+        .set_password_reset(&body.email, &reset, |user| {
+            password_reset_mail(&db, user, &token, &reset.expires)
+        })
+        .await
+    {
+        Ok(user) => user,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    match user {
+        Some(user) => log::info!(
+            "Mail with password reset token queued for {} <{}>.",
+            user.username,
+            user.email
+        ),
+        None => log::info!("No activated user with email {}.", &body.email),
+    }
+    let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
+        ok: true,
+        message: Option::default(),
+    }));
+    Ok(warp::reply::with_status(reply, StatusCode::OK))
+}
+
+pub async fn user_password_reset_confirm_handler(
+    mut body: UserPasswordResetConfirmRequest,
+    mut db: DB,
+    config: Arc<Config>,
+    client: ClientInfo,
+) -> WebResult<impl Reply> {
+    let token: String = body.token;
+    let password: String = body.password;
+    body.token = "******".to_string();
+    body.password = "******".to_string();
+    log::info!("user_password_reset_confirm_handler(); body = {:?}", &body);
+    if password.len() < 8 {
+        return Err(reject::custom(Error::PasswordTooShortError));
+    }
+    let password_is_bad = match is_bad_password(&password, &config.bad_passwords_md5) {
+        Ok(bad) => bad,
+        Err(_) => false, // soft fail
+    };
+    if password_is_bad {
+        return Err(reject::custom(Error::UnsafePasswordError));
+    }
+    let digest: String = auth::refresh_token_digest(token.trim());
+    let user: User = match db.get_user_by_password_reset(&digest).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(reject::custom(Error::WrongCredentialsError)),
+        Err(e) => return Err(reject::custom(e)),
+    };
+    let reset: PasswordReset = match user.password_reset {
+        Some(reset) => reset,
+        None => return Err(reject::custom(Error::WrongCredentialsError)),
+    };
+    if reset.used {
+        return Err(reject::custom(Error::PasswordResetTokenUsedError));
+    }
+    if reset.expires < db.clock.now() {
+        return Err(reject::custom(Error::PasswordResetTokenExpiredError));
+    }
+    match db.use_password_reset(&user.username, &digest).await {
+        Ok(true) => (),
+        Ok(false) => return Err(reject::custom(Error::PasswordResetTokenUsedError)),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    match db.set_user_password(&user.username, &password).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    // whoever made the reset necessary may still hold a session; the
+    // JWTs have been revoked along with the password
+    match db.revoke_refresh_tokens_of_user(&user.username).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    log_auth_event(
+        AuthEvent::PasswordChanged,
+        &user.username,
+        &client,
+        None,
+        Some("reset"),
+    );
     let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
         ok: true,
         message: Option::default(),
@@ -1609,9 +5582,88 @@ pub async fn user_password_change_handler(
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
+fn pin_mail(db: &DB, user: &User) -> Result<OutboxMail> {
+    let to = match format!("{} <{}>", user.username, user.email).parse() {
+        Ok(to) => to,
+        Err(_) => return Err(Error::MalformedAddressError), // TODO: propagate info of `lettre::address::AddressError`
+    };
+    let email: lettre::Message = match Message::builder()
+        .header(lettre::message::header::ContentType::TEXT_PLAIN)
+        .from(
+            "Labyrinth Mailer <nirwana@raetselonkel.de>"
+                .parse()
+                .unwrap(),
+        )
+        .to(to)
+        .date_now()
+        .subject("Deine Aktivierungs-PIN für Labyrinth")
+        .body(format!(
+            r#"Moin {}!
+
+Du hast dich erfolgreich bei Labyrinth registriert.
+
+Deine PIN zur Aktivierung des Accounts: {:06}
+
+Bitte gib diese PIN auf der Labyrinth-Website ein.
+
+Viele Grüße,
+Dein Rätselonkel
+
+
+*** Falls du keinen Schimmer hast, was es mit dieser Mail auf sich hat, kannst du sie getrost ignorieren ;-)"#,
+            user.username, user.pin
+        )) {
+        Ok(email) => email,
+        Err(_) => return Err(Error::MailBuilderError), // TODO: propagate info of `lettre::error::Error`
+    };
+    outbox::render(&email, db.clock.now())
+}
+
+/// Tells the owner of an existing account that someone tried to register
+/// with its username or mail address.
+async fn queue_account_exists_mail(db: &DB, user: &User) -> Result<()> {
+    let to = match format!("{} <{}>", user.username, user.email).parse() {
+        Ok(to) => to,
+        Err(_) => return Err(Error::MalformedAddressError),
+    };
+    let email: lettre::Message = match Message::builder()
+        .header(lettre::message::header::ContentType::TEXT_PLAIN)
+        .from(
+            "Labyrinth Mailer <nirwana@raetselonkel.de>"
+                .parse()
+                .unwrap(),
+        )
+        .to(to)
+        .date_now()
+        .subject("Du hast schon einen Account bei Labyrinth")
+        .body(format!(
+            r#"Moin {}!
+
+Jemand (vielleicht du) wollte sich gerade mit deinem Benutzernamen oder deiner Mail-Adresse bei Labyrinth registrieren.
+
+Du hast schon einen Account. Falls du dein Passwort vergessen hast, kannst du es auf der Labyrinth-Website zurücksetzen.
+
+Viele Grüße,
+Dein Rätselonkel
+
+
+*** Falls du dich nicht registrieren wolltest, kannst du diese Mail getrost ignorieren ;-)"#,
+            user.username
+        )) {
+        Ok(email) => email,
+        Err(_) => return Err(Error::MailBuilderError),
+    };
+    outbox::enqueue(db, &email).await
+}
+
+/// Registers a new user and mails the activation PIN. If the username or
+/// the mail address is taken, the owners of the existing accounts get a
+/// notice instead, and the reply is the same, so that strangers can't find
+/// out who has an account (unless `REVEAL_TAKEN_ACCOUNTS` is set).
 pub async fn user_registration_handler(
     mut body: UserRegistrationRequest,
     mut db: DB,
+    config: Arc<Config>,
 ) -> WebResult<impl Reply> {
     let password: String = body.password;
     body.password = "******".to_string();
@@ -1619,7 +5671,7 @@ pub async fn user_registration_handler(
     if password.len() < 8 {
         return Err(reject::custom(Error::PasswordTooShortError));
     }
-    let password_is_bad = match is_bad_password(&password) {
+    let password_is_bad = match is_bad_password(&password, &config.bad_passwords_md5) {
         Ok(bad) => bad,
         Err(_) => false, // soft fail
     };
@@ -1630,22 +5682,67 @@ pub async fn user_registration_handler(
         return Err(reject::custom(Error::InvalidUsernameError));
     }
     if !RE_MAIL.is_match(&body.email.as_str()) {
-        return Err(reject::custom(Error::InvalidEmailError));
+        return Err(reject::custom(Error::InvalidEmailError(
+            "malformed address".to_string(),
+        )));
+    }
+    body.email = email::normalize(&body.email);
+    match config.email_domain_check.check(&body.email).await {
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
     }
-    let taken = match db
-        .is_username_or_email_taken(&body.username, &body.email)
+    match config
+        .registration_challenge
+        .verify(&body.username, &body.email, &body.challenge)
         .await
     {
-        Ok(taken) => taken,
-        Err(e) => return Err(reject::custom(Error::DatabaseQueryError(e.to_string()))),
+        Ok(()) => (),
+        Err(e) => return Err(reject::custom(e)),
+    }
+    let game_id: Option<ObjectId> = match body.game_id {
+        Some(ref game_id) => match ObjectId::parse_str(game_id) {
+            Ok(game_id) => Some(game_id),
+            Err(e) => return Err(reject::custom(Error::BsonOidError(e))),
+        },
+        None => None,
     };
-    if taken {
-        return Err(reject::custom(Error::UsernameOrEmailNotAvailableError));
+    if let Some(ref game_id) = game_id {
+        match db.get_game(game_id).await {
+            Ok(Some(_)) => (),
+            Ok(None) => return Err(reject::custom(Error::GameNotFoundError)),
+            Err(e) => return Err(reject::custom(e)),
+        }
+        match db.get_entry_room(&Some(*game_id)).await {
+            Ok(Some(_)) => (),
+            Ok(None) => return Err(reject::custom(Error::NoEntryRoomError)),
+            Err(e) => return Err(reject::custom(e)),
+        }
     }
+    // hashed before looking for existing accounts so that both cases take
+    // about as long
     let hash: String = match Password::hash(&password) {
         Ok(hash) => hash,
         Err(e) => return Err(reject::custom(e)),
     };
+    let existing: Vec<User> = match db
+        .get_users_by_username_or_email(&body.username, &body.email)
+        .await
+    {
+        Ok(existing) => existing,
+        Err(e) => return Err(reject::custom(Error::DatabaseQueryError(e.to_string()))),
+    };
+    if !existing.is_empty() {
+        if config.reveal_taken_accounts {
+            return Err(reject::custom(Error::UsernameOrEmailNotAvailableError));
+        }
+        for user in existing.iter() {
+            match queue_account_exists_mail(&db, user).await {
+                Ok(()) => log::info!("Account notice queued for {}.", user.username),
+                Err(e) => log::error!("Error: cannot queue account notice: {}", e),
+            }
+        }
+        return Ok(registration_reply());
+    }
     let mut pin: PinType = 0;
     while pin == 0 {
         pin = OsRng.next_u32() % 1000000;
@@ -1654,81 +5751,51 @@ pub async fn user_registration_handler(
         Some(SecondFactor::Totp) => rand::thread_rng().gen::<[u8; 32]>().to_vec(),
         _ => Vec::new(),
     };
-    match db
-        .create_user(&User::new(
-            &body.username,
-            &body.email,
-            Role::User,
-            hash,
-            pin,
-            totp_key,
-        ))
-        .await
-    {
+    let mut user: User = User::new(
+        &body.username,
+        &body.email,
+        Role::User,
+        hash,
+        pin,
+        totp_key,
+        db.clock.now(),
+    );
+    user.game_id = game_id;
+    user.last_pin_sent = Some(db.clock.now());
+    let mail: OutboxMail = match pin_mail(&db, &user) {
+        Ok(mail) => mail,
+        Err(e) => return Err(reject::custom(e)),
+    };
+    match db.create_user(&user, &mail).await {
         Ok(()) => (),
         Err(e) => return Err(reject::custom(e)),
     }
-    let to = match format!("{} <{}>", body.username, body.email).parse() {
-        Ok(to) => to,
-        Err(_) => return Err(reject::custom(Error::MalformedAddressError)), // TODO: propagate info of `lettre::address::AddressError`
-    };
-    let email: lettre::Message = match Message::builder()
-        .header(lettre::message::header::ContentType::TEXT_PLAIN)
-        .from(
-            "Labyrinth Mailer <nirwana@raetselonkel.de>"
-                .parse()
-                .unwrap(),
-        )
-        .to(to)
-        .date_now()
-        .subject("Deine Aktivierungs-PIN für Labyrinth")
-        .body(format!(
-            r#"Moin {}!
-
-Du hast dich erfolgreich bei Labyrinth registriert.
-
-Deine PIN zur Aktivierung des Accounts: {:06}
-
-Bitte gib diese PIN auf der Labyrinth-Website ein.
-
-Viele Grüße,
-Dein Rätselonkel
-
+    log::info!(
+        "Mail with PIN queued for {} <{}>.",
+        body.username,
+        body.email
+    );
+    Ok(registration_reply())
+}
 
-*** Falls du keinen Schimmer hast, was es mit dieser Mail auf sich hat, kannst du sie getrost ignorieren ;-)"#,
-            body.username, pin
-        )) {
-        Ok(email) => email,
-        Err(_) => return Err(reject::custom(Error::MailBuilderError)), // TODO: propagate info of `lettre::error::Error`
-    };
-    let mailer: lettre::SmtpTransport = SmtpTransport::unencrypted_localhost();
-    match mailer.send(&email) {
-        Ok(_) => {
-            log::info!(
-                "Mail with PIN {:06} successfully sent to {} <{}>.",
-                pin,
-                body.username,
-                body.email
-            );
-        }
-        Err(_) => return Err(reject::custom(Error::SmtpTransportError)), // TODO: propagate info of `lettre::transport::smtp::Error`
-    }
+fn registration_reply() -> warp::reply::WithStatus<warp::reply::Json> {
     let reply: warp::reply::Json = warp::reply::json(&json!(&StatusResponse {
         ok: true,
         message: Option::default(),
     }));
-    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+    warp::reply::with_status(reply, StatusCode::CREATED)
 }
 
 pub async fn webauthn_register_start_handler(
     username: String,
     mut db: DB,
+    config: Arc<Config>,
 ) -> WebResult<impl Reply> {
     log::info!(
         "webauthn_register_start_handler(); username = {}",
         &username
     );
-    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config());
+    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config(&config));
     let ccr = match wa_actor.challenge_register(&mut db, &username).await {
         Ok(ccr) => ccr,
         Err(_) => return Err(reject::custom(Error::WebauthnError)),
@@ -1747,9 +5814,10 @@ pub async fn webauthn_register_finish_handler(
     username: String,
     body: RegisterPublicKeyCredential,
     mut db: DB,
+    config: Arc<Config>,
 ) -> WebResult<impl Reply> {
     log::info!("webauthn_register_finish_handler(); body = {:?}", &body);
-    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config());
+    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config(&config));
     match wa_actor.register(&mut db, &username, &body).await {
         Ok(()) => (),
         Err(_) => return Err(reject::custom(Error::WebauthnError)),
@@ -1761,9 +5829,13 @@ pub async fn webauthn_register_finish_handler(
     Ok(warp::reply::with_status(reply, StatusCode::OK))
 }
 
-pub async fn webauthn_login_start_handler(username: String, mut db: DB) -> WebResult<impl Reply> {
+pub async fn webauthn_login_start_handler(
+    username: String,
+    mut db: DB,
+    config: Arc<Config>,
+) -> WebResult<impl Reply> {
     log::info!("webauthn_login_start_handler(); username = {}", &username);
-    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config());
+    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config(&config));
     let rcr = match wa_actor.challenge_authenticate(&mut db, &username).await {
         Ok(rcr) => rcr,
         Err(_) => return Err(reject::custom(Error::WebauthnError)),
@@ -1782,44 +5854,68 @@ pub async fn webauthn_login_finish_handler(
     username: String,
     body: PublicKeyCredential,
     mut db: DB,
+    config: Arc<Config>,
+    client: ClientInfo,
 ) -> WebResult<impl Reply> {
-    log::info!(
-        "webauthn_login_finish_handler(); username = {}, body = {:?}",
-        &username,
-        &body
-    );
+    log::info!("webauthn_login_finish_handler(); username = {}", &username);
     let user: User = match db.get_user(&username).await {
         Ok(user) => user,
         Err(e) => return Err(reject::custom(e)),
     };
-    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config());
+    if user.webauthn.credentials.is_empty() {
+        return Err(reject::custom(Error::Fido2NotConfiguredError));
+    }
+    let wa_actor = webauthn::WebauthnActor::new(webauthn_default_config(&config));
     match wa_actor.authenticate(&mut db, &user, &body).await {
-        Ok(()) => (),
-        Err(_) => return Err(reject::custom(Error::WebauthnError)),
+        Ok(()) => log_auth_event(
+            AuthEvent::SecondFactorSucceeded,
+            &username,
+            &client,
+            None,
+            Some("FIDO2"),
+        ),
+        Err(e) => {
+            let error_kind: &str = match &e {
+                Error::CredentialCounterRegressionError => "credential_counter_regression",
+                _ => "webauthn",
+            };
+            log_auth_event(
+                AuthEvent::SecondFactorFailed,
+                &username,
+                &client,
+                Some(error_kind),
+                Some("FIDO2"),
+            );
+            return Err(reject::custom(e));
+        }
     }
     match db.set_user_awaiting_2fa(&user, false).await {
         Ok(()) => (),
         Err(_) => return Err(reject::custom(Error::WebauthnError)),
     }
-    let jwt: Option<String> = match auth::create_jwt(&username, &user.role) {
-        Ok(jwt) => Some(jwt),
-        Err(e) => return Err(reject::custom(e)),
-    };
-    let in_room: ObjectId = match user.in_room {
-        Some(room) => room,
-        None => return Err(reject::custom(Error::UserIsInNoRoom)),
-    };
-    let room_response: RoomResponse = match get_room_by_id(&in_room, &db).await {
+    log_auth_event(AuthEvent::LoginSucceeded, &username, &client, None, None);
+    let jwt: Option<String> =
+        match auth::create_jwt(&username, &user.role, user.token_version, db.clock.now()) {
+            Ok(jwt) => Some(jwt),
+            Err(e) => return Err(reject::custom(e)),
+        };
+    let refresh_token: Option<String> =
+        match issue_refresh_token(&db, &username, None, &config).await {
+            Ok(refresh_token) => refresh_token,
+            Err(e) => return Err(reject::custom(e)),
+        };
+    let room_response: Option<RoomResponse> = match get_user_room(&user, &db).await {
         Ok(room_response) => room_response,
         Err(e) => return Err(reject::custom(e)),
     };
     let mut configured_2fa: Vec<SecondFactor> = Vec::new();
-    if user.totp_key.len() > 0 {
+    if user.has_totp() {
         configured_2fa.push(SecondFactor::Totp);
     }
     if user.webauthn.credentials.len() > 0 {
         configured_2fa.push(SecondFactor::Fido2);
     }
+    let two_factor_state: TwoFactorState = user.two_factor_setup();
     let reply: warp::reply::Json = warp::reply::json(&json!(&UserWhoamiResponse {
         ok: true,
         message: Option::default(),
@@ -1835,12 +5931,18 @@ pub async fn webauthn_login_finish_handler(
         in_room: room_response,
         solved: user.solved,
         rooms_entered: user.rooms_entered,
-        jwt,
+        jwt: jwt.clone(),
+        refresh_token,
+        active_sessions: Option::default(),
         totp: Option::default(),
         recovery_keys: Option::default(),
         configured_2fa,
+        two_factor_state,
     }));
-    Ok(warp::reply::with_status(reply, StatusCode::OK))
+    Ok(with_jwt_cookie(
+        warp::reply::with_status(reply, StatusCode::OK),
+        &jwt,
+    ))
 }
 
 #[tokio::main]
@@ -1850,163 +5952,900 @@ async fn main() -> Result<()> {
     const CARGO_PKG_NAME: &str = env!("CARGO_PKG_NAME");
     const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
     log::info!("{} {}", CARGO_PKG_NAME, CARGO_PKG_VERSION);
+    let config: Config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", &e);
+            return Err(e);
+        }
+    };
+    log::info!("Argon2 parameters: {:?}", &config.argon2);
     log::info!("Trying to connect to database ...");
-    let db = DB::init().await?;
-    db.get_database()
-        .run_command(doc! {"ping": 1}, None)
-        .await?;
+    let db = DB::init(&config, Arc::new(SystemClock)).await?;
+    db.ping().await?;
     log::info!("Connected successfully.");
+    db.migrate_embedded_solved().await?;
+    db.migrate_totp_keys().await?;
+    db.migrate_totp_key_lists().await?;
+    db.migrate_pending_totp().await?;
+    db.migrate_refresh_tokens().await?;
+    db.ensure_outbox_indexes().await?;
+    db.ensure_riddle_indexes().await?;
+    let config: Arc<Config> = Arc::new(config);
+    outbox::spawn_mail_worker(db.clone(), config.clone());
     let script_env = Arc::new(Mutex::new(ScriptEnvMap::new()));
+    let recovery_limiter: SharedRateLimiter = Arc::new(Mutex::new(RateLimiter::new(
+        config.recovery_rate_limit,
+        config.recovery_rate_limit_window,
+        db.clock.clone(),
+    )));
+    let second_factor_limiter: SharedRateLimiter = Arc::new(Mutex::new(RateLimiter::new(
+        config.recovery_rate_limit,
+        config.recovery_rate_limit_window,
+        db.clock.clone(),
+    )));
+    let maintenance: SharedMaintenance = Arc::new(RwLock::new(Maintenance::new(
+        config.maintenance_mode,
+        config.maintenance_notice.clone(),
+    )));
+    let api_host: SocketAddr = config.api_host;
+    let metrics_host: Option<SocketAddr> = config.metrics_host;
     let root = warp::path::end().map(|| "Labyrinth API root.");
     /* Routes accessible to all users */
     let ping_route = warp::path!("ping").and(warp::get()).and_then(ping_handler);
+    let healthz_route = warp::path!("healthz")
+        .and(warp::get())
+        .and_then(healthz_handler);
+    let readyz_route = warp::path!("readyz")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and_then(readyz_handler);
     let user_register_route = warp::path!("user" / "register")
         .and(warp::post())
         .and(warp::body::json())
         .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
         .and_then(user_registration_handler);
     let user_activation_route = warp::path!("user" / "activate")
         .and(warp::post())
         .and(warp::body::json())
         .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
         .and_then(user_activation_handler);
+    let user_activation_resend_route = warp::path!("user" / "activate" / "resend")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(user_activation_resend_handler);
     let user_login_route = warp::path!("user" / "login")
         .and(warp::post())
         .and(warp::body::json())
         .and(with_db(db.clone()))
+        .and(with_client_info())
+        .and(with_config(config.clone()))
         .and_then(user_login_handler);
+    let user_refresh_route = warp::path!("user" / "refresh")
+        .and(warp::post())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(REFRESH_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(user_refresh_handler);
+    let user_logout_route = warp::path!("user" / "logout")
+        .and(warp::post())
+        .and(with_auth(Role::Spectator, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_client_info())
+        .and_then(user_logout_handler);
     let user_password_route = warp::path!("user" / "passwd")
         .and(warp::post())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and(warp::body::json())
         .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and(with_client_info())
         .and_then(user_password_change_handler);
+    let user_password_reset_request_route = warp::path!("user" / "passwd" / "reset" / "request")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and(with_rate_limiter(recovery_limiter.clone()))
+        .and(with_client_info())
+        .and(with_config(config.clone()))
+        .and_then(user_password_reset_request_handler);
+    let user_password_reset_confirm_route = warp::path!("user" / "passwd" / "reset" / "confirm")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and(with_client_info())
+        .and_then(user_password_reset_confirm_handler);
+    let user_recovery_validate_route = warp::path!("user" / "recovery" / "validate")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and(with_rate_limiter(recovery_limiter.clone()))
+        .and(with_client_info())
+        .and_then(user_recovery_validate_handler);
+    let user_recovery_route = warp::path!("user" / "recovery")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and(with_rate_limiter(recovery_limiter.clone()))
+        .and(with_client_info())
+        .and(with_config(config.clone()))
+        .and_then(user_recovery_handler);
+    let user_2fa_methods_route = warp::path!("user" / String / "2fa-methods")
+        .and(warp::get())
+        .and(with_db(db.clone()))
+        .and(with_rate_limiter(second_factor_limiter.clone()))
+        .and(with_client_info())
+        .and_then(user_2fa_methods_handler);
     let user_totp_login_route = warp::path!("user" / "totp" / "login")
         .and(warp::post())
         .and(warp::body::json())
         .and(with_db(db.clone()))
+        .and(with_client_info())
+        .and(with_config(config.clone()))
         .and_then(user_totp_login_handler);
+    let user_totp_confirm_route = warp::path!("user" / "totp" / "confirm")
+        .and(warp::post())
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(user_totp_confirm_handler);
     let user_totp_enable_route = warp::path!("user" / "totp" / "enable")
         .and(warp::post())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::query::<TotpEnableQuery>())
+        .and(warp::body::json())
         .and(with_db(db.clone()))
         .and_then(user_totp_enable_handler);
+    let user_totp_add_route = warp::path!("user" / "totp" / "add")
+        .and(warp::post())
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(user_totp_add_handler);
+    let user_totp_remove_route = warp::path!("user" / "totp" / "remove")
+        .and(warp::post())
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(user_totp_remove_handler);
     let user_totp_disable_route = warp::path!("user" / "totp" / "disable")
         .and(warp::post())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::body::json())
         .and(with_db(db.clone()))
         .and_then(user_totp_disable_handler);
     let webauthn_login_start_route = warp::path!("user" / "webauthn" / "login" / "start" / String)
         .and(warp::post())
         .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
         .and_then(webauthn_login_start_handler);
     let webauthn_login_finish_route =
         warp::path!("user" / "webauthn" / "login" / "finish" / String)
             .and(warp::post())
             .and(warp::body::json())
             .and(with_db(db.clone()))
+            .and(with_config(config.clone()))
+            .and(with_client_info())
             .and_then(webauthn_login_finish_handler);
     /* Routes accessible only to authorized users */
     let webauthn_register_start_route = warp::path!("user" / "webauthn" / "register" / "start")
         .and(warp::post())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
         .and_then(webauthn_register_start_handler);
     let webauthn_register_finish_route = warp::path!("user" / "webauthn" / "register" / "finish")
         .and(warp::post())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and(warp::body::json())
         .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
         .and_then(webauthn_register_finish_handler);
     let user_auth_route = warp::path!("user" / "auth")
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and_then(user_authentication_handler);
+    let user_stats_route = warp::path!("user" / "stats")
+        .and(warp::get())
+        .and(with_auth(Role::User, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(user_stats_handler);
+    let user_export_route = warp::path!("user" / "export")
+        .and(warp::post())
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(user_export_handler);
+    let user_finished_route = warp::path!("user" / "finished")
+        .and(warp::get())
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::query::<PageQuery>())
+        .and(with_db(db.clone()))
+        .and_then(user_finished_handler);
     let user_whoami_route = warp::path!("user" / "whoami")
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::Spectator, db.clone()))
         .and(with_db(db.clone()))
         .and_then(user_whoami_handler);
     let riddle_get_by_oid_route = warp::path!("riddle" / OidString)
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::query::<RiddleGetQuery>())
         .and(with_db(db.clone()))
         .and(with_script_env(script_env.clone()))
+        .and(with_config(config.clone()))
         .and_then(riddle_get_oid_handler);
+    let riddle_files_route = warp::path!("riddle" / OidString / "files")
+        .and(warp::get())
+        .and(with_auth(Role::User, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_files_handler);
+    let riddle_file_get_route = warp::path!("riddle" / OidString / "file" / String)
+        .and(warp::get())
+        .and(with_auth(Role::User, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_file_get_handler);
+    // revealing costs points, so it must not be triggered by a prefetch or crawler
+    let riddle_solution_route = warp::path!("riddle" / OidString / "solution")
+        .and(warp::post())
+        .and(with_auth(Role::User, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_solution_handler);
+    // like revealing a solution, unlocking a hint costs points
+    let riddle_hint_route = warp::path!("riddle" / OidString / "hint" / u32)
+        .and(warp::post())
+        .and(with_auth(Role::User, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_hint_handler);
     let debriefing_get_by_riddle_id_route = warp::path!("riddle" / "debriefing" / OidString)
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
         .and_then(debriefing_get_by_riddle_id_handler);
+    let riddle_solve_batch_route = warp::path!("riddle" / "solve" / "batch")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            config.max_solve_batch_bytes,
+        ))
+        .and(warp::body::json())
+        .and(with_auth(Role::User, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_script_env(script_env.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_solve_batch_handler);
     let riddle_solve_route = warp::path!("riddle" / "solve" / OidString)
         .and(warp::post())
         .and(warp::body::json())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and(with_db(db.clone()))
         .and(with_script_env(script_env.clone()))
+        .and(with_config(config.clone()))
         .and_then(riddle_solve_handler);
     let go_route = warp::path!("go" / String)
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and(with_db(db.clone()))
         .and_then(go_handler);
     let highscores_route = warp::path!("game" / "highscores" / OidString)
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::query::<PageQuery>())
         .and(with_db(db.clone()))
         .and_then(highscores_handler);
+    let spectate_route = warp::path!("spectate" / String)
+        .and(warp::get())
+        .and(with_auth(Role::Spectator, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(spectate_handler);
+    let leaderboard_route = warp::path!("game" / "leaderboard" / OidString)
+        .and(warp::get())
+        .and(with_auth(Role::User, db.clone()))
+        .and(warp::query::<PageQuery>())
+        .and(with_db(db.clone()))
+        .and_then(leaderboard_handler);
+    let path_home_route = warp::path!("game" / "path-home")
+        .and(warp::get())
+        .and(with_auth(Role::User, db.clone()))
+        .and(with_db(db.clone()))
+        .and_then(path_home_handler);
     let game_stats_route = warp::path!("game" / "stats" / OidString)
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and(with_db(db.clone()))
         .and_then(game_stats_handler);
+    let game_directions_route = warp::path!("game" / OidString / "directions")
+        .and(warp::get())
+        .and(with_auth(Role::User, db.clone()))
+        .and_then(game_directions_handler);
     let cheat_route = warp::path!("cheat")
         .and(warp::get())
-        .and(with_auth(Role::User))
+        .and(with_auth(Role::User, db.clone()))
         .and_then(cheat_handler);
     /* Routes accessible only to authorized admins */
     let riddle_get_by_level_route = warp::path!("admin" / "riddle" / "by" / "level" / u32)
         .and(warp::get())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Role::Admin, db.clone()))
         .and(with_db(db.clone()))
         .and(with_script_env(script_env.clone()))
+        .and(with_config(config.clone()))
         .and_then(riddle_get_by_level_handler);
+    let riddle_stats_route = warp::path!("admin" / "riddle" / String / "stats")
+        .and(warp::get())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(with_db(db.clone()))
+        .and_then(riddle_stats_handler);
+    let admin_stats_route = warp::path!("admin" / "stats")
+        .and(warp::get())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(with_db(db.clone()))
+        .and_then(admin_stats_handler);
     let promote_user_route = warp::path!("admin" / "promote" / String / String)
         .and(warp::get())
-        .and(with_auth(Role::Admin))
+        .and(with_auth(Role::Admin, db.clone()))
         .and(with_db(db.clone()))
+        .and(with_client_info())
         .and_then(promote_user_handler);
+    let user_import_route = warp::path!("admin" / "users" / "import")
+        .and(warp::post())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(warp::query::<UserImportQuery>())
+        .and(warp::body::content_length_limit(config.max_import_bytes))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(user_import_handler);
+    let admin_score_route = warp::path!("admin" / "user" / String / "score")
+        .and(warp::post())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(admin_score_handler);
+    let teleport_by_number_route =
+        warp::path!("admin" / "user" / String / "teleport" / "number" / u32)
+            .and(warp::post())
+            .and(with_auth(Role::Admin, db.clone()))
+            .and(warp::query::<TeleportQuery>())
+            .and(with_db(db.clone()))
+            .and_then(teleport_by_number_handler);
+    let deactivate_user_route = warp::path!("admin" / "user" / String / "deactivate")
+        .and(warp::post())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(with_db(db.clone()))
+        .and_then(deactivate_user_handler);
+    let delete_user_route = warp::path!("admin" / "user" / String)
+        .and(warp::delete())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(with_db(db.clone()))
+        .and_then(delete_user_handler);
+    let game_state_route = warp::path!("admin" / "game" / OidString / "state")
+        .and(warp::post())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and_then(game_state_handler);
+    let maintenance_get_route = warp::path!("admin" / "maintenance")
+        .and(warp::get())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(with_maintenance(maintenance.clone()))
+        .and_then(maintenance_get_handler);
+    let maintenance_set_route = warp::path!("admin" / "maintenance")
+        .and(warp::post())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(warp::body::json())
+        .and(with_maintenance(maintenance.clone()))
+        .and_then(maintenance_set_handler);
+    let game_import_route = warp::path!("admin" / "game" / "import")
+        .and(warp::post())
+        .and(with_auth(Role::Admin, db.clone()))
+        .and(warp::query::<GameImportQuery>())
+        .and(warp::body::content_length_limit(config.max_import_bytes))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(game_import_handler);
+    /* Routes accessible to designers and admins */
+    let riddle_create_route = warp::path!("designer" / "riddle")
+        .and(warp::post())
+        .and(with_auth(Role::Designer, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_create_handler);
+    let riddle_update_route = warp::path!("designer" / "riddle" / OidString)
+        .and(warp::put())
+        .and(with_auth(Role::Designer, db.clone()))
+        .and(warp::body::json())
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_update_handler);
+    let riddle_file_upload_route = warp::path!("designer" / "riddle" / OidString / "file")
+        .and(warp::post())
+        .and(with_auth(Role::Designer, db.clone()))
+        .and(warp::multipart::form().max_length(config.max_upload_bytes))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_file_upload_handler);
+    let riddle_file_delete_route = warp::path!("designer" / "riddle" / OidString / "file" / String)
+        .and(warp::delete())
+        .and(with_auth(Role::Designer, db.clone()))
+        .and(with_db(db.clone()))
+        .and(with_config(config.clone()))
+        .and_then(riddle_file_delete_handler);
+    let normalize_preview_route = warp::path!("admin" / "riddle" / "normalize-preview")
+        .and(warp::post())
+        .and(with_auth(Role::Designer, db.clone()))
+        .and(warp::body::json())
+        .and_then(normalize_preview_handler);
 
     let routes = root
-        .or(riddle_get_by_oid_route)
-        .or(debriefing_get_by_riddle_id_route)
+        .or(ping_route)
+        .or(healthz_route)
+        .or(readyz_route)
+        /* Admin and login routes stay available during maintenance */
         .or(riddle_get_by_level_route)
+        .or(riddle_stats_route)
         .or(promote_user_route)
-        .or(riddle_solve_route)
-        .or(go_route)
-        .or(user_whoami_route)
-        .or(user_auth_route)
-        .or(user_login_route)
-        .or(user_password_route)
-        .or(user_totp_enable_route)
-        .or(user_totp_disable_route)
-        .or(user_totp_login_route)
-        .or(user_register_route)
-        .or(user_activation_route)
-        .or(webauthn_register_start_route)
-        .or(webauthn_register_finish_route)
+        .or(admin_score_route)
+        .or(teleport_by_number_route)
+        .or(deactivate_user_route)
+        .or(admin_stats_route)
+        .or(delete_user_route)
+        .or(user_import_route)
+        .or(game_import_route)
+        .or(riddle_create_route)
+        .or(riddle_update_route)
+        .or(riddle_file_upload_route)
+        .or(riddle_file_delete_route)
+        .or(normalize_preview_route)
+        .or(game_state_route)
+        .or(maintenance_get_route)
+        .or(maintenance_set_route)
+        .or(metrics::timed("login", user_login_route))
+        .or(metrics::timed("refresh", user_refresh_route))
+        .or(user_logout_route)
+        .or(metrics::timed("totp_login", user_totp_login_route))
         .or(webauthn_login_start_route)
-        .or(webauthn_login_finish_route)
-        .or(ping_route)
-        .or(cheat_route)
-        .or(highscores_route)
-        .or(game_stats_route)
+        .or(metrics::timed(
+            "webauthn_login",
+            webauthn_login_finish_route,
+        ))
+        .or(maintenance::guard(maintenance.clone(), Access::Read).and(
+            riddle_files_route
+                .or(riddle_file_get_route)
+                .or(debriefing_get_by_riddle_id_route)
+                .or(user_whoami_route)
+                .or(user_stats_route)
+                .or(user_finished_route)
+                .or(user_export_route)
+                .or(user_auth_route)
+                .or(user_recovery_validate_route)
+                .or(user_2fa_methods_route)
+                .or(cheat_route)
+                .or(highscores_route)
+                .or(leaderboard_route)
+                .or(spectate_route)
+                .or(game_stats_route)
+                .or(path_home_route)
+                .or(game_directions_route),
+        ))
+        .or(maintenance::guard(maintenance.clone(), Access::Write).and(
+            riddle_get_by_oid_route
+                .or(riddle_solution_route)
+                .or(riddle_hint_route)
+                .or(metrics::timed("solve_batch", riddle_solve_batch_route))
+                .or(metrics::timed("solve", riddle_solve_route))
+                .or(metrics::timed("go", go_route))
+                .or(user_password_route)
+                .or(user_password_reset_request_route)
+                .or(user_password_reset_confirm_route)
+                .or(user_totp_enable_route)
+                .or(user_totp_confirm_route)
+                .or(user_totp_disable_route)
+                .or(user_totp_add_route)
+                .or(user_totp_remove_route)
+                .or(user_register_route)
+                .or(user_activation_route)
+                .or(user_activation_resend_route)
+                .or(metrics::timed("recovery", user_recovery_route))
+                .or(webauthn_register_start_route)
+                .or(webauthn_register_finish_route),
+        ))
         .or(warp::any().and(warp::options()).map(warp::reply))
         .recover(error::handle_rejection);
 
-    let host = env::var("API_HOST").expect("API_HOST is not in .env file");
-    let addr: SocketAddr = host.parse().expect("Cannot parse host address");
-    log::info!("Listening on http://{}", host);
-    warp::serve(routes).run(addr).await;
+    // served apart from the API, so that it can be kept internal
+    if let Some(metrics_host) = metrics_host {
+        let metrics_route = warp::path!("metrics")
+            .and(warp::get())
+            .and_then(metrics_handler);
+        log::info!("Serving metrics on http://{}/metrics", metrics_host);
+        tokio::spawn(warp::serve(metrics_route).run(metrics_host));
+    }
+    log::info!("Listening on http://{}", api_host);
+    warp::serve(routes).run(api_host).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+
+    #[test]
+    fn normalize_answer_keeps_case_unless_ignored() {
+        assert_eq!(normalize_answer("Labyrinth", false, &None), "Labyrinth");
+        assert_eq!(normalize_answer("Labyrinth", true, &None), "labyrinth");
+        assert_eq!(normalize_answer("ÄRGER", true, &None), "ärger");
+    }
+
+    #[test]
+    fn normalize_answer_follows_turkish_case_rules() {
+        let turkish: Option<String> = Some("tr-TR".to_string());
+        assert_eq!(normalize_answer("KIRMIZI", true, &turkish), "kırmızı");
+        assert_eq!(normalize_answer("İSTANBUL", true, &turkish), "istanbul");
+        assert_eq!(
+            normalize_answer("KIRMIZI", true, &Some("az".to_string())),
+            "kırmızı"
+        );
+        // without the locale, I is lowercased to i
+        assert_eq!(normalize_answer("KIRMIZI", true, &None), "kirmizi");
+        assert_eq!(
+            normalize_answer("KIRMIZI", true, &Some("de".to_string())),
+            "kirmizi"
+        );
+    }
+
+    #[test]
+    fn score_for_attempt_without_window_is_the_difficulty() {
+        let elapsed = chrono::Duration::seconds(10);
+        assert_eq!(score_for_attempt(7, elapsed, None), 7);
+        assert_eq!(score_for_attempt(7, elapsed, Some(0)), 7);
+        assert_eq!(score_for_attempt(-3, elapsed, None), 0);
+    }
+
+    #[test]
+    fn score_for_attempt_bonus_decays_over_the_window() {
+        let window: Option<i64> = Some(100);
+        assert_eq!(
+            score_for_attempt(10, chrono::Duration::seconds(0), window),
+            20
+        );
+        assert_eq!(
+            score_for_attempt(10, chrono::Duration::seconds(50), window),
+            15
+        );
+        assert_eq!(
+            score_for_attempt(10, chrono::Duration::seconds(100), window),
+            10
+        );
+        // beyond the window and for a t0 in the future the bonus is clamped
+        assert_eq!(
+            score_for_attempt(10, chrono::Duration::seconds(1000), window),
+            10
+        );
+        assert_eq!(
+            score_for_attempt(10, chrono::Duration::seconds(-30), window),
+            20
+        );
+    }
+
+    #[test]
+    fn score_breakdown_puts_the_rest_into_penalties() {
+        let solved: Vec<SolvedDifficulty> = vec![
+            SolvedDifficulty {
+                difficulty: 2,
+                dt: Some(0),
+                revealed: false,
+            },
+            SolvedDifficulty {
+                difficulty: 6,
+                dt: Some(100),
+                revealed: false,
+            },
+            SolvedDifficulty {
+                difficulty: 12,
+                dt: None,
+                revealed: false,
+            },
+            SolvedDifficulty {
+                difficulty: 3,
+                dt: Some(0),
+                revealed: true,
+            },
+        ];
+        let breakdown: ScoreBreakdown = score_breakdown(&solved, 20, 5, 10, Some(100));
+        // the immediate solve earns the full bonus, the untimed one none,
+        // revealed solutions nothing
+        assert_eq!(breakdown.easy, 4);
+        assert_eq!(breakdown.medium, 6);
+        assert_eq!(breakdown.hard, 12);
+        assert_eq!(breakdown.penalties, 2);
+        assert_eq!(
+            breakdown.easy + breakdown.medium + breakdown.hard - breakdown.penalties,
+            20
+        );
+    }
+
+    fn test_user() -> User {
+        User::new(
+            &"alice".to_string(),
+            &"alice@example.com".to_string(),
+            Role::User,
+            String::new(),
+            0,
+            Vec::new(),
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn solve_cooldown_runs_out() {
+        let now: DateTime<Utc> = Utc::now();
+        let riddle_id: ObjectId = ObjectId::new();
+        let mut user: User = test_user();
+        assert_eq!(solve_cooldown_left(&user, &riddle_id, now, 5), 0);
+        user.last_wrong_answers.push(WrongAnswer {
+            riddle_id,
+            at: now,
+            count: 1,
+        });
+        assert_eq!(solve_cooldown_left(&user, &riddle_id, now, 5), 5);
+        let later: DateTime<Utc> = now + chrono::Duration::seconds(3);
+        assert_eq!(solve_cooldown_left(&user, &riddle_id, later, 5), 2);
+        let much_later: DateTime<Utc> = now + chrono::Duration::seconds(60);
+        assert_eq!(solve_cooldown_left(&user, &riddle_id, much_later, 5), 0);
+        // other riddles aren't affected
+        assert_eq!(solve_cooldown_left(&user, &ObjectId::new(), now, 5), 0);
+    }
+
+    fn test_riddle(max_attempts: Option<u32>) -> Riddle {
+        let mut riddle: Riddle =
+            bson::from_document(doc! { "_id": ObjectId::new(), "difficulty": 5i32 }).unwrap();
+        riddle.max_attempts = max_attempts;
+        riddle
+    }
+
+    #[test]
+    fn attempts_left_counts_down_to_zero() {
+        let riddle: Riddle = test_riddle(Some(3));
+        let mut user: User = test_user();
+        assert_eq!(attempts_left(&user, &riddle), Some(3));
+        user.last_wrong_answers.push(WrongAnswer {
+            riddle_id: riddle.id,
+            at: Utc::now(),
+            count: 2,
+        });
+        assert_eq!(attempts_left(&user, &riddle), Some(1));
+        user.last_wrong_answers[0].count = 5;
+        assert_eq!(attempts_left(&user, &riddle), Some(0));
+    }
+
+    #[test]
+    fn attempts_left_is_none_without_limit() {
+        let riddle: Riddle = test_riddle(None);
+        let mut user: User = test_user();
+        user.last_wrong_answers.push(WrongAnswer {
+            riddle_id: riddle.id,
+            at: Utc::now(),
+            count: 100,
+        });
+        assert_eq!(attempts_left(&user, &riddle), None);
+    }
+
+    fn test_refresh_token(expires: DateTime<Utc>) -> RefreshToken {
+        let (_, digest) = auth::create_refresh_token();
+        RefreshToken {
+            digest,
+            family: ObjectId::new(),
+            username: "alice".to_string(),
+            issued: expires - chrono::Duration::minutes(60),
+            expires,
+            used: false,
+        }
+    }
+
+    #[test]
+    fn refresh_token_rotates_once() {
+        let now: DateTime<Utc> = Utc::now();
+        let token: RefreshToken = test_refresh_token(now + chrono::Duration::minutes(5));
+        assert_eq!(refresh_outcome(&token, true, now), RefreshOutcome::Rotate);
+        // a second request finds the token used already
+        assert_eq!(refresh_outcome(&token, false, now), RefreshOutcome::Reused);
+    }
+
+    #[test]
+    fn refresh_token_reuse_wins_over_expiry() {
+        let now: DateTime<Utc> = Utc::now();
+        let token: RefreshToken = test_refresh_token(now - chrono::Duration::minutes(5));
+        assert_eq!(refresh_outcome(&token, true, now), RefreshOutcome::Expired);
+        assert_eq!(refresh_outcome(&token, false, now), RefreshOutcome::Reused);
+    }
+
+    #[test]
+    fn refresh_tokens_are_stored_by_digest() {
+        let (token, digest) = auth::create_refresh_token();
+        let (other, _) = auth::create_refresh_token();
+        assert_ne!(token, other);
+        assert_ne!(token, digest);
+        assert_eq!(auth::refresh_token_digest(&token), digest);
+        // hex-encoded SHA-256
+        assert_eq!(digest.len(), 64);
+    }
+
+    fn test_time() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2022-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn totp_is_accepted_within_the_skew_window() {
+        let clock = MockClock::new(test_time());
+        let key: Vec<u8> = b"12345678901234567890".to_vec();
+        let totp_keys: Vec<TotpSecret> = vec![TotpSecret::new("phone", key.clone(), clock.now())];
+        let code: String = TOTP_PARAMS.code(&key, clock.now().timestamp() as u64);
+        let interval: i64 = TOTP_PARAMS.interval as i64;
+        let skew: i64 = TOTP_PARAMS.skew_steps as i64;
+        assert!(totp_matches(&totp_keys, &code, clock.now()));
+        // the code has been shown on a device whose clock is behind
+        clock.advance(chrono::Duration::seconds(skew * interval));
+        assert!(totp_matches(&totp_keys, &code, clock.now()));
+        clock.advance(chrono::Duration::seconds(interval));
+        assert!(!totp_matches(&totp_keys, &code, clock.now()));
+        // ... or ahead
+        clock.set(test_time() - chrono::Duration::seconds(skew * interval));
+        assert!(totp_matches(&totp_keys, &code, clock.now()));
+        clock.advance(chrono::Duration::seconds(-interval));
+        assert!(!totp_matches(&totp_keys, &code, clock.now()));
+    }
+
+    #[test]
+    fn solve_duration_follows_the_clock() {
+        let t0: DateTime<Utc> = test_time();
+        let clock = MockClock::new(t0);
+        let window: Option<i64> = Some(60);
+        let points = |clock: &MockClock| -> u32 {
+            score_for_attempt(10, clock.now().signed_duration_since(t0), window)
+        };
+        assert_eq!(points(&clock), 20);
+        clock.advance(chrono::Duration::seconds(30));
+        assert_eq!(points(&clock), 15);
+        clock.advance(chrono::Duration::seconds(30));
+        assert_eq!(points(&clock), 10);
+        clock.advance(chrono::Duration::hours(1));
+        assert_eq!(points(&clock), 10);
+        // a clock behind the one that stamped t0 doesn't earn extra points
+        clock.set(t0 - chrono::Duration::seconds(30));
+        assert_eq!(points(&clock), 20);
+    }
+
+    fn close(solution: &str, guess: &str, threshold: Option<f32>) -> bool {
+        is_close_answer(
+            &solution.to_string(),
+            &guess.to_string(),
+            false,
+            &None,
+            threshold,
+        )
+    }
+
+    #[test]
+    fn near_miss_gets_a_hint() {
+        assert!(close("labyrinth", "labyrintx", Some(0.8)));
+        assert!(close("labyrinth", " labyrintx ", Some(0.8)));
+        assert!(is_close_answer(
+            &"Labyrinth".to_string(),
+            &"LABYRINTX".to_string(),
+            true,
+            &None,
+            Some(0.8)
+        ));
+    }
+
+    #[test]
+    fn far_miss_gets_no_hint() {
+        assert!(!close("labyrinth", "minotaur", Some(0.8)));
+        assert!(!close("labyrinth", "", Some(0.8)));
+        // riddles have to opt in
+        assert!(!close("labyrinth", "labyrintx", None));
+        // the right answer isn't close, it's right
+        assert!(!close("labyrinth", "labyrinth", Some(0.8)));
+    }
+
+    #[test]
+    fn close_answer_threshold_has_a_floor() {
+        assert!(close("labyrinth", "labxxinth", Some(0.1)));
+        assert!(!close("labyrinth", "labxxxnth", Some(0.1)));
+    }
+
+    #[test]
+    fn short_solutions_get_no_hint() {
+        let solution: String = "x".repeat(CLOSE_ANSWER_MIN_LENGTH - 1);
+        let guess: String = format!("{}y", "x".repeat(CLOSE_ANSWER_MIN_LENGTH - 2));
+        assert!(!close(&solution, &guess, Some(CLOSE_ANSWER_MIN_THRESHOLD)));
+        let solution: String = "x".repeat(CLOSE_ANSWER_MIN_LENGTH);
+        let guess: String = format!("{}y", "x".repeat(CLOSE_ANSWER_MIN_LENGTH - 1));
+        assert!(close(&solution, &guess, Some(CLOSE_ANSWER_MIN_THRESHOLD)));
+    }
+
+    fn test_game(score_multiplier: f32, default_deduction: Option<i32>, state: GameState) -> Game {
+        Game {
+            id: ObjectId::new(),
+            name: "test".to_string(),
+            score_multiplier,
+            level_gating: false,
+            default_deduction,
+            state,
+            starts_at: None,
+            ends_at: None,
+        }
+    }
+
+    #[test]
+    fn wrong_answer_deduction_follows_riddle_game_and_default() {
+        let mut riddle: Riddle = test_riddle(None);
+        assert_eq!(wrong_answer_deduction(&riddle, &None, 2), 2);
+        let game: Option<Game> = Some(test_game(1.0, Some(3), GameState::Open));
+        assert_eq!(wrong_answer_deduction(&riddle, &game, 2), 3);
+        riddle.deduction = Some(4);
+        assert_eq!(wrong_answer_deduction(&riddle, &game, 2), 4);
+    }
+
+    #[test]
+    fn wrong_answer_deduction_is_scaled_and_frozen_games_spare_it() {
+        let riddle: Riddle = test_riddle(None);
+        let game: Option<Game> = Some(test_game(1.5, Some(3), GameState::Locked));
+        assert_eq!(wrong_answer_deduction(&riddle, &game, 0), 5);
+        let game: Option<Game> = Some(test_game(1.5, Some(3), GameState::Frozen));
+        assert_eq!(wrong_answer_deduction(&riddle, &game, 0), 0);
+    }
+
+    fn page(limit: Option<u32>, offset: u32) -> PageQuery {
+        PageQuery { limit, offset }
+    }
+
+    #[test]
+    fn last_page_has_no_more() {
+        let all: Vec<u32> = (0..25).collect();
+        let first: Paginated<u32> = Paginated::from_vec(all.clone(), &page(Some(10), 10));
+        assert_eq!(first.items, (10..20).collect::<Vec<u32>>());
+        assert_eq!(first.total, 25);
+        assert!(first.has_more);
+        let last: Paginated<u32> = Paginated::from_vec(all.clone(), &page(Some(10), 20));
+        assert_eq!(last.items, (20..25).collect::<Vec<u32>>());
+        assert_eq!(last.total, 25);
+        assert!(!last.has_more);
+        let beyond: Paginated<u32> = Paginated::from_vec(all, &page(Some(10), 30));
+        assert!(beyond.items.is_empty());
+        assert_eq!(beyond.total, 25);
+        assert!(!beyond.has_more);
+    }
+
+    #[test]
+    fn page_ending_exactly_at_the_total_has_no_more() {
+        let full: Paginated<u32> = Paginated::new((10..20).collect(), 20, &page(Some(10), 10));
+        assert!(!full.has_more);
+        let short: Paginated<u32> = Paginated::new((10..19).collect(), 20, &page(Some(10), 10));
+        assert!(short.has_more);
+    }
+
+    #[test]
+    fn page_limit_is_bounded() {
+        assert_eq!(page(None, 0).limit(), DEFAULT_PAGE_LIMIT);
+        assert_eq!(page(Some(0), 0).limit(), 1);
+        assert_eq!(page(Some(MAX_PAGE_LIMIT + 1), 0).limit(), MAX_PAGE_LIMIT);
+    }
+}
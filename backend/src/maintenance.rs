@@ -0,0 +1,109 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use warp::{reject, Filter, Rejection};
+
+const DEFAULT_NOTICE: &str = "Labyrinth is down for maintenance, please come back later.";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceMode {
+    Off,
+    /// only requests that don't change anything are served
+    ReadOnly,
+    /// no requests are served
+    Down,
+}
+
+impl Default for MaintenanceMode {
+    fn default() -> Self {
+        MaintenanceMode::Off
+    }
+}
+
+impl FromStr for MaintenanceMode {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "" | "off" | "false" | "0" => Ok(MaintenanceMode::Off),
+            "read_only" | "readonly" => Ok(MaintenanceMode::ReadOnly),
+            "down" | "on" | "true" | "1" => Ok(MaintenanceMode::Down),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What a route does to the data, which decides whether it's served in
+/// read-only mode. Routes are marked explicitly because not every route
+/// that writes is a POST, e.g. entering a room via GET /go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+pub struct Maintenance {
+    pub mode: MaintenanceMode,
+    /// shown to clients instead of the default notice
+    pub notice: Option<String>,
+}
+
+impl Maintenance {
+    pub fn new(mode: MaintenanceMode, notice: Option<String>) -> Maintenance {
+        Maintenance { mode, notice }
+    }
+
+    /// Returns `false` if requests to routes with the given access must be
+    /// turned away in the current mode.
+    pub fn admits(&self, access: Access) -> bool {
+        match self.mode {
+            MaintenanceMode::Off => true,
+            MaintenanceMode::ReadOnly => access == Access::Read,
+            MaintenanceMode::Down => false,
+        }
+    }
+
+    pub fn notice(&self) -> String {
+        match self.notice {
+            Some(ref notice) => notice.clone(),
+            None => DEFAULT_NOTICE.to_string(),
+        }
+    }
+}
+
+pub type SharedMaintenance = Arc<RwLock<Maintenance>>;
+
+pub fn with_maintenance(
+    maintenance: SharedMaintenance,
+) -> impl Filter<Extract = (SharedMaintenance,), Error = Infallible> + Clone {
+    warp::any().map(move || maintenance.clone())
+}
+
+/// Rejects requests to routes with the given access with
+/// `Error::MaintenanceError` while maintenance mode doesn't admit them.
+/// Routes that must stay reachable during maintenance (admin, health and
+/// login) are not put behind this filter.
+pub fn guard(
+    maintenance: SharedMaintenance,
+    access: Access,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_maintenance(maintenance)
+        .and_then(move |maintenance: SharedMaintenance| async move {
+            let maintenance = maintenance.read().unwrap();
+            if maintenance.admits(access) {
+                Ok(())
+            } else {
+                Err(reject::custom(Error::MaintenanceError(
+                    maintenance.notice(),
+                )))
+            }
+        })
+        .untuple_one()
+}
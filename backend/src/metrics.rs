@@ -0,0 +1,164 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::{Filter, Rejection, Reply};
+
+/// Upper bounds of the latency buckets in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+/// Request latencies of a single route. Observations only touch atomics,
+/// so timing a request doesn't allocate or lock.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// per bucket, not cumulative; the last one counts requests slower
+    /// than the largest bound
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs: f64 = elapsed.as_secs_f64();
+        let bucket: usize = BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    routes: Mutex<Vec<(&'static str, Arc<Histogram>)>>,
+    errors: Mutex<HashMap<(u16, &'static str), u64>>,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            routes: Mutex::new(Vec::new()),
+            errors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the histogram of `route`, registering it on first use.
+    pub fn histogram(&self, route: &'static str) -> Arc<Histogram> {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.iter().find(|(name, _)| *name == route) {
+            Some((_, histogram)) => histogram.clone(),
+            None => {
+                let histogram: Arc<Histogram> = Arc::new(Histogram::default());
+                routes.push((route, histogram.clone()));
+                histogram
+            }
+        }
+    }
+
+    pub fn count_error(&self, status: u16, error_kind: &'static str) {
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry((status, error_kind))
+            .or_insert(0) += 1;
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out: String = String::new();
+        out.push_str("# TYPE labyrinth_request_duration_seconds histogram\n");
+        for (route, histogram) in self.routes.lock().unwrap().iter() {
+            let mut cumulative: u64 = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count.load(Ordering::Relaxed);
+                let le: String = match BUCKETS.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "labyrinth_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "labyrinth_request_duration_seconds_sum{{route=\"{}\"}} {}",
+                route,
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+            );
+            let _ = writeln!(
+                out,
+                "labyrinth_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route, cumulative
+            );
+        }
+        out.push_str("# TYPE labyrinth_errors_total counter\n");
+        for ((status, error_kind), count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "labyrinth_errors_total{{status=\"{}\",error_kind=\"{}\"}} {}",
+                status, error_kind, count
+            );
+        }
+        out
+    }
+}
+
+/// Records the latency of every request handled by `route` under `name`.
+/// Requests the route turns away as not found (i.e. those meant for
+/// other routes) aren't counted.
+pub fn timed<F, T>(
+    name: &'static str,
+    route: F,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Future: Send,
+    T: Reply + Send + 'static,
+{
+    let histogram: Arc<Histogram> = METRICS.histogram(name);
+    warp::any()
+        .map(Instant::now)
+        .and(
+            route
+                .map(|reply: T| -> std::result::Result<T, Rejection> { Ok(reply) })
+                .or_else(
+                    |rejection: Rejection| async move { Ok::<_, Rejection>((Err(rejection),)) },
+                ),
+        )
+        .and_then(
+            move |start: Instant, result: std::result::Result<T, Rejection>| {
+                let histogram: Arc<Histogram> = histogram.clone();
+                async move {
+                    match result {
+                        Ok(reply) => {
+                            histogram.observe(start.elapsed());
+                            Ok(reply)
+                        }
+                        Err(rejection) => {
+                            if !rejection.is_not_found() {
+                                histogram.observe(start.elapsed());
+                            }
+                            Err(rejection)
+                        }
+                    }
+                }
+            },
+        )
+}
@@ -0,0 +1,159 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::config::Config;
+use crate::db::{MailState, OutboxMail, DB};
+use crate::error::Error;
+use crate::Result;
+use bson::oid::ObjectId;
+use chrono::{DateTime, Utc};
+use lettre::address::{Address, Envelope};
+use lettre::{Message, SmtpTransport, Transport};
+use log;
+use std::sync::Arc;
+
+/// Upper bound of the delay between two attempts to deliver a mail.
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+
+/// Days a mail is kept in the outbox after it has been sent or given up
+/// on. MongoDB removes it afterwards through a TTL index.
+const MAIL_RETENTION_DAYS: i64 = 30;
+
+/// Renders `message` into an outbox entry that is due at `now`. Used by
+/// callers that store the mail in the same transaction as the change it
+/// is about, so that neither gets lost without the other.
+pub fn render(message: &Message, now: DateTime<Utc>) -> Result<OutboxMail> {
+    let envelope: &Envelope = message.envelope();
+    let raw: String = match String::from_utf8(message.formatted()) {
+        Ok(raw) => raw,
+        Err(_) => return Err(Error::MailBuilderError),
+    };
+    Ok(OutboxMail {
+        id: ObjectId::new(),
+        from: match envelope.from() {
+            Some(from) => from.to_string(),
+            None => return Err(Error::MailBuilderError),
+        },
+        to: envelope.to().iter().map(|to| to.to_string()).collect(),
+        raw,
+        created: now,
+        state: MailState::Pending,
+        attempts: 0,
+        next_attempt_at: now,
+        claimed_at: None,
+        sent_at: None,
+        last_error: None,
+        expire_at: None,
+    })
+}
+
+/// Puts `message` into the outbox. It's delivered by the worker started
+/// with `spawn_mail_worker()`, so the caller doesn't depend on the SMTP
+/// server being reachable.
+pub async fn enqueue(db: &DB, message: &Message) -> Result<()> {
+    db.enqueue_mail(&render(message, db.clock.now())?).await
+}
+
+/// Seconds to wait after the `attempts`-th failed attempt.
+fn retry_delay_secs(attempts: u32) -> i64 {
+    (30i64 << attempts.min(16)).min(MAX_RETRY_DELAY_SECS)
+}
+
+fn send(mail: &OutboxMail) -> std::result::Result<(), String> {
+    let from: Address = match mail.from.parse() {
+        Ok(from) => from,
+        Err(e) => return Err(e.to_string()),
+    };
+    let to: Vec<Address> = match mail
+        .to
+        .iter()
+        .map(|to| to.parse::<Address>())
+        .collect::<std::result::Result<Vec<Address>, _>>()
+    {
+        Ok(to) => to,
+        Err(e) => return Err(e.to_string()),
+    };
+    let envelope: Envelope = match Envelope::new(Some(from), to) {
+        Ok(envelope) => envelope,
+        Err(e) => return Err(e.to_string()),
+    };
+    let mailer: lettre::SmtpTransport = SmtpTransport::unencrypted_localhost();
+    match mailer.send_raw(&envelope, mail.raw.as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn deliver(db: &DB, mail: OutboxMail, max_attempts: u32) -> Result<()> {
+    let id: ObjectId = mail.id;
+    let attempts: u32 = mail.attempts;
+    let result = match tokio::task::spawn_blocking(move || send(&mail)).await {
+        Ok(result) => result,
+        Err(e) => Err(e.to_string()),
+    };
+    let expire_at: DateTime<Utc> = db.clock.now() + chrono::Duration::days(MAIL_RETENTION_DAYS);
+    match result {
+        Ok(()) => db.mark_mail_sent(&id, expire_at).await,
+        Err(e) => {
+            let retry_at: Option<DateTime<Utc>> = match attempts < max_attempts {
+                true => {
+                    Some(db.clock.now() + chrono::Duration::seconds(retry_delay_secs(attempts)))
+                }
+                false => None,
+            };
+            match retry_at {
+                Some(retry_at) => log::warn!(
+                    "sending mail {} failed (attempt {}), retrying at {}: {}",
+                    id,
+                    attempts,
+                    retry_at,
+                    &e
+                ),
+                None => log::error!(
+                    "sending mail {} failed {} times, giving up: {}",
+                    id,
+                    attempts,
+                    &e
+                ),
+            }
+            db.mark_mail_failed(&id, &e, retry_at, expire_at).await
+        }
+    }
+}
+
+/// Delivers queued mails in the background until the server exits.
+/// Mails that have been in `Sending` for longer than
+/// `Config::mail_sending_timeout` may or may not have gone out; they are
+/// tried again, so a recipient may get such a mail twice.
+pub fn spawn_mail_worker(db: DB, config: Arc<Config>) {
+    let sending_timeout: chrono::Duration =
+        chrono::Duration::seconds(config.mail_sending_timeout.as_secs() as i64);
+    tokio::spawn(async move {
+        match db.count_mails_in_flight().await {
+            Ok(0) => (),
+            Ok(count) => log::warn!(
+                "{} mail(s) were being sent when the server stopped; they are retried after {} seconds",
+                count,
+                sending_timeout.num_seconds()
+            ),
+            Err(e) => log::error!("Error: cannot inspect mail outbox: {}", e),
+        }
+        loop {
+            let now: DateTime<Utc> = db.clock.now();
+            let stuck_before: DateTime<Utc> = now - sending_timeout;
+            match db.claim_due_mail(now, stuck_before).await {
+                Ok(Some(mail)) => {
+                    if let Err(e) = deliver(&db, mail, config.mail_max_attempts).await {
+                        log::error!("Error: cannot update mail outbox: {}", e);
+                    }
+                }
+                Ok(None) => tokio::time::sleep(config.mail_poll_interval).await,
+                Err(e) => {
+                    log::error!("Error: cannot read mail outbox: {}", e);
+                    tokio::time::sleep(config.mail_poll_interval).await;
+                }
+            }
+        }
+    });
+}
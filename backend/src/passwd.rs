@@ -1,19 +1,88 @@
 use crate::error::Error;
 use argon2::{self, Config, ThreadMode, Variant, Version};
+use lazy_static::lazy_static;
 use rand;
+use serde::Serialize;
+use std::env;
 
 pub struct Password {}
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Cost parameters for new password hashes, tunable via `ARGON2_MEM_COST`
+/// (KiB), `ARGON2_TIME_COST` and `ARGON2_LANES`. Existing hashes keep the
+/// parameters they were created with.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Argon2Params {
+    pub mem_cost: u32,
+    pub time_cost: u32,
+    pub lanes: u32,
+}
+
+lazy_static! {
+    pub static ref ARGON2_PARAMS: Argon2Params = Argon2Params::from_env().unwrap_or_default();
+    /// hash of no one's password, verified against when there is no user,
+    /// so that the response takes as long as for a wrong password
+    static ref DUMMY_HASH: String = Password::hash(&"labyrinth".to_string()).unwrap_or_default();
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Argon2Params {
+            mem_cost: 65536,
+            time_cost: 10,
+            lanes: 4,
+        }
+    }
+}
+
+impl Argon2Params {
+    /// Reads the parameters from the environment, returning a description
+    /// of every invalid one.
+    pub fn from_env() -> std::result::Result<Argon2Params, Vec<String>> {
+        let defaults: Argon2Params = Argon2Params::default();
+        let mut problems: Vec<String> = Vec::new();
+        let mut read = |name: &str, default: u32| -> u32 {
+            match env::var(name) {
+                Ok(value) => match value.parse() {
+                    Ok(value) => value,
+                    Err(_) => {
+                        problems.push(format!("{} = {:?} is not valid", name, &value));
+                        default
+                    }
+                },
+                Err(_) => default,
+            }
+        };
+        let params = Argon2Params {
+            mem_cost: read("ARGON2_MEM_COST", defaults.mem_cost),
+            time_cost: read("ARGON2_TIME_COST", defaults.time_cost),
+            lanes: read("ARGON2_LANES", defaults.lanes),
+        };
+        if params.time_cost < 1 {
+            problems.push("ARGON2_TIME_COST must be at least 1".to_string());
+        }
+        if params.lanes < 1 || params.lanes > 64 {
+            problems.push("ARGON2_LANES must be between 1 and 64".to_string());
+        }
+        if params.mem_cost < 8 * params.lanes.max(1) {
+            problems.push("ARGON2_MEM_COST must be at least 8 KiB per lane".to_string());
+        }
+        match problems.is_empty() {
+            true => Ok(params),
+            false => Err(problems),
+        }
+    }
+}
+
 impl Password {
     pub fn hash(password: &String) -> Result<String> {
         let config: argon2::Config = Config {
             variant: Variant::Argon2i,
             version: Version::Version13,
-            mem_cost: 65536,
-            time_cost: 10,
-            lanes: 4,
+            mem_cost: ARGON2_PARAMS.mem_cost,
+            time_cost: ARGON2_PARAMS.time_cost,
+            lanes: ARGON2_PARAMS.lanes,
             thread_mode: ThreadMode::Parallel,
             secret: &[],
             ad: &[],
@@ -31,4 +100,8 @@ impl Password {
             Err(_) => return Err(Error::HashingError),
         }
     }
+    /// Spends as much time as checking `password` against a real hash.
+    pub fn dummy_verify(password: &String) {
+        let _ = Password::matches(&DUMMY_HASH, password);
+    }
 }
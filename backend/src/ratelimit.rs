@@ -0,0 +1,141 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::clock::SharedClock;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::Filter;
+
+/// Simple sliding-window rate limiter. Allows at most `max_requests` hits
+/// per `window` for each combination of client IP and account, so that
+/// nobody can use up the budget of someone else's account from another
+/// address. Times come from the same clock as everything else, see
+/// `clock::Clock`.
+pub struct RateLimiter {
+    pub max_requests: usize,
+    pub window: chrono::Duration,
+    clock: SharedClock,
+    hits: HashMap<(String, String), VecDeque<DateTime<Utc>>>,
+    /// when keys without hits in the current window were last dropped
+    last_sweep: DateTime<Utc>,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests: usize, window: Duration, clock: SharedClock) -> RateLimiter {
+        let last_sweep: DateTime<Utc> = clock.now();
+        RateLimiter {
+            max_requests,
+            window: chrono::Duration::from_std(window).unwrap_or(chrono::Duration::max_value()),
+            clock,
+            hits: HashMap::new(),
+            last_sweep,
+        }
+    }
+
+    /// Registers a hit for `account` from `ip` and returns `false` if the
+    /// pair has exceeded its budget within the current window. Pairs that
+    /// haven't been seen for a whole window are forgotten, at most once per
+    /// window, so that the map doesn't grow with every address ever seen.
+    pub fn check(&mut self, ip: &str, account: &str) -> bool {
+        let now: DateTime<Utc> = self.clock.now();
+        let window_start: DateTime<Utc> = now - self.window;
+        if self.last_sweep <= window_start {
+            self.hits
+                .retain(|_, hits| hits.back().map_or(false, |t| *t > window_start));
+            self.last_sweep = now;
+        }
+        let hits: &mut VecDeque<DateTime<Utc>> = self
+            .hits
+            .entry((ip.to_string(), account.to_string()))
+            .or_default();
+        while hits.front().map_or(false, |t| *t <= window_start) {
+            hits.pop_front();
+        }
+        if hits.len() >= self.max_requests {
+            return false;
+        }
+        hits.push_back(now);
+        true
+    }
+
+    /// Number of (IP, account) pairs currently remembered.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.hits.len()
+    }
+}
+
+pub type SharedRateLimiter = Arc<Mutex<RateLimiter>>;
+
+pub fn with_rate_limiter(
+    limiter: SharedRateLimiter,
+) -> impl Filter<Extract = (SharedRateLimiter,), Error = Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn limiter(max_requests: usize, window_secs: u64) -> (RateLimiter, Arc<MockClock>) {
+        let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2022-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let clock: Arc<MockClock> = Arc::new(MockClock::new(start));
+        let limiter = RateLimiter::new(
+            max_requests,
+            Duration::from_secs(window_secs),
+            clock.clone(),
+        );
+        (limiter, clock)
+    }
+
+    #[test]
+    fn rejects_hits_beyond_the_budget() {
+        let (mut limiter, _) = limiter(3, 60);
+        assert!(limiter.check("10.0.0.1", "alice"));
+        assert!(limiter.check("10.0.0.1", "alice"));
+        assert!(limiter.check("10.0.0.1", "alice"));
+        assert!(!limiter.check("10.0.0.1", "alice"));
+    }
+
+    #[test]
+    fn window_slides() {
+        let (mut limiter, clock) = limiter(2, 60);
+        assert!(limiter.check("10.0.0.1", "alice"));
+        clock.advance(chrono::Duration::seconds(30));
+        assert!(limiter.check("10.0.0.1", "alice"));
+        assert!(!limiter.check("10.0.0.1", "alice"));
+        // the first hit leaves the window
+        clock.advance(chrono::Duration::seconds(30));
+        assert!(limiter.check("10.0.0.1", "alice"));
+        assert!(!limiter.check("10.0.0.1", "alice"));
+        clock.advance(chrono::Duration::seconds(61));
+        assert!(limiter.check("10.0.0.1", "alice"));
+    }
+
+    #[test]
+    fn budgets_are_per_ip_and_account() {
+        let (mut limiter, _) = limiter(1, 60);
+        assert!(limiter.check("10.0.0.1", "alice"));
+        assert!(!limiter.check("10.0.0.1", "alice"));
+        assert!(limiter.check("10.0.0.2", "alice"));
+        assert!(limiter.check("10.0.0.1", "bob"));
+    }
+
+    #[test]
+    fn forgets_idle_pairs() {
+        let (mut limiter, clock) = limiter(5, 60);
+        limiter.check("10.0.0.1", "alice");
+        limiter.check("10.0.0.2", "bob");
+        assert_eq!(limiter.len(), 2);
+        clock.advance(chrono::Duration::seconds(61));
+        limiter.check("10.0.0.3", "carol");
+        assert_eq!(limiter.len(), 1);
+    }
+}
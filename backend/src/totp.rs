@@ -0,0 +1,129 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::env;
+use totp_lite::{totp_custom, Sha1};
+
+/// TOTP settings, tunable via `TOTP_INTERVAL` (seconds), `TOTP_DIGITS` and
+/// `TOTP_SKEW_STEPS`. Changing the interval or the number of digits
+/// invalidates the setup of every authenticator app out there, so they
+/// should be chosen once and left alone.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct TotpParams {
+    pub interval: u32,
+    pub digits: u32,
+    /// intervals a code may be early or late, for device clocks that are
+    /// slightly off
+    pub skew_steps: u32,
+}
+
+lazy_static! {
+    pub static ref TOTP_PARAMS: TotpParams = TotpParams::from_env().unwrap_or_default();
+}
+
+impl Default for TotpParams {
+    fn default() -> Self {
+        TotpParams {
+            interval: 30,
+            digits: 6,
+            skew_steps: 1,
+        }
+    }
+}
+
+impl TotpParams {
+    /// Reads the parameters from the environment, returning a description
+    /// of every invalid one.
+    pub fn from_env() -> std::result::Result<TotpParams, Vec<String>> {
+        let defaults: TotpParams = TotpParams::default();
+        let mut problems: Vec<String> = Vec::new();
+        let mut read = |name: &str, default: u32| -> u32 {
+            match env::var(name) {
+                Ok(value) => match value.parse() {
+                    Ok(value) => value,
+                    Err(_) => {
+                        problems.push(format!("{} = {:?} is not valid", name, &value));
+                        default
+                    }
+                },
+                Err(_) => default,
+            }
+        };
+        let params = TotpParams {
+            interval: read("TOTP_INTERVAL", defaults.interval),
+            digits: read("TOTP_DIGITS", defaults.digits),
+            skew_steps: read("TOTP_SKEW_STEPS", defaults.skew_steps),
+        };
+        if params.interval < 1 {
+            problems.push("TOTP_INTERVAL must be at least 1".to_string());
+        }
+        // the range authenticator apps commonly support
+        if params.digits < 6 || params.digits > 8 {
+            problems.push("TOTP_DIGITS must be between 6 and 8".to_string());
+        }
+        if params.skew_steps > 10 {
+            problems.push("TOTP_SKEW_STEPS must not exceed 10".to_string());
+        }
+        match problems.is_empty() {
+            true => Ok(params),
+            false => Err(problems),
+        }
+    }
+
+    /// Returns the code for `key` that is valid at `seconds` since the epoch.
+    pub fn code(&self, key: &[u8], seconds: u64) -> String {
+        totp_custom::<Sha1>(self.interval as u64, self.digits, key, seconds)
+    }
+}
+
+/// Checks `code` against the codes for `key` that are valid up to
+/// `skew_steps` intervals before or after `seconds` since the epoch.
+pub fn verify_totp(key: &[u8], code: &str, skew_steps: i64, seconds: u64) -> bool {
+    let interval: i64 = TOTP_PARAMS.interval as i64;
+    (-skew_steps..=skew_steps).any(|step| {
+        let time: i64 = seconds as i64 + step * interval;
+        time >= 0 && TOTP_PARAMS.code(key, time as u64) == code
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the SHA-1 secret from the test vectors in RFC 6238
+    const KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn codes_match_rfc_6238() {
+        let params = TotpParams {
+            interval: 30,
+            digits: 8,
+            skew_steps: 1,
+        };
+        assert_eq!(params.code(KEY, 59), "94287082");
+        assert_eq!(params.code(KEY, 1111111109), "07081804");
+        assert_eq!(params.code(KEY, 2000000000), "69279037");
+    }
+
+    #[test]
+    fn verify_accepts_codes_within_skew() {
+        let interval: u64 = TOTP_PARAMS.interval as u64;
+        let now: u64 = 1111111109;
+        let code: String = TOTP_PARAMS.code(KEY, now);
+        assert!(verify_totp(KEY, &code, 0, now));
+        assert!(verify_totp(KEY, &code, 1, now + interval));
+        assert!(verify_totp(KEY, &code, 1, now - interval));
+        assert!(!verify_totp(KEY, &code, 0, now + interval));
+        assert!(!verify_totp(KEY, &code, 1, now + 2 * interval));
+        assert!(!verify_totp(b"another secret", &code, 1, now));
+    }
+
+    #[test]
+    fn verify_does_not_look_before_the_epoch() {
+        let code: String = TOTP_PARAMS.code(KEY, 0);
+        assert!(verify_totp(KEY, &code, 10, 0));
+    }
+}
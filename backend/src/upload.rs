@@ -0,0 +1,330 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use crate::db::UploadedFile;
+use crate::error::Error;
+use crate::Result;
+use bson::oid::ObjectId;
+use futures::stream::TryStreamExt;
+use hyper::body::Buf;
+use log;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use warp::multipart::{FormData, Part};
+
+/// Bytes read from disk at a time when streaming a stored file.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The only types accepted for upload, with the extensions they may be
+/// uploaded with. Files are served from the upload folder as they are, so
+/// anything a browser might run, like HTML or SVG, is kept out.
+const ALLOWED_TYPES: [(&str, &[&str]); 4] = [
+    ("image/png", &["png"]),
+    ("image/jpeg", &["jpg", "jpeg"]),
+    ("image/gif", &["gif"]),
+    ("image/webp", &["webp"]),
+];
+
+/// Returns the extension of `name`, lowercased.
+fn extension_of(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Returns the allowed type files with the extension of `name` have.
+pub fn mime_type_of(name: &str) -> Option<&'static str> {
+    let extension: String = extension_of(name);
+    ALLOWED_TYPES
+        .iter()
+        .find(|(_, extensions)| extensions.contains(&extension.as_str()))
+        .map(|(mime_type, _)| *mime_type)
+}
+
+/// Tells the allowed type of a file by its first bytes.
+fn sniff_mime_type(head: &[u8]) -> Option<&'static str> {
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if head.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Reads the type and the size of a stored image. Blocks, so it's run
+/// with `spawn_blocking()`.
+fn inspect(path: &Path) -> std::io::Result<(Option<&'static str>, Option<imagesize::ImageSize>)> {
+    let mut head: Vec<u8> = Vec::with_capacity(12);
+    std::fs::File::open(path)?.take(12).read_to_end(&mut head)?;
+    let size: Option<imagesize::ImageSize> = match imagesize::size(path) {
+        Ok(size) => Some(size),
+        Err(e) => {
+            log::warn!("cannot determine size of image {:?}: {:?}", path, e);
+            None
+        }
+    };
+    Ok((sniff_mime_type(&head), size))
+}
+
+/// Tells whether `name` stays inside the upload folder when joined to it:
+/// no path separators, no `..`, no hidden files.
+pub fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains("..")
+        && !name.contains(|c: char| c == '/' || c == '\\' || c == '\0')
+}
+
+/// Tells whether `name` has been made by `uploaded_name()`: an ObjectId
+/// in hex plus an optional extension. Other files in the upload folder,
+/// e.g. ones put there by hand, are never deleted through the API.
+pub fn is_generated_name(name: &str) -> bool {
+    let (stem, extension): (&str, &str) = match name.split_once('.') {
+        Some((stem, extension)) => (stem, extension),
+        None => (name, ""),
+    };
+    stem.len() == 24
+        && stem
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+        && extension.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Name a file is stored under: a fresh ObjectId, so that names never
+/// collide, plus `extension`, which the web server needs to pick the
+/// right `Content-Type`.
+fn uploaded_name(extension: &str) -> String {
+    format!("{}.{}", ObjectId::new().to_hex(), extension)
+}
+
+async fn write_part(path: &Path, part: Part) -> Result<()> {
+    let mut file: tokio::fs::File = match tokio::fs::File::create(path).await {
+        Ok(file) => file,
+        Err(e) => return Err(Error::FileAccessError(e.to_string())),
+    };
+    let stream = part.stream();
+    futures::pin_mut!(stream);
+    loop {
+        let chunk = match stream.try_next().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => return Err(Error::UploadError(e.to_string())),
+        };
+        if let Err(e) = file.write_all(chunk.chunk()).await {
+            return Err(Error::FileAccessError(e.to_string()));
+        }
+    }
+    match file.flush().await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error::FileAccessError(e.to_string())),
+    }
+}
+
+/// Checks the stored file at `path` against `mime_type`, which was
+/// derived from its name, and measures it.
+async fn check_stored(path: &Path, mime_type: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let path_buf: PathBuf = path.to_path_buf();
+    let (sniffed, size) = match tokio::task::spawn_blocking(move || inspect(&path_buf)).await {
+        Ok(Ok(inspected)) => inspected,
+        Ok(Err(e)) => return Err(Error::FileAccessError(e.to_string())),
+        Err(e) => return Err(Error::FileAccessError(e.to_string())),
+    };
+    if sniffed != Some(mime_type) {
+        return Err(Error::UploadError(format!(
+            "content is not of type {}",
+            mime_type
+        )));
+    }
+    Ok(match size {
+        Some(size) => (Some(size.width as u32), Some(size.height as u32)),
+        None => (None, None),
+    })
+}
+
+async fn store_part(dir: &str, part: Part) -> Result<UploadedFile> {
+    let original_name: String = part.filename().unwrap_or("upload").to_string();
+    let mime_type: &str = match mime_type_of(&original_name) {
+        Some(mime_type) => mime_type,
+        None => {
+            return Err(Error::UploadError(format!(
+                "type of {} is not allowed",
+                &original_name
+            )))
+        }
+    };
+    let uploaded_name: String = uploaded_name(&extension_of(&original_name));
+    let path: PathBuf = Path::new(dir).join(&uploaded_name);
+    let result: Result<(Option<u32>, Option<u32>)> = match write_part(&path, part).await {
+        Ok(()) => check_stored(&path, mime_type).await,
+        Err(e) => Err(e),
+    };
+    let (width, height): (Option<u32>, Option<u32>) = match result {
+        Ok(size) => size,
+        Err(e) => {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                log::warn!("cannot remove rejected upload {:?}: {}", &path, e);
+            }
+            return Err(e);
+        }
+    };
+    Ok(UploadedFile {
+        original_name,
+        uploaded_name,
+        mime_type: mime_type.to_string(),
+        retina: None,
+        width,
+        height,
+        scale: None,
+        variants: None,
+    })
+}
+
+/// Writes the part named "file" of `form` to `dir`, chunk by chunk, and
+/// describes it for the riddle's `files`. The caller limits the size of
+/// the form.
+pub async fn store_file(dir: &str, form: FormData) -> Result<UploadedFile> {
+    futures::pin_mut!(form);
+    loop {
+        match form.try_next().await {
+            Ok(Some(part)) if part.name() == "file" => return store_part(dir, part).await,
+            Ok(Some(_)) => (),
+            Ok(None) => return Err(Error::UploadError("form has no part \"file\"".to_string())),
+            Err(e) => return Err(Error::UploadError(e.to_string())),
+        }
+    }
+}
+
+/// Deletes a stored file from `dir`.
+pub async fn remove_file(dir: &str, uploaded_name: &str) -> Result<()> {
+    if !is_safe_name(uploaded_name) {
+        return Err(Error::InvalidFileNameError(uploaded_name.to_string()));
+    }
+    match tokio::fs::remove_file(Path::new(dir).join(uploaded_name)).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error::FileAccessError(e.to_string())),
+    }
+}
+
+/// Reads a stored file from `dir` into memory as a whole.
+pub async fn read_file(dir: &str, uploaded_name: &str) -> Result<Vec<u8>> {
+    if !is_safe_name(uploaded_name) {
+        return Err(Error::InvalidFileNameError(uploaded_name.to_string()));
+    }
+    match tokio::fs::read(Path::new(dir).join(uploaded_name)).await {
+        Ok(data) => Ok(data),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(Error::RiddleFileNotFoundError(uploaded_name.to_string()))
+        }
+        Err(e) => Err(Error::FileAccessError(e.to_string())),
+    }
+}
+
+/// Opens a stored file as a response body that is read from disk chunk by
+/// chunk, and returns it with the file's length.
+pub async fn open_file(dir: &str, uploaded_name: &str) -> Result<(hyper::Body, u64)> {
+    if !is_safe_name(uploaded_name) {
+        return Err(Error::InvalidFileNameError(uploaded_name.to_string()));
+    }
+    let file: tokio::fs::File =
+        match tokio::fs::File::open(Path::new(dir).join(uploaded_name)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::RiddleFileNotFoundError(uploaded_name.to_string()))
+            }
+            Err(e) => return Err(Error::FileAccessError(e.to_string())),
+        };
+    let len: u64 = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return Err(Error::FileAccessError(e.to_string())),
+    };
+    let chunks = futures::stream::try_unfold(file, |mut file| async move {
+        let mut chunk: Vec<u8> = vec![0; READ_CHUNK_SIZE];
+        let n: usize = file.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok::<_, std::io::Error>(None);
+        }
+        chunk.truncate(n);
+        Ok(Some((chunk, file)))
+    });
+    Ok((hyper::Body::wrap_stream(chunks), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_image_types() {
+        assert_eq!(
+            sniff_mime_type(b"\x89PNG\r\n\x1a\n\0\0\0\x0d"),
+            Some("image/png")
+        );
+        assert_eq!(
+            sniff_mime_type(&[0xff, 0xd8, 0xff, 0xe0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_mime_type(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff_mime_type(b"RIFF\0\0\0\0WEBP"), Some("image/webp"));
+    }
+
+    #[test]
+    fn does_not_sniff_other_content() {
+        assert_eq!(sniff_mime_type(b""), None);
+        assert_eq!(sniff_mime_type(b"<svg xmlns="), None);
+        // a RIFF container that is not a WebP image, e.g. a WAV file
+        assert_eq!(sniff_mime_type(b"RIFF\0\0\0\0WAVE"), None);
+        assert_eq!(sniff_mime_type(b"RIFF\0\0\0\0WEB"), None);
+    }
+
+    #[test]
+    fn safe_names_stay_in_the_upload_folder() {
+        assert!(is_safe_name("map.png"));
+        assert!(!is_safe_name(""));
+        assert!(!is_safe_name(".htaccess"));
+        assert!(!is_safe_name("../secret"));
+        assert!(!is_safe_name("a..b"));
+        assert!(!is_safe_name("sub/map.png"));
+        assert!(!is_safe_name("sub\\map.png"));
+        assert!(!is_safe_name("map\0.png"));
+    }
+
+    #[test]
+    fn generated_names_are_recognized() {
+        let name: String = uploaded_name("png");
+        assert!(is_generated_name(&name));
+        assert!(is_generated_name(&ObjectId::new().to_hex()));
+        assert!(!is_generated_name("map.png"));
+        assert!(!is_generated_name(&name.to_uppercase()));
+        assert!(!is_generated_name(&format!(
+            "{}.tar.gz",
+            ObjectId::new().to_hex()
+        )));
+    }
+
+    #[tokio::test]
+    async fn read_file_returns_the_stored_bytes() {
+        let dir = std::env::temp_dir().join(format!("labyrinth-{}", ObjectId::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("map.png"), b"\x89PNG").unwrap();
+        let dir_str: &str = dir.to_str().unwrap();
+        assert_eq!(read_file(dir_str, "map.png").await.unwrap(), b"\x89PNG");
+        assert!(matches!(
+            read_file(dir_str, "gone.png").await,
+            Err(Error::RiddleFileNotFoundError(_))
+        ));
+        assert!(matches!(
+            read_file(dir_str, "../map.png").await,
+            Err(Error::InvalidFileNameError(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -10,6 +10,8 @@ use webauthn_rs::{Webauthn, WebauthnConfig};
 type WebauthnResult<T> = core::result::Result<T, WebauthnError>;
 
 use crate::db::{User, DB};
+use crate::error::Error;
+use crate::Result;
 
 pub struct WebauthnVolatileConfig {
     pub rp_name: String,
@@ -79,7 +81,6 @@ impl WebauthnVolatileConfig {
         rp_id: &str,
         attachment: Option<AuthenticatorAttachment>,
     ) -> Self {
-        dbg!(rp_origin);
         WebauthnVolatileConfig {
             rp_name: rp_name.to_string(),
             rp_id: rp_id.to_string(),
@@ -105,7 +106,7 @@ impl WebauthnActor {
         db: &mut DB,
         username: &String,
     ) -> WebauthnResult<CreationChallengeResponse> {
-        println!("handle challenge_register -> {:?}", &username);
+        log::info!("challenge_register(); username = {}", username);
         let user: User = match db.get_user(username).await {
             Ok(user) => user,
             Err(_) => return Err(WebauthnError::UserNotPresent),
@@ -133,7 +134,7 @@ impl WebauthnActor {
             Ok(()) => (),
             Err(_) => return Err(WebauthnError::ChallengePersistenceError),
         }
-        println!("complete challenge_register -> {:?}", &ccr);
+        log::debug!("challenge_register(); ccr = {:?}", &ccr);
         Ok(ccr)
     }
 
@@ -143,10 +144,8 @@ impl WebauthnActor {
         username: &String,
         reg: &RegisterPublicKeyCredential,
     ) -> WebauthnResult<()> {
-        println!(
-            "handle register -> (username: {:?}, reg: {:?})",
-            username, reg
-        );
+        log::info!("register(); username = {}", username);
+        log::debug!("register(); reg = {:?}", reg);
         // TODO: query only required fields
         let user = match db.get_user(&username).await {
             Ok(user) => user,
@@ -159,21 +158,17 @@ impl WebauthnActor {
         let mut ucreds: Vec<Credential> = user.webauthn.credentials;
         match self
             .wan
-            .register_credential(reg, &rs, |cred_id| {
-                dbg!(&cred_id);
-                Ok(false)
-            })
+            .register_credential(reg, &rs, |_| Ok(false))
             .map(|cred| {
                 ucreds.push(cred.0);
             }) {
             Ok(()) => (),
-            Err(e) => println!("Error: {:?}", e),
+            Err(e) => log::error!("Error: {:?}", e),
         }
         match db.save_webauthn_registration(username, &ucreds).await {
             Ok(()) => (),
-            Err(e) => println!("Error: {:?}", e),
+            Err(e) => log::error!("Error: {:?}", e),
         }
-        println!("complete register");
         Ok(())
     }
 
@@ -182,7 +177,7 @@ impl WebauthnActor {
         db: &mut DB,
         username: &String,
     ) -> WebauthnResult<RequestChallengeResponse> {
-        println!("handle challenge_authenticate -> {:?}", &username);
+        log::info!("challenge_authenticate(); username = {}", username);
         // TODO: query only required fields
         let user: User = match db.get_user(&username).await {
             Ok(user) => user,
@@ -200,9 +195,9 @@ impl WebauthnActor {
             .generate_challenge_authenticate_options(creds, Some(exts))?;
         match db.save_webauthn_authentication_state(username, &st).await {
             Ok(()) => (),
-            Err(e) => println!("Error: {:?}", e),
+            Err(_) => return Err(WebauthnError::ChallengePersistenceError),
         }
-        println!("complete challenge_authenticate -> {:?}", &acr);
+        log::debug!("challenge_authenticate(); acr = {:?}", &acr);
         Ok(acr)
     }
 
@@ -211,29 +206,31 @@ impl WebauthnActor {
         db: &mut DB,
         user: &User,
         lgn: &PublicKeyCredential,
-    ) -> WebauthnResult<()> {
-        println!(
-            "handle authenticate -> (username: {:?}, lgn: {:?})",
-            user.username, lgn
-        );
+    ) -> Result<()> {
+        log::info!("authenticate(); username = {}", &user.username);
+        log::debug!("authenticate(); lgn = {:?}", lgn);
         let st = match user.webauthn.authentication_state {
             Some(ref st) => st,
-            None => return Err(WebauthnError::ChallengeNotFound),
+            None => return Err(Error::WebauthnError),
         };
+        // a challenge can be answered only once
+        match db.clear_webauthn_authentication_state(&user.username).await {
+            Ok(()) => (),
+            Err(_) => return Err(Error::WebauthnError),
+        }
         match self.wan.authenticate_credential(lgn, &st) {
             Ok((cred_id, auth_data)) => {
-                dbg!(&cred_id, &auth_data);
                 match db
                     .update_webauthn_cred(&user.username, cred_id, &auth_data)
                     .await
                 {
                     Ok(()) => (),
-                    Err(_) => return Err(WebauthnError::CredentialPersistenceError),
+                    Err(e @ Error::CredentialCounterRegressionError) => return Err(e),
+                    Err(_) => return Err(Error::WebauthnError),
                 }
             }
-            Err(_) => return Err(WebauthnError::AuthenticationFailure),
+            Err(_) => return Err(Error::WebauthnError),
         }
-        println!("complete authenticate");
         Ok(())
     }
 }
@@ -0,0 +1,60 @@
+/**
+ * Copyright (c) 2022 Oliver Lau <oliver@ersatzworld.net>
+ * All rights reserved.
+ */
+use bson::oid::ObjectId;
+use chrono::{serde::ts_seconds, DateTime, Utc};
+use lazy_static::lazy_static;
+use log;
+use serde::Serialize;
+use std::env;
+use std::time::Duration;
+
+lazy_static! {
+    /// URL to POST to whenever a user steps through an exit; unset disables
+    /// the notification.
+    static ref GAME_COMPLETED_WEBHOOK_URL: Option<String> =
+        env::var("GAME_COMPLETED_WEBHOOK_URL").ok().filter(|url| !url.is_empty());
+    static ref WEBHOOK_TIMEOUT: Duration = Duration::from_millis(
+        env::var("WEBHOOK_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5000)
+    );
+}
+
+#[derive(Serialize, Debug)]
+pub struct GameCompletedPayload {
+    pub username: String,
+    pub game_id: ObjectId,
+    pub score: i32,
+    #[serde(with = "ts_seconds")]
+    pub finished_at: DateTime<Utc>,
+}
+
+/// Sends `payload` to `GAME_COMPLETED_WEBHOOK_URL` in the background.
+/// Never blocks the caller; failures are only logged.
+pub fn notify_game_completed(payload: GameCompletedPayload) {
+    let url: String = match GAME_COMPLETED_WEBHOOK_URL.as_ref() {
+        Some(url) => url.clone(),
+        None => return,
+    };
+    tokio::spawn(async move {
+        let client = match reqwest::Client::builder().timeout(*WEBHOOK_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => {
+                log::error!("cannot build webhook client: {:?}", e);
+                return;
+            }
+        };
+        match client.post(&url).json(&payload).send().await {
+            Ok(response) if response.status().is_success() => (),
+            Ok(response) => log::warn!(
+                "game completed webhook {} answered with {}",
+                &url,
+                response.status()
+            ),
+            Err(e) => log::warn!("game completed webhook {} failed: {:?}", &url, e),
+        }
+    });
+}
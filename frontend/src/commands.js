@@ -431,7 +431,8 @@ const COMMANDS = [
                     }
                     switch (subcommand.toLowerCase()) {
                         case 'totp':
-                            const totp_enable_reply = await authenticatedRequest(Game.URL.USER.TOTP.ENABLE, 'POST')
+                            const password = await this.getInput(tr('Passwort: '), { password: true, match: RE.PASSWORD });
+                            const totp_enable_reply = await authenticatedRequest(Game.URL.USER.TOTP.ENABLE, 'POST', {password})
                             .then(response => response.json());
                             if (totp_enable_reply.totp) {
                                 this.print(tr('\nHier ist dein <a href="https://www.bsi.bund.de/DE/Themen/Verbraucherinnen-und-Verbraucher/Informationen-und-Empfehlungen/Cyber-Sicherheitsempfehlungen/Accountschutz/Zwei-Faktor-Authentisierung/zwei-faktor-authentisierung_node.html" target="_blank">zweiter Faktor</a> als QR-Code für <a href="https://de.wikipedia.org/wiki/Time-based_One-time_Password_Algorithmus" target="_blank">TOTP</a>-Generatoren wie <a href="https://authy.com/" targer="_blank">Authy</a> oder <a href="https://de.wikipedia.org/wiki/Google_Authenticator" target="_blank">Google Authenticator</a>:'));
@@ -655,11 +656,11 @@ const COMMANDS = [
         fn: async function() {
             const reply = await authenticatedRequest(constructURL(Game.URL.GAME.HIGHSCORES, {gameid: this.user.in_room.game_id.$oid}), 'GET')
             .then(response => response.json());
-            if (reply.ok && reply.highscores instanceof Array) {
+            if (reply.ok && reply.items instanceof Array) {
                 this.print(tr(`<b>Name                                Score        Punkte/h</b>`));
                 this.print(`---------------------------------------------------------`);
-                reply.highscores.sort((a, b) => a.relScore > b.relScore ? -1 : a.relScore < b.relScore ? 1 : a.absScore > b.absScore ? -1 : a.absScore < b.absScore ? 1 : 0);
-                for (const user of reply.highscores) {
+                reply.items.sort((a, b) => a.relScore > b.relScore ? -1 : a.relScore < b.relScore ? 1 : a.absScore > b.absScore ? -1 : a.absScore < b.absScore ? 1 : 0);
+                for (const user of reply.items) {
                     this.print(`${user.username.substring(0, 30).padEnd(30)} ${user.absScore.toString().padStart(10, ' ')} ${user.relScore.toFixed(2).padStart(15, ' ')}`);
                 }
                 return Promise.resolve();